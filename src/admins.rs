@@ -0,0 +1,85 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Admins added from the web admin, in addition to the ones listed in
+//! config.yml. The IRC loop keeps them in memory and reloads them on
+//! `ClientQuery::ReloadAdmins`.
+
+use rusqlite::{named_params, Connection, Result};
+use std::collections::HashMap;
+
+use crate::db;
+
+pub const DB: db::Database = db::Database {
+    name: "admins.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS admins (
+            id INTEGER PRIMARY KEY,
+            network TEXT NOT NULL,
+            mask TEXT NOT NULL,
+            UNIQUE(network, mask) ON CONFLICT IGNORE
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Masks by network
+pub fn get_all(conn: &Connection) -> Result<HashMap<String, Vec<String>>> {
+    let mut statement = conn.prepare("SELECT network, mask FROM admins ORDER BY id")?;
+    let mut rows = statement.query([])?;
+
+    let mut admins: HashMap<String, Vec<String>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        admins.entry(row.get(0)?).or_default().push(row.get(1)?);
+    }
+
+    Ok(admins)
+}
+
+pub fn add(conn: &Connection, network: &str, mask: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO admins (network, mask) VALUES (:network, :mask)",
+        named_params! {":network": network, ":mask": mask},
+    )?;
+
+    Ok(())
+}
+
+/// Returns whether the mask was an admin
+pub fn remove(conn: &Connection, network: &str, mask: &str) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM admins WHERE network = :network AND mask = :mask",
+        named_params! {":network": network, ":mask": mask},
+    )?;
+
+    Ok(removed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_remove() {
+        let conn = db::open(&DB, true).unwrap();
+        let mask = "owner!owner@example.com";
+
+        add(&conn, "IRCnet", mask).unwrap();
+        add(&conn, "IRCnet", mask).unwrap();
+        add(&conn, "QuakeNet", mask).unwrap();
+        let admins = get_all(&conn).unwrap();
+        assert_eq!(admins["IRCnet"], vec![mask.to_owned()]);
+        assert_eq!(admins.len(), 2);
+
+        assert_eq!(remove(&conn, "IRCnet", mask), Ok(true));
+        assert_eq!(remove(&conn, "IRCnet", mask), Ok(false));
+        assert!(!get_all(&conn).unwrap().contains_key("IRCnet"));
+    }
+}
@@ -0,0 +1,326 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use log::{debug, error};
+use reqwest::header::CONTENT_TYPE;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::blitzortung::{distance_km, lookup_place};
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const TANKILLE_URL: &str = "https://api.tankille.fi";
+const DEFAULT_RADIUS_KM: f64 = 10.0;
+const CACHE_MINUTES: i64 = 15;
+// Access tokens are refreshed well before they expire
+const TOKEN_MINUTES: i64 = 30;
+// Prices that nobody has reported for a week are ignored
+const MAX_PRICE_AGE_DAYS: i64 = 7;
+
+#[derive(Clone, Debug, PartialEq)]
+struct FuelPrice {
+    fuel: String,
+    price: f64,
+    updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct FuelStation {
+    name: String,
+    lat: f64,
+    lon: f64,
+    prices: Vec<FuelPrice>,
+}
+
+struct CachedStations {
+    stations: Vec<FuelStation>,
+    fetched: DateTime<Utc>,
+}
+
+lazy_static! {
+    static ref ACCESS_TOKEN: Mutex<Option<(String, DateTime<Utc>)>> = Mutex::new(None);
+    static ref STATION_CACHE: Mutex<HashMap<String, CachedStations>> = Mutex::new(HashMap::new());
+}
+
+async fn post_json(path: &str, body: serde_json::Value) -> Result<serde_json::Value, String> {
+    let request = HTTP_CLIENT
+        .post(format!("{}{}", TANKILLE_URL, path))
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    let text = http_client::send(request)
+        .await
+        .and_then(|r| r.error_for_status());
+    let text = match text {
+        Ok(r) => r.text().await.map_err(|e| e.to_string())?,
+        Err(e) => {
+            return Err(e.to_string());
+        }
+    };
+
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+/// Logs in with the account in `bensa: email/password`
+async fn access_token(email: &str, password: &str) -> Result<String, String> {
+    let now = Utc::now();
+    if let Some((token, fetched)) = ACCESS_TOKEN.lock().unwrap().as_ref() {
+        if now - *fetched < chrono::Duration::minutes(TOKEN_MINUTES) {
+            return Ok(token.to_owned());
+        }
+    }
+
+    let login = serde_json::json!({ "device": "T-botti", "email": email, "password": password });
+    let refresh_token = post_json("/auth/login", login).await?["refreshToken"]
+        .as_str()
+        .ok_or("No refresh token")?
+        .to_owned();
+    let token = post_json(
+        "/auth/refresh",
+        serde_json::json!({ "token": refresh_token }),
+    )
+    .await?["accessToken"]
+        .as_str()
+        .ok_or("No access token")?
+        .to_owned();
+
+    *ACCESS_TOKEN.lock().unwrap() = Some((token.to_owned(), now));
+    Ok(token)
+}
+
+async fn get_stations_json(
+    token: &str,
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT
+        .get(format!("{}/stations", TANKILLE_URL))
+        .header("x-access-token", token)
+        .query(&[
+            ("location", format!("{},{}", lon, lat)),
+            ("distance", format!("{:.0}", radius_km * 1000.0)),
+        ]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+fn parse_stations(json_text: &str) -> Result<Vec<FuelStation>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let stations = json
+        .as_array()
+        .ok_or_else(|| "Error parsing JSON".to_owned())?
+        .iter()
+        .filter_map(|s| {
+            let prices = s["price"]
+                .as_array()?
+                .iter()
+                .filter_map(|p| {
+                    Some(FuelPrice {
+                        fuel: p["tag"].as_str()?.to_owned(),
+                        price: p["price"].as_f64()?,
+                        updated: p["updated"].as_str()?.parse().ok()?,
+                    })
+                })
+                .collect();
+            Some(FuelStation {
+                name: s["name"].as_str()?.to_owned(),
+                lon: s["loc"]["coordinates"][0].as_f64()?,
+                lat: s["loc"]["coordinates"][1].as_f64()?,
+                prices,
+            })
+        })
+        .collect();
+
+    Ok(stations)
+}
+
+/// The cheapest recent price of `fuel` with the station and its distance
+fn cheapest<'a>(
+    stations: &'a [FuelStation],
+    fuel: &str,
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+    now: DateTime<Utc>,
+) -> Option<(&'a FuelStation, f64, f64)> {
+    stations
+        .iter()
+        .filter_map(|s| {
+            let price = s.prices.iter().find(|p| {
+                p.fuel == fuel && now - p.updated < chrono::Duration::days(MAX_PRICE_AGE_DAYS)
+            })?;
+            let distance = distance_km(lat, lon, s.lat, s.lon);
+            if distance > radius_km {
+                return None;
+            }
+            Some((s, price.price, distance))
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1).then(a.2.total_cmp(&b.2)))
+}
+
+fn generate_msg(
+    place: &str,
+    stations: &[FuelStation],
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+    now: DateTime<Utc>,
+) -> String {
+    let parts: Vec<String> = [("95", "95E10"), ("dsl", "Diesel")]
+        .iter()
+        .filter_map(|(fuel, name)| {
+            let (station, price, distance) = cheapest(stations, fuel, lat, lon, radius_km, now)?;
+            Some(format!(
+                "{}: {:.3} € {} ({:.1} km)",
+                name, price, station.name, distance
+            ))
+        })
+        .collect();
+
+    if parts.is_empty() {
+        format!("{}: ei hintoja {:.0} km säteellä", place, radius_km)
+    } else {
+        format!("{}: {}", place, parts.join(" | "))
+    }
+}
+
+async fn stations_near(
+    config: &Yaml,
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+) -> Result<Vec<FuelStation>, String> {
+    let now = Utc::now();
+    let key = format!("{:.2},{:.2},{}", lat, lon, radius_km);
+    {
+        let mut cache = STATION_CACHE.lock().unwrap();
+        cache.retain(|_, c| now - c.fetched < chrono::Duration::minutes(CACHE_MINUTES));
+        if let Some(c) = cache.get(&key) {
+            debug!("Fuel prices for {} from cache", key);
+            return Ok(c.stations.clone());
+        }
+    }
+
+    let (email, password) = match (
+        config["bensa"]["email"].as_str(),
+        config["bensa"]["password"].as_str(),
+    ) {
+        (Some(e), Some(p)) => (e, p),
+        _ => {
+            return Err("Polttoainehintoja ei ole asetettu".to_owned());
+        }
+    };
+
+    let token = access_token(email, password).await.map_err(|e| {
+        error!("Tankille login failed: {}", e);
+        "Hintojen haku ei onnistunut".to_owned()
+    })?;
+    let stations = match get_stations_json(&token, lat, lon, radius_km).await {
+        Ok(json) => parse_stations(&json)?,
+        Err(_) => {
+            return Err("Hintojen haku ei onnistunut".to_owned());
+        }
+    };
+
+    STATION_CACHE.lock().unwrap().insert(
+        key,
+        CachedStations {
+            stations: stations.clone(),
+            fetched: now,
+        },
+    );
+
+    Ok(stations)
+}
+
+async fn fuel_prices(config: &Yaml, query: &str) -> Result<String, String> {
+    let radius_km = config["bensa"]["radius"]
+        .as_f64()
+        .or_else(|| config["bensa"]["radius"].as_i64().map(|r| r as f64))
+        .filter(|r| *r > 0.0)
+        .unwrap_or(DEFAULT_RADIUS_KM);
+
+    let place = match lookup_place(query).await {
+        Ok(p) => p,
+        Err(_) => {
+            return Err("Paikkaa ei löytynyt".to_owned());
+        }
+    };
+    let stations = stations_near(config, place.lat, place.lon, radius_km).await?;
+
+    Ok(generate_msg(
+        &place.name,
+        &stations,
+        place.lat,
+        place.lon,
+        radius_km,
+        Utc::now(),
+    ))
+}
+
+pub async fn command_bensa(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let msg = if params.is_empty() {
+        "Usage: .bensa <paikka>".to_owned()
+    } else {
+        match fuel_prices(&config, params).await {
+            Ok(m) => m,
+            Err(e) => e,
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATIONS_JSON: &str = r###"[
+        {"_id":"1","name":"Neste Hervanta","chain":"Neste","loc":{"type":"Point","coordinates":[23.85,61.45]},"fuels":["95","98","dsl"],
+         "price":[{"tag":"95","price":1.899,"updated":"2023-06-01T08:00:00.000Z"},{"tag":"dsl","price":1.749,"updated":"2023-06-01T08:00:00.000Z"}]},
+        {"_id":"2","name":"ABC Hervanta","chain":"ABC","loc":{"type":"Point","coordinates":[23.86,61.44]},"fuels":["95","dsl"],
+         "price":[{"tag":"95","price":1.879,"updated":"2023-06-01T09:00:00.000Z"},{"tag":"dsl","price":1.699,"updated":"2023-05-01T09:00:00.000Z"}]},
+        {"_id":"3","name":"St1 Lahti","chain":"St1","loc":{"type":"Point","coordinates":[25.66,60.98]},"fuels":["95"],
+         "price":[{"tag":"95","price":1.799,"updated":"2023-06-01T09:00:00.000Z"}]}
+    ]"###;
+
+    #[test]
+    fn cheapest_prices() {
+        let stations = parse_stations(STATIONS_JSON).unwrap();
+        assert_eq!(stations.len(), 3);
+
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            generate_msg("Hervanta, Tampere", &stations, 61.45, 23.85, 10.0, now),
+            "Hervanta, Tampere: 95E10: 1.879 € ABC Hervanta (1.2 km) | \
+            Diesel: 1.749 € Neste Hervanta (0.0 km)"
+        );
+        assert_eq!(
+            generate_msg("Kuopio", &stations, 62.89, 27.68, 10.0, now),
+            "Kuopio: ei hintoja 10 km säteellä"
+        );
+    }
+}
@@ -0,0 +1,394 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use core::time::Duration;
+use irc::client::prelude::Prefix;
+use log::{error, info};
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::seen::recent_channels;
+use crate::ChatTarget;
+
+// Birthdays are congratulated in the morning on the channels the nick has
+// been on during the last month
+const GREETING_HOUR: u32 = 9;
+const ACTIVE_DAYS: i64 = 30;
+const UPCOMING_COUNT: usize = 5;
+
+#[derive(Debug, PartialEq)]
+struct Birthday {
+    network: String,
+    nick: String,
+    day: u32,
+    month: u32,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "birthdays.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS birthdays (
+            network TEXT NOT NULL,
+            nick_lower TEXT NOT NULL,
+            nick TEXT NOT NULL,
+            day INTEGER NOT NULL,
+            month INTEGER NOT NULL,
+            greeted_year INTEGER,
+            PRIMARY KEY(network, nick_lower)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// "24.6." -> (24, 6), 29.2. is allowed
+fn parse_date(date: &str) -> Option<(u32, u32)> {
+    let mut parts = date.trim().trim_end_matches('.').split('.');
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    NaiveDate::from_ymd_opt(2000, month, day)?;
+
+    Some((day, month))
+}
+
+/// The birthday in `year`, 29.2. is celebrated on 28.2. in common years
+fn date_in_year(day: u32, month: u32, year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day)
+        .unwrap_or_else(|| NaiveDate::from_ymd_opt(year, month, day - 1).unwrap())
+}
+
+fn next_birthday(day: u32, month: u32, today: NaiveDate) -> NaiveDate {
+    let date = date_in_year(day, month, today.year());
+    if date >= today {
+        date
+    } else {
+        date_in_year(day, month, today.year() + 1)
+    }
+}
+
+fn birthday_from_row(row: &rusqlite::Row) -> Result<Birthday> {
+    Ok(Birthday {
+        network: row.get(0)?,
+        nick: row.get(1)?,
+        day: row.get(2)?,
+        month: row.get(3)?,
+    })
+}
+
+fn set_birthday(conn: &Connection, network: &str, nick: &str, day: u32, month: u32) -> Result<()> {
+    conn.execute(
+        "INSERT INTO birthdays (network, nick_lower, nick, day, month)
+        VALUES (:network, :nick_lower, :nick, :day, :month)
+        ON CONFLICT(network, nick_lower) DO UPDATE SET
+            nick = :nick, day = :day, month = :month, greeted_year = NULL",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+            ":nick": nick,
+            ":day": day,
+            ":month": month,
+        },
+    )?;
+
+    Ok(())
+}
+
+fn remove_birthday(conn: &Connection, network: &str, nick: &str) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM birthdays WHERE network = :network AND nick_lower = :nick_lower",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+        },
+    )?;
+
+    Ok(removed > 0)
+}
+
+fn get_birthday(conn: &Connection, network: &str, nick: &str) -> Result<Option<Birthday>> {
+    conn.query_row(
+        "SELECT network, nick, day, month FROM birthdays
+        WHERE network = :network AND nick_lower = :nick_lower",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+        },
+        birthday_from_row,
+    )
+    .optional()
+}
+
+/// Birthdays on `today` on any network that have not been congratulated yet
+fn ungreeted(conn: &Connection, today: NaiveDate) -> Result<Vec<Birthday>> {
+    let mut statement = conn.prepare(
+        "SELECT network, nick, day, month FROM birthdays
+        WHERE greeted_year IS NULL OR greeted_year != :year",
+    )?;
+    let mut rows = statement.query(named_params! { ":year": today.year() })?;
+
+    let mut birthdays = Vec::new();
+    while let Some(row) = rows.next()? {
+        let birthday = birthday_from_row(row)?;
+        if date_in_year(birthday.day, birthday.month, today.year()) == today {
+            birthdays.push(birthday);
+        }
+    }
+
+    Ok(birthdays)
+}
+
+fn mark_greeted(conn: &Connection, birthday: &Birthday, year: i32) -> Result<()> {
+    conn.execute(
+        "UPDATE birthdays SET greeted_year = :year
+        WHERE network = :network AND nick_lower = :nick_lower",
+        named_params! {
+            ":network": birthday.network,
+            ":nick_lower": birthday.nick.to_lowercase(),
+            ":year": year,
+        },
+    )?;
+
+    Ok(())
+}
+
+fn upcoming(
+    conn: &Connection,
+    network: &str,
+    today: NaiveDate,
+    count: usize,
+) -> Result<Vec<Birthday>> {
+    let mut statement =
+        conn.prepare("SELECT network, nick, day, month FROM birthdays WHERE network = :network")?;
+    let mut rows = statement.query(named_params! { ":network": network })?;
+
+    let mut birthdays = Vec::new();
+    while let Some(row) = rows.next()? {
+        birthdays.push(birthday_from_row(row)?);
+    }
+    birthdays.sort_by_key(|b| (next_birthday(b.day, b.month, today), b.nick.to_lowercase()));
+    birthdays.truncate(count);
+
+    Ok(birthdays)
+}
+
+fn upcoming_msg(birthdays: &[Birthday]) -> String {
+    if birthdays.is_empty() {
+        return "Ei tallennettuja syntymäpäiviä".to_owned();
+    }
+
+    let list: Vec<String> = birthdays
+        .iter()
+        .map(|b| format!("{} {}.{}.", b.nick, b.day, b.month))
+        .collect();
+    format!("Seuraavat syntymäpäivät: {}", list.join(", "))
+}
+
+/// Marks today's birthdays greeted, adding each to `greeted`
+fn greet_birthdays(conn: &Connection, today: NaiveDate, greeted: &mut Vec<Birthday>) -> Result<()> {
+    for birthday in ungreeted(conn, today)? {
+        mark_greeted(conn, &birthday, today.year())?;
+        greeted.push(birthday);
+    }
+
+    Ok(())
+}
+
+/// Congratulates everyone whose birthday it is, once a day after GREETING_HOUR
+pub async fn birthday_manager(sender: mpsc::Sender<BotAction>) {
+    let update_interval = Duration::from_secs(15 * 60);
+
+    loop {
+        let now = Utc::now();
+        let local = now.with_timezone(&Helsinki);
+
+        if local.hour() >= GREETING_HOUR {
+            let today = local.date_naive();
+            let since = (now - chrono::Duration::days(ACTIVE_DAYS)).timestamp();
+
+            // The nicks already marked greeted are congratulated even if a
+            // later one fails
+            let (greeted, result) = db::call(&DB, move |c| {
+                let mut greeted = Vec::new();
+                let result = greet_birthdays(c, today, &mut greeted);
+                Ok((greeted, result))
+            })
+            .await
+            .unwrap_or_else(|e| (Vec::new(), Err(e)));
+            if let Err(e) = result {
+                error!("Error checking birthdays: {}", e);
+            }
+
+            for birthday in greeted {
+                for channel in recent_channels(&birthday.network, &birthday.nick, since).await {
+                    info!("Congratulating {} on {}", birthday.nick, channel);
+                    let action = BotAction {
+                        target: ChatTarget {
+                            network: birthday.network.to_owned(),
+                            channel,
+                        },
+                        action_type: ActionType::Message(format!(
+                            "Hyvää syntymäpäivää, {}!",
+                            birthday.nick
+                        )),
+                    };
+                    sender.send(action).await.unwrap();
+                }
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+fn birthday_command(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    params: &str,
+    today: NaiveDate,
+) -> Result<String> {
+    let mut words = params.split_whitespace();
+
+    Ok(match (words.next(), words.next(), words.next()) {
+        (Some("set"), Some(date), None) => match parse_date(date) {
+            Some((day, month)) => {
+                set_birthday(conn, network, nick, day, month)?;
+                format!("Syntymäpäivä tallennettu: {}.{}.", day, month)
+            }
+            None => "Usage: .birthday set <pp.kk.>".to_owned(),
+        },
+        (Some("remove"), None, None) => match remove_birthday(conn, network, nick)? {
+            true => "Syntymäpäivä poistettu".to_owned(),
+            false => "Syntymäpäivääsi ei ole tallennettu".to_owned(),
+        },
+        (Some("next"), None, None) => {
+            upcoming_msg(&upcoming(conn, network, today, UPCOMING_COUNT)?)
+        }
+        (Some(other), None, None) => match get_birthday(conn, network, other)? {
+            Some(b) => format!("{}: {}.{}.", b.nick, b.day, b.month),
+            None => format!("En tiedä milloin {} täyttää vuosia", other),
+        },
+        _ => "Usage: .birthday set <pp.kk.> | remove | next | <nick>".to_owned(),
+    })
+}
+
+pub async fn command_birthday(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let today = Utc::now().with_timezone(&Helsinki).date_naive();
+    let network = source.network.to_owned();
+    let params = params.to_owned();
+    let msg = match db::call(&DB, move |c| {
+        birthday_command(c, &network, &nick, &params, today)
+    })
+    .await
+    {
+        Ok(m) => m,
+        Err(_) => "Database error".to_owned(),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("24.6."), Some((24, 6)));
+        assert_eq!(parse_date("1.12"), Some((1, 12)));
+        assert_eq!(parse_date("29.2."), Some((29, 2)));
+        assert_eq!(parse_date("31.4."), None);
+        assert_eq!(parse_date("24.6.1990"), None);
+        assert_eq!(parse_date("kesäkuu"), None);
+
+        let today = NaiveDate::from_ymd_opt(2023, 6, 24).unwrap();
+        assert_eq!(next_birthday(24, 6, today), today);
+        assert_eq!(
+            next_birthday(23, 6, today),
+            NaiveDate::from_ymd_opt(2024, 6, 23).unwrap()
+        );
+        assert_eq!(
+            next_birthday(29, 2, today),
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        );
+        assert_eq!(
+            date_in_year(29, 2, 2023),
+            NaiveDate::from_ymd_opt(2023, 2, 28).unwrap()
+        );
+    }
+
+    #[test]
+    fn birthdays() {
+        let conn = open_db(true).unwrap();
+        let today = NaiveDate::from_ymd_opt(2023, 6, 24).unwrap();
+        let command = |nick: &str, params: &str| {
+            birthday_command(&conn, "testnet", nick, params, today).unwrap()
+        };
+
+        assert_eq!(
+            command("Nick", "set 24.6."),
+            "Syntymäpäivä tallennettu: 24.6."
+        );
+        command("toinen", "set 1.1.");
+        command("kolmas", "set 20.6.");
+        set_birthday(&conn, "othernet", "muu", 24, 6).unwrap();
+
+        assert_eq!(command("x", "nick"), "Nick: 24.6.");
+        assert_eq!(
+            command("x", "next"),
+            "Seuraavat syntymäpäivät: Nick 24.6., toinen 1.1., kolmas 20.6."
+        );
+
+        let due = ungreeted(&conn, today).unwrap();
+        assert_eq!(due.len(), 2);
+        for birthday in &due {
+            mark_greeted(&conn, birthday, 2023).unwrap();
+        }
+        assert!(ungreeted(&conn, today).unwrap().is_empty());
+        assert_eq!(
+            ungreeted(&conn, NaiveDate::from_ymd_opt(2024, 6, 24).unwrap())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        assert_eq!(command("nick", "remove"), "Syntymäpäivä poistettu");
+        assert_eq!(command("x", "nick"), "En tiedä milloin nick täyttää vuosia");
+    }
+}
@@ -2,27 +2,126 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use chrono::prelude::*;
+use core::time::Duration;
+use log::{debug, error};
+use rusqlite::{named_params, Connection};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::db;
+use crate::fmi::wfs_query;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const DEFAULT_RADIUS_KM: f64 = 50.0;
+const MAX_RADIUS_KM: f64 = 500.0;
+const MAX_WATCHES_PER_CHANNEL: i64 = 10;
+const STRIKE_WINDOW_MINUTES: i64 = 30;
+// Places don't move, so geocoding results are kept for a long time to keep
+// the request rate within Nominatim's usage policy
+const GEOCODE_CACHE_DAYS: i64 = 30;
+const GEOCODE_CACHE_SIZE: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Place {
+    pub lat: f64,
+    pub lon: f64,
+    pub name: String,
+}
+
+struct CachedPlace {
+    place: Place,
+    fetched: DateTime<Utc>,
+}
+
+lazy_static! {
+    static ref GEOCODE_CACHE: Mutex<HashMap<String, CachedPlace>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, PartialEq)]
+struct Strike {
+    lat: f64,
+    lon: f64,
+    time: DateTime<Utc>,
+}
 
 async fn get_json(place: &str) -> reqwest::Result<String> {
     let baseurl = "https://nominatim.openstreetmap.org/search";
 
-    let json = HTTP_CLIENT
+    let request = HTTP_CLIENT
         .get(baseurl)
-        .query(&[("q", place), ("format", "jsonv2")])
-        .send()
-        .await?
-        .text()
-        .await?;
+        .query(&[("q", place), ("format", "jsonv2")]);
+    let json = http_client::send(request).await?.text().await?;
 
     Ok(json)
 }
 
-async fn coordinates(place: &str) -> Result<String, ()> {
+/// "Hervanta, Tampere, Tampereen seutukunta, Pirkanmaa, ..." -> "Hervanta, Tampere"
+fn short_name(display_name: &str) -> String {
+    display_name
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .take(2)
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+fn parse_place(json_text: &str) -> Option<Place> {
+    let json: serde_json::Value = serde_json::from_str(json_text).ok()?;
+
+    let lat = json[0]["lat"].as_str()?.parse().ok()?;
+    let lon = json[0]["lon"].as_str()?.parse().ok()?;
+    let name = json[0]["display_name"]
+        .as_str()
+        .map(short_name)
+        .unwrap_or_default();
+
+    Some(Place { lat, lon, name })
+}
+
+fn cache_get(key: &str, now: DateTime<Utc>) -> Option<Place> {
+    GEOCODE_CACHE
+        .lock()
+        .unwrap()
+        .get(key)
+        .filter(|c| now - c.fetched < chrono::Duration::days(GEOCODE_CACHE_DAYS))
+        .map(|c| c.place.clone())
+}
+
+fn cache_put(key: String, place: Place, now: DateTime<Utc>) {
+    let mut cache = GEOCODE_CACHE.lock().unwrap();
+    cache.retain(|_, c| now - c.fetched < chrono::Duration::days(GEOCODE_CACHE_DAYS));
+    if cache.len() >= GEOCODE_CACHE_SIZE {
+        cache.clear();
+    }
+    cache.insert(
+        key,
+        CachedPlace {
+            place,
+            fetched: now,
+        },
+    );
+}
+
+/// Coordinates and a short display name of a place from nominatim
+pub async fn lookup_place(place: &str) -> Result<Place, ()> {
+    let key = place
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase();
+    let now = Utc::now();
+
+    if let Some(p) = cache_get(&key, now) {
+        return Ok(p);
+    }
+
     let json_text = match get_json(place).await {
         Ok(s) => s,
         Err(_) => {
@@ -30,36 +129,471 @@ async fn coordinates(place: &str) -> Result<String, ()> {
         }
     };
 
-    let json: serde_json::Value = match serde_json::from_str(&json_text) {
-        Ok(j) => j,
+    match parse_place(&json_text) {
+        Some(p) => {
+            cache_put(key, p.clone(), now);
+            Ok(p)
+        }
+        None => Err(()),
+    }
+}
+
+/// Latitude and longitude of a place from nominatim
+pub async fn geocode(place: &str) -> Result<(f64, f64), ()> {
+    lookup_place(place).await.map(|p| (p.lat, p.lon))
+}
+
+/// Great-circle distance between two points in kilometers
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+
+    6371.0 * 2.0 * a.sqrt().asin()
+}
+
+fn map_coordinates(lat: f64, lon: f64) -> String {
+    format!("10/{}/{}", lat, lon)
+}
+
+/// Lightning flashes from FMI's lightning network in a box around the point
+async fn get_strikes_xml(
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+    since: DateTime<Utc>,
+) -> reqwest::Result<String> {
+    let d_lat = radius_km / 111.0;
+    let d_lon = radius_km / (111.0 * lat.to_radians().cos());
+    // FMI's bbox is lon,lat order
+    let bbox = format!(
+        "{:.4},{:.4},{:.4},{:.4}",
+        lon - d_lon,
+        lat - d_lat,
+        lon + d_lon,
+        lat + d_lat
+    );
+
+    wfs_query(
+        "fmi::observations::lightning::simple",
+        &[("bbox", &bbox)],
+        since,
+    )
+    .await
+}
+
+fn parse_strikes(xml: &str) -> Result<Vec<Strike>, String> {
+    let root = match xmltree::Element::parse(xml.as_bytes()) {
+        Ok(r) => r,
         Err(_) => {
-            return Err(());
+            return Err("Error parsing lightning XML".to_owned());
         }
     };
 
-    if let Some(lat) = json[0]["lat"].as_str() {
-        if let Some(lon) = json[0]["lon"].as_str() {
-            return Ok(format!("10/{}/{}", lat, lon));
+    let text = |e: &xmltree::Element, name: &str| -> Option<String> {
+        Some(e.get_child(name)?.get_text()?.trim().to_owned())
+    };
+
+    let mut strikes = Vec::new();
+    for member in root.children.iter().filter_map(|c| c.as_element()) {
+        let element = match member.get_child("BsWfsElement") {
+            Some(e) => e,
+            None => continue,
+        };
+
+        // Every flash is listed once per parameter
+        if text(element, "ParameterName").as_deref() != Some("multiplicity") {
+            continue;
+        }
+
+        let pos = element
+            .get_child("Location")
+            .and_then(|l| l.get_child("Point"))
+            .and_then(|p| text(p, "pos"));
+        let time = text(element, "Time").and_then(|t| t.parse::<DateTime<Utc>>().ok());
+
+        if let (Some(pos), Some(time)) = (pos, time) {
+            let mut coords = pos.split_whitespace().filter_map(|c| c.parse::<f64>().ok());
+            if let (Some(lat), Some(lon)) = (coords.next(), coords.next()) {
+                strikes.push(Strike { lat, lon, time });
+            }
         }
     }
 
-    Err(())
+    Ok(strikes)
+}
+
+/// Number of strikes within the radius and the distance to the nearest one
+fn strikes_near(strikes: &[Strike], lat: f64, lon: f64, radius_km: f64) -> (usize, Option<f64>) {
+    let distances: Vec<f64> = strikes
+        .iter()
+        .map(|s| distance_km(lat, lon, s.lat, s.lon))
+        .filter(|d| *d <= radius_km)
+        .collect();
+
+    (distances.len(), distances.iter().copied().reduce(f64::min))
+}
+
+/// "3 salamaa", "1 salama"
+fn strike_count(count: usize) -> String {
+    match count {
+        1 => "1 salama".to_owned(),
+        n => format!("{} salamaa", n),
+    }
+}
+
+fn generate_strikes_msg(count: usize, nearest: Option<f64>, radius_km: f64) -> String {
+    match (count, nearest) {
+        (0, _) | (_, None) => format!(
+            "Ei salamoita {:.0} km säteellä viimeisen {} minuutin aikana",
+            radius_km, STRIKE_WINDOW_MINUTES
+        ),
+        (count, Some(nearest)) => format!(
+            "{} {:.0} km säteellä viimeisen {} minuutin aikana, lähin {:.1} km päässä",
+            strike_count(count),
+            radius_km,
+            STRIKE_WINDOW_MINUTES,
+            nearest
+        ),
+    }
+}
+
+fn radius_from_config(config: &Yaml) -> f64 {
+    config["ukkostutka"]["radius"]
+        .as_f64()
+        .or_else(|| config["ukkostutka"]["radius"].as_i64().map(|r| r as f64))
+        .filter(|r| r.is_finite() && *r > 0.0)
+        .unwrap_or(DEFAULT_RADIUS_KM)
+        .min(MAX_RADIUS_KM)
+}
+
+/// Strike count and nearest distance within the radius during the last window
+async fn recent_strikes(lat: f64, lon: f64, radius_km: f64) -> Option<(usize, Option<f64>)> {
+    let since = Utc::now() - chrono::Duration::minutes(STRIKE_WINDOW_MINUTES);
+    let xml = get_strikes_xml(lat, lon, radius_km, since).await.ok()?;
+    let strikes = parse_strikes(&xml).ok()?;
+
+    Some(strikes_near(&strikes, lat, lon, radius_km))
+}
+
+async fn strikes_msg(lat: f64, lon: f64, radius_km: f64) -> Option<String> {
+    let (count, nearest) = recent_strikes(lat, lon, radius_km).await?;
+
+    Some(generate_strikes_msg(count, nearest, radius_km))
+}
+
+#[derive(Debug, PartialEq)]
+struct Watch {
+    id: i64,
+    target: ChatTarget,
+    place: String,
+    lat: f64,
+    lon: f64,
+    radius: f64,
+    active: bool,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "ukkostutka.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS watches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            place TEXT NOT NULL,
+            lat REAL NOT NULL,
+            lon REAL NOT NULL,
+            radius REAL NOT NULL,
+            active INTEGER NOT NULL DEFAULT 0,
+            UNIQUE(network, channel, place)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Returns false if the channel already watches as many other places as it can
+fn add_watch(
+    conn: &Connection,
+    source: &ChatTarget,
+    place: &str,
+    (lat, lon): (f64, f64),
+    radius: f64,
+) -> rusqlite::Result<bool> {
+    let others: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM watches
+        WHERE network = :network AND channel = :channel AND place != :place",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":place": place,
+        },
+        |row| row.get(0),
+    )?;
+    if others >= MAX_WATCHES_PER_CHANNEL {
+        return Ok(false);
+    }
+
+    conn.execute(
+        "INSERT INTO watches (network, channel, place, lat, lon, radius)
+        VALUES (:network, :channel, :place, :lat, :lon, :radius)
+        ON CONFLICT(network, channel, place) DO UPDATE SET radius = :radius",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":place": place,
+            ":lat": lat,
+            ":lon": lon,
+            ":radius": radius,
+        },
+    )?;
+
+    Ok(true)
+}
+
+/// Returns whether the channel was watching the place
+fn remove_watch(conn: &Connection, source: &ChatTarget, place: &str) -> rusqlite::Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM watches WHERE network = :network AND channel = :channel
+        AND lower(place) = lower(:place)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":place": place,
+        },
+    )?;
+
+    Ok(removed > 0)
+}
+
+fn get_watches(conn: &Connection) -> rusqlite::Result<Vec<Watch>> {
+    let mut statement = conn.prepare(
+        "SELECT id, network, channel, place, lat, lon, radius, active FROM watches ORDER BY id",
+    )?;
+    let mut rows = statement.query([])?;
+
+    let mut watches = Vec::new();
+    while let Some(row) = rows.next()? {
+        watches.push(Watch {
+            id: row.get(0)?,
+            target: ChatTarget {
+                network: row.get(1)?,
+                channel: row.get(2)?,
+            },
+            place: row.get(3)?,
+            lat: row.get(4)?,
+            lon: row.get(5)?,
+            radius: row.get(6)?,
+            active: row.get(7)?,
+        });
+    }
+
+    Ok(watches)
+}
+
+fn set_active(conn: &Connection, watch_id: i64, active: bool) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE watches SET active = :active WHERE id = :id",
+        named_params! {
+            ":active": active,
+            ":id": watch_id,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// "Tampere 30" -> ("Tampere", Some(30.0)), radius at most MAX_RADIUS_KM
+fn parse_watch(params: &str) -> (&str, Option<f64>) {
+    match params.rsplit_once(' ') {
+        Some((place, radius)) => match radius.parse::<f64>() {
+            Ok(r) if r.is_finite() && r > 0.0 => (place.trim(), Some(r.min(MAX_RADIUS_KM))),
+            _ => (params, None),
+        },
+        None => (params, None),
+    }
+}
+
+/// Announcement when the watched area changes between quiet and active
+fn watch_transition(watch: &Watch, count: usize, nearest: Option<f64>) -> Option<String> {
+    match (watch.active, count, nearest) {
+        (false, count, Some(nearest)) if count > 0 => Some(format!(
+            "Ukkosta lähellä: {} {:.0} km säteellä paikasta {}, lähin {:.1} km päässä",
+            strike_count(count),
+            watch.radius,
+            watch.place,
+            nearest
+        )),
+        (true, 0, _) => Some(format!(
+            "Ukkonen ohi: ei salamoita {:.0} km säteellä paikasta {} viimeisen {} minuutin aikana",
+            watch.radius, watch.place, STRIKE_WINDOW_MINUTES
+        )),
+        _ => None,
+    }
+}
+
+async fn watch_msg(source: &ChatTarget, params: &str, config: &Yaml, admin: bool) -> String {
+    if !admin {
+        return "Vain ylläpitäjät voivat lisätä seurattavia paikkoja".to_owned();
+    }
+
+    let (place, radius) = parse_watch(params);
+    let radius = radius.unwrap_or_else(|| radius_from_config(config));
+
+    let found = match lookup_place(place).await {
+        Ok(p) => p,
+        Err(_) => {
+            return "Paikkaa ei löytynyt".to_owned();
+        }
+    };
+    let coords = (found.lat, found.lon);
+
+    let (target, name) = (source.clone(), place.to_owned());
+    match db::call(&DB, move |c| add_watch(c, &target, &name, coords, radius)).await {
+        Ok(true) => format!(
+            "Ilmoitetaan salamoista {:.0} km säteellä paikasta {} ({})",
+            radius, place, found.name
+        ),
+        Ok(false) => format!(
+            "Kanavalla voi seurata enintään {} paikkaa",
+            MAX_WATCHES_PER_CHANNEL
+        ),
+        Err(_) => "Database error".to_owned(),
+    }
+}
+
+async fn unwatch_msg(source: &ChatTarget, place: &str) -> String {
+    let (target, name) = (source.clone(), place.to_owned());
+    match db::call(&DB, move |c| remove_watch(c, &target, &name)).await {
+        Ok(true) => format!("Ei enää ilmoiteta salamoista paikassa {}", place),
+        Ok(false) => format!("Paikkaa {} ei seurata", place),
+        Err(_) => "Database error".to_owned(),
+    }
+}
+
+async fn watching_msg(source: &ChatTarget) -> String {
+    let watches = match db::call(&DB, get_watches).await {
+        Ok(w) => w,
+        Err(_) => {
+            return "Database error".to_owned();
+        }
+    };
+
+    let places: Vec<String> = watches
+        .iter()
+        .filter(|w| w.target == *source)
+        .map(|w| format!("{} ({:.0} km)", w.place, w.radius))
+        .collect();
+
+    if places.is_empty() {
+        "Salamoita ei seurata tällä kanavalla".to_owned()
+    } else {
+        format!("Seurataan salamoita: {}", places.join(", "))
+    }
+}
+
+async fn check_watches(sender: &mpsc::Sender<BotAction>) {
+    let watches = match db::call(&DB, get_watches).await {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Error reading lightning watches: {}", e);
+            return;
+        }
+    };
+
+    let mut changed = Vec::new();
+
+    for watch in watches {
+        let (count, nearest) = match recent_strikes(watch.lat, watch.lon, watch.radius).await {
+            Some(s) => s,
+            None => continue,
+        };
+
+        if let Some(msg) = watch_transition(&watch, count, nearest) {
+            changed.push((watch.id, !watch.active));
+            let action = BotAction {
+                target: watch.target,
+                action_type: ActionType::Message(msg),
+            };
+            sender.send(action).await.unwrap();
+        }
+    }
+
+    let result = db::call(&DB, move |conn| {
+        for (id, active) in changed {
+            if let Err(e) = set_active(conn, id, active) {
+                error!("Error updating lightning watch: {}", e);
+            }
+        }
+        Ok(())
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Error updating lightning watches: {}", e);
+    }
+}
+
+/// Announce when lightning starts or stops near the watched places
+pub async fn lightning_manager(sender: mpsc::Sender<BotAction>) {
+    let update_interval = Duration::from_secs(5 * 60);
+
+    loop {
+        check_watches(&sender).await;
+        debug!("Checked lightning watches");
+        sleep(update_interval).await;
+    }
 }
 
 pub async fn command_ukkostutka(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
+    config: Arc<Yaml>,
+    admin: bool,
 ) {
+    let subcommand_msg = match params.split_once(' ').unwrap_or((params, "")) {
+        ("watch", "") => Some(watching_msg(&source).await),
+        ("watch", place) => Some(watch_msg(&source, place.trim(), &config, admin).await),
+        ("unwatch", place) if !place.is_empty() => Some(unwatch_msg(&source, place.trim()).await),
+        _ => None,
+    };
+    if let Some(msg) = subcommand_msg {
+        let action = BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        };
+        bot_sender.send(action).await.unwrap();
+        return;
+    }
+
     let mut coords = "5.47/62.79/25.728".to_owned();
+    let mut strikes = None;
+    let mut name = None;
 
     if !params.is_empty() {
-        if let Ok(c) = coordinates(params).await {
-            coords = c;
+        if let Ok(place) = lookup_place(params).await {
+            coords = map_coordinates(place.lat, place.lon);
+            strikes = strikes_msg(place.lat, place.lon, radius_from_config(&config)).await;
+            name = Some(place.name).filter(|n| !n.is_empty());
         }
     }
 
-    let msg = format!("https://map.blitzortung.org/#{}", coords);
+    let map = format!("https://map.blitzortung.org/#{}", coords);
+    let msg = match strikes {
+        Some(s) => format!("{} | {}", s, map),
+        None => map,
+    };
+    let msg = match name {
+        Some(n) => format!("{}: {}", n, msg),
+        None => msg,
+    };
 
     let action = BotAction {
         target: source,
@@ -73,9 +607,146 @@ pub async fn command_ukkostutka(
 mod tests {
     use super::*;
 
+    fn open_db(testing: bool) -> rusqlite::Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn distance() {
+        // Helsinki to Tampere
+        let d = distance_km(60.1699, 24.9384, 61.4978, 23.7610);
+        assert!((d - 160.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn lightning_strikes() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<wfs:FeatureCollection xmlns:wfs="http://www.opengis.net/wfs/2.0" xmlns:BsWfs="http://xml.fmi.fi/schema/wfs/2.0" xmlns:gml="http://www.opengis.net/gml/3.2">
+  <wfs:member>
+    <BsWfs:BsWfsElement gml:id="BsWfsElement.1.1.1">
+      <BsWfs:Location><gml:Point gml:id="BsWfsElementP.1.1.1" srsDimension="2" srsName="http://www.opengis.net/def/crs/EPSG/0/4258"><gml:pos>61.50000 23.80000 </gml:pos></gml:Point></BsWfs:Location>
+      <BsWfs:Time>2023-07-01T12:00:00Z</BsWfs:Time>
+      <BsWfs:ParameterName>multiplicity</BsWfs:ParameterName>
+      <BsWfs:ParameterValue>1</BsWfs:ParameterValue>
+    </BsWfs:BsWfsElement>
+  </wfs:member>
+  <wfs:member>
+    <BsWfs:BsWfsElement gml:id="BsWfsElement.1.1.2">
+      <BsWfs:Location><gml:Point gml:id="BsWfsElementP.1.1.2" srsDimension="2" srsName="http://www.opengis.net/def/crs/EPSG/0/4258"><gml:pos>61.50000 23.80000 </gml:pos></gml:Point></BsWfs:Location>
+      <BsWfs:Time>2023-07-01T12:00:00Z</BsWfs:Time>
+      <BsWfs:ParameterName>peak_current</BsWfs:ParameterName>
+      <BsWfs:ParameterValue>-12</BsWfs:ParameterValue>
+    </BsWfs:BsWfsElement>
+  </wfs:member>
+  <wfs:member>
+    <BsWfs:BsWfsElement gml:id="BsWfsElement.1.2.1">
+      <BsWfs:Location><gml:Point gml:id="BsWfsElementP.1.2.1" srsDimension="2" srsName="http://www.opengis.net/def/crs/EPSG/0/4258"><gml:pos>62.50000 25.70000 </gml:pos></gml:Point></BsWfs:Location>
+      <BsWfs:Time>2023-07-01T12:05:00Z</BsWfs:Time>
+      <BsWfs:ParameterName>multiplicity</BsWfs:ParameterName>
+      <BsWfs:ParameterValue>2</BsWfs:ParameterValue>
+    </BsWfs:BsWfsElement>
+  </wfs:member>
+</wfs:FeatureCollection>"#;
+
+        let strikes = parse_strikes(xml).unwrap();
+        assert_eq!(strikes.len(), 2);
+
+        // Hervanta, Tampere
+        let (count, nearest) = strikes_near(&strikes, 61.45, 23.85, 50.0);
+        assert_eq!(count, 1);
+        assert_eq!(
+            generate_strikes_msg(count, nearest, 50.0),
+            "1 salama 50 km säteellä viimeisen 30 minuutin aikana, lähin 6.2 km päässä"
+        );
+
+        assert_eq!(
+            generate_strikes_msg(0, None, 50.0),
+            "Ei salamoita 50 km säteellä viimeisen 30 minuutin aikana"
+        );
+    }
+
+    #[test]
+    fn lightning_watch() {
+        assert_eq!(
+            parse_watch("Hervanta, Tampere 30"),
+            ("Hervanta, Tampere", Some(30.0))
+        );
+        assert_eq!(parse_watch("Tampere"), ("Tampere", None));
+        assert_eq!(parse_watch("Ylöjärvi -5"), ("Ylöjärvi -5", None));
+        assert_eq!(parse_watch("Tampere inf"), ("Tampere inf", None));
+        assert_eq!(parse_watch("Tampere NaN"), ("Tampere NaN", None));
+        assert_eq!(parse_watch("Tampere 1e9"), ("Tampere", Some(MAX_RADIUS_KM)));
+
+        let conn = open_db(true).unwrap();
+        let source = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#test".to_owned(),
+        };
+        assert!(add_watch(&conn, &source, "Tampere", (61.5, 23.8), 30.0).unwrap());
+        assert!(add_watch(&conn, &source, "Tampere", (61.5, 23.8), 20.0).unwrap());
+
+        let watches = get_watches(&conn).unwrap();
+        assert_eq!(watches.len(), 1);
+        assert_eq!(watches[0].radius, 20.0);
+        assert!(!watches[0].active);
+
+        assert_eq!(watch_transition(&watches[0], 0, None), None);
+        assert_eq!(
+            watch_transition(&watches[0], 3, Some(12.34)).unwrap(),
+            "Ukkosta lähellä: 3 salamaa 20 km säteellä paikasta Tampere, lähin 12.3 km päässä"
+        );
+        assert_eq!(
+            watch_transition(&watches[0], 1, Some(7.0)).unwrap(),
+            "Ukkosta lähellä: 1 salama 20 km säteellä paikasta Tampere, lähin 7.0 km päässä"
+        );
+
+        set_active(&conn, watches[0].id, true).unwrap();
+        let watches = get_watches(&conn).unwrap();
+        assert_eq!(watch_transition(&watches[0], 5, Some(2.0)), None);
+        assert_eq!(
+            watch_transition(&watches[0], 0, None).unwrap(),
+            "Ukkonen ohi: ei salamoita 20 km säteellä paikasta Tampere viimeisen 30 minuutin aikana"
+        );
+
+        assert!(remove_watch(&conn, &source, "tampere").unwrap());
+        assert!(get_watches(&conn).unwrap().is_empty());
+
+        for i in 0..MAX_WATCHES_PER_CHANNEL {
+            let place = format!("Paikka {}", i);
+            assert!(add_watch(&conn, &source, &place, (61.5, 23.8), 30.0).unwrap());
+        }
+        assert!(!add_watch(&conn, &source, "Tampere", (61.5, 23.8), 30.0).unwrap());
+        assert!(add_watch(&conn, &source, "Paikka 0", (61.5, 23.8), 10.0).unwrap());
+    }
+
+    #[test]
+    fn place_name() {
+        let json = r#"[{"place_id":123,"lat":"61.4509034","lon":"23.8514239",
+            "display_name":"Hervanta, Tampere, Tampereen seutukunta, Pirkanmaa, Manner-Suomi, Suomi / Finland"}]"#;
+        assert_eq!(
+            parse_place(json),
+            Some(Place {
+                lat: 61.4509034,
+                lon: 23.8514239,
+                name: "Hervanta, Tampere".to_owned()
+            })
+        );
+        assert_eq!(parse_place("[]"), None);
+
+        let now = Utc::now();
+        let place = parse_place(json).unwrap();
+        cache_put("hervanta".to_owned(), place.clone(), now);
+        assert_eq!(cache_get("hervanta", now), Some(place));
+        assert_eq!(
+            cache_get("hervanta", now + chrono::Duration::days(GEOCODE_CACHE_DAYS)),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn hervanta_coords() {
-        let r = coordinates(&"Hervanta").await.unwrap();
+        let (lat, lon) = geocode("Hervanta").await.unwrap();
+        let r = map_coordinates(lat, lon);
         assert_eq!(r, "10/61.4509034/23.8514239");
     }
 }
@@ -2,16 +2,17 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::IrcChannel;
+use crate::ChatTarget;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ActionType {
     Message(String),
     Action(String),
+    Notice(String),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct BotAction {
-    pub target: IrcChannel,
+    pub target: ChatTarget,
     pub action_type: ActionType,
 }
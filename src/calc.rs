@@ -0,0 +1,436 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::wolfram_alpha;
+use crate::ChatTarget;
+
+#[derive(Debug, PartialEq)]
+enum CalcError {
+    /// A name that is not a known function or constant, so probably not math
+    UnknownName(String),
+    Syntax(String),
+    Math(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+// Deeper expressions would overflow the stack
+const MAX_DEPTH: usize = 64;
+
+const OPERATORS: [&str; 12] = [
+    "<<", ">>", "**", "+", "-", "*", "/", "%", "^", "&", "|", "~",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, CalcError> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = expr.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            let number = if c == '0' && matches!(chars.get(i + 1), Some('x') | Some('X')) {
+                i += 2;
+                while i < chars.len() && chars[i].is_ascii_hexdigit() {
+                    i += 1;
+                }
+                let hex: String = chars[start + 2..i].iter().collect();
+                i64::from_str_radix(&hex, 16).map(|n| n as f64).ok()
+            } else {
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                // Scientific notation, e.g. 1.5e3
+                if i + 1 < chars.len()
+                    && (chars[i] == 'e' || chars[i] == 'E')
+                    && (chars[i + 1].is_ascii_digit() || chars[i + 1] == '-')
+                {
+                    i += 2;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                chars[start..i].iter().collect::<String>().parse().ok()
+            };
+            match number {
+                Some(n) => tokens.push(Token::Number(n)),
+                None => {
+                    let text: String = chars[start..i].iter().collect();
+                    return Err(CalcError::Syntax(format!("Invalid number {}", text)));
+                }
+            }
+        } else if c.is_alphabetic() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(
+                chars[start..i].iter().collect::<String>().to_lowercase(),
+            ));
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == ',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                }
+                None => {
+                    return Err(CalcError::Syntax(format!("Unexpected character {}", c)));
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, lowest precedence first:
+/// | xor & shifts +- */% unary ^
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn accept_op(&mut self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.xor()?;
+        while self.accept_op(&["|"]).is_some() {
+            value = (integer(value)? | integer(self.xor()?)?) as f64;
+        }
+        Ok(value)
+    }
+
+    fn xor(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.and()?;
+        while self.peek() == Some(&Token::Name("xor".to_owned())) {
+            self.pos += 1;
+            value = (integer(value)? ^ integer(self.and()?)?) as f64;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.shift()?;
+        while self.accept_op(&["&"]).is_some() {
+            value = (integer(value)? & integer(self.shift()?)?) as f64;
+        }
+        Ok(value)
+    }
+
+    fn shift(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.sum()?;
+        while let Some(op) = self.accept_op(&["<<", ">>"]) {
+            let amount = integer(self.sum()?)?;
+            if !(0..64).contains(&amount) {
+                return Err(CalcError::Math("Shift out of range".to_owned()));
+            }
+            value = match op {
+                "<<" => (integer(value)? << amount) as f64,
+                _ => (integer(value)? >> amount) as f64,
+            };
+        }
+        Ok(value)
+    }
+
+    fn sum(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.product()?;
+        while let Some(op) = self.accept_op(&["+", "-"]) {
+            let rhs = self.product()?;
+            value = match op {
+                "+" => value + rhs,
+                _ => value - rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, CalcError> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.accept_op(&["*", "/", "%"]) {
+            let rhs = self.unary()?;
+            value = match op {
+                "*" => value * rhs,
+                _ if rhs == 0.0 => {
+                    return Err(CalcError::Math("Division by zero".to_owned()));
+                }
+                "/" => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    /// Parentheses, function arguments, signs and exponents all nest through here
+    fn unary(&mut self) -> Result<f64, CalcError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(CalcError::Syntax("Too deeply nested".to_owned()));
+        }
+
+        let value = match self.accept_op(&["-", "+", "~"]) {
+            Some("-") => self.unary().map(|v| -v),
+            Some("~") => self.unary().and_then(integer).map(|v| !v as f64),
+            Some(_) => self.unary(),
+            None => self.power(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn power(&mut self) -> Result<f64, CalcError> {
+        let base = self.primary()?;
+        if self.accept_op(&["^", "**"]).is_some() {
+            // Right associative, and binds tighter than a unary minus on the left
+            let exponent = self.unary()?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<f64, CalcError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::LParen) => {
+                let value = self.expression()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(value),
+                    _ => Err(CalcError::Syntax("Missing )".to_owned())),
+                }
+            }
+            Some(Token::Name(name)) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    let mut args = vec![self.expression()?];
+                    while self.peek() == Some(&Token::Comma) {
+                        self.pos += 1;
+                        args.push(self.expression()?);
+                    }
+                    match self.next() {
+                        Some(Token::RParen) => function(&name, &args),
+                        _ => Err(CalcError::Syntax("Missing )".to_owned())),
+                    }
+                } else {
+                    constant(&name)
+                }
+            }
+            Some(t) => Err(CalcError::Syntax(format!("Unexpected {:?}", t))),
+            None => Err(CalcError::Syntax("Unexpected end of expression".to_owned())),
+        }
+    }
+}
+
+fn integer(value: f64) -> Result<i64, CalcError> {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        Ok(value as i64)
+    } else {
+        Err(CalcError::Math(
+            "Bitwise operations need integers".to_owned(),
+        ))
+    }
+}
+
+fn constant(name: &str) -> Result<f64, CalcError> {
+    match name {
+        "pi" => Ok(std::f64::consts::PI),
+        "e" => Ok(std::f64::consts::E),
+        "tau" => Ok(std::f64::consts::TAU),
+        _ => Err(CalcError::UnknownName(name.to_owned())),
+    }
+}
+
+fn function(name: &str, args: &[f64]) -> Result<f64, CalcError> {
+    let x = args[0];
+    let value = match (name, args.len()) {
+        ("sqrt", 1) => x.sqrt(),
+        ("cbrt", 1) => x.cbrt(),
+        ("abs", 1) => x.abs(),
+        ("floor", 1) => x.floor(),
+        ("ceil", 1) => x.ceil(),
+        ("round", 1) => x.round(),
+        ("exp", 1) => x.exp(),
+        ("ln", 1) => x.ln(),
+        ("log", 1) => x.log10(),
+        ("log", 2) => x.log(args[1]),
+        ("log2", 1) => x.log2(),
+        ("sin", 1) => x.sin(),
+        ("cos", 1) => x.cos(),
+        ("tan", 1) => x.tan(),
+        ("asin", 1) => x.asin(),
+        ("acos", 1) => x.acos(),
+        ("atan", 1) => x.atan(),
+        ("min", _) => args.iter().copied().fold(f64::INFINITY, f64::min),
+        ("max", _) => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        (
+            "sqrt" | "cbrt" | "abs" | "floor" | "ceil" | "round" | "exp" | "ln" | "log" | "log2"
+            | "sin" | "cos" | "tan" | "asin" | "acos" | "atan",
+            n,
+        ) => {
+            return Err(CalcError::Syntax(format!(
+                "{} does not take {} arguments",
+                name, n
+            )));
+        }
+        _ => {
+            return Err(CalcError::UnknownName(name.to_owned()));
+        }
+    };
+
+    Ok(value)
+}
+
+fn evaluate(expr: &str) -> Result<f64, CalcError> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        depth: 0,
+    };
+
+    let value = parser.expression()?;
+    if let Some(t) = parser.peek() {
+        return Err(CalcError::Syntax(format!("Unexpected {:?}", t)));
+    }
+    if value.is_nan() {
+        return Err(CalcError::Math("Not a number".to_owned()));
+    }
+
+    Ok(value)
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+
+    let formatted = format!("{:.10}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "0" || trimmed == "-0" || value.abs() >= 1e15 {
+        format!("{:e}", value)
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+pub async fn command_calc(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let msg = match evaluate(params) {
+        Ok(value) => format!("{} = {}", params.trim(), format_number(value)),
+        // Words that aren't math are better answered by Wolfram Alpha
+        Err(CalcError::UnknownName(name)) => match config["wolfram_alpha"]["apikey"].as_str() {
+            Some(apikey) => wolfram_alpha::response(params, apikey).await,
+            None => format!("Unknown function or constant {}", name),
+        },
+        Err(CalcError::Syntax(e)) | Err(CalcError::Math(e)) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(evaluate("-2^2"), Ok(-4.0));
+        assert_eq!(evaluate("2^3^2"), Ok(512.0));
+        assert_eq!(evaluate("2**-1"), Ok(0.5));
+        assert_eq!(evaluate("10 % 4"), Ok(2.0));
+        assert_eq!(evaluate("1.5e3 / 3"), Ok(500.0));
+        assert_eq!(evaluate("sqrt(16) + abs(-2)"), Ok(6.0));
+        assert_eq!(evaluate("log(8, 2)"), Ok(3.0));
+        assert_eq!(evaluate("max(1, 5, 3)"), Ok(5.0));
+        assert_eq!(format_number(evaluate("pi").unwrap()), "3.1415926536");
+        assert_eq!(format_number(evaluate("1/3").unwrap()), "0.3333333333");
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(evaluate("0xff & 0x0f"), Ok(15.0));
+        assert_eq!(evaluate("1 << 4 | 1"), Ok(17.0));
+        assert_eq!(evaluate("6 xor 3"), Ok(5.0));
+        assert_eq!(evaluate("~0"), Ok(-1.0));
+        assert!(matches!(evaluate("1.5 & 1"), Err(CalcError::Math(_))));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(evaluate("1 / 0"), Err(CalcError::Math(_))));
+        assert!(matches!(evaluate("(1 + 2"), Err(CalcError::Syntax(_))));
+        assert!(matches!(evaluate("1 +"), Err(CalcError::Syntax(_))));
+
+        let nested = |s: &str| s.repeat(300) + "1";
+        let too_deep = Err(CalcError::Syntax("Too deeply nested".to_owned()));
+        assert_eq!(evaluate(&nested("(")), too_deep);
+        assert_eq!(evaluate(&nested("sqrt(")), too_deep);
+        assert_eq!(evaluate(&nested("-")), too_deep);
+        assert_eq!(evaluate(&nested("2^")), too_deep);
+        assert_eq!(
+            evaluate(&format!("{}1{}", "(".repeat(20), ")".repeat(20))),
+            Ok(1.0)
+        );
+        assert_eq!(
+            evaluate("population of finland"),
+            Err(CalcError::UnknownName("population".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,120 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The connectors the bot talks through: irc_loop, matrix_loop,
+//! discord_loop and telegram_loop. Incoming messages from all of them reach
+//! message_handler as IRC messages, so commands, feeds and timers work the
+//! same everywhere, and every action is routed to the connector of its
+//! network.
+
+use log::warn;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::BotAction;
+
+/// The connector of every network named in `section` of the config
+fn networks(config: &Yaml, section: &str) -> Vec<String> {
+    config[section]
+        .as_vec()
+        .map(|n| {
+            n.iter()
+                .filter_map(|n| n["network"].as_str())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Connector by network name, from the config section of each connector.
+/// A name that is already taken keeps its first connector.
+pub fn routes(
+    config: &Yaml,
+    connectors: &[(&str, mpsc::Sender<BotAction>)],
+) -> HashMap<String, mpsc::Sender<BotAction>> {
+    let mut routes = HashMap::new();
+    for (section, connector) in connectors {
+        for network in networks(config, section) {
+            if routes.contains_key(&network) {
+                warn!("Network name {} in {} is already in use", network, section);
+                continue;
+            }
+            routes.insert(network, connector.clone());
+        }
+    }
+
+    routes
+}
+
+/// Cuts a message to the length limit of a chat service, counted in characters
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+
+    let mut out: String = text.chars().take(max_chars - 1).collect();
+    out.push('…');
+    out
+}
+
+pub async fn route_actions(
+    mut receiver: mpsc::Receiver<BotAction>,
+    routes: HashMap<String, mpsc::Sender<BotAction>>,
+) {
+    while let Some(action) = receiver.recv().await {
+        match routes.get(&action.target.network) {
+            Some(connector) => {
+                let _ = connector.send(action).await;
+            }
+            None => warn!("No connection to {}", action.target.network),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::botaction::ActionType;
+    use crate::ChatTarget;
+
+    #[tokio::test]
+    async fn routing() {
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "networks:\n  - network: IRCnet\nmatrix:\n  - network: matrix\n  - network: IRCnet",
+        )
+        .unwrap();
+        let (irc_tx, mut irc_rx) = mpsc::channel(10);
+        let (matrix_tx, mut matrix_rx) = mpsc::channel(10);
+        let routes = routes(&config[0], &[("networks", irc_tx), ("matrix", matrix_tx)]);
+        assert_eq!(routes.len(), 2);
+
+        let (tx, rx) = mpsc::channel(10);
+        for network in ["matrix", "IRCnet", "QuakeNet"] {
+            tx.send(BotAction {
+                target: ChatTarget {
+                    network: network.to_owned(),
+                    channel: "!room:example.org".to_owned(),
+                },
+                action_type: ActionType::Message("hello".to_owned()),
+            })
+            .await
+            .unwrap();
+        }
+        drop(tx);
+        route_actions(rx, routes).await;
+
+        assert_eq!(matrix_rx.recv().await.unwrap().target.network, "matrix");
+        assert_eq!(irc_rx.recv().await.unwrap().target.network, "IRCnet");
+        assert!(matrix_rx.try_recv().is_err());
+        assert!(irc_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("hei", 3), "hei");
+        assert_eq!(truncate("hyvää", 4), "hyv…");
+        assert_eq!(truncate(&"a".repeat(2500), 2000).chars().count(), 2000);
+    }
+}
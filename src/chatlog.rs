@@ -0,0 +1,266 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::Utc;
+use core::time::Duration;
+use log::{error, info};
+use rusqlite::{named_params, Connection, Result};
+use std::sync::Arc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
+
+use crate::db;
+use crate::ChatTarget;
+
+const DEFAULT_RETENTION_DAYS: i64 = 365;
+
+pub const DB: db::Database = db::Database {
+    name: "chatlog.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            nick TEXT NOT NULL,
+            action INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS messages_channel_time ON messages (network, channel, time)",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Channels listed under `chatlog: channels` in config.yml, logging is off elsewhere
+fn logged_channels(config: &Yaml) -> Vec<ChatTarget> {
+    let mut channels = Vec::new();
+
+    if let Some(list) = config["chatlog"]["channels"].as_vec() {
+        for c in list {
+            if let (Some(network), Some(channel)) = (c["network"].as_str(), c["channel"].as_str()) {
+                channels.push(ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                });
+            }
+        }
+    }
+
+    channels
+}
+
+fn is_logged(config: &Yaml, source: &ChatTarget) -> bool {
+    logged_channels(config)
+        .iter()
+        .any(|c| c.network == source.network && c.channel.eq_ignore_ascii_case(&source.channel))
+}
+
+/// "\x01ACTION waves\x01" -> ("waves", true)
+fn split_action(msg: &str) -> (&str, bool) {
+    match msg.strip_prefix("\u{1}ACTION ") {
+        Some(action) => (action.trim_end_matches('\u{1}'), true),
+        None => (msg, false),
+    }
+}
+
+fn insert(conn: &Connection, source: &ChatTarget, nick: &str, msg: &str, time: i64) -> Result<()> {
+    let (message, action) = split_action(msg);
+
+    conn.execute(
+        "INSERT INTO messages (network, channel, time, nick, action, message)
+        VALUES (:network, :channel, :time, :nick, :action, :message)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+            ":time": time,
+            ":nick": nick,
+            ":action": action,
+            ":message": message,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Removes lines older than the retention period and, when `max_messages` is
+/// set, the oldest lines of channels over the limit
+fn prune(
+    conn: &Connection,
+    retention_days: i64,
+    max_messages: Option<i64>,
+    now: i64,
+) -> Result<usize> {
+    let mut removed = conn.execute(
+        "DELETE FROM messages WHERE time < :oldest",
+        named_params! { ":oldest": now - retention_days * 24 * 60 * 60 },
+    )?;
+
+    if let Some(max) = max_messages {
+        removed += conn.execute(
+            "DELETE FROM messages WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY network, channel ORDER BY time DESC, id DESC
+                    ) AS n FROM messages
+                ) WHERE n > :max
+            )",
+            named_params! { ":max": max },
+        )?;
+    }
+
+    Ok(removed)
+}
+
+/// Called for every message on a channel; stores it if the channel is logged
+pub async fn log_message(config: Arc<Yaml>, source: ChatTarget, nick: &str, msg: &str) {
+    if !is_logged(&config, &source) {
+        return;
+    }
+
+    let nick = nick.to_owned();
+    let msg = msg.to_owned();
+    let time = Utc::now().timestamp();
+    let result = db::call(&DB, move |c| insert(c, &source, &nick, &msg, time)).await;
+    if let Err(e) = result {
+        error!("Error writing chat log: {}", e);
+    }
+}
+
+/// Prunes the log daily according to `chatlog: retention_days` and `max_messages`
+pub async fn chatlog_manager(config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(24 * 60 * 60);
+
+    if logged_channels(&config).is_empty() {
+        info!("No logged channels configured");
+        return;
+    }
+
+    let retention_days = config["chatlog"]["retention_days"]
+        .as_i64()
+        .filter(|d| *d > 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    let max_messages = config["chatlog"]["max_messages"]
+        .as_i64()
+        .filter(|m| *m > 0);
+
+    loop {
+        let now = Utc::now().timestamp();
+        match db::call(&DB, move |c| prune(c, retention_days, max_messages, now)).await {
+            Ok(removed) => info!("Pruned {} lines from the chat log", removed),
+            Err(e) => error!("Error pruning chat log: {}", e),
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct LogLine {
+        nick: String,
+        message: String,
+        action: bool,
+        time: i64,
+    }
+
+    fn recent_lines(conn: &Connection, source: &ChatTarget, count: i64) -> Result<Vec<LogLine>> {
+        let mut statement = conn.prepare(
+            "SELECT nick, message, action, time FROM messages
+            WHERE network = :network AND channel = :channel
+            ORDER BY time DESC, id DESC LIMIT :count",
+        )?;
+        let mut rows = statement.query(named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+            ":count": count,
+        })?;
+
+        let mut lines = Vec::new();
+        while let Some(row) = rows.next()? {
+            lines.push(LogLine {
+                nick: row.get(0)?,
+                message: row.get(1)?,
+                action: row.get(2)?,
+                time: row.get(3)?,
+            });
+        }
+
+        Ok(lines)
+    }
+
+    #[test]
+    fn opt_in() {
+        let config = YamlLoader::load_from_str(
+            "chatlog:\n  channels:\n    - network: testnet\n      channel: '#Logged'",
+        )
+        .unwrap();
+        let channel = |c: &str| ChatTarget {
+            network: "testnet".to_owned(),
+            channel: c.to_owned(),
+        };
+
+        assert!(is_logged(&config[0], &channel("#logged")));
+        assert!(!is_logged(&config[0], &channel("#other")));
+    }
+
+    #[test]
+    fn log_and_prune() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#Testing".to_owned(),
+        };
+        let day = 24 * 60 * 60;
+
+        insert(&conn, &channel, "vanha", "ikivanha", 0).unwrap();
+        insert(&conn, &channel, "nick", "moi", 10 * day).unwrap();
+        insert(
+            &conn,
+            &channel,
+            "nick",
+            "\u{1}ACTION heiluttaa\u{1}",
+            10 * day + 1,
+        )
+        .unwrap();
+        insert(&conn, &channel, "toinen", "hei", 10 * day + 2).unwrap();
+
+        assert_eq!(prune(&conn, 5, None, 10 * day).unwrap(), 1);
+        assert_eq!(prune(&conn, 5, Some(2), 10 * day).unwrap(), 1);
+
+        assert_eq!(
+            recent_lines(&conn, &channel, 10).unwrap(),
+            vec![
+                LogLine {
+                    nick: "toinen".to_owned(),
+                    message: "hei".to_owned(),
+                    action: false,
+                    time: 10 * day + 2
+                },
+                LogLine {
+                    nick: "nick".to_owned(),
+                    message: "heiluttaa".to_owned(),
+                    action: true,
+                    time: 10 * day + 1
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::fs;
+use std::path::Path;
+
+/// Configuration as read from config.yml, shared by the tasks as `Arc<Yaml>`
+pub use yaml_rust::yaml::Yaml;
+use yaml_rust::yaml::YamlLoader;
+
+/// The first YAML document of the configuration file
+pub fn load_config(path: &Path) -> Result<Yaml, String> {
+    let file = fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Error when reading {}: {}. Copy config.yml.example as config.yml in the same \
+            directory as the executable and edit it to your liking.",
+            path.display(),
+            e
+        )
+    })?;
+
+    match YamlLoader::load_from_str(&file) {
+        Ok(mut docs) if !docs.is_empty() => Ok(docs.swap_remove(0)),
+        Ok(_) => Err(format!("{} is empty", path.display())),
+        Err(e) => Err(format!("Error when parsing {}: {}", path.display(), e)),
+    }
+}
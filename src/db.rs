@@ -0,0 +1,290 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Storage shared by the modules: every database lives in the data
+//! directory and is opened with the same settings. Async code goes through
+//! `call`, which reuses one connection per database and keeps the disk
+//! access off the runtime threads.
+//!
+//! Schemas are versioned. A module lists the changes to its tables in
+//! `Database::migrations` and they are applied in order, each exactly once,
+//! when the database is opened. Migrations that have been released are never
+//! edited; a change to a table is a new migration at the end of the list.
+
+use log::{info, warn};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use yaml_rust::Yaml;
+
+const DEFAULT_DATA_DIR: &str = "db";
+// Writers wait this long for a lock held by another connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One step of a schema, run in a transaction
+pub type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+pub struct Database {
+    /// File name in the data directory
+    pub name: &'static str,
+    /// Migration n brings the schema to version n + 1
+    pub migrations: &'static [Migration],
+}
+
+/// Every database of the bot, migrated at startup
+const DATABASES: [&Database; 20] = [
+    &crate::admins::DB,
+    &crate::birthday::DB,
+    &crate::blitzortung::DB,
+    &crate::chatlog::DB,
+    &crate::epic::DB,
+    &crate::factoids::DB,
+    &crate::karma::DB,
+    &crate::leaderboard::DB,
+    &crate::links::DB,
+    &crate::poll::DB,
+    &crate::rss::DB,
+    &crate::sahko::DB,
+    &crate::sanuli::DB,
+    &crate::seen::DB,
+    &crate::tell::DB,
+    &crate::timer::DB,
+    &crate::timezone::DB,
+    &crate::tvmaze::DB,
+    &crate::twitch::DB,
+    &crate::weather_db::DB,
+];
+
+lazy_static! {
+    static ref DATA_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::from(DEFAULT_DATA_DIR));
+    static ref CONNECTIONS: Mutex<HashMap<PathBuf, Arc<Mutex<Connection>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Uses `storage: data_dir` for the databases, creating the directory if needed
+pub fn init(config: &Yaml) -> std::io::Result<()> {
+    let dir = PathBuf::from(
+        config["storage"]["data_dir"]
+            .as_str()
+            .unwrap_or(DEFAULT_DATA_DIR),
+    );
+    std::fs::create_dir_all(&dir)?;
+    *DATA_DIR.write().unwrap() = dir;
+
+    Ok(())
+}
+
+fn path(name: &str) -> PathBuf {
+    DATA_DIR.read().unwrap().join(name)
+}
+
+/// The number of migrations applied to the database
+fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    let version: i64 = conn.query_row(
+        "SELECT coalesce(max(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(version as usize)
+}
+
+/// Applies the migrations the database is missing
+pub fn migrate(conn: &Connection, db: &Database) -> rusqlite::Result<()> {
+    let version = schema_version(conn)?;
+    if version > db.migrations.len() {
+        warn!(
+            "{} has schema version {}, newer than this version of the bot knows",
+            db.name, version
+        );
+    }
+
+    for (i, migration) in db.migrations.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            [i as i64 + 1],
+        )?;
+        tx.commit()?;
+        info!("Migrated {} to schema version {}", db.name, i + 1);
+    }
+
+    Ok(())
+}
+
+/// Brings every database up to date, called once at startup
+pub fn migrate_all() -> rusqlite::Result<()> {
+    for db in DATABASES {
+        open(db, false)?;
+    }
+
+    Ok(())
+}
+
+/// Runs a trivial query on every database, for the health check
+pub async fn check_all() -> Vec<(&'static str, rusqlite::Result<()>)> {
+    let mut results = Vec::new();
+    for db in DATABASES {
+        let result = call(db, |c| c.query_row("SELECT 1", [], |_| Ok(()))).await;
+        results.push((db.name, result));
+    }
+
+    results
+}
+
+/// Opens the database in the data directory, or an empty in-memory one when
+/// testing, and applies the migrations it is missing
+pub fn open(db: &Database, testing: bool) -> rusqlite::Result<Connection> {
+    match testing {
+        true => open_at(db, Path::new(":memory:")),
+        false => open_at(db, &path(db.name)),
+    }
+}
+
+fn open_at(db: &Database, path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Readers don't block the writer and vice versa
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    migrate(&conn, db)?;
+
+    Ok(conn)
+}
+
+fn shared(db: &Database, path: PathBuf) -> rusqlite::Result<Arc<Mutex<Connection>>> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if let Some(conn) = connections.get(&path) {
+        return Ok(conn.clone());
+    }
+
+    let conn = Arc::new(Mutex::new(open_at(db, &path)?));
+    connections.insert(path, conn.clone());
+
+    Ok(conn)
+}
+
+/// Runs `f` with the shared connection to the database on a blocking thread
+pub async fn call<T, F>(db: &'static Database, f: F) -> rusqlite::Result<T>
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    call_at(db, path(db.name), f).await
+}
+
+async fn call_at<T, F>(db: &'static Database, path: PathBuf, f: F) -> rusqlite::Result<T>
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || {
+        let conn = shared(db, path)?;
+        let conn = conn.lock().unwrap();
+        f(&conn)
+    })
+    .await;
+
+    result.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// Adds a column to an existing table unless it is already there. Used to
+/// bring databases created by older versions up to date.
+pub fn add_column_if_missing(
+    conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(());
+        }
+    }
+
+    conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        [],
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_opened(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute("CREATE TABLE opened (id INTEGER)", [])?;
+        Ok(())
+    }
+
+    fn add_name(conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute("ALTER TABLE opened ADD COLUMN name TEXT", [])?;
+        Ok(())
+    }
+
+    const V1: Database = Database {
+        name: "test.db",
+        migrations: &[create_opened],
+    };
+
+    const V2: Database = Database {
+        name: "test.db",
+        migrations: &[create_opened, add_name],
+    };
+
+    #[test]
+    fn migrations() {
+        let conn = open(&V1, true).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+
+        migrate(&conn, &V2).unwrap();
+        migrate(&conn, &V2).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+        conn.execute("INSERT INTO opened (id, name) VALUES (1, 'x')", [])
+            .unwrap();
+
+        // A failing migration leaves the version where it was
+        const BROKEN: Database = Database {
+            name: "test.db",
+            migrations: &[create_opened, add_name, create_opened],
+        };
+        assert!(migrate(&conn, &BROKEN).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn shared_connection() {
+        // The global data directory is left alone, other tests use it
+        let dir = std::env::temp_dir().join(format!("tbotti-db-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("test.db");
+
+        let insert = |conn: &Connection| {
+            conn.execute("INSERT INTO opened (id, name) VALUES (1, 'x')", [])?;
+            conn.query_row("SELECT count(*) FROM opened", [], |r| r.get(0))
+        };
+        assert_eq!(call_at(&V2, file.clone(), insert).await, Ok(1));
+        assert_eq!(call_at(&V2, file.clone(), insert).await, Ok(2));
+        assert!(file.exists());
+
+        let mode: String = open_at(&V2, &file)
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
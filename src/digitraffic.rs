@@ -0,0 +1,580 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::blitzortung::{distance_km, geocode};
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const ROAD_WEATHER_URL: &str = "https://tie.digitraffic.fi/api/weather/v1/stations";
+const RAIL_URL: &str = "https://rata.digitraffic.fi/api/v1";
+const NEXT_STOPS: usize = 3;
+const DEPARTURES: usize = 5;
+
+#[derive(Debug, PartialEq)]
+struct RoadStation {
+    id: i64,
+    name: String,
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct RailStation {
+    code: String,
+    name: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct TimetableRow {
+    station: String,
+    departure: bool,
+    scheduled: DateTime<Utc>,
+    estimate: Option<DateTime<Utc>>,
+    actual: Option<DateTime<Utc>>,
+    difference: Option<i64>,
+    commercial: bool,
+    cancelled: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Train {
+    /// "IC 27", or the line letter of commuter trains
+    name: String,
+    cancelled: bool,
+    rows: Vec<TimetableRow>,
+}
+
+lazy_static! {
+    // The station list rarely changes, it is fetched once
+    static ref RAIL_STATIONS: Mutex<Vec<RailStation>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct RoadWeather {
+    air_temperature: Option<f64>,
+    road_temperature: Option<f64>,
+    condition: Option<String>,
+    warning: Option<String>,
+}
+
+async fn get_json(url: &str) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT
+        .get(url)
+        // Digitraffic asks clients to identify themselves
+        .header("Digitraffic-User", "T-botti");
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+fn parse_stations(json_text: &str) -> Result<Vec<RoadStation>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let stations = json["features"]
+        .as_array()
+        .map(|features| {
+            features
+                .iter()
+                .filter_map(|f| {
+                    Some(RoadStation {
+                        id: f["id"].as_i64()?,
+                        name: f["properties"]["name"].as_str()?.to_owned(),
+                        lon: f["geometry"]["coordinates"][0].as_f64()?,
+                        lat: f["geometry"]["coordinates"][1].as_f64()?,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(stations)
+}
+
+fn parse_station_data(json_text: &str) -> Result<RoadWeather, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let mut weather = RoadWeather::default();
+
+    if let Some(sensors) = json["sensorValues"].as_array() {
+        for sensor in sensors {
+            let description = sensor["sensorValueDescriptionFi"]
+                .as_str()
+                .map(|d| d.to_owned());
+            match sensor["name"].as_str() {
+                Some("ILMA") => weather.air_temperature = sensor["value"].as_f64(),
+                Some("TIE_1") => weather.road_temperature = sensor["value"].as_f64(),
+                Some("KELI_1") => weather.condition = description,
+                Some("VAROITUS_1") => weather.warning = description,
+                _ => {}
+            }
+        }
+    }
+
+    if weather == RoadWeather::default() {
+        return Err("Tietoja ei löytynyt".to_owned());
+    }
+
+    Ok(weather)
+}
+
+/// Stations whose name matches the query, e.g. "vt4_Oulu_Kello" for "oulu kello"
+fn station_by_name<'a>(stations: &'a [RoadStation], query: &str) -> Option<&'a RoadStation> {
+    let query = query.to_lowercase();
+
+    stations
+        .iter()
+        .find(|s| s.name.to_lowercase().replace('_', " ").contains(&query))
+}
+
+fn nearest_station(stations: &[RoadStation], lat: f64, lon: f64) -> Option<(&RoadStation, f64)> {
+    stations
+        .iter()
+        .map(|s| (s, distance_km(lat, lon, s.lat, s.lon)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+fn generate_msg(station: &str, distance: Option<f64>, weather: &RoadWeather) -> String {
+    let mut msg = match distance {
+        Some(d) => format!("{} ({:.0} km): ", station, d),
+        None => format!("{}: ", station),
+    };
+
+    let mut parts = Vec::new();
+    if let Some(t) = weather.air_temperature {
+        parts.push(format!("ilma {:.1}°C", t));
+    }
+    if let Some(t) = weather.road_temperature {
+        parts.push(format!("tie {:.1}°C", t));
+    }
+    if let Some(c) = &weather.condition {
+        parts.push(format!("keli: {}", c.to_lowercase()));
+    }
+    if let Some(w) = &weather.warning {
+        parts.push(format!("varoitus: {}", w.to_lowercase()));
+    }
+
+    msg.push_str(&parts.join(", "));
+    msg
+}
+
+async fn road_weather(query: &str) -> Result<String, String> {
+    let stations = match get_json(ROAD_WEATHER_URL).await {
+        Ok(json) => parse_stations(&json)?,
+        Err(_) => {
+            return Err("Tietojen haku ei onnistunut".to_owned());
+        }
+    };
+
+    let (station, distance) = match station_by_name(&stations, query) {
+        Some(s) => (s, None),
+        None => {
+            let (lat, lon) = match geocode(query).await {
+                Ok(c) => c,
+                Err(_) => {
+                    return Err("Paikkaa ei löytynyt".to_owned());
+                }
+            };
+            match nearest_station(&stations, lat, lon) {
+                Some((s, d)) => (s, Some(d)),
+                None => {
+                    return Err("Tietoja ei löytynyt".to_owned());
+                }
+            }
+        }
+    };
+
+    let url = format!("{}/{}/data", ROAD_WEATHER_URL, station.id);
+    match get_json(&url).await {
+        Ok(json) => Ok(generate_msg(
+            &station.name,
+            distance,
+            &parse_station_data(&json)?,
+        )),
+        Err(_) => Err("Tietojen haku ei onnistunut".to_owned()),
+    }
+}
+
+fn parse_rail_stations(json_text: &str) -> Result<Vec<RailStation>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    Ok(json
+        .as_array()
+        .map(|stations| {
+            stations
+                .iter()
+                .filter(|s| s["passengerTraffic"].as_bool() == Some(true))
+                .filter_map(|s| {
+                    Some(RailStation {
+                        code: s["stationShortCode"].as_str()?.to_owned(),
+                        // "Helsinki asema" -> "Helsinki"
+                        name: s["stationName"]
+                            .as_str()?
+                            .trim_end_matches(" asema")
+                            .to_owned(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn parse_trains(json_text: &str) -> Result<Vec<Train>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+    let time = |v: &serde_json::Value| v.as_str().and_then(|t| t.parse::<DateTime<Utc>>().ok());
+
+    Ok(json
+        .as_array()
+        .map(|trains| {
+            trains
+                .iter()
+                .filter_map(|t| {
+                    let name = match t["commuterLineID"].as_str().filter(|l| !l.is_empty()) {
+                        Some(line) => line.to_owned(),
+                        None => format!(
+                            "{} {}",
+                            t["trainType"].as_str()?,
+                            t["trainNumber"].as_i64()?
+                        ),
+                    };
+                    let rows = t["timeTableRows"]
+                        .as_array()?
+                        .iter()
+                        .filter_map(|r| {
+                            Some(TimetableRow {
+                                station: r["stationShortCode"].as_str()?.to_owned(),
+                                departure: r["type"].as_str()? == "DEPARTURE",
+                                scheduled: time(&r["scheduledTime"])?,
+                                estimate: time(&r["liveEstimateTime"]),
+                                actual: time(&r["actualTime"]),
+                                difference: r["differenceInMinutes"].as_i64(),
+                                commercial: r["commercialStop"].as_bool().unwrap_or(false),
+                                cancelled: r["cancelled"].as_bool().unwrap_or(false),
+                            })
+                        })
+                        .collect();
+                    Some(Train {
+                        name,
+                        cancelled: t["cancelled"].as_bool().unwrap_or(false),
+                        rows,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Station by its code or name, "hki", "Tampere" or "tikkuri"
+fn find_rail_station<'a>(stations: &'a [RailStation], query: &str) -> Option<&'a RailStation> {
+    let query = query.to_lowercase();
+
+    stations
+        .iter()
+        .find(|s| s.code.to_lowercase() == query || s.name.to_lowercase() == query)
+        .or_else(|| {
+            stations
+                .iter()
+                .find(|s| s.name.to_lowercase().starts_with(&query))
+        })
+}
+
+fn station_name(stations: &[RailStation], code: &str) -> String {
+    match stations.iter().find(|s| s.code == code) {
+        Some(s) => s.name.to_owned(),
+        None => code.to_owned(),
+    }
+}
+
+fn local_time(time: DateTime<Utc>) -> String {
+    time.with_timezone(&Helsinki).format("%H:%M").to_string()
+}
+
+fn delay_text(minutes: i64) -> String {
+    match minutes {
+        m if m > 0 => format!("{} min myöhässä", m),
+        m if m < 0 => format!("{} min etuajassa", -m),
+        _ => "ajassa".to_owned(),
+    }
+}
+
+/// "IC 27: 5 min myöhässä, seuraavaksi Pasila 12:05, Tikkurila 12:15"
+fn train_msg(train: &Train, stations: &[RailStation]) -> String {
+    if train.cancelled {
+        return format!("{}: peruttu", train.name);
+    }
+
+    let last_passed = train.rows.iter().rev().find(|r| r.actual.is_some());
+    let next_stops: Vec<String> = train
+        .rows
+        .iter()
+        .filter(|r| r.actual.is_none() && r.commercial && !r.cancelled)
+        // Arrivals, except for the first station that only has a departure
+        .filter(|r| !r.departure || last_passed.is_none())
+        .take(NEXT_STOPS)
+        .map(|r| {
+            format!(
+                "{} {}",
+                station_name(stations, &r.station),
+                local_time(r.estimate.unwrap_or(r.scheduled))
+            )
+        })
+        .collect();
+
+    let status = match (last_passed, train.rows.last()) {
+        (None, _) => "ei vielä lähtenyt".to_owned(),
+        (Some(r), Some(last)) if r == last => {
+            return format!(
+                "{}: saapui {} {}",
+                train.name,
+                station_name(stations, &last.station),
+                local_time(last.actual.unwrap())
+            );
+        }
+        (Some(r), _) => delay_text(r.difference.unwrap_or(0)),
+    };
+
+    if next_stops.is_empty() {
+        format!("{}: {}", train.name, status)
+    } else {
+        format!(
+            "{}: {}, seuraavaksi {}",
+            train.name,
+            status,
+            next_stops.join(", ")
+        )
+    }
+}
+
+/// Next departures from the station with their destinations and delays
+fn departures_msg(
+    trains: &[Train],
+    station: &RailStation,
+    stations: &[RailStation],
+    now: DateTime<Utc>,
+) -> String {
+    let mut departures: Vec<(DateTime<Utc>, String)> = trains
+        .iter()
+        .filter_map(|t| {
+            let row = t.rows.iter().find(|r| {
+                r.station == station.code && r.departure && r.commercial && r.actual.is_none()
+            })?;
+            let destination = station_name(stations, &t.rows.last()?.station);
+            let time = row.estimate.unwrap_or(row.scheduled);
+            if time < now || destination == station.name {
+                return None;
+            }
+
+            let mut text = format!("{} {} → {}", local_time(row.scheduled), t.name, destination);
+            if row.cancelled {
+                text.push_str(" (peruttu)");
+            } else if let Some(d) = row.difference.filter(|d| *d > 0) {
+                text.push_str(&format!(" (+{})", d));
+            }
+            Some((row.scheduled, text))
+        })
+        .collect();
+    departures.sort();
+
+    if departures.is_empty() {
+        return format!("{}: ei lähtöjä", station.name);
+    }
+
+    let list: Vec<String> = departures
+        .into_iter()
+        .take(DEPARTURES)
+        .map(|(_, text)| text)
+        .collect();
+    format!("{}: {}", station.name, list.join(", "))
+}
+
+async fn rail_stations() -> Result<Vec<RailStation>, String> {
+    if let Ok(stations) = RAIL_STATIONS.lock() {
+        if !stations.is_empty() {
+            return Ok(stations.clone());
+        }
+    }
+
+    let stations = match get_json(&format!("{}/metadata/stations", RAIL_URL)).await {
+        Ok(json) => parse_rail_stations(&json)?,
+        Err(_) => {
+            return Err("Tietojen haku ei onnistunut".to_owned());
+        }
+    };
+    *RAIL_STATIONS.lock().unwrap() = stations.clone();
+
+    Ok(stations)
+}
+
+async fn train_status(query: &str) -> Result<String, String> {
+    let stations = rail_stations().await?;
+
+    if let Ok(number) = query.parse::<u32>() {
+        let url = format!("{}/trains/latest/{}", RAIL_URL, number);
+        return match get_json(&url).await {
+            Ok(json) => match parse_trains(&json)?.first() {
+                Some(train) => Ok(train_msg(train, &stations)),
+                None => Err(format!("Junaa {} ei löytynyt", number)),
+            },
+            Err(_) => Err("Tietojen haku ei onnistunut".to_owned()),
+        };
+    }
+
+    let station = match find_rail_station(&stations, query) {
+        Some(s) => s,
+        None => {
+            return Err("Asemaa ei löytynyt".to_owned());
+        }
+    };
+    let url = format!(
+        "{}/live-trains/station/{}?arrived_trains=0&arriving_trains=0\
+        &departed_trains=0&departing_trains=15&include_nonstopping=false",
+        RAIL_URL, station.code
+    );
+    match get_json(&url).await {
+        Ok(json) => Ok(departures_msg(
+            &parse_trains(&json)?,
+            station,
+            &stations,
+            Utc::now(),
+        )),
+        Err(_) => Err("Tietojen haku ei onnistunut".to_owned()),
+    }
+}
+
+pub async fn command_juna(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let msg = if params.is_empty() {
+        "Usage: .juna <junan numero | asema>".to_owned()
+    } else {
+        match train_status(params).await {
+            Ok(m) => m,
+            Err(e) => e,
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_tiesaa(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    if params.is_empty() {
+        return;
+    }
+
+    let msg = match road_weather(params).await {
+        Ok(m) => m,
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATIONS_JSON: &str = r###"{"type":"FeatureCollection","features":[{"type":"Feature","id":1001,"geometry":{"type":"Point","coordinates":[24.93,60.25,0.0]},"properties":{"id":1001,"name":"kt51_Espoo_Lahnus","collectionStatus":"GATHERING"}},{"type":"Feature","id":12017,"geometry":{"type":"Point","coordinates":[25.47,65.0,0.0]},"properties":{"id":12017,"name":"vt4_Oulu_Kello","collectionStatus":"GATHERING"}}]}"###;
+
+    const DATA_JSON: &str = r###"{"id":12017,"dataUpdatedTime":"2023-01-10T10:00:00Z","sensorValues":[{"id":1,"stationId":12017,"name":"ILMA","shortName":"Ilma","value":-12.3,"unit":"°C"},{"id":3,"stationId":12017,"name":"TIE_1","shortName":"Tie1","value":-14.1,"unit":"°C"},{"id":27,"stationId":12017,"name":"KELI_1","shortName":"Keli1","value":5.0,"unit":"***","sensorValueDescriptionFi":"Luminen","sensorValueDescriptionEn":"Snowy"},{"id":28,"stationId":12017,"name":"VAROITUS_1","shortName":"Var1","value":1.0,"unit":"***","sensorValueDescriptionFi":"Hälytys","sensorValueDescriptionEn":"Alarm"}]}"###;
+
+    #[test]
+    fn tiesaa() {
+        let stations = parse_stations(STATIONS_JSON).unwrap();
+        assert_eq!(stations.len(), 2);
+
+        assert_eq!(
+            station_by_name(&stations, "oulu kello").map(|s| s.id),
+            Some(12017)
+        );
+        let (nearest, _) = nearest_station(&stations, 60.17, 24.94).unwrap();
+        assert_eq!(nearest.name, "kt51_Espoo_Lahnus");
+
+        let weather = parse_station_data(DATA_JSON).unwrap();
+        assert_eq!(
+            generate_msg("vt4_Oulu_Kello", Some(3.2), &weather),
+            "vt4_Oulu_Kello (3 km): ilma -12.3°C, tie -14.1°C, keli: luminen, varoitus: hälytys"
+        );
+        assert!(parse_station_data(r#"{"sensorValues":[]}"#).is_err());
+    }
+
+    const RAIL_STATIONS_JSON: &str = r###"[{"passengerTraffic":true,"type":"STATION","stationName":"Helsinki asema","stationShortCode":"HKI","stationUICCode":1,"countryCode":"FI"},{"passengerTraffic":true,"type":"STOPPING_POINT","stationName":"Pasila asema","stationShortCode":"PSL","stationUICCode":10,"countryCode":"FI"},{"passengerTraffic":true,"type":"STATION","stationName":"Tampere asema","stationShortCode":"TPE","stationUICCode":160,"countryCode":"FI"},{"passengerTraffic":false,"type":"STATION","stationName":"Ilmala ratapiha","stationShortCode":"ILR","stationUICCode":1029,"countryCode":"FI"}]"###;
+
+    const TRAIN_JSON: &str = r###"[{"trainNumber":27,"departureDate":"2023-06-01","trainType":"IC","commuterLineID":"","cancelled":false,"timeTableRows":[
+        {"stationShortCode":"HKI","type":"DEPARTURE","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T09:00:00.000Z","actualTime":"2023-06-01T09:01:00.000Z","differenceInMinutes":1},
+        {"stationShortCode":"PSL","type":"ARRIVAL","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T09:05:00.000Z","actualTime":"2023-06-01T09:09:00.000Z","differenceInMinutes":4},
+        {"stationShortCode":"PSL","type":"DEPARTURE","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T09:06:00.000Z","actualTime":"2023-06-01T09:11:00.000Z","differenceInMinutes":5},
+        {"stationShortCode":"ILR","type":"ARRIVAL","commercialStop":false,"cancelled":false,"scheduledTime":"2023-06-01T09:20:00.000Z","liveEstimateTime":"2023-06-01T09:25:00.000Z"},
+        {"stationShortCode":"ILR","type":"DEPARTURE","commercialStop":false,"cancelled":false,"scheduledTime":"2023-06-01T09:20:00.000Z","liveEstimateTime":"2023-06-01T09:25:00.000Z"},
+        {"stationShortCode":"TPE","type":"ARRIVAL","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T10:30:00.000Z","liveEstimateTime":"2023-06-01T10:34:00.000Z","differenceInMinutes":4}]},
+        {"trainNumber":9655,"departureDate":"2023-06-01","trainType":"HL","commuterLineID":"R","cancelled":false,"timeTableRows":[
+        {"stationShortCode":"HKI","type":"DEPARTURE","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T08:58:00.000Z","liveEstimateTime":"2023-06-01T08:58:00.000Z"},
+        {"stationShortCode":"TPE","type":"ARRIVAL","commercialStop":true,"cancelled":false,"scheduledTime":"2023-06-01T10:50:00.000Z"}]}]"###;
+
+    #[test]
+    fn juna() {
+        let stations = parse_rail_stations(RAIL_STATIONS_JSON).unwrap();
+        assert_eq!(stations.len(), 3);
+        assert_eq!(
+            find_rail_station(&stations, "hki").unwrap().name,
+            "Helsinki"
+        );
+        assert_eq!(find_rail_station(&stations, "tampere").unwrap().code, "TPE");
+        assert_eq!(find_rail_station(&stations, "pasi").unwrap().code, "PSL");
+        assert!(find_rail_station(&stations, "ilmala").is_none());
+
+        let trains = parse_trains(TRAIN_JSON).unwrap();
+        assert_eq!(trains.len(), 2);
+        assert_eq!(
+            train_msg(&trains[0], &stations),
+            "IC 27: 5 min myöhässä, seuraavaksi Tampere 13:34"
+        );
+        assert_eq!(
+            train_msg(&trains[1], &stations),
+            "R: ei vielä lähtenyt, seuraavaksi Helsinki 11:58, Tampere 13:50"
+        );
+
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 8, 50, 0).unwrap();
+        assert_eq!(
+            departures_msg(&trains, &stations[0], &stations, now),
+            "Helsinki: 11:58 R → Tampere"
+        );
+        assert_eq!(
+            departures_msg(&trains, &stations[2], &stations, now),
+            "Tampere: ei lähtöjä"
+        );
+    }
+}
@@ -0,0 +1,411 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Discord bots from the `discord:` section of the config. Messages come in
+//! over the gateway and go to message_handler as IRC PRIVMSGs from
+//! `username!userid@discord`. Guild channels are `#<channel id>` and direct
+//! messages `<channel id>`, so they count as channels and private messages
+//! like on IRC. Admins are listed as Discord user ids.
+
+use futures::prelude::*;
+use irc::client::prelude::{Command, Message, Prefix};
+use log::{error, info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message as Frame;
+use yaml_rust::yaml::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::chat;
+use crate::health;
+use crate::http_client;
+
+const GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const API_URL: &str = "https://discord.com/api/v10";
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// When Discord asks for a reconnect
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// In characters
+const MAX_MESSAGE_LEN: usize = 2000;
+const USER_HOST: &str = "discord";
+
+// GUILDS, GUILD_MESSAGES, DIRECT_MESSAGES and MESSAGE_CONTENT
+const INTENTS: u64 = 1 | 1 << 9 | 1 << 12 | 1 << 15;
+
+struct Bot {
+    network: String,
+    token: String,
+}
+
+/// Admin masks of the Discord bots, by network
+pub fn admins(config: &Yaml) -> HashMap<String, Vec<String>> {
+    let mut admins = HashMap::new();
+    for bot in config["discord"].as_vec().into_iter().flatten() {
+        if let Some(network) = bot["network"].as_str() {
+            let masks = bot["admins"]
+                .as_vec()
+                .into_iter()
+                .flatten()
+                .filter_map(|a| match a {
+                    Yaml::String(s) => Some(s.to_owned()),
+                    Yaml::Integer(i) => Some(i.to_string()),
+                    _ => None,
+                })
+                .map(|id| format!("*!{}@{}", id, USER_HOST))
+                .collect();
+            admins.insert(network.to_owned(), masks);
+        }
+    }
+
+    admins
+}
+
+/// A MESSAGE_CREATE from a user as an IRC message
+fn parse_message(message: &serde_json::Value) -> Option<Message> {
+    let author = &message["author"];
+    if author["bot"].as_bool().unwrap_or(false) {
+        return None;
+    }
+    let content = message["content"].as_str().filter(|c| !c.is_empty())?;
+    let channel_id = message["channel_id"].as_str()?;
+    let channel = if message["guild_id"].is_string() {
+        format!("#{}", channel_id)
+    } else {
+        channel_id.to_owned()
+    };
+
+    Some(Message {
+        tags: None,
+        prefix: Some(Prefix::Nickname(
+            author["username"].as_str()?.to_owned(),
+            author["id"].as_str()?.to_owned(),
+            USER_HOST.to_owned(),
+        )),
+        command: Command::PRIVMSG(channel, content.replace('\n', " ")),
+    })
+}
+
+/// Text channels of a GUILD_CREATE
+fn guild_channels(guild: &serde_json::Value) -> Vec<String> {
+    guild["channels"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|c| c["type"] == 0)
+        .filter_map(|c| c["id"].as_str())
+        .map(|id| format!("#{}", id))
+        .collect()
+}
+
+/// What is needed to resume a gateway session after reconnecting
+#[derive(Debug, Default)]
+struct Session {
+    id: Option<String>,
+    resume_url: Option<String>,
+    sequence: Option<u64>,
+}
+
+impl Session {
+    fn resumable(&self) -> bool {
+        self.id.is_some() && self.sequence.is_some()
+    }
+}
+
+/// Resume for a session that is still valid, identify otherwise. Discord
+/// allows only so many identifies a day.
+fn handshake(token: &str, session: &Session) -> serde_json::Value {
+    match (&session.id, session.sequence) {
+        (Some(id), Some(sequence)) => json!({"op": 6, "d": {
+            "token": token,
+            "session_id": id,
+            "seq": sequence,
+        }}),
+        _ => json!({"op": 2, "d": {
+            "token": token,
+            "intents": INTENTS,
+            "properties": {"os": "linux", "browser": "tbotti", "device": "tbotti"},
+        }}),
+    }
+}
+
+/// One gateway connection, until Discord closes it or asks to reconnect
+async fn gateway_session(
+    bot: &Bot,
+    session: &mut Session,
+    input: &mpsc::Sender<(String, Message)>,
+) -> Result<(), String> {
+    let url = match (&session.resume_url, session.resumable()) {
+        (Some(u), true) => format!("{}/?v=10&encoding=json", u.trim_end_matches('/')),
+        _ => GATEWAY_URL.to_owned(),
+    };
+    let (stream, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (mut write, mut read) = stream.split();
+
+    let mut heartbeat: Option<tokio::time::Interval> = None;
+    let mut channels: Vec<String> = vec![];
+
+    loop {
+        let frame = tokio::select! {
+            _ = async { heartbeat.as_mut().unwrap().tick().await }, if heartbeat.is_some() => {
+                let beat = json!({"op": 1, "d": session.sequence}).to_string();
+                write.send(Frame::Text(beat)).await.map_err(|e| e.to_string())?;
+                continue;
+            }
+            frame = read.next() => frame,
+        };
+
+        let text = match frame {
+            Some(Ok(Frame::Text(t))) => t,
+            Some(Ok(Frame::Close(close))) => {
+                // Invalid sequence and session timeout, the session is gone
+                if let Some(code) = close.as_ref().map(|c| u16::from(c.code)) {
+                    if code == 4007 || code == 4009 {
+                        *session = Session::default();
+                    }
+                }
+                return Err(format!("closed by Discord: {:?}", close));
+            }
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.to_string()),
+            None => return Err("connection closed".to_owned()),
+        };
+        let event: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        if let Some(s) = event["s"].as_u64() {
+            session.sequence = Some(s);
+        }
+
+        match event["op"].as_u64() {
+            // Hello
+            Some(10) => {
+                let interval = event["d"]["heartbeat_interval"].as_u64().unwrap_or(41250);
+                let start = tokio::time::Instant::now() + Duration::from_millis(interval);
+                heartbeat = Some(tokio::time::interval_at(
+                    start,
+                    Duration::from_millis(interval),
+                ));
+                write
+                    .send(Frame::Text(handshake(&bot.token, session).to_string()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            // Heartbeat request
+            Some(1) => {
+                let beat = json!({"op": 1, "d": session.sequence}).to_string();
+                write
+                    .send(Frame::Text(beat))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            // Reconnect
+            Some(7) => return Ok(()),
+            // Invalid Session, resumable if d is true
+            Some(9) => {
+                if event["d"] != true {
+                    *session = Session::default();
+                }
+                return Ok(());
+            }
+            Some(0) => match event["t"].as_str() {
+                Some("READY") => {
+                    info!("Connected to Discord as {}", bot.network);
+                    session.id = event["d"]["session_id"].as_str().map(str::to_owned);
+                    session.resume_url =
+                        event["d"]["resume_gateway_url"].as_str().map(str::to_owned);
+                    health::set_connected(&bot.network, true);
+                }
+                Some("RESUMED") => {
+                    info!("Resumed Discord session of {}", bot.network);
+                    health::set_connected(&bot.network, true);
+                }
+                Some("GUILD_CREATE") => {
+                    channels.extend(guild_channels(&event["d"]));
+                    health::set_channels(&bot.network, channels.clone());
+                }
+                Some("MESSAGE_CREATE") => {
+                    if let Some(message) = parse_message(&event["d"]) {
+                        if input.send((bot.network.to_owned(), message)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+async fn gateway_loop(bot: Arc<Bot>, input: mpsc::Sender<(String, Message)>) {
+    let mut session = Session::default();
+    while !input.is_closed() {
+        match gateway_session(&bot, &mut session, &input).await {
+            Ok(()) => {
+                info!("Reconnecting to Discord as {}", bot.network);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+            Err(e) => {
+                error!("Discord gateway of {} failed: {}", bot.network, e);
+                health::set_connected(&bot.network, false);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// A message that can't ping anyone, whatever a factoid or feed title says
+fn message_body(action_type: &ActionType) -> serde_json::Value {
+    let content = match action_type {
+        ActionType::Message(t) | ActionType::Notice(t) => chat::truncate(t, MAX_MESSAGE_LEN),
+        ActionType::Action(t) => chat::truncate(&format!("_{}_", t), MAX_MESSAGE_LEN),
+    };
+
+    json!({
+        "content": content,
+        "allowed_mentions": {"parse": []},
+    })
+}
+
+async fn send_loop(bot: Arc<Bot>, mut actions: mpsc::Receiver<BotAction>) {
+    while let Some(action) = actions.recv().await {
+        let channel_id = action.target.channel.trim_start_matches('#');
+
+        let request = http_client::HTTP_CLIENT
+            .post(format!("{}/channels/{}/messages", API_URL, channel_id))
+            .header(reqwest::header::AUTHORIZATION, format!("Bot {}", bot.token))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(message_body(&action.action_type).to_string());
+        match http_client::send(request).await {
+            Ok(r) if !r.status().is_success() => warn!(
+                "Could not send to {} on {}: {}",
+                channel_id,
+                bot.network,
+                r.status()
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Could not send to {} on {}: {}", channel_id, bot.network, e),
+        }
+    }
+}
+
+pub async fn discord_loop(
+    input: mpsc::Sender<(String, Message)>,
+    mut actions: mpsc::Receiver<BotAction>,
+    config: Arc<Yaml>,
+) {
+    let mut senders: HashMap<String, mpsc::Sender<BotAction>> = HashMap::new();
+    for bot in config["discord"].as_vec().into_iter().flatten() {
+        let (network, token) = match (bot["network"].as_str(), bot["token"].as_str()) {
+            (Some(n), Some(t)) => (n.to_owned(), t.to_owned()),
+            _ => {
+                error!("Discord bot needs a network name and a token");
+                continue;
+            }
+        };
+        health::set_connected(&network, false);
+        let bot = Arc::new(Bot { network, token });
+
+        let (tx, rx) = mpsc::channel(10);
+        senders.insert(bot.network.to_owned(), tx);
+        let gateway_bot = bot.clone();
+        let bot_input = input.clone();
+        tokio::spawn(async move { gateway_loop(gateway_bot, bot_input).await });
+        tokio::spawn(async move { send_loop(bot, rx).await });
+    }
+
+    while let Some(action) = actions.recv().await {
+        if let Some(sender) = senders.get(&action.target.network) {
+            let _ = sender.send(action).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages() {
+        let guild_message = json!({
+            "channel_id": "1093500000000000000",
+            "guild_id": "1093400000000000000",
+            "author": {"id": "80351110224678912", "username": "nelly"},
+            "content": ".sää Tampere",
+        });
+        let message = parse_message(&guild_message).unwrap();
+        assert_eq!(
+            message.prefix,
+            Some(Prefix::Nickname(
+                "nelly".to_owned(),
+                "80351110224678912".to_owned(),
+                "discord".to_owned()
+            ))
+        );
+        assert_eq!(
+            message.command,
+            Command::PRIVMSG("#1093500000000000000".to_owned(), ".sää Tampere".to_owned())
+        );
+
+        let direct_message = json!({
+            "channel_id": "1093600000000000000",
+            "author": {"id": "80351110224678912", "username": "nelly"},
+            "content": ".flip",
+        });
+        assert_eq!(
+            parse_message(&direct_message).unwrap().command,
+            Command::PRIVMSG("1093600000000000000".to_owned(), ".flip".to_owned())
+        );
+
+        let bot_message = json!({
+            "channel_id": "1093500000000000000",
+            "guild_id": "1093400000000000000",
+            "author": {"id": "1", "username": "tbotti", "bot": true},
+            "content": "Tampere: 3 °C",
+        });
+        assert!(parse_message(&bot_message).is_none());
+    }
+
+    #[test]
+    fn no_mentions() {
+        let body = message_body(&ActionType::Message(
+            "@everyone <@&123> uusi jakso".to_owned(),
+        ));
+        assert_eq!(body["content"], "@everyone <@&123> uusi jakso");
+        assert_eq!(body["allowed_mentions"], json!({"parse": []}));
+        assert_eq!(
+            message_body(&ActionType::Action("heiluttaa".to_owned()))["content"],
+            "_heiluttaa_"
+        );
+    }
+
+    #[test]
+    fn resume_or_identify() {
+        let mut session = Session::default();
+        assert_eq!(handshake("token", &session)["op"], 2);
+
+        session.id = Some("abc".to_owned());
+        assert_eq!(handshake("token", &session)["op"], 2);
+        session.sequence = Some(42);
+        let resume = handshake("token", &session);
+        assert_eq!(resume["op"], 6);
+        assert_eq!(resume["d"]["session_id"], "abc");
+        assert_eq!(resume["d"]["seq"], 42);
+    }
+
+    #[test]
+    fn discord_admins() {
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "discord:\n  - network: discord\n    admins:\n      - '80351110224678912'",
+        )
+        .unwrap();
+        assert_eq!(
+            admins(&config[0])["discord"],
+            vec!["*!80351110224678912@discord".to_owned()]
+        );
+    }
+}
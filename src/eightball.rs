@@ -0,0 +1,88 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use rand::prelude::*;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::ChatTarget;
+
+const DEFAULT_ANSWERS: [&str; 20] = [
+    "Varmasti.",
+    "Ehdottomasti.",
+    "Epäilemättä.",
+    "Kyllä, ehdottomasti.",
+    "Voit luottaa siihen.",
+    "Näkemykseni mukaan kyllä.",
+    "Todennäköisesti.",
+    "Näyttää hyvältä.",
+    "Kyllä.",
+    "Merkit viittaavat siihen.",
+    "Vastaus on epäselvä, yritä uudelleen.",
+    "Kysy myöhemmin uudelleen.",
+    "Parempi etten kerro nyt.",
+    "En voi ennustaa sitä nyt.",
+    "Keskity ja kysy uudelleen.",
+    "Älä luota siihen.",
+    "Vastaukseni on ei.",
+    "Lähteeni sanovat ei.",
+    "Ei näytä hyvältä.",
+    "Erittäin epätodennäköistä.",
+];
+
+/// Answers from `eightball: answers:` in config.yml, or the Finnish defaults
+fn answers_from_config(config: &Yaml) -> Vec<String> {
+    let answers: Vec<String> = config["eightball"]["answers"]
+        .as_vec()
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a.as_str())
+        .map(|a| a.to_owned())
+        .collect();
+
+    if answers.is_empty() {
+        DEFAULT_ANSWERS.iter().map(|a| (*a).to_owned()).collect()
+    } else {
+        answers
+    }
+}
+
+pub async fn command_8ball(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let msg = if params.is_empty() {
+        "Usage: .8ball <question>".to_owned()
+    } else {
+        let answers = answers_from_config(&config);
+        // answers_from_config never returns an empty table
+        answers.choose(&mut thread_rng()).unwrap().to_owned()
+    };
+
+    let a = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+    bot_sender.send(a).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn answer_table() {
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert_eq!(answers_from_config(&config[0]).len(), DEFAULT_ANSWERS.len());
+
+        let config =
+            YamlLoader::load_from_str("eightball:\n  answers:\n    - 'Yes'\n    - 'No'").unwrap();
+        assert_eq!(answers_from_config(&config[0]), vec!["Yes", "No"]);
+    }
+}
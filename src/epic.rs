@@ -3,22 +3,84 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use chrono::prelude::*;
+use core::time::Duration;
+use log::{error, info, warn};
+use rusqlite::{named_params, Connection};
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::db;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
 
-async fn get_json() -> reqwest::Result<String> {
-    let baseurl = "https://store-site-backend-static.ak.epicgames.com/freeGamesPromotions?locale=en-US&country=FI&allowCountries=FI";
+// Promotions change a few times a week, .epic and the announcer share the response
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
 
-    let json = HTTP_CLIENT.get(baseurl).send().await?.text().await?;
+/// Store region from `epic: locale/country` in config.yml, defaulting to Finland
+#[derive(Debug, PartialEq)]
+struct Region {
+    locale: String,
+    country: String,
+}
 
-    Ok(json)
+impl Region {
+    fn from_config(config: &Yaml) -> Region {
+        Region {
+            locale: config["epic"]["locale"]
+                .as_str()
+                .unwrap_or("en-US")
+                .to_owned(),
+            country: config["epic"]["country"]
+                .as_str()
+                .unwrap_or("FI")
+                .to_uppercase(),
+        }
+    }
 }
 
-fn parse_json(json_text: &str) -> Result<Vec<String>, String> {
-    let mut free_game_names = Vec::new();
+async fn get_json(region: &Region) -> reqwest::Result<String> {
+    let baseurl = "https://store-site-backend-static.ak.epicgames.com/freeGamesPromotions";
+
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("locale", region.locale.as_str()),
+        ("country", &region.country),
+        ("allowCountries", &region.country),
+    ]);
+
+    http_client::get_cached(request, CACHE_TTL).await
+}
+
+const STORE_URL: &str = "https://store.epicgames.com/p/";
+
+#[derive(Clone, Debug, PartialEq)]
+struct FreeGame {
+    title: String,
+    end: DateTime<Utc>,
+    url: Option<String>,
+}
+
+/// Store page of a game. The product slug is missing from some promotions,
+/// so the catalog page mapping is tried first.
+fn store_url(game: &serde_json::Value) -> Option<String> {
+    let mapping = game["catalogNs"]["mappings"]
+        .as_array()
+        .and_then(|m| m.iter().find(|m| m["pageType"] == "productHome"))
+        .and_then(|m| m["pageSlug"].as_str());
+    let product = game["productSlug"]
+        .as_str()
+        .map(|s| s.trim_end_matches("/home"));
+
+    mapping
+        .or(product)
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("{}{}", STORE_URL, s))
+}
+
+fn parse_json(json_text: &str, now: DateTime<Utc>) -> Result<Vec<FreeGame>, String> {
+    let mut free_games = Vec::new();
 
     let json: serde_json::Value = match serde_json::from_str(json_text) {
         Ok(j) => j,
@@ -52,52 +114,201 @@ fn parse_json(json_text: &str) -> Result<Vec<String>, String> {
             }
             let offer = &game["promotions"]["promotionalOffers"][0]["promotionalOffers"][0];
 
-            let find_offer_date_strings = || -> Option<(&str, &str)> {
-                let start = offer["startDate"].as_str()?;
-                let end = offer["endDate"].as_str()?;
+            let find_offer_dates = || -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+                let start = offer["startDate"].as_str()?.parse().ok()?;
+                let end = offer["endDate"].as_str()?.parse().ok()?;
                 Some((start, end))
             };
-            if let Some((start_str, end_str)) = find_offer_date_strings() {
-                let offer_valid = || -> Result<bool, chrono::ParseError> {
-                    let start = start_str.parse::<DateTime<Utc>>()?;
-                    let end = end_str.parse::<DateTime<Utc>>()?;
-                    let now = Utc::now();
-                    if start > now || end < now {
-                        return Ok(false);
-                    }
-                    Ok(true)
-                };
-                match offer_valid() {
-                    Ok(true) => {}
-                    _ => {
-                        continue;
-                    }
+            let end = match find_offer_dates() {
+                Some((start, end)) if start <= now && end >= now => end,
+                _ => {
+                    continue;
                 }
-            } else {
-                continue;
-            }
+            };
 
-            free_game_names.push(title.to_owned());
+            free_games.push(FreeGame {
+                title: title.to_owned(),
+                end,
+                url: store_url(game),
+            });
         }
     } else {
         return Err("No games found".to_owned());
     }
 
-    Ok(free_game_names)
+    Ok(free_games)
+}
+
+/// "vielä 3 päivää"
+fn time_left(end: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let left = end - now;
+    match left.num_days() {
+        0 => match left.num_hours() {
+            0 | 1 => "vielä tunnin".to_owned(),
+            h => format!("vielä {} tuntia", h),
+        },
+        1 => "vielä päivän".to_owned(),
+        d => format!("vielä {} päivää", d),
+    }
 }
 
-fn generate_msg(games: Vec<String>) -> String {
+fn format_games(games: &[&FreeGame], now: DateTime<Utc>) -> String {
+    games
+        .iter()
+        .map(|g| match &g.url {
+            Some(url) => format!("{} ({}, {})", g.title, time_left(g.end, now), url),
+            None => format!("{} ({})", g.title, time_left(g.end, now)),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn generate_msg(games: &[FreeGame], now: DateTime<Utc>) -> String {
     if games.is_empty() {
         "Ei ilmaisia pelejä Epicissä.".to_owned()
     } else {
-        format!("Epicissä nyt ilmaiseksi: {}", games.join(", "))
+        let games: Vec<&FreeGame> = games.iter().collect();
+        format!("Epicissä nyt ilmaiseksi: {}", format_games(&games, now))
+    }
+}
+
+pub const DB: db::Database = db::Database {
+    name: "epic.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS announced (
+            title TEXT PRIMARY KEY,
+            time TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Games in `games` that have not been announced yet, marking them announced
+fn unannounced_games<'a>(
+    conn: &Connection,
+    games: &'a [FreeGame],
+) -> rusqlite::Result<Vec<&'a FreeGame>> {
+    let mut new_games = Vec::new();
+
+    for game in games {
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO announced (title, time) VALUES (:title, :time)",
+            named_params! {
+                ":title": game.title,
+                ":time": Utc::now().to_rfc3339(),
+            },
+        )?;
+        if inserted > 0 {
+            new_games.push(game);
+        }
+    }
+
+    Ok(new_games)
+}
+
+fn subscriptions_from_config(config: &Yaml) -> Vec<ChatTarget> {
+    let mut subscriptions = Vec::new();
+
+    if let Some(channels) = config["epic"]["channels"].as_vec() {
+        for c in channels {
+            if let (Some(network), Some(channel)) = (c["network"].as_str(), c["channel"].as_str()) {
+                subscriptions.push(ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                });
+            }
+        }
     }
+
+    subscriptions
+}
+
+/// Announce new free games to the subscribed channels. The rotation normally
+/// changes on Thursday evenings, but polling also catches the daily games
+/// around Christmas.
+pub async fn epic_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(30 * 60);
+    let subscriptions = subscriptions_from_config(&config);
+    let region = Region::from_config(&config);
+
+    if subscriptions.is_empty() {
+        info!("No Epic free game subscriptions configured");
+        return;
+    }
+
+    loop {
+        let now = Utc::now();
+        let games = match get_json(&region).await.map(|json| parse_json(&json, now)) {
+            Ok(Ok(games)) => games,
+            Ok(Err(e)) => {
+                warn!("Epic: {}", e);
+                Vec::new()
+            }
+            Err(e) => {
+                warn!("Epic: {}", e);
+                Vec::new()
+            }
+        };
+
+        let new_games = db::call(&DB, move |c| {
+            let new_games = unannounced_games(c, &games)?;
+            Ok(new_games.into_iter().cloned().collect::<Vec<FreeGame>>())
+        })
+        .await;
+        let new_games = match new_games {
+            Ok(g) => g,
+            Err(e) => {
+                error!("Epic: database error: {}", e);
+                Vec::new()
+            }
+        };
+
+        if !new_games.is_empty() {
+            let msg = format!(
+                "Uusia ilmaispelejä Epicissä: {}",
+                format_games(&new_games.iter().collect::<Vec<&FreeGame>>(), now)
+            );
+            for s in &subscriptions {
+                let action = BotAction {
+                    target: ChatTarget {
+                        network: s.network.to_owned(),
+                        channel: s.channel.to_owned(),
+                    },
+                    action_type: ActionType::Message(msg.to_owned()),
+                };
+                sender.send(action).await.unwrap();
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+/// Titles of the games currently free, for the combined store listing
+pub async fn free_titles(config: &Yaml) -> Result<Vec<String>, String> {
+    let json = get_json(&Region::from_config(config))
+        .await
+        .map_err(|e| e.to_string())?;
+    let games = parse_json(&json, Utc::now())?;
+
+    Ok(games.into_iter().map(|g| g.title).collect())
 }
 
-pub async fn command_epic(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel) {
-    let msg = if let Ok(json) = get_json().await {
-        match parse_json(&json) {
-            Ok(data) => generate_msg(data),
+pub async fn command_epic(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    config: Arc<Yaml>,
+) {
+    let msg = if let Ok(json) = get_json(&Region::from_config(&config)).await {
+        let now = Utc::now();
+        match parse_json(&json, now) {
+            Ok(data) => generate_msg(&data, now),
             Err(_) => "Virhe ilmaispelien haussa".to_owned(),
         }
     } else {
@@ -111,3 +322,100 @@ pub async fn command_epic(bot_sender: mpsc::Sender<BotAction>, source: IrcChanne
 
     bot_sender.send(action).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn open_db(testing: bool) -> rusqlite::Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    fn game(title: &str) -> FreeGame {
+        FreeGame {
+            title: title.to_owned(),
+            end: Utc.with_ymd_and_hms(2023, 6, 8, 15, 0, 0).unwrap(),
+            url: None,
+        }
+    }
+
+    #[test]
+    fn region() {
+        let config = YamlLoader::load_from_str("epic:\n  locale: de-DE\n  country: de").unwrap();
+        assert_eq!(
+            Region::from_config(&config[0]),
+            Region {
+                locale: "de-DE".to_owned(),
+                country: "DE".to_owned()
+            }
+        );
+
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert_eq!(
+            Region::from_config(&config[0]),
+            Region {
+                locale: "en-US".to_owned(),
+                country: "FI".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn announce_once() {
+        let conn = open_db(true).unwrap();
+        let games = vec![game("Game A"), game("Game B")];
+
+        assert_eq!(unannounced_games(&conn, &games).unwrap().len(), 2);
+        assert!(unannounced_games(&conn, &games).unwrap().is_empty());
+
+        let rotated = vec![game("Game B"), game("Game C")];
+        assert_eq!(
+            unannounced_games(&conn, &rotated).unwrap(),
+            vec![&rotated[1]]
+        );
+    }
+
+    #[test]
+    fn end_dates_and_links() {
+        let json = r#"{"data":{"Catalog":{"searchStore":{"elements":[
+            {
+                "title": "Game A",
+                "productSlug": "game-a/home",
+                "catalogNs": {"mappings": []},
+                "price": {"totalPrice": {"discountPrice": 0}},
+                "promotions": {"promotionalOffers": [{"promotionalOffers": [
+                    {"startDate": "2023-06-01T15:00:00.000Z", "endDate": "2023-06-08T15:00:00.000Z"}
+                ]}]}
+            },
+            {
+                "title": "Game B",
+                "productSlug": null,
+                "catalogNs": {"mappings": [{"pageSlug": "game-b-1a2b3c", "pageType": "productHome"}]},
+                "price": {"totalPrice": {"discountPrice": 0}},
+                "promotions": {"promotionalOffers": [{"promotionalOffers": [
+                    {"startDate": "2023-06-01T15:00:00.000Z", "endDate": "2023-06-05T15:00:00.000Z"}
+                ]}]}
+            },
+            {
+                "title": "Game C",
+                "price": {"totalPrice": {"discountPrice": 1999}},
+                "promotions": null
+            }
+        ]}}}}"#;
+
+        let now = Utc.with_ymd_and_hms(2023, 6, 5, 9, 0, 0).unwrap();
+        let games = parse_json(json, now).unwrap();
+        assert_eq!(
+            generate_msg(&games, now),
+            "Epicissä nyt ilmaiseksi: Game A (vielä 3 päivää, https://store.epicgames.com/p/game-a), \
+            Game B (vielä 6 tuntia, https://store.epicgames.com/p/game-b-1a2b3c)"
+        );
+
+        let hours = |h: i64| now + chrono::Duration::minutes(h * 60 - 1);
+        assert_eq!(time_left(hours(1), now), "vielä tunnin");
+        assert_eq!(time_left(hours(2), now), "vielä tunnin");
+        assert_eq!(time_left(hours(3), now), "vielä 2 tuntia");
+        assert_eq!(time_left(hours(49), now), "vielä 2 päivää");
+    }
+}
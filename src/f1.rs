@@ -0,0 +1,340 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use irc::client::prelude::Prefix;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+const F1_URL: &str = "https://api.jolpi.ca/ergast/f1/current";
+const STANDINGS_COUNT: usize = 5;
+// When no session is coming up, e.g. between seasons
+const DEFAULT_CACHE_HOURS: i64 = 6;
+
+/// Sessions of a race weekend in the API and how they are shown
+const SESSIONS: [(&str, &str); 6] = [
+    ("FirstPractice", "1. harjoitus"),
+    ("SecondPractice", "2. harjoitus"),
+    ("ThirdPractice", "3. harjoitus"),
+    ("SprintQualifying", "sprintin aika-ajo"),
+    ("Sprint", "sprintti"),
+    ("Qualifying", "aika-ajo"),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Session {
+    name: &'static str,
+    start: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Race {
+    name: String,
+    circuit: String,
+    locality: String,
+    /// In order, the race itself last
+    sessions: Vec<Session>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Standing {
+    position: String,
+    driver: String,
+    team: String,
+    points: String,
+}
+
+/// API responses are kept until the next session starts, as nothing changes before that
+#[derive(Default)]
+struct Cache {
+    race: Option<(Option<Race>, DateTime<Utc>)>,
+    standings: Option<(Vec<Standing>, DateTime<Utc>)>,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::default());
+}
+
+async fn get_json(path: &str) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT.get(format!("{}/{}", F1_URL, path));
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+fn session_time(json: &serde_json::Value) -> Option<DateTime<Utc>> {
+    format!("{}T{}", json["date"].as_str()?, json["time"].as_str()?)
+        .parse()
+        .ok()
+}
+
+/// The next race, None when the season is over
+fn parse_next_race(json_text: &str) -> Result<Option<Race>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let race = &json["MRData"]["RaceTable"]["Races"][0];
+    if race.is_null() {
+        return Ok(None);
+    }
+
+    let mut sessions: Vec<Session> = SESSIONS
+        .iter()
+        .filter_map(|(key, name)| {
+            Some(Session {
+                name,
+                start: session_time(&race[key])?,
+            })
+        })
+        .collect();
+    sessions.sort_by_key(|s| s.start);
+    if let Some(start) = session_time(race) {
+        sessions.push(Session {
+            name: "kilpailu",
+            start,
+        });
+    }
+
+    let text = |v: &serde_json::Value| v.as_str().unwrap_or_default().to_owned();
+    Ok(Some(Race {
+        name: text(&race["raceName"]),
+        circuit: text(&race["Circuit"]["circuitName"]),
+        locality: text(&race["Circuit"]["Location"]["locality"]),
+        sessions,
+    }))
+}
+
+fn parse_standings(json_text: &str) -> Result<Vec<Standing>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    Ok(
+        json["MRData"]["StandingsTable"]["StandingsLists"][0]["DriverStandings"]
+            .as_array()
+            .map(|standings| {
+                standings
+                    .iter()
+                    .filter_map(|s| {
+                        Some(Standing {
+                            position: s["position"].as_str()?.to_owned(),
+                            driver: s["Driver"]["familyName"].as_str()?.to_owned(),
+                            team: s["Constructors"][0]["name"].as_str()?.to_owned(),
+                            points: s["points"].as_str()?.to_owned(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    )
+}
+
+fn weekday_fi(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "ma",
+        Weekday::Tue => "ti",
+        Weekday::Wed => "ke",
+        Weekday::Thu => "to",
+        Weekday::Fri => "pe",
+        Weekday::Sat => "la",
+        Weekday::Sun => "su",
+    }
+}
+
+fn race_msg(race: &Option<Race>, tz: Tz) -> String {
+    let race = match race {
+        Some(r) => r,
+        None => {
+            return "Kauden kilpailut on ajettu".to_owned();
+        }
+    };
+
+    let sessions: Vec<String> = race
+        .sessions
+        .iter()
+        .map(|s| {
+            let start = s.start.with_timezone(&tz);
+            format!(
+                "{} {} {}",
+                s.name,
+                weekday_fi(start.weekday()),
+                start.format("%H:%M")
+            )
+        })
+        .collect();
+    let date = match race.sessions.last() {
+        Some(s) => s.start.with_timezone(&tz).format(" %-d.%-m.").to_string(),
+        None => String::new(),
+    };
+
+    format!(
+        "{} ({}, {}){}: {}",
+        race.name,
+        race.circuit,
+        race.locality,
+        date,
+        sessions.join(", ")
+    )
+}
+
+fn standings_msg(standings: &[Standing]) -> String {
+    if standings.is_empty() {
+        return "Sarjataulukkoa ei löytynyt".to_owned();
+    }
+
+    let list: Vec<String> = standings
+        .iter()
+        .take(STANDINGS_COUNT)
+        .map(|s| format!("{}. {} ({}) {}", s.position, s.driver, s.team, s.points))
+        .collect();
+    format!("MM-sarja: {}", list.join(", "))
+}
+
+/// Responses are valid until the next session of the race weekend starts
+fn cache_until(race: &Option<Race>, now: DateTime<Utc>) -> DateTime<Utc> {
+    let default = now + chrono::Duration::hours(DEFAULT_CACHE_HOURS);
+
+    race.iter()
+        .flat_map(|r| r.sessions.iter())
+        .map(|s| s.start)
+        .filter(|s| *s > now)
+        .min()
+        .map_or(default, |s| s.min(default))
+}
+
+async fn next_race(now: DateTime<Utc>) -> Result<Option<Race>, String> {
+    if let Some((race, until)) = &CACHE.lock().unwrap().race {
+        if now < *until {
+            return Ok(race.clone());
+        }
+    }
+
+    let race = match get_json("next.json").await {
+        Ok(json) => parse_next_race(&json)?,
+        Err(_) => {
+            return Err("F1 API error".to_owned());
+        }
+    };
+    CACHE.lock().unwrap().race = Some((race.clone(), cache_until(&race, now)));
+
+    Ok(race)
+}
+
+async fn standings(now: DateTime<Utc>) -> Result<Vec<Standing>, String> {
+    if let Some((standings, until)) = &CACHE.lock().unwrap().standings {
+        if now < *until {
+            return Ok(standings.clone());
+        }
+    }
+
+    let standings = match get_json("driverStandings.json").await {
+        Ok(json) => parse_standings(&json)?,
+        Err(_) => {
+            return Err("F1 API error".to_owned());
+        }
+    };
+    // Standings change after a session, so they expire with the race info
+    let until = next_race(now).await.map_or_else(
+        |_| now + chrono::Duration::hours(1),
+        |r| cache_until(&r, now),
+    );
+    CACHE.lock().unwrap().standings = Some((standings.clone(), until));
+
+    Ok(standings)
+}
+
+pub async fn command_f1(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let now = Utc::now();
+    let tz = get_timezone(&prefix, &source.network)
+        .await
+        .unwrap_or(chrono_tz::Europe::Helsinki);
+
+    let result = match params.trim() {
+        "" => next_race(now).await.map(|r| race_msg(&r, tz)),
+        "standings" => standings(now).await.map(|s| standings_msg(&s)),
+        _ => Ok("Usage: .f1 [standings]".to_owned()),
+    };
+    let msg = match result {
+        Ok(m) => m,
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn race_weekend() {
+        let json = r#"{"MRData":{"series":"f1","RaceTable":{"season":"2024","round":"8","Races":[{"season":"2024","round":"8","raceName":"Monaco Grand Prix",
+            "Circuit":{"circuitId":"monaco","circuitName":"Circuit de Monaco","Location":{"lat":"43.7347","long":"7.42056","locality":"Monte-Carlo","country":"Monaco"}},
+            "date":"2024-05-26","time":"13:00:00Z",
+            "FirstPractice":{"date":"2024-05-24","time":"11:30:00Z"},
+            "SecondPractice":{"date":"2024-05-24","time":"15:00:00Z"},
+            "ThirdPractice":{"date":"2024-05-25","time":"10:30:00Z"},
+            "Qualifying":{"date":"2024-05-25","time":"14:00:00Z"}}]}}}"#;
+
+        let race = parse_next_race(json).unwrap();
+        assert_eq!(
+            race_msg(&race, chrono_tz::Europe::Helsinki),
+            "Monaco Grand Prix (Circuit de Monaco, Monte-Carlo) 26.5.: \
+            1. harjoitus pe 14:30, 2. harjoitus pe 18:00, 3. harjoitus la 13:30, \
+            aika-ajo la 17:00, kilpailu su 16:00"
+        );
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 25, 12, 0, 0).unwrap();
+        assert_eq!(
+            cache_until(&race, now),
+            Utc.with_ymd_and_hms(2024, 5, 25, 14, 0, 0).unwrap()
+        );
+        assert_eq!(
+            cache_until(&race, now + chrono::Duration::days(2)),
+            now + chrono::Duration::days(2) + chrono::Duration::hours(DEFAULT_CACHE_HOURS)
+        );
+
+        let over = r#"{"MRData":{"RaceTable":{"season":"2024","Races":[]}}}"#;
+        assert_eq!(
+            race_msg(&parse_next_race(over).unwrap(), chrono_tz::Europe::Helsinki),
+            "Kauden kilpailut on ajettu"
+        );
+    }
+
+    #[test]
+    fn driver_standings() {
+        let json = r#"{"MRData":{"StandingsTable":{"season":"2024","StandingsLists":[{"season":"2024","round":"7","DriverStandings":[
+            {"position":"1","points":"161","wins":"5","Driver":{"driverId":"max_verstappen","code":"VER","givenName":"Max","familyName":"Verstappen"},"Constructors":[{"constructorId":"red_bull","name":"Red Bull"}]},
+            {"position":"2","points":"113","wins":"1","Driver":{"driverId":"leclerc","code":"LEC","givenName":"Charles","familyName":"Leclerc"},"Constructors":[{"constructorId":"ferrari","name":"Ferrari"}]}]}]}}}"#;
+
+        assert_eq!(
+            standings_msg(&parse_standings(json).unwrap()),
+            "MM-sarja: 1. Verstappen (Red Bull) 161, 2. Leclerc (Ferrari) 113"
+        );
+        assert_eq!(standings_msg(&[]), "Sarjataulukkoa ei löytynyt");
+    }
+}
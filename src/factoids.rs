@@ -0,0 +1,396 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::Utc;
+use irc::client::prelude::Prefix;
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+const MAX_LISTED: usize = 20;
+
+#[derive(Debug, PartialEq)]
+struct Factoid {
+    key: String,
+    answer: String,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "factoids.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS factoids (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT,
+            key_lower TEXT NOT NULL,
+            key TEXT NOT NULL,
+            answer TEXT NOT NULL,
+            author TEXT NOT NULL,
+            time INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Factoids taught in a private message are known on every channel of the network
+fn scope(source: &ChatTarget) -> Option<&str> {
+    if source.is_channel() {
+        Some(source.channel.as_str())
+    } else {
+        None
+    }
+}
+
+/// The channel's own factoid, or a network-wide one
+fn get_factoid(conn: &Connection, source: &ChatTarget, key: &str) -> Result<Option<Factoid>> {
+    conn.query_row(
+        "SELECT key, answer FROM factoids
+        WHERE network = :network AND key_lower = :key_lower
+        AND (channel = :channel OR channel IS NULL)
+        ORDER BY channel IS NULL LIMIT 1",
+        named_params! {
+            ":network": source.network,
+            ":channel": scope(source),
+            ":key_lower": key.to_lowercase(),
+        },
+        |row| {
+            Ok(Factoid {
+                key: row.get(0)?,
+                answer: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Returns the existing factoid instead of overwriting it
+fn learn(
+    conn: &Connection,
+    source: &ChatTarget,
+    key: &str,
+    answer: &str,
+    author: &str,
+) -> Result<Option<Factoid>> {
+    let existing = conn
+        .query_row(
+            "SELECT key, answer FROM factoids
+            WHERE network = :network AND channel IS :channel AND key_lower = :key_lower",
+            named_params! {
+                ":network": source.network,
+                ":channel": scope(source),
+                ":key_lower": key.to_lowercase(),
+            },
+            |row| {
+                Ok(Factoid {
+                    key: row.get(0)?,
+                    answer: row.get(1)?,
+                })
+            },
+        )
+        .optional()?;
+    if existing.is_some() {
+        return Ok(existing);
+    }
+
+    conn.execute(
+        "INSERT INTO factoids (network, channel, key_lower, key, answer, author, time)
+        VALUES (:network, :channel, :key_lower, :key, :answer, :author, :time)",
+        named_params! {
+            ":network": source.network,
+            ":channel": scope(source),
+            ":key_lower": key.to_lowercase(),
+            ":key": key,
+            ":answer": answer,
+            ":author": author,
+            ":time": Utc::now().timestamp(),
+        },
+    )?;
+
+    Ok(None)
+}
+
+/// Returns whether there was a factoid to forget
+fn forget(conn: &Connection, source: &ChatTarget, key: &str) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM factoids
+        WHERE network = :network AND channel IS :channel AND key_lower = :key_lower",
+        named_params! {
+            ":network": source.network,
+            ":channel": scope(source),
+            ":key_lower": key.to_lowercase(),
+        },
+    )?;
+
+    Ok(removed > 0)
+}
+
+/// Keys of the factoids known on the channel, optionally matching `search`
+/// in the key or the answer
+fn search(conn: &Connection, source: &ChatTarget, search: &str) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT min(key) FROM factoids
+        WHERE network = :network AND (channel = :channel OR channel IS NULL)
+        AND (key_lower LIKE :pattern OR lower(answer) LIKE :pattern)
+        GROUP BY key_lower ORDER BY key_lower",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": source.network,
+        ":channel": scope(source),
+        ":pattern": format!("%{}%", search.to_lowercase()),
+    })?;
+
+    let mut keys = Vec::new();
+    while let Some(row) = rows.next()? {
+        keys.push(row.get(0)?);
+    }
+
+    Ok(keys)
+}
+
+/// "foo is bar" -> ("foo", "bar")
+fn parse_learn(params: &str) -> Option<(&str, &str)> {
+    let (key, answer) = params.split_once(" is ")?;
+    let (key, answer) = (key.trim(), answer.trim());
+
+    if key.is_empty() || answer.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+
+    Some((key, answer))
+}
+
+/// Admins can always teach the bot, `factoids: open: true` lets everyone
+fn can_edit(config: &Yaml, admin: bool) -> bool {
+    admin || config["factoids"]["open"].as_bool().unwrap_or(false)
+}
+
+fn list_msg(keys: &[String], search: &str) -> String {
+    if keys.is_empty() {
+        return match search {
+            "" => "Ei faktoideja".to_owned(),
+            s => format!("Ei faktoideja haulla {}", s),
+        };
+    }
+
+    let mut msg = format!(
+        "Faktoidit: {}",
+        keys[..keys.len().min(MAX_LISTED)].join(", ")
+    );
+    if keys.len() > MAX_LISTED {
+        msg.push_str(&format!(" (+{} muuta)", keys.len() - MAX_LISTED));
+    }
+
+    msg
+}
+
+async fn send(bot_sender: &mpsc::Sender<BotAction>, source: &ChatTarget, msg: String) {
+    let action = BotAction {
+        target: ChatTarget {
+            network: source.network.to_owned(),
+            channel: source.channel.to_owned(),
+        },
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_learn(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+    admin: bool,
+) {
+    let author = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+    if !can_edit(&config, admin) {
+        return;
+    }
+
+    let msg = match parse_learn(params) {
+        Some((key, answer)) => {
+            let (target, new_key, answer) = (source.clone(), key.to_owned(), answer.to_owned());
+            match db::call(&DB, move |c| learn(c, &target, &new_key, &answer, &author)).await {
+                Ok(None) => format!("Opittu: {}", key),
+                Ok(Some(existing)) => format!(
+                    "{} on jo: {}. Poista se ensin komennolla .forget {}",
+                    existing.key, existing.answer, existing.key
+                ),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        None => "Usage: .learn <key> is <answer>".to_owned(),
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+pub async fn command_forget(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+    admin: bool,
+) {
+    if !can_edit(&config, admin) {
+        return;
+    }
+
+    let msg = match params.trim() {
+        "" => "Usage: .forget <key>".to_owned(),
+        key => {
+            let (target, forgotten) = (source.clone(), key.to_owned());
+            match db::call(&DB, move |c| forget(c, &target, &forgotten)).await {
+                Ok(true) => format!("Unohdettu: {}", key),
+                Ok(false) => format!("En tiedä mitä {} on", key),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+pub async fn command_factoids(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+) {
+    let query = params.trim();
+    let (target, pattern) = (source.clone(), query.to_owned());
+    let msg = match db::call(&DB, move |c| search(c, &target, &pattern)).await {
+        Ok(keys) => list_msg(&keys, query),
+        Err(_) => "Database error".to_owned(),
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+/// Answers `.foo` for unknown commands and `?? foo`. Only `??` says when
+/// there is nothing to answer, so typos of commands stay quiet.
+pub async fn handle_factoid(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    key: &str,
+    explicit: bool,
+) {
+    let key = key.trim();
+    if key.is_empty() {
+        return;
+    }
+
+    let (target, wanted) = (source.clone(), key.to_owned());
+    let msg = match db::call(&DB, move |c| get_factoid(c, &target, &wanted)).await {
+        Ok(Some(f)) => format!("{}: {}", f.key, f.answer),
+        Ok(None) if explicit => format!("En tiedä mitä {} on", key),
+        _ => {
+            return;
+        }
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn learn_and_forget() {
+        assert_eq!(
+            parse_learn("foo is bar is baz"),
+            Some(("foo", "bar is baz"))
+        );
+        assert_eq!(parse_learn("foo bar is baz"), None);
+        assert_eq!(parse_learn("foo is "), None);
+
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        let other = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#other".to_owned(),
+        };
+        let private = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "teacher".to_owned(),
+        };
+
+        assert_eq!(
+            learn(&conn, &channel, "Sauna", "kuuma", "nick").unwrap(),
+            None
+        );
+        assert_eq!(
+            learn(&conn, &channel, "sauna", "kylmä", "nick").unwrap(),
+            Some(Factoid {
+                key: "Sauna".to_owned(),
+                answer: "kuuma".to_owned()
+            })
+        );
+        assert_eq!(
+            learn(&conn, &private, "sauna", "löylyä", "nick").unwrap(),
+            None
+        );
+
+        assert_eq!(
+            get_factoid(&conn, &channel, "SAUNA")
+                .unwrap()
+                .unwrap()
+                .answer,
+            "kuuma"
+        );
+        assert_eq!(
+            get_factoid(&conn, &other, "sauna").unwrap().unwrap().answer,
+            "löylyä"
+        );
+
+        assert_eq!(search(&conn, &channel, "").unwrap(), vec!["Sauna"]);
+        assert_eq!(search(&conn, &other, "LÖYLY").unwrap(), vec!["sauna"]);
+        assert!(search(&conn, &other, "kuuma").unwrap().is_empty());
+
+        assert!(forget(&conn, &channel, "sauna").unwrap());
+        assert!(!forget(&conn, &channel, "sauna").unwrap());
+        assert_eq!(
+            get_factoid(&conn, &channel, "sauna")
+                .unwrap()
+                .unwrap()
+                .answer,
+            "löylyä"
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        let config = YamlLoader::load_from_str("factoids:\n  open: true").unwrap();
+        assert!(can_edit(&config[0], false));
+
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert!(!can_edit(&config[0], false));
+        assert!(can_edit(&config[0], true));
+    }
+}
@@ -0,0 +1,333 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const DEFAULT_AREA: &str = "Helsinki";
+const MAX_SHOWTIMES: usize = 8;
+const MAX_FILMS: usize = 5;
+// Without a title, the shows of the evening are listed
+const EVENING_HOUR: u32 = 17;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Area {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Show {
+    title: String,
+    start: NaiveDateTime,
+    theatre: String,
+}
+
+lazy_static! {
+    // The theatre areas rarely change, they are fetched once
+    static ref AREAS: Mutex<Vec<Area>> = Mutex::new(Vec::new());
+}
+
+async fn get_xml(url: &str, query: &[(&str, &str)]) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT.get(url).query(query);
+    let xml = http_client::send(request).await?.text().await?;
+
+    Ok(xml)
+}
+
+fn child_text(element: &xmltree::Element, name: &str) -> Option<String> {
+    Some(element.get_child(name)?.get_text()?.trim().to_owned())
+}
+
+fn parse_areas(xml: &str) -> Result<Vec<Area>, String> {
+    let root = match xmltree::Element::parse(xml.as_bytes()) {
+        Ok(r) => r,
+        Err(_) => {
+            return Err("Error parsing Finnkino XML".to_owned());
+        }
+    };
+
+    Ok(root
+        .children
+        .iter()
+        .filter_map(|c| c.as_element())
+        .filter_map(|e| {
+            Some(Area {
+                id: child_text(e, "ID")?,
+                name: child_text(e, "Name")?,
+            })
+        })
+        .collect())
+}
+
+fn parse_shows(xml: &str) -> Result<Vec<Show>, String> {
+    let root = match xmltree::Element::parse(xml.as_bytes()) {
+        Ok(r) => r,
+        Err(_) => {
+            return Err("Error parsing Finnkino XML".to_owned());
+        }
+    };
+    let shows = match root.get_child("Shows") {
+        Some(s) => s,
+        None => {
+            return Ok(Vec::new());
+        }
+    };
+
+    Ok(shows
+        .children
+        .iter()
+        .filter_map(|c| c.as_element())
+        .filter_map(|e| {
+            let start = child_text(e, "dttmShowStart")?;
+            Some(Show {
+                title: child_text(e, "Title")?,
+                start: NaiveDateTime::parse_from_str(&start, "%Y-%m-%dT%H:%M:%S").ok()?,
+                theatre: child_text(e, "Theatre")?,
+            })
+        })
+        .collect())
+}
+
+/// Matches "tampere" to "Tampere" and "hki" to nothing. Areas that are not
+/// a single city, like "Valitse alue/teatteri", need the exact name.
+fn find_area<'a>(areas: &'a [Area], name: &str) -> Option<&'a Area> {
+    let name = name.to_lowercase();
+
+    areas
+        .iter()
+        .find(|a| a.name.to_lowercase() == name)
+        .or_else(|| {
+            areas.iter().find(|a| {
+                a.name
+                    .to_lowercase()
+                    .split(|c: char| !c.is_alphanumeric())
+                    .any(|w| w == name)
+            })
+        })
+}
+
+/// "Tennispalatsi, Helsinki" -> "Tennispalatsi"
+fn short_theatre(theatre: &str) -> &str {
+    theatre.split(',').next().unwrap_or(theatre).trim()
+}
+
+fn showtimes_msg(area: &Area, shows: &[Show], title: &str, now: NaiveDateTime) -> String {
+    let title_lower = title.to_lowercase();
+    let matching: Vec<&Show> = shows
+        .iter()
+        .filter(|s| s.start >= now && s.title.to_lowercase().contains(&title_lower))
+        .collect();
+
+    if matching.is_empty() {
+        return format!("Ei näytöksiä tänään ({}): {}", area.name, title);
+    }
+
+    let mut films: Vec<&str> = matching.iter().map(|s| s.title.as_str()).collect();
+    films.sort_unstable();
+    films.dedup();
+    let times: Vec<String> = matching
+        .iter()
+        .take(MAX_SHOWTIMES)
+        .map(|s| {
+            let name = if films.len() > 1 {
+                format!(" {}", s.title)
+            } else {
+                String::new()
+            };
+            format!(
+                "{}{} {}",
+                s.start.format("%H:%M"),
+                name,
+                short_theatre(&s.theatre)
+            )
+        })
+        .collect();
+
+    let header = if films.len() == 1 {
+        format!("{} ({})", films[0], area.name)
+    } else {
+        area.name.to_owned()
+    };
+    format!("{}: {}", header, times.join(", "))
+}
+
+/// Films with the most shows starting this evening, with the first showtime
+fn evening_msg(area: &Area, shows: &[Show], now: NaiveDateTime) -> String {
+    let evening = now.date().and_hms_opt(EVENING_HOUR, 0, 0).unwrap().max(now);
+
+    let mut films: Vec<(&str, usize, NaiveDateTime)> = Vec::new();
+    for show in shows.iter().filter(|s| s.start >= evening) {
+        match films.iter_mut().find(|f| f.0 == show.title) {
+            Some(f) => {
+                f.1 += 1;
+                f.2 = f.2.min(show.start);
+            }
+            None => films.push((&show.title, 1, show.start)),
+        }
+    }
+    films.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+
+    if films.is_empty() {
+        return format!("Ei enää näytöksiä tänään ({})", area.name);
+    }
+
+    let list: Vec<String> = films
+        .iter()
+        .take(MAX_FILMS)
+        .map(|(title, count, first)| format!("{} {} ({}×)", title, first.format("%H:%M"), count))
+        .collect();
+    format!("Tänä iltana ({}): {}", area.name, list.join(", "))
+}
+
+async fn areas() -> Result<Vec<Area>, String> {
+    if let Ok(areas) = AREAS.lock() {
+        if !areas.is_empty() {
+            return Ok(areas.clone());
+        }
+    }
+
+    let xml = match get_xml("https://www.finnkino.fi/xml/TheatreAreas/", &[]).await {
+        Ok(x) => x,
+        Err(_) => {
+            return Err("Error getting Finnkino areas".to_owned());
+        }
+    };
+    let areas = parse_areas(&xml)?;
+    *AREAS.lock().unwrap() = areas.clone();
+
+    Ok(areas)
+}
+
+async fn leffat_msg(params: &str, default_area: &str) -> Result<String, String> {
+    let areas = areas().await?;
+
+    // The first word is an area if there is one with that name
+    let (first, rest) = params.split_once(' ').unwrap_or((params, ""));
+    let (area, title) = match find_area(&areas, first) {
+        Some(a) if !first.is_empty() => (a, rest.trim()),
+        _ => match find_area(&areas, default_area) {
+            Some(a) => (a, params),
+            None => {
+                return Err(format!("Unknown area: {}", default_area));
+            }
+        },
+    };
+
+    let now = Utc::now().with_timezone(&Helsinki).naive_local();
+    let date = now.format("%d.%m.%Y").to_string();
+    let xml = match get_xml(
+        "https://www.finnkino.fi/xml/Schedule/",
+        &[("area", &area.id), ("dt", &date)],
+    )
+    .await
+    {
+        Ok(x) => x,
+        Err(_) => {
+            return Err("Error getting Finnkino schedule".to_owned());
+        }
+    };
+    let shows = parse_shows(&xml)?;
+
+    if title.is_empty() {
+        Ok(evening_msg(area, &shows, now))
+    } else {
+        Ok(showtimes_msg(area, &shows, title, now))
+    }
+}
+
+pub async fn command_leffat(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let default_area = config["finnkino"]["area"].as_str().unwrap_or(DEFAULT_AREA);
+
+    let msg = match leffat_msg(params.trim(), default_area).await {
+        Ok(m) => m,
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theatre_areas() {
+        let xml = r#"<?xml version="1.0"?>
+<TheatreAreas xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <TheatreArea><ID>1029</ID><Name>Valitse alue/teatteri</Name></TheatreArea>
+  <TheatreArea><ID>1014</ID><Name>Pääkaupunkiseutu</Name></TheatreArea>
+  <TheatreArea><ID>1002</ID><Name>Helsinki</Name></TheatreArea>
+  <TheatreArea><ID>1045</ID><Name>Helsinki: ITIS</Name></TheatreArea>
+  <TheatreArea><ID>1021</ID><Name>Tampere</Name></TheatreArea>
+</TheatreAreas>"#;
+        let areas = parse_areas(xml).unwrap();
+        assert_eq!(areas.len(), 5);
+
+        assert_eq!(find_area(&areas, "helsinki").unwrap().id, "1002");
+        assert_eq!(find_area(&areas, "ITIS").unwrap().id, "1045");
+        assert_eq!(find_area(&areas, "tampere").unwrap().id, "1021");
+        assert!(find_area(&areas, "dune").is_none());
+    }
+
+    #[test]
+    fn showtimes() {
+        let xml = r#"<?xml version="1.0"?>
+<Schedule xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <PubDate>2023-06-01T12:00:00</PubDate>
+  <Shows>
+    <Show><ID>1</ID><dttmShowStart>2023-06-01T14:00:00</dttmShowStart><Title>Dune</Title><Theatre>Plevna, Tampere</Theatre></Show>
+    <Show><ID>2</ID><dttmShowStart>2023-06-01T18:00:00</dttmShowStart><Title>Dune</Title><Theatre>Plevna, Tampere</Theatre></Show>
+    <Show><ID>3</ID><dttmShowStart>2023-06-01T18:15:00</dttmShowStart><Title>Muumit</Title><Theatre>Cine Atlas, Tampere</Theatre></Show>
+    <Show><ID>4</ID><dttmShowStart>2023-06-01T20:30:00</dttmShowStart><Title>Dune</Title><Theatre>Cine Atlas, Tampere</Theatre></Show>
+    <Show><ID>5</ID><dttmShowStart>2023-06-01T21:00:00</dttmShowStart><Title>Oppenheimer</Title><Theatre>Plevna, Tampere</Theatre></Show>
+  </Shows>
+</Schedule>"#;
+        let shows = parse_shows(xml).unwrap();
+        assert_eq!(shows.len(), 5);
+
+        let area = Area {
+            id: "1021".to_owned(),
+            name: "Tampere".to_owned(),
+        };
+        let now = NaiveDate::from_ymd_opt(2023, 6, 1)
+            .unwrap()
+            .and_hms_opt(15, 0, 0)
+            .unwrap();
+
+        assert_eq!(
+            showtimes_msg(&area, &shows, "dune", now),
+            "Dune (Tampere): 18:00 Plevna, 20:30 Cine Atlas"
+        );
+        assert_eq!(
+            showtimes_msg(&area, &shows, "barbie", now),
+            "Ei näytöksiä tänään (Tampere): barbie"
+        );
+        assert_eq!(
+            evening_msg(&area, &shows, now),
+            "Tänä iltana (Tampere): Dune 18:00 (2×), Muumit 18:15 (1×), Oppenheimer 21:00 (1×)"
+        );
+        assert_eq!(
+            evening_msg(&area, &shows, now + chrono::Duration::hours(7)),
+            "Ei enää näytöksiä tänään (Tampere)"
+        );
+    }
+}
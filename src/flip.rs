@@ -0,0 +1,39 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use rand::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::ChatTarget;
+
+fn flip<R: Rng>(rng: &mut R) -> &'static str {
+    if rng.gen_bool(0.5) {
+        "Kruuna"
+    } else {
+        "Klaava"
+    }
+}
+
+pub async fn command_flip(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget) {
+    let a = BotAction {
+        target: source,
+        action_type: ActionType::Message(flip(&mut thread_rng()).to_owned()),
+    };
+    bot_sender.send(a).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let flips: Vec<&str> = (0..100).map(|_| flip(&mut rng)).collect();
+
+        assert!(flips.contains(&"Kruuna"));
+        assert!(flips.contains(&"Klaava"));
+    }
+}
@@ -4,14 +4,17 @@
 
 use irc::client::prelude::Prefix;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use chrono::prelude::*;
 use tokio::sync::mpsc;
+use yaml_rust::yaml::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::weather::{self, summer_humidex, WeatherError};
 use crate::weather_db::get_location;
-use crate::IrcChannel;
+use crate::ChatTarget;
 
 lazy_static! {
     // https://www.ilmatieteenlaitos.fi/latauspalvelun-pikaohje
@@ -67,6 +70,155 @@ lazy_static! {
         m.insert(89, "raekuuroja");
         m
     };
+    static ref WAWA_EN: HashMap<u32, &'static str> = {
+        let mut m = HashMap::new();
+        m.insert(4, "haze, smoke or dust in the air");
+        m.insert(5, "haze, smoke or dust in the air");
+        m.insert(20, "fog");
+        m.insert(21, "precipitation");
+        m.insert(22, "drizzle or snow grains");
+        m.insert(23, "rain");
+        m.insert(24, "snow");
+        m.insert(25, "freezing rain or freezing drizzle");
+        m.insert(30, "fog");
+        m.insert(31, "fog");
+        m.insert(32, "fog");
+        m.insert(33, "fog");
+        m.insert(34, "fog");
+        m.insert(40, "precipitation");
+        m.insert(41, "light or moderate precipitation");
+        m.insert(42, "heavy precipitation");
+        m.insert(50, "drizzle");
+        m.insert(51, "light drizzle");
+        m.insert(52, "moderate drizzle");
+        m.insert(53, "heavy drizzle");
+        m.insert(54, "light freezing drizzle");
+        m.insert(55, "moderate freezing drizzle");
+        m.insert(56, "heavy freezing drizzle");
+        m.insert(60, "rain");
+        m.insert(61, "light rain");
+        m.insert(62, "moderate rain");
+        m.insert(63, "heavy rain");
+        m.insert(64, "light freezing rain");
+        m.insert(65, "moderate freezing rain");
+        m.insert(66, "heavy freezing rain");
+        m.insert(70, "snow");
+        m.insert(71, "light snow");
+        m.insert(72, "moderate snow");
+        m.insert(73, "heavy snow");
+        m.insert(74, "light ice pellets");
+        m.insert(75, "moderate ice pellets");
+        m.insert(76, "heavy ice pellets");
+        m.insert(77, "snow grains");
+        m.insert(78, "ice crystals");
+        m.insert(80, "showers or intermittent precipitation");
+        m.insert(81, "light rain showers");
+        m.insert(82, "moderate rain showers");
+        m.insert(83, "heavy rain showers");
+        m.insert(84, "violent rain showers");
+        m.insert(85, "light snow showers");
+        m.insert(86, "moderate snow showers");
+        m.insert(87, "heavy snow showers");
+        m.insert(89, "hail showers");
+        m
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    Finnish,
+    English,
+}
+
+struct Labels {
+    temperature: &'static str,
+    feels_like: &'static str,
+    wind: &'static str,
+    gust: &'static str,
+    humidity: &'static str,
+    cloudiness: &'static str,
+    dew_point: &'static str,
+    pressure: &'static str,
+    visibility: &'static str,
+    precipitation_1h: &'static str,
+    precipitation_intensity: &'static str,
+    snow_depth: &'static str,
+    other_stations: &'static str,
+    fetch_failed: &'static str,
+    short_feels_like: &'static str,
+    short_wind: &'static str,
+    coldest: &'static str,
+    warmest: &'static str,
+    humidex: &'static str,
+    warmer_than_yesterday: &'static str,
+    colder_than_yesterday: &'static str,
+    same_as_yesterday: &'static str,
+}
+
+const LABELS_FI: Labels = Labels {
+    temperature: "lämpötila",
+    feels_like: "tuntuu kuin",
+    wind: "tuulen nopeus",
+    gust: "puuskat",
+    humidity: "ilman kosteus",
+    cloudiness: "pilvisyys",
+    dew_point: "kastepiste",
+    pressure: "ilmanpaine",
+    visibility: "näkyvyys",
+    precipitation_1h: "sademäärä 1h",
+    precipitation_intensity: "sateen intensiteetti",
+    snow_depth: "lumen syvyys",
+    other_stations: "täydennetty asemilta",
+    fetch_failed: "Tietojen haku ei onnistunut",
+    short_feels_like: "tuntuu",
+    short_wind: "tuuli",
+    coldest: "Kylmin",
+    warmest: "lämpimin",
+    humidex: "helleindeksi",
+    warmer_than_yesterday: "lämpimämpää kuin eilen",
+    colder_than_yesterday: "kylmempää kuin eilen",
+    same_as_yesterday: "sama lämpötila kuin eilen",
+};
+
+const LABELS_EN: Labels = Labels {
+    temperature: "temperature",
+    feels_like: "feels like",
+    wind: "wind speed",
+    gust: "gusts",
+    humidity: "humidity",
+    cloudiness: "cloudiness",
+    dew_point: "dew point",
+    pressure: "pressure",
+    visibility: "visibility",
+    precipitation_1h: "precipitation 1h",
+    precipitation_intensity: "precipitation intensity",
+    snow_depth: "snow depth",
+    other_stations: "supplemented from stations",
+    fetch_failed: "Fetching weather data failed",
+    short_feels_like: "feels",
+    short_wind: "wind",
+    coldest: "Coldest",
+    warmest: "warmest",
+    humidex: "humidex",
+    warmer_than_yesterday: "warmer than yesterday",
+    colder_than_yesterday: "colder than yesterday",
+    same_as_yesterday: "same temperature as yesterday",
+};
+
+impl Language {
+    fn labels(&self) -> &'static Labels {
+        match self {
+            Language::Finnish => &LABELS_FI,
+            Language::English => &LABELS_EN,
+        }
+    }
+
+    fn wawa(&self, code: u32) -> Option<&'static str> {
+        match self {
+            Language::Finnish => WAWA.get(&code).copied(),
+            Language::English => WAWA_EN.get(&code).copied(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -78,58 +230,133 @@ struct WeatherData {
     feels_like: Option<String>,
     humidity: Option<String>,
     cloudiness: Option<String>,
+    dew_point: Option<String>,
+    pressure: Option<String>,
+    visibility: Option<String>,
+    precipitation_1h: Option<String>,
+    precipitation_intensity: Option<String>,
     snow_depth: Option<String>,
-    wawa: Option<String>,
+    wawa: Option<u32>,
+    time: Option<DateTime<Utc>>,
+    // Temperature at the same station about 24 hours earlier
+    temperature_yesterday: Option<String>,
+    // Nearby stations that filled in values the nearest one did not report
+    other_stations: Vec<String>,
 }
 
-async fn get_xml(place: &str) -> reqwest::Result<String> {
-    let starttime = Utc::now() - chrono::Duration::minutes(30);
+#[derive(Debug)]
+struct Station {
+    id: String,
+    name: Option<String>,
+    values: HashMap<String, String>,
+}
+
+/// Split a series id like "obs-obs-2-1-t2m" into station ("2") and parameter ("t2m")
+fn split_series_id(id: &str) -> Option<(String, String)> {
+    let mut parts = id.strip_prefix("obs-obs-")?.splitn(3, '-');
+    let station = parts.next()?;
+    let _ = parts.next()?;
+    let param = parts.next()?;
+
+    Some((station.to_owned(), param.to_owned()))
+}
+
+fn is_winter(month: u32) -> bool {
+    !(5..=10).contains(&month)
+}
+
+/// Run one of FMI's WFS stored queries for observations since `starttime`
+pub async fn wfs_query(
+    storedquery_id: &str,
+    params: &[(&str, &str)],
+    starttime: DateTime<Utc>,
+) -> reqwest::Result<String> {
     let timestamp = starttime.to_rfc3339_opts(SecondsFormat::Secs, true);
 
     let baseurl = "https://opendata.fmi.fi/wfs";
 
-    let xml = HTTP_CLIENT
+    let request = HTTP_CLIENT
         .get(baseurl)
         .query(&[
             ("service", "WFS"),
             ("version", "2.0.0"),
             ("request", "getFeature"),
-            (
-                "storedquery_id",
-                "fmi::observations::weather::timevaluepair",
-            ),
-            ("maxlocations", "1"),
-            ("place", place),
+            ("storedquery_id", storedquery_id),
             ("starttime", &timestamp),
         ])
-        .send()
-        .await?
-        .text()
-        .await?;
+        .query(params);
+    let xml = http_client::send(request).await?.text().await?;
 
     Ok(xml)
 }
 
-fn parse_xml(xml: &str) -> Result<WeatherData, String> {
-    fn get_value(element: &xmltree::Element) -> Option<String> {
-        let last_point = element.children.last()?;
-        if let xmltree::XMLNode::Element(ce) = last_point {
-            if let Some(mtvp) = ce.get_child("MeasurementTVP") {
-                if let Some(value) = mtvp.get_child("value") {
-                    return Some(value.get_text()?.to_string());
-                }
+async fn get_xml(place: &str) -> reqwest::Result<String> {
+    wfs_query(
+        "fmi::observations::weather::timevaluepair",
+        &[("maxlocations", "5"), ("place", place)],
+        Utc::now() - chrono::Duration::minutes(30),
+    )
+    .await
+}
+
+/// Temperatures from the place's nearest stations about 24 hours ago
+async fn get_yesterday_xml(place: &str) -> reqwest::Result<String> {
+    let endtime = Utc::now() - chrono::Duration::hours(24);
+    let endtime_str = endtime.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    wfs_query(
+        "fmi::observations::weather::timevaluepair",
+        &[
+            ("parameters", "t2m"),
+            ("maxlocations", "5"),
+            ("place", place),
+            ("endtime", &endtime_str),
+        ],
+        endtime - chrono::Duration::minutes(30),
+    )
+    .await
+}
+
+/// Current temperatures from every station in Finland
+async fn get_finland_xml() -> reqwest::Result<String> {
+    wfs_query(
+        "fmi::observations::weather::timevaluepair",
+        &[("parameters", "t2m"), ("bbox", "19.0,59.5,31.6,70.1")],
+        Utc::now() - chrono::Duration::minutes(20),
+    )
+    .await
+}
+
+fn get_value(element: &xmltree::Element) -> Option<String> {
+    let last_point = element.children.last()?;
+    if let xmltree::XMLNode::Element(ce) = last_point {
+        if let Some(mtvp) = ce.get_child("MeasurementTVP") {
+            if let Some(value) = mtvp.get_child("value") {
+                return Some(value.get_text()?.to_string());
             }
         }
-
-        None
     }
 
-    fn calc_feels_like(temperature: f64, wind: f64) -> f64 {
-        // https://fi.wikipedia.org/wiki/Pakkasen_purevuus#Uusi_kaava
-        13.12 + 0.6215 * temperature - 13.956 * wind.powf(0.16)
-            + 0.4867 * temperature * wind.powf(0.16)
+    None
+}
+
+fn get_time(element: &xmltree::Element) -> Option<DateTime<Utc>> {
+    let last_point = element.children.last()?;
+    if let xmltree::XMLNode::Element(ce) = last_point {
+        let time = ce
+            .get_child("MeasurementTVP")?
+            .get_child("time")?
+            .get_text()?;
+        return DateTime::parse_from_rfc3339(&time)
+            .ok()
+            .map(|t| t.with_timezone(&Utc));
     }
 
+    None
+}
+
+/// Values by station, in the order FMI lists them (nearest first for place queries)
+fn parse_stations(xml: &str) -> Result<(Vec<Station>, Option<DateTime<Utc>>), String> {
     let root = match xmltree::Element::parse(xml.as_bytes()) {
         Ok(r) => r,
         Err(_) => {
@@ -137,98 +364,108 @@ fn parse_xml(xml: &str) -> Result<WeatherData, String> {
         }
     };
 
-    let mut place = None;
-    let mut temperature = None;
-    let mut wind = None;
-    let mut gust = None;
-    let mut feels_like = None;
-    let mut humidity = None;
-    let mut cloudiness = None;
-    let mut snow_depth = None;
-    let mut wawa = None;
-
-    if let Some(p) = root
-        .get_child("member")
-        .and_then(|m| m.get_child("PointTimeSeriesObservation"))
-        .and_then(|p| p.get_child("featureOfInterest"))
-        .and_then(|f| f.get_child("SF_SpatialSamplingFeature"))
-        .and_then(|s| s.get_child("shape"))
-        .and_then(|s| s.get_child("Point"))
-        .and_then(|p| p.get_child("name"))
-        .and_then(|n| n.get_text())
-    {
-        place = Some(p.to_string());
-    }
+    let mut stations: Vec<Station> = Vec::new();
+    let mut time = None;
 
     for c in root.children {
         if let xmltree::XMLNode::Element(ce) = c {
-            if let Some(mts) = ce
-                .get_child("PointTimeSeriesObservation")
-                .and_then(|ptso| ptso.get_child("result"))
+            let ptso = match ce.get_child("PointTimeSeriesObservation") {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some(mts) = ptso
+                .get_child("result")
                 .and_then(|result| result.get_child("MeasurementTimeseries"))
             {
-                if let Some(id) = mts.attributes.get("id") {
-                    if let Some(value) = get_value(mts) {
-                        match id as &str {
-                            "obs-obs-1-1-t2m" => {
-                                if value != "NaN" {
-                                    temperature = Some(value);
-                                }
-                            }
-                            "obs-obs-1-1-ws_10min" => {
-                                if value != "NaN" {
-                                    wind = Some(value);
-                                }
-                            }
-                            "obs-obs-1-1-wg_10min" => {
-                                if value != "NaN" {
-                                    gust = Some(value);
-                                }
-                            }
-                            "obs-obs-1-1-rh" => {
-                                if value != "NaN" {
-                                    if let Some(i) = value.strip_suffix(".0") {
-                                        humidity = Some(i.to_owned());
-                                    } else {
-                                        humidity = Some(value);
-                                    }
-                                }
-                            }
-                            "obs-obs-1-1-wawa" => {
-                                if let Some(v) = value.strip_suffix(".0") {
-                                    if let Ok(i) = v.parse::<u32>() {
-                                        if let Some(d) = WAWA.get(&i) {
-                                            wawa = Some(d.to_string());
-                                        }
-                                    }
-                                }
-                            }
-                            "obs-obs-1-1-n_man" => {
-                                if value != "NaN" {
-                                    if let Some(i) = value.strip_suffix(".0") {
-                                        cloudiness = Some(i.to_owned());
-                                    } else {
-                                        cloudiness = Some(value);
-                                    }
-                                }
-                            }
-                            "obs-obs-1-1-snow_aws" => {
-                                if value != "NaN" {
-                                    if let Ok(v) = value.parse::<f64>() {
-                                        if v > 0.0 {
-                                            snow_depth = Some(value.to_owned());
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {}
+                if let Some((station_id, param)) =
+                    mts.attributes.get("id").and_then(|id| split_series_id(id))
+                {
+                    if time.is_none() {
+                        time = get_time(mts);
+                    }
+                    let station = match stations.iter().position(|s| s.id == station_id) {
+                        Some(i) => &mut stations[i],
+                        None => {
+                            let name = ptso
+                                .get_child("featureOfInterest")
+                                .and_then(|f| f.get_child("SF_SpatialSamplingFeature"))
+                                .and_then(|s| s.get_child("shape"))
+                                .and_then(|s| s.get_child("Point"))
+                                .and_then(|p| p.get_child("name"))
+                                .and_then(|n| n.get_text())
+                                .map(|n| n.to_string());
+                            stations.push(Station {
+                                id: station_id,
+                                name,
+                                values: HashMap::new(),
+                            });
+                            stations.last_mut().unwrap()
                         }
+                    };
+                    if let Some(value) = get_value(mts).filter(|v| v != "NaN") {
+                        station.values.insert(param, value);
                     }
                 }
             }
         }
     }
 
+    Ok((stations, time))
+}
+
+fn parse_xml(xml: &str) -> Result<WeatherData, String> {
+    fn calc_feels_like(temperature: f64, wind: f64) -> f64 {
+        // https://fi.wikipedia.org/wiki/Pakkasen_purevuus#Uusi_kaava
+        13.12 + 0.6215 * temperature - 13.956 * wind.powf(0.16)
+            + 0.4867 * temperature * wind.powf(0.16)
+    }
+
+    let (stations, time) = parse_stations(xml)?;
+
+    let mut used = Vec::new();
+    let mut find = |param: &str| -> Option<String> {
+        for (i, s) in stations.iter().enumerate() {
+            if let Some(v) = s.values.get(param) {
+                if !used.contains(&i) {
+                    used.push(i);
+                }
+                return Some(v.to_owned());
+            }
+        }
+        None
+    };
+    let strip_zero = |v: String| match v.strip_suffix(".0") {
+        Some(i) => i.to_owned(),
+        None => v,
+    };
+
+    let temperature = find("t2m");
+    let wind = find("ws_10min");
+    let gust = find("wg_10min");
+    let humidity = find("rh").map(strip_zero);
+    let wawa = find("wawa")
+        .and_then(|v| v.strip_suffix(".0").and_then(|v| v.parse::<u32>().ok()))
+        .filter(|i| WAWA.contains_key(i));
+    let cloudiness = find("n_man").map(strip_zero);
+    let dew_point = find("td");
+    let pressure = find("p_sea");
+    let visibility = find("vis");
+    let precipitation_1h = find("r_1h");
+    let precipitation_intensity = find("ri_10min");
+    let snow_depth = find("snow_aws")
+        .filter(|v| v.parse::<f64>().is_ok_and(|v| v > 0.0))
+        .map(strip_zero);
+    let mut feels_like = None;
+
+    let place = stations.first().and_then(|s| s.name.to_owned());
+    let mut other_stations: Vec<String> = used
+        .iter()
+        .filter(|i| **i != 0)
+        .filter_map(|i| stations[*i].name.to_owned())
+        .collect();
+    other_stations.sort();
+    other_stations.dedup();
+
     if let Some(ref t) = temperature {
         if let Some(ref w) = wind {
             if let Ok(t_f) = t.parse::<f64>() {
@@ -264,67 +501,382 @@ fn parse_xml(xml: &str) -> Result<WeatherData, String> {
         feels_like,
         humidity,
         cloudiness,
+        dew_point,
+        pressure,
+        visibility,
+        precipitation_1h,
+        precipitation_intensity,
         snow_depth,
         wawa,
+        time,
+        temperature_yesterday: None,
+        other_stations,
     })
 }
 
-fn generate_msg(data: WeatherData) -> String {
+/// The yesterday's temperature of the station that reported `place`, or the nearest one
+fn find_yesterday_temperature(xml: &str, place: Option<&str>) -> Option<String> {
+    let (stations, _) = parse_stations(xml).ok()?;
+
+    stations
+        .iter()
+        .find(|s| s.name.as_deref() == place && place.is_some())
+        .or_else(|| stations.first())
+        .and_then(|s| s.values.get("t2m"))
+        .map(|t| t.to_owned())
+}
+
+fn temperature_trend(now: Option<&str>, yesterday: Option<&str>, lang: Language) -> Option<String> {
+    let l = lang.labels();
+    let now = now?.parse::<f64>().ok()?;
+    let yesterday = yesterday?.parse::<f64>().ok()?;
+    let difference = now - yesterday;
+
+    if difference.abs() < 0.05 {
+        Some(l.same_as_yesterday.to_owned())
+    } else if difference > 0.0 {
+        Some(format!("{:.1}°C {}", difference, l.warmer_than_yesterday))
+    } else {
+        Some(format!("{:.1}°C {}", -difference, l.colder_than_yesterday))
+    }
+}
+
+fn generate_msg(data: WeatherData, lang: Language) -> String {
+    let l = lang.labels();
     let mut msg = String::new();
 
     if let Some(p) = data.place {
         msg.push_str(&format!("{}: ", p));
     }
-    if let Some(t) = data.temperature {
-        msg.push_str(&format!("lämpötila: {}°C, ", t));
+    if let Some(t) = &data.temperature {
+        msg.push_str(&format!("{}: {}°C, ", l.temperature, t));
     }
     if let Some(f) = data.feels_like {
-        msg.push_str(&format!("tuntuu kuin: {}°C, ", f));
+        msg.push_str(&format!("{}: {}°C, ", l.feels_like, f));
+    }
+    // Wind chill only applies in the cold, so summer gets the humidex instead
+    if let (Some(Ok(t)), Some(Ok(h))) = (
+        data.temperature.as_ref().map(|t| t.parse::<f64>()),
+        data.humidity.as_ref().map(|h| h.parse::<f64>()),
+    ) {
+        if let Some(humidex) = summer_humidex(t, h) {
+            msg.push_str(&format!("{}: {:.1}°C, ", l.humidex, humidex));
+        }
     }
     if let Some(w) = data.wind {
-        msg.push_str(&format!("tuulen nopeus: {}m/s, ", w));
+        msg.push_str(&format!("{}: {}m/s, ", l.wind, w));
     }
     if let Some(g) = data.gust {
-        msg.push_str(&format!("puuskat: {}m/s, ", g));
+        msg.push_str(&format!("{}: {}m/s, ", l.gust, g));
     }
     if let Some(h) = data.humidity {
-        msg.push_str(&format!("ilman kosteus: {}%, ", h));
+        msg.push_str(&format!("{}: {}%, ", l.humidity, h));
     }
     if let Some(c) = data.cloudiness {
-        msg.push_str(&format!("pilvisyys: {}/8, ", c));
+        msg.push_str(&format!("{}: {}/8, ", l.cloudiness, c));
     }
-    if let Some(s) = data.snow_depth {
-        msg.push_str(&format!("lumen syvyys: {}cm, ", s));
+    // Dew point is mostly interesting when it gets muggy
+    if let Some(d) = data
+        .dew_point
+        .filter(|d| d.parse::<f64>().is_ok_and(|v| v >= 16.0))
+    {
+        msg.push_str(&format!("{}: {}°C, ", l.dew_point, d));
+    }
+    if let Some(p) = data.pressure {
+        msg.push_str(&format!("{}: {}hPa, ", l.pressure, p));
+    }
+    if let Some(v) = data
+        .visibility
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v < 10000.0)
+    {
+        msg.push_str(&format!("{}: {:.1}km, ", l.visibility, v / 1000.0));
+    }
+    if let Some(r) = data
+        .precipitation_1h
+        .filter(|r| r.parse::<f64>().is_ok_and(|v| v > 0.0))
+    {
+        msg.push_str(&format!("{}: {}mm, ", l.precipitation_1h, r));
     }
-    if let Some(w) = data.wawa {
-        msg.push_str(&w);
+    if let Some(r) = data
+        .precipitation_intensity
+        .filter(|r| r.parse::<f64>().is_ok_and(|v| v > 0.0))
+    {
+        msg.push_str(&format!("{}: {}mm/h, ", l.precipitation_intensity, r));
+    }
+    let month = data.time.unwrap_or_else(Utc::now).month();
+    if let Some(s) = data.snow_depth.filter(|_| is_winter(month)) {
+        msg.push_str(&format!("{}: {}cm, ", l.snow_depth, s));
+    }
+    if let Some(w) = data.wawa.and_then(|code| lang.wawa(code)) {
+        msg.push_str(&format!("{}, ", w));
+    }
+
+    if let Some(trend) = temperature_trend(
+        data.temperature.as_deref(),
+        data.temperature_yesterday.as_deref(),
+        lang,
+    ) {
+        msg.push_str(&trend);
     }
 
     if let Some(s) = msg.strip_suffix(", ") {
         msg = s.to_owned();
     }
 
+    if !data.other_stations.is_empty() {
+        msg.push_str(&format!(
+            " ({}: {})",
+            l.other_stations,
+            data.other_stations.join(", ")
+        ));
+    }
+
     msg
 }
 
+/// One place's side of a `.sää A vs B` comparison
+fn generate_compact_msg(data: &WeatherData, lang: Language) -> String {
+    let l = lang.labels();
+    let mut parts = Vec::new();
+
+    if let Some(t) = &data.temperature {
+        match &data.feels_like {
+            Some(f) => parts.push(format!("{}°C ({} {}°C)", t, l.short_feels_like, f)),
+            None => parts.push(format!("{}°C", t)),
+        }
+    }
+    if let Some(w) = &data.wind {
+        parts.push(format!("{} {}m/s", l.short_wind, w));
+    }
+
+    match &data.place {
+        Some(p) => format!("{}: {}", p, parts.join(", ")),
+        None => parts.join(", "),
+    }
+}
+
+async fn get_weather(location: &str, lang: Language) -> Result<WeatherData, WeatherError> {
+    match get_xml(location).await {
+        Ok(xml) => match parse_xml(&xml) {
+            Ok(data) => Ok(data),
+            Err(e) if lang == Language::Finnish => Err(WeatherError::NotFound(e)),
+            Err(_) => Err(WeatherError::NotFound("No data found".to_owned())),
+        },
+        Err(_) => Err(WeatherError::Failed(lang.labels().fetch_failed.to_owned())),
+    }
+}
+
+/// Current weather with the trend against yesterday, for `.sää`
+pub async fn current_weather(location: &str, lang: Language) -> Result<String, WeatherError> {
+    let (weather, yesterday) =
+        tokio::join!(get_weather(location, lang), get_yesterday_xml(location));
+
+    let mut data = weather?;
+    if let Ok(xml) = yesterday {
+        data.temperature_yesterday = find_yesterday_temperature(&xml, data.place.as_deref());
+    }
+
+    Ok(generate_msg(data, lang))
+}
+
+async fn compare_weather(place1: &str, place2: &str, lang: Language) -> String {
+    let (first, second) = tokio::join!(get_weather(place1, lang), get_weather(place2, lang));
+
+    let side = |place: &str, result: Result<WeatherData, WeatherError>| match result {
+        Ok(data) => generate_compact_msg(&data, lang),
+        Err(e) => format!("{}: {}", place, e.message()),
+    };
+
+    format!("{} vs {}", side(place1, first), side(place2, second))
+}
+
+/// The coldest and warmest stations as (name, temperature)
+fn find_min_max(stations: &[Station]) -> Option<((String, f64), (String, f64))> {
+    let temperatures: Vec<(String, f64)> = stations
+        .iter()
+        .filter_map(|s| {
+            let t = s.values.get("t2m")?.parse::<f64>().ok()?;
+            Some((s.name.to_owned()?, t))
+        })
+        .collect();
+
+    let min = temperatures
+        .iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))?
+        .to_owned();
+    let max = temperatures
+        .iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?
+        .to_owned();
+
+    Some((min, max))
+}
+
+fn generate_min_max_msg(stations: &[Station], lang: Language) -> String {
+    let l = lang.labels();
+
+    match find_min_max(stations) {
+        Some(((min_name, min), (max_name, max))) => format!(
+            "{}: {} {:.1}°C, {}: {} {:.1}°C",
+            l.coldest, min_name, min, l.warmest, max_name, max
+        ),
+        None => match lang {
+            Language::Finnish => "Tietoja ei löytynyt".to_owned(),
+            Language::English => "No data found".to_owned(),
+        },
+    }
+}
+
+pub async fn command_minmax(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    config: Arc<Yaml>,
+) {
+    let lang = channel_language(&config, &source);
+
+    let msg = match get_finland_xml().await {
+        Ok(xml) => match parse_stations(&xml) {
+            Ok((stations, _)) => generate_min_max_msg(&stations, lang),
+            Err(e) => e,
+        },
+        Err(_) => lang.labels().fetch_failed.to_owned(),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+const DEFAULT_SEA_STATION: &str = "Helsinki";
+
+fn generate_sea_msg(wave: Option<&Station>, mareograph: Option<&Station>) -> String {
+    let mut sections = Vec::new();
+
+    if let Some(station) = wave {
+        let mut parts = Vec::new();
+        if let Some(t) = station.values.get("TWATER") {
+            parts.push(format!("veden lämpötila: {}°C", t));
+        }
+        if let Some(h) = station.values.get("WaveHs") {
+            parts.push(format!("aallonkorkeus: {}m", h));
+        }
+        if !parts.is_empty() {
+            let name = station.name.as_deref().unwrap_or("Aaltopoiju");
+            sections.push(format!("{}: {}", name, parts.join(", ")));
+        }
+    }
+
+    if let Some(station) = mareograph {
+        // Sea level is reported in millimeters relative to the theoretical mean
+        if let Some(level) = station
+            .values
+            .get("WATLEV")
+            .and_then(|l| l.parse::<f64>().ok())
+        {
+            let name = station.name.as_deref().unwrap_or("Mareografi");
+            sections.push(format!("{}: vedenkorkeus: {:+.0}cm", name, level / 10.0));
+        }
+    }
+
+    if sections.is_empty() {
+        return "Tietoja ei löytynyt".to_owned();
+    }
+
+    sections.join(" | ")
+}
+
+pub async fn command_meri(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let place = match params {
+        "" => DEFAULT_SEA_STATION,
+        _ => params,
+    };
+    let starttime = Utc::now() - chrono::Duration::hours(1);
+
+    let wave_params = [
+        ("parameters", "TWATER,WaveHs"),
+        ("maxlocations", "1"),
+        ("place", place),
+    ];
+    let mareograph_params = [
+        ("parameters", "WATLEV"),
+        ("maxlocations", "1"),
+        ("place", place),
+    ];
+
+    let (wave, mareograph) = tokio::join!(
+        wfs_query(
+            "fmi::observations::wave::timevaluepair",
+            &wave_params,
+            starttime
+        ),
+        wfs_query(
+            "fmi::observations::mareograph::timevaluepair",
+            &mareograph_params,
+            starttime
+        )
+    );
+
+    let msg = if wave.is_err() && mareograph.is_err() {
+        "Tietojen haku ei onnistunut".to_owned()
+    } else {
+        let wave = wave.ok().and_then(|xml| parse_stations(&xml).ok());
+        let mareograph = mareograph.ok().and_then(|xml| parse_stations(&xml).ok());
+        generate_sea_msg(
+            wave.as_ref().and_then(|(s, _)| s.first()),
+            mareograph.as_ref().and_then(|(s, _)| s.first()),
+        )
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+fn channel_language(config: &Yaml, source: &ChatTarget) -> Language {
+    let english = config["fmi"]["english_channels"]
+        .as_vec()
+        .is_some_and(|channels| {
+            channels.iter().any(|c| {
+                c["network"].as_str() == Some(&source.network)
+                    && c["channel"].as_str() == Some(&source.channel)
+            })
+        });
+
+    if english {
+        Language::English
+    } else {
+        Language::Finnish
+    }
+}
+
 pub async fn command_fmi(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     prefix: Option<Prefix>,
     params: &str,
+    config: Arc<Yaml>,
 ) {
-    let location = match params {
-        "" => get_location(&prefix, &source.network),
-        _ => params.to_owned(),
+    let (lang, params) = match params.strip_prefix("-en") {
+        Some(p) if p.is_empty() || p.starts_with(' ') => (Language::English, p.trim()),
+        _ => (channel_language(&config, &source), params),
     };
 
-    let msg = if let Ok(xml) = get_xml(&location).await {
-        match parse_xml(&xml) {
-            Ok(data) => generate_msg(data),
-            Err(e) => e,
-        }
+    let msg = if let Some((place1, place2)) = params.split_once(" vs ") {
+        compare_weather(place1.trim(), place2.trim(), lang).await
     } else {
-        "Tietojen haku ei onnistunut".to_owned()
+        let location = match params {
+            "" => get_location(&prefix, &source).await,
+            _ => params.to_owned(),
+        };
+
+        weather::current_weather(&location, lang, &prefix, &source, &config).await
     };
 
     let action = BotAction {
@@ -336,6 +888,7 @@ pub async fn command_fmi(
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
@@ -1142,9 +1695,163 @@ mod tests {
         assert_eq!(parsed.feels_like, Some("-7.4".to_owned()));
         assert_eq!(parsed.humidity, Some("96".to_owned()));
         assert_eq!(parsed.cloudiness, Some("8".to_owned()));
-        assert_eq!(parsed.wawa, Some("jäätävää heikkoa vesisadetta".to_owned()));
+        assert_eq!(parsed.wawa, Some(64));
+        assert_eq!(parsed.dew_point, Some("-1.8".to_owned()));
+        assert_eq!(parsed.pressure, Some("1018.7".to_owned()));
+        assert_eq!(parsed.visibility, Some("3900.0".to_owned()));
+        assert_eq!(parsed.precipitation_1h, None);
+        assert_eq!(parsed.precipitation_intensity, Some("1.1".to_owned()));
+        assert_eq!(parsed.snow_depth, Some("28".to_owned()));
+        assert_eq!(
+            parsed.time,
+            Some(Utc.with_ymd_and_hms(2021, 2, 21, 14, 30, 0).unwrap())
+        );
+
+        let msg = generate_msg(parsed, Language::Finnish);
+        assert_eq!(msg, "Helsinki Kaisaniemi: lämpötila: -1.3°C, tuntuu kuin: -7.4°C, tuulen nopeus: 6.5m/s, puuskat: 9.0m/s, ilman kosteus: 96%, pilvisyys: 8/8, ilmanpaine: 1018.7hPa, näkyvyys: 3.9km, sateen intensiteetti: 1.1mm/h, lumen syvyys: 28cm, jäätävää heikkoa vesisadetta");
+
+        let mut summer = parse_xml(FMI_XML).unwrap();
+        summer.time = Some(Utc.with_ymd_and_hms(2021, 7, 1, 12, 0, 0).unwrap());
+        summer.dew_point = Some("17.5".to_owned());
+        summer.visibility = Some("20000.0".to_owned());
+        let msg = generate_msg(summer, Language::Finnish);
+        assert!(msg.contains("kastepiste: 17.5°C"));
+        assert!(!msg.contains("näkyvyys"));
+        assert!(!msg.contains("lumen syvyys"));
+
+        let msg = generate_msg(parse_xml(FMI_XML).unwrap(), Language::English);
+        assert_eq!(msg, "Helsinki Kaisaniemi: temperature: -1.3°C, feels like: -7.4°C, wind speed: 6.5m/s, gusts: 9.0m/s, humidity: 96%, cloudiness: 8/8, pressure: 1018.7hPa, visibility: 3.9km, precipitation intensity: 1.1mm/h, snow depth: 28cm, light freezing rain");
+    }
+
+    #[test]
+    fn fmi_trend() {
+        let yesterday = wrap_members(&[
+            station_member(1, "Helsinki Kumpula", "t2m", "-2.0"),
+            station_member(2, "Helsinki Kaisaniemi", "t2m", "-3.8"),
+        ]);
+        let mut parsed = parse_xml(FMI_XML).unwrap();
+        parsed.temperature_yesterday =
+            find_yesterday_temperature(&yesterday, parsed.place.as_deref());
+        assert_eq!(parsed.temperature_yesterday, Some("-3.8".to_owned()));
+        assert!(generate_msg(parsed, Language::Finnish)
+            .ends_with("jäätävää heikkoa vesisadetta, 2.5°C lämpimämpää kuin eilen"));
+
+        assert_eq!(
+            find_yesterday_temperature(&yesterday, Some("Vantaa")),
+            Some("-2.0".to_owned())
+        );
+        assert_eq!(
+            temperature_trend(Some("1.0"), Some("3.5"), Language::English),
+            Some("2.5°C colder than yesterday".to_owned())
+        );
+        assert_eq!(
+            temperature_trend(Some("1.0"), Some("1.0"), Language::Finnish),
+            Some("sama lämpötila kuin eilen".to_owned())
+        );
+        assert_eq!(
+            temperature_trend(Some("1.0"), None, Language::Finnish),
+            None
+        );
+    }
+
+    #[test]
+    fn fmi_compact() {
+        let parsed = parse_xml(FMI_XML).unwrap();
+        assert_eq!(
+            generate_compact_msg(&parsed, Language::Finnish),
+            "Helsinki Kaisaniemi: -1.3°C (tuntuu -7.4°C), tuuli 6.5m/s"
+        );
+        assert_eq!(
+            generate_compact_msg(&parsed, Language::English),
+            "Helsinki Kaisaniemi: -1.3°C (feels -7.4°C), wind 6.5m/s"
+        );
+    }
+
+    #[test]
+    fn fmi_min_max() {
+        let members = [
+            station_member(1, "Helsinki Kaisaniemi", "t2m", "-1.3"),
+            station_member(2, "Enontekiö Kilpisjärvi", "t2m", "-25.4"),
+            station_member(3, "Hanko Russarö", "t2m", "2.0"),
+            station_member(4, "Rikkinäinen asema", "t2m", "NaN"),
+        ];
+        let xml = wrap_members(&members);
+        let (stations, _) = parse_stations(&xml).unwrap();
+
+        assert_eq!(
+            generate_min_max_msg(&stations, Language::Finnish),
+            "Kylmin: Enontekiö Kilpisjärvi -25.4°C, lämpimin: Hanko Russarö 2.0°C"
+        );
+        assert_eq!(
+            generate_min_max_msg(&[], Language::Finnish),
+            "Tietoja ei löytynyt"
+        );
+    }
+
+    #[test]
+    fn fmi_sea() {
+        let wave = wrap_members(&[
+            station_member(1, "Helsinki Suomenlinna aaltopoiju", "TWATER", "15.2"),
+            station_member(1, "Helsinki Suomenlinna aaltopoiju", "WaveHs", "0.8"),
+        ]);
+        let mareograph = wrap_members(&[station_member(
+            1,
+            "Helsinki Kaivopuisto",
+            "WATLEV",
+            "-123.0",
+        )]);
+        let (wave, _) = parse_stations(&wave).unwrap();
+        let (mareograph, _) = parse_stations(&mareograph).unwrap();
+
+        assert_eq!(
+            generate_sea_msg(wave.first(), mareograph.first()),
+            "Helsinki Suomenlinna aaltopoiju: veden lämpötila: 15.2°C, aallonkorkeus: 0.8m | Helsinki Kaivopuisto: vedenkorkeus: -12cm"
+        );
+        assert_eq!(generate_sea_msg(None, None), "Tietoja ei löytynyt");
+    }
+
+    fn wrap_members(members: &[String]) -> String {
+        format!(
+            r#"<wfs:FeatureCollection xmlns:wfs="http://www.opengis.net/wfs/2.0" xmlns:om="http://www.opengis.net/om/2.0" xmlns:omso="http://inspire.ec.europa.eu/schemas/omso/3.0" xmlns:gml="http://www.opengis.net/gml/3.2" xmlns:sams="http://www.opengis.net/samplingSpatial/2.0" xmlns:wml2="http://www.opengis.net/waterml/2.0">{}</wfs:FeatureCollection>"#,
+            members.join("")
+        )
+    }
+
+    fn station_member(station: u32, name: &str, param: &str, value: &str) -> String {
+        format!(
+            r#"<wfs:member><omso:PointTimeSeriesObservation>
+            <om:featureOfInterest><sams:SF_SpatialSamplingFeature><sams:shape>
+            <gml:Point><gml:name>{name}</gml:name></gml:Point>
+            </sams:shape></sams:SF_SpatialSamplingFeature></om:featureOfInterest>
+            <om:result><wml2:MeasurementTimeseries gml:id="obs-obs-{station}-1-{param}">
+            <wml2:point><wml2:MeasurementTVP>
+            <wml2:time>2021-07-01T12:00:00Z</wml2:time><wml2:value>{value}</wml2:value>
+            </wml2:MeasurementTVP></wml2:point>
+            </wml2:MeasurementTimeseries></om:result>
+            </omso:PointTimeSeriesObservation></wfs:member>"#
+        )
+    }
 
-        let msg = generate_msg(parsed);
-        assert_eq!(msg, "Helsinki Kaisaniemi: lämpötila: -1.3°C, tuntuu kuin: -7.4°C, tuulen nopeus: 6.5m/s, puuskat: 9.0m/s, ilman kosteus: 96%, pilvisyys: 8/8, jäätävää heikkoa vesisadetta");
+    #[test]
+    fn fmi_nearest_station_fallback() {
+        let members = [
+            station_member(1, "Pieni asema", "t2m", "20.1"),
+            station_member(1, "Pieni asema", "ws_10min", "NaN"),
+            station_member(2, "Iso asema", "t2m", "19.5"),
+            station_member(2, "Iso asema", "ws_10min", "3.2"),
+            station_member(3, "Kaukainen asema", "ws_10min", "5.0"),
+        ];
+        let xml = wrap_members(&members);
+
+        let parsed = parse_xml(&xml).unwrap();
+        assert_eq!(parsed.place, Some("Pieni asema".to_owned()));
+        assert_eq!(parsed.temperature, Some("20.1".to_owned()));
+        assert_eq!(parsed.wind, Some("3.2".to_owned()));
+        assert_eq!(parsed.other_stations, vec!["Iso asema".to_owned()]);
+
+        assert_eq!(
+            generate_msg(parsed, Language::Finnish),
+            "Pieni asema: lämpötila: 20.1°C, tuulen nopeus: 3.2m/s (täydennetty asemilta: Iso asema)"
+        );
     }
 }
@@ -0,0 +1,283 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use core::time::Duration;
+
+use feed_rs::parser;
+
+use log::{info, warn};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use yaml_rust::yaml::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::get_url;
+use crate::ChatTarget;
+
+const WARNINGS_URL: &str = "https://alerts.fmi.fi/cap/feed/atom_fi-FI.xml";
+const MAX_WARNINGS_IN_MSG: usize = 5;
+
+#[derive(Debug, PartialEq)]
+struct Warning {
+    id: String,
+    title: String,
+    summary: String,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    target: ChatTarget,
+    area: Option<String>,
+}
+
+fn parse_warnings(body: &str) -> Result<Vec<Warning>, String> {
+    let feed = match parser::parse(body.as_bytes()) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(format!("Error parsing warnings: {}", e));
+        }
+    };
+
+    Ok(feed
+        .entries
+        .into_iter()
+        .map(|entry| Warning {
+            id: entry.id,
+            title: entry.title.map(|t| t.content).unwrap_or_default(),
+            summary: entry.summary.map(|s| s.content).unwrap_or_default(),
+        })
+        .collect())
+}
+
+fn warning_in_area(warning: &Warning, area: Option<&str>) -> bool {
+    match area {
+        Some(area) => {
+            let area = area.to_lowercase();
+            warning.title.to_lowercase().contains(&area)
+                || warning.summary.to_lowercase().contains(&area)
+        }
+        None => true,
+    }
+}
+
+fn generate_msg(warnings: &[&Warning], area: Option<&str>) -> String {
+    let header = match area {
+        Some(a) => format!("Varoitukset ({})", a),
+        None => "Varoitukset".to_owned(),
+    };
+
+    if warnings.is_empty() {
+        return format!("{}: ei voimassa olevia varoituksia.", header);
+    }
+
+    // The same warning is often issued for several areas, in any order
+    let mut seen = HashSet::new();
+    let titles: Vec<&str> = warnings
+        .iter()
+        .map(|w| w.title.as_str())
+        .filter(|t| seen.insert(*t))
+        .collect();
+
+    let shown = &titles[..titles.len().min(MAX_WARNINGS_IN_MSG)];
+    let mut msg = format!("{}: {}", header, shown.join(" | "));
+    if titles.len() > MAX_WARNINGS_IN_MSG {
+        msg.push_str(&format!(" (+{} muuta)", titles.len() - MAX_WARNINGS_IN_MSG));
+    }
+
+    msg
+}
+
+async fn get_warnings() -> Result<Vec<Warning>, String> {
+    match get_url(WARNINGS_URL).await {
+        Ok(body) => parse_warnings(&body),
+        Err(e) => Err(format!("Error fetching warnings: {}", e)),
+    }
+}
+
+pub async fn command_varoitukset(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+) {
+    let area = if params.is_empty() {
+        None
+    } else {
+        Some(params)
+    };
+
+    let msg = match get_warnings().await {
+        Ok(warnings) => {
+            let active: Vec<&Warning> = warnings
+                .iter()
+                .filter(|w| warning_in_area(w, area))
+                .collect();
+            generate_msg(&active, area)
+        }
+        Err(e) => e,
+    };
+
+    bot_sender
+        .send(BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        })
+        .await
+        .unwrap();
+}
+
+fn subscriptions_from_config(config: &Yaml) -> Vec<Subscription> {
+    let mut subscriptions = Vec::new();
+
+    if let Some(channels) = config["fmi_warnings"]["channels"].as_vec() {
+        for c in channels {
+            if let (Some(network), Some(channel)) = (c["network"].as_str(), c["channel"].as_str()) {
+                subscriptions.push(Subscription {
+                    target: ChatTarget {
+                        network: network.to_owned(),
+                        channel: channel.to_owned(),
+                    },
+                    area: c["area"].as_str().map(|a| a.to_owned()),
+                });
+            }
+        }
+    }
+
+    subscriptions
+}
+
+/// Announce warnings that were not in `seen` to the subscribed channels
+fn new_warning_actions(
+    warnings: &[Warning],
+    seen: &HashSet<String>,
+    subscriptions: &[Subscription],
+) -> Vec<BotAction> {
+    let mut actions = Vec::new();
+
+    for warning in warnings.iter().filter(|w| !seen.contains(&w.id)) {
+        for s in subscriptions {
+            if warning_in_area(warning, s.area.as_deref()) {
+                actions.push(BotAction {
+                    target: ChatTarget {
+                        network: s.target.network.to_owned(),
+                        channel: s.target.channel.to_owned(),
+                    },
+                    action_type: ActionType::Message(format!("Uusi varoitus: {}", warning.title)),
+                });
+            }
+        }
+    }
+
+    actions
+}
+
+pub async fn fmi_warnings_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(10 * 60);
+    let subscriptions = subscriptions_from_config(&config);
+
+    if subscriptions.is_empty() {
+        info!("No FMI warning subscriptions configured");
+        return;
+    }
+
+    // Warnings already active at startup are not announced
+    let mut seen: Option<HashSet<String>> = None;
+
+    loop {
+        match get_warnings().await {
+            Ok(warnings) => {
+                if let Some(seen) = &seen {
+                    for action in new_warning_actions(&warnings, seen, &subscriptions) {
+                        sender.send(action).await.unwrap();
+                    }
+                }
+                seen = Some(warnings.into_iter().map(|w| w.id).collect());
+            }
+            Err(e) => {
+                warn!("{}", e);
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WARNINGS_ATOM: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>https://alerts.fmi.fi/cap/feed/atom_fi-FI.xml</id>
+  <title>Ilmatieteen laitoksen varoitukset</title>
+  <updated>2023-06-01T10:00:00Z</updated>
+  <entry>
+    <id>urn:oid:2.49.0.1.246.0.0.2023.6.1.1</id>
+    <title>Keltainen tuulivaroitus: Pirkanmaa</title>
+    <updated>2023-06-01T10:00:00Z</updated>
+    <summary>Puuskatuuli voi olla 20 m/s.</summary>
+  </entry>
+  <entry>
+    <id>urn:oid:2.49.0.1.246.0.0.2023.6.1.2</id>
+    <title>Metsäpalovaroitus: Uusimaa</title>
+    <updated>2023-06-01T10:00:00Z</updated>
+    <summary>Metsäpalovaroitus voimassa.</summary>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn fmi_warnings_parse() {
+        let warnings = parse_warnings(WARNINGS_ATOM).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].title, "Keltainen tuulivaroitus: Pirkanmaa");
+
+        let active: Vec<&Warning> = warnings
+            .iter()
+            .filter(|w| warning_in_area(w, Some("uusimaa")))
+            .collect();
+        assert_eq!(
+            generate_msg(&active, Some("uusimaa")),
+            "Varoitukset (uusimaa): Metsäpalovaroitus: Uusimaa"
+        );
+        assert_eq!(
+            generate_msg(&[], Some("Lappi")),
+            "Varoitukset (Lappi): ei voimassa olevia varoituksia."
+        );
+
+        let warning = |title: &str| Warning {
+            id: title.to_owned(),
+            title: title.to_owned(),
+            summary: String::new(),
+        };
+        let many: Vec<Warning> = ["A", "B", "A", "C", "D", "B", "E", "F", "G"]
+            .iter()
+            .map(|t| warning(t))
+            .collect();
+        let many: Vec<&Warning> = many.iter().collect();
+        assert_eq!(
+            generate_msg(&many, None),
+            "Varoitukset: A | B | C | D | E (+2 muuta)"
+        );
+        assert_eq!(generate_msg(&many[..4], None), "Varoitukset: A | B | C");
+
+        let subscriptions = vec![Subscription {
+            target: ChatTarget {
+                network: "testnetwork".to_owned(),
+                channel: "#testing".to_owned(),
+            },
+            area: Some("Pirkanmaa".to_owned()),
+        }];
+        let mut seen = HashSet::new();
+        assert_eq!(
+            new_warning_actions(&warnings, &seen, &subscriptions).len(),
+            1
+        );
+        seen.insert(warnings[0].id.to_owned());
+        assert!(new_warning_actions(&warnings, &seen, &subscriptions).is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use futures::future::join_all;
+use log::warn;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::epic;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const GOG_URL: &str = "https://catalog.gog.com/v1/catalog";
+const STEAM_URL: &str = "https://store.steampowered.com/search/results/";
+const PRIME_URL: &str = "https://gaming.amazon.com/graphql";
+
+const PRIME_QUERY: &str = r#"{"operationName":"OffersContext_Offers_And_Items","variables":{"pageSize":999},"extensions":{},"query":"query OffersContext_Offers_And_Items($dateOverride: Time, $pageSize: Int) { games: items(collectionType: FREE_GAMES, dateOverride: $dateOverride, pageSize: $pageSize) { items { id assets { title } } } }"}"#;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Store {
+    Epic,
+    Gog,
+    Steam,
+    Prime,
+}
+
+const STORES: [Store; 4] = [Store::Epic, Store::Gog, Store::Steam, Store::Prime];
+
+impl Store {
+    fn name(&self) -> &'static str {
+        match self {
+            Store::Epic => "Epic",
+            Store::Gog => "GOG",
+            Store::Steam => "Steam",
+            Store::Prime => "Prime Gaming",
+        }
+    }
+
+    fn config_key(&self) -> &'static str {
+        match self {
+            Store::Epic => "epic",
+            Store::Gog => "gog",
+            Store::Steam => "steam",
+            Store::Prime => "prime",
+        }
+    }
+
+    async fn free_titles(&self, config: &Yaml) -> Result<Vec<String>, String> {
+        match self {
+            Store::Epic => epic::free_titles(config).await,
+            Store::Gog => parse_gog(&get_gog_json().await.map_err(|e| e.to_string())?),
+            Store::Steam => parse_steam(&get_steam_json().await.map_err(|e| e.to_string())?),
+            Store::Prime => parse_prime(&get_prime_json().await.map_err(|e| e.to_string())?),
+        }
+    }
+}
+
+/// Stores that are not disabled under `ilmaispelit: stores:` in config.yml
+fn enabled_stores(config: &Yaml) -> Vec<Store> {
+    STORES
+        .iter()
+        .filter(|s| {
+            config["ilmaispelit"]["stores"][s.config_key()]
+                .as_bool()
+                .unwrap_or(true)
+        })
+        .copied()
+        .collect()
+}
+
+async fn get_gog_json() -> reqwest::Result<String> {
+    let request = HTTP_CLIENT.get(GOG_URL).query(&[
+        ("limit", "48"),
+        ("price", "between:0,0"),
+        ("discounted", "eq:true"),
+        ("productType", "in:game,pack"),
+        ("countryCode", "FI"),
+    ]);
+    http_client::send(request).await?.text().await
+}
+
+async fn get_steam_json() -> reqwest::Result<String> {
+    let request =
+        HTTP_CLIENT
+            .get(STEAM_URL)
+            .query(&[("maxprice", "free"), ("specials", "1"), ("json", "1")]);
+    http_client::send(request).await?.text().await
+}
+
+async fn get_prime_json() -> reqwest::Result<String> {
+    let request = HTTP_CLIENT
+        .post(PRIME_URL)
+        .header("client-id", "CarboniteApp")
+        .header("content-type", "application/json")
+        .body(PRIME_QUERY);
+    http_client::send(request).await?.text().await
+}
+
+fn parse(json_text: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())
+}
+
+fn titles(items: &serde_json::Value, title: &[&str]) -> Vec<String> {
+    items
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|i| title.iter().fold(i, |v, key| &v[key]).as_str())
+        .map(|t| t.to_owned())
+        .collect()
+}
+
+/// GOG giveaways are games discounted to zero
+fn parse_gog(json_text: &str) -> Result<Vec<String>, String> {
+    let json = parse(json_text)?;
+    Ok(titles(&json["products"], &["title"]))
+}
+
+/// Games with a 100% discount; the search only lists specials that are free
+fn parse_steam(json_text: &str) -> Result<Vec<String>, String> {
+    let json = parse(json_text)?;
+    Ok(titles(&json["items"], &["name"]))
+}
+
+fn parse_prime(json_text: &str) -> Result<Vec<String>, String> {
+    let json = parse(json_text)?;
+    Ok(titles(
+        &json["data"]["games"]["items"],
+        &["assets", "title"],
+    ))
+}
+
+fn generate_msg(results: &[(Store, Result<Vec<String>, String>)]) -> String {
+    let stores: Vec<String> = results
+        .iter()
+        .filter_map(|(store, titles)| match titles {
+            Ok(t) if !t.is_empty() => Some(format!("{}: {}", store.name(), t.join(", "))),
+            _ => None,
+        })
+        .collect();
+
+    if stores.is_empty() {
+        "Ei ilmaispelejä juuri nyt.".to_owned()
+    } else {
+        format!("Ilmaiseksi nyt: {}", stores.join(" | "))
+    }
+}
+
+pub async fn command_ilmaispelit(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    config: Arc<Yaml>,
+) {
+    let stores = enabled_stores(&config);
+    let titles = join_all(stores.iter().map(|s| s.free_titles(&config))).await;
+
+    let results: Vec<(Store, Result<Vec<String>, String>)> =
+        stores.into_iter().zip(titles).collect();
+    for (store, r) in &results {
+        if let Err(e) = r {
+            warn!("Free games from {}: {}", store.name(), e);
+        }
+    }
+
+    let msg = if results.iter().all(|(_, r)| r.is_err()) {
+        "Virhe ilmaispelien haussa".to_owned()
+    } else {
+        generate_msg(&results)
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn store_toggles() {
+        let config =
+            YamlLoader::load_from_str("ilmaispelit:\n  stores:\n    prime: false\n    gog: false")
+                .unwrap();
+        assert_eq!(enabled_stores(&config[0]), vec![Store::Epic, Store::Steam]);
+
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert_eq!(enabled_stores(&config[0]), STORES.to_vec());
+    }
+
+    #[test]
+    fn combined_msg() {
+        let gog = parse_gog(r#"{"products":[{"title":"Gog Game","price":{"final":"0.00"}}]}"#);
+        let steam = parse_steam(r#"{"desc":"","items":[{"name":"Steam Game","logo":""}]}"#);
+        let prime = parse_prime(
+            r#"{"data":{"games":{"items":[{"id":"1","assets":{"title":"Prime Game"}}]}}}"#,
+        );
+
+        let results = vec![
+            (Store::Epic, Err("Error parsing JSON".to_owned())),
+            (Store::Gog, gog),
+            (Store::Steam, steam),
+            (Store::Prime, prime),
+        ];
+        assert_eq!(
+            generate_msg(&results),
+            "Ilmaiseksi nyt: GOG: Gog Game | Steam: Steam Game | Prime Gaming: Prime Game"
+        );
+    }
+}
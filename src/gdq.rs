@@ -3,53 +3,460 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use chrono::prelude::*;
+use chrono_tz::Tz;
+use irc::client::prelude::Prefix;
+use log::warn;
 use select::document::Document;
-use select::predicate::{Predicate, Attr, Class, Name};
+use select::predicate::{Attr, Class, Name, Predicate};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::timer::TimerEvent;
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+// The schedule page is large and only changes when runs go long or short
+const SCHEDULE_TTL: Duration = Duration::from_secs(10 * 60);
+
+const DEFAULT_UPCOMING: usize = 3;
+const MAX_UPCOMING: usize = 8;
+
+const NOTIFY_MINUTES_BEFORE: i64 = 10;
+const STREAM_URL: &str = "https://www.twitch.tv/gamesdonequick";
+
+#[derive(Clone, Debug, PartialEq)]
+struct Run {
+    start: DateTime<FixedOffset>,
+    game: String,
+    runners: String,
+    estimate: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Event {
+    name: String,
+    start: DateTime<FixedOffset>,
+    amount: Option<f64>,
+}
 
 async fn get_html() -> reqwest::Result<String> {
     let baseurl = "https://gamesdonequick.com/schedule";
 
-    let html = HTTP_CLIENT.get(baseurl).send().await?.text().await?;
+    http_client::get_cached(HTTP_CLIENT.get(baseurl), SCHEDULE_TTL).await
+}
+
+async fn get_events_json() -> reqwest::Result<String> {
+    let baseurl = "https://tracker.gamesdonequick.com/tracker/api/v2/events/";
+
+    let request = HTTP_CLIENT.get(baseurl).query(&[("totals", "")]);
+
+    http_client::get_cached(request, SCHEDULE_TTL).await
+}
+
+fn parse_events(json_text: &str) -> Result<Vec<Event>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let events = json["results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| {
+            Some(Event {
+                name: e["name"].as_str()?.to_owned(),
+                start: DateTime::parse_from_rfc3339(e["datetime"].as_str()?).ok()?,
+                amount: e["amount"].as_f64(),
+            })
+        })
+        .collect();
+
+    Ok(events)
+}
+
+/// Tracker events, or none if the tracker can't be reached
+async fn events() -> Vec<Event> {
+    match get_events_json().await.map(|json| parse_events(&json)) {
+        Ok(Ok(events)) => events,
+        Ok(Err(e)) => {
+            warn!("GDQ tracker: {}", e);
+            Vec::new()
+        }
+        Err(e) => {
+            warn!("GDQ tracker: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// "1:30:00" as a duration
+fn parse_estimate(estimate: &str) -> Option<chrono::Duration> {
+    let mut parts = estimate.trim().split(':').map(|p| p.parse::<i64>());
+    let (h, m, s) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+
+    Some(chrono::Duration::seconds(h * 3600 + m * 60 + s))
+}
+
+/// Whether `now` is between the first run's start and the last run's end
+fn marathon_live(runs: &[Run], now: DateTime<Utc>) -> bool {
+    match (runs.first(), runs.last()) {
+        (Some(first), Some(last)) => {
+            let end = last.start
+                + last
+                    .estimate
+                    .as_deref()
+                    .and_then(parse_estimate)
+                    .unwrap_or_else(chrono::Duration::zero);
+            first.start <= now && now <= end
+        }
+        _ => false,
+    }
+}
+
+/// "$1,234,567"
+fn format_dollars(amount: f64) -> String {
+    let digits = format!("{:.0}", amount);
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
 
-    Ok(html)
+    format!("${}", grouped)
 }
 
-fn parse_html(raw_html: &str) -> Result<(String, String), String> {
-    let now = Utc::now();
-    let mut current = String::new();
-    let mut next = String::new();
+fn parse_html(raw_html: &str) -> Result<Vec<Run>, String> {
+    let mut runs: Vec<Run> = Vec::new();
 
     let doc = Document::from(raw_html);
     for line in doc.find(Attr("id", "runTable").descendant(Name("tr"))) {
+        let cells: Vec<String> = line
+            .find(Name("td"))
+            .map(|td| td.text().trim().to_owned())
+            .collect();
+
+        // Each run has a second row starting with the estimate
+        if line.is(Class("second-row")) {
+            if let (Some(run), Some(estimate)) = (runs.last_mut(), cells.first()) {
+                run.estimate = Some(estimate.to_owned()).filter(|e| !e.is_empty());
+            }
+            continue;
+        }
+
         if let Some(start_time) = line.find(Class("start-time")).next() {
-            if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(&start_time.text()) {
-                let game_name = line.find(Name("td")).take(2).nth(1).unwrap().text();
-                if dt < now {
-                    current = game_name;
-                } else {
-                    next = game_name;
-                    break;
+            if let Ok(start) = chrono::DateTime::parse_from_rfc3339(start_time.text().trim()) {
+                if let Some(game) = cells.get(1) {
+                    runs.push(Run {
+                        start,
+                        game: game.to_owned(),
+                        runners: cells.get(2).cloned().unwrap_or_default(),
+                        estimate: None,
+                    });
                 }
             }
         }
     }
 
-    Ok((current, next))
+    if runs.is_empty() {
+        return Err("No runs found in the GDQ schedule".to_owned());
+    }
+
+    Ok(runs)
+}
+
+/// The schedule through the shared response cache, which also falls back to
+/// an expired schedule if the site can't be reached
+async fn schedule() -> Result<Vec<Run>, String> {
+    match get_html().await {
+        Ok(html) => parse_html(&html),
+        Err(_) => Err("Error fetching the GDQ schedule".to_owned()),
+    }
+}
+
+/// The run in progress at `now` and the one after it
+fn current_and_next(runs: &[Run], now: DateTime<Utc>) -> (Option<&Run>, Option<&Run>) {
+    let next_index = runs.iter().position(|r| r.start > now);
+    let current = match next_index {
+        Some(0) => None,
+        Some(i) => runs.get(i - 1),
+        None => runs.last(),
+    };
+
+    (current, next_index.and_then(|i| runs.get(i)))
 }
 
-fn generate_msg(games: (String, String)) -> String {
-    format!("Now playing: {} | Up next: {}", games.0, games.1)
+fn starts_msg(name: &str, start: DateTime<FixedOffset>, now: DateTime<Utc>, tz: Tz) -> String {
+    let days = start
+        .with_timezone(&tz)
+        .date_naive()
+        .signed_duration_since(now.with_timezone(&tz).date_naive())
+        .num_days();
+    let from_now = match days {
+        0 => "today".to_owned(),
+        1 => "tomorrow".to_owned(),
+        d => format!("in {} days", d),
+    };
+
+    format!(
+        "{} starts on {}, {}",
+        name,
+        start.with_timezone(&tz).format("%Y-%m-%d %H:%M"),
+        from_now
+    )
 }
 
-pub async fn command_gdq(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel) {
-    let html = get_html().await.unwrap();
-    let parsed = parse_html(&html).unwrap();
-    let msg = generate_msg(parsed);
+fn generate_msg(runs: &[Run], events: &[Event], now: DateTime<Utc>, tz: Tz) -> String {
+    let current_event = events
+        .iter()
+        .filter(|e| e.start <= now)
+        .max_by_key(|e| e.start);
+    let next_event = events
+        .iter()
+        .filter(|e| e.start > now)
+        .min_by_key(|e| e.start);
+
+    if marathon_live(runs, now) {
+        let mut msg = match current_and_next(runs, now) {
+            (Some(current), Some(next)) => {
+                format!("Now playing: {} | Up next: {}", current.game, next.game)
+            }
+            (Some(current), None) => format!("Now playing: {}", current.game),
+            _ => "GDQ is live".to_owned(),
+        };
+        if let Some(amount) = current_event.and_then(|e| e.amount) {
+            msg.push_str(&format!(" | Raised: {}", format_dollars(amount)));
+        }
+        return msg;
+    }
+
+    // The schedule page switches to the next marathon before the tracker does
+    if let Some(first) = runs.first().filter(|r| r.start > now) {
+        let name = next_event
+            .map(|e| e.name.as_str())
+            .unwrap_or("The next GDQ");
+        return starts_msg(name, first.start, now, tz);
+    }
+    if let Some(event) = next_event {
+        return starts_msg(&event.name, event.start, now, tz);
+    }
+
+    match current_event {
+        Some(Event {
+            name,
+            amount: Some(amount),
+            ..
+        }) => format!(
+            "No GDQ marathon running. {} raised {}",
+            name,
+            format_dollars(*amount)
+        ),
+        _ => "No GDQ marathon running".to_owned(),
+    }
+}
+
+/// Timezone for run start times: the user's own, the configured one or Finnish time
+fn display_timezone(user_tz: Option<Tz>, config: &Yaml) -> Tz {
+    user_tz
+        .or_else(|| config["gdq"]["timezone"].as_str()?.parse().ok())
+        .unwrap_or(chrono_tz::Europe::Helsinki)
+}
+
+fn generate_upcoming_msg(runs: &[Run], count: usize, now: DateTime<Utc>, tz: Tz) -> String {
+    let today = now.with_timezone(&tz).date_naive();
+
+    let upcoming: Vec<String> = runs
+        .iter()
+        .filter(|r| r.start > now)
+        .take(count)
+        .map(|r| {
+            let start = r.start.with_timezone(&tz);
+            let time = if start.date_naive() == today {
+                start.format("%H:%M").to_string()
+            } else {
+                start.format("%-d.%-m. %H:%M").to_string()
+            };
+
+            let details: Vec<&str> = [Some(r.runners.as_str()), r.estimate.as_deref()]
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|d| !d.is_empty())
+                .collect();
+            if details.is_empty() {
+                format!("{} {}", time, r.game)
+            } else {
+                format!("{} {} ({})", time, r.game, details.join(", "))
+            }
+        })
+        .collect();
+
+    if upcoming.is_empty() {
+        "No upcoming runs in the GDQ schedule".to_owned()
+    } else {
+        upcoming.join(" | ")
+    }
+}
+
+/// The next run matching `query`, or the latest one if all of them are over
+fn find_run<'a>(runs: &'a [Run], query: &str, now: DateTime<Utc>) -> Option<&'a Run> {
+    let query = query.to_lowercase();
+    let matches = runs
+        .iter()
+        .filter(|r| r.game.to_lowercase().contains(&query));
+
+    let mut last_past = None;
+    for run in matches {
+        if run.start > now {
+            return Some(run);
+        }
+        last_past = Some(run);
+    }
+
+    last_past
+}
+
+fn find_msg(runs: &[Run], query: &str, now: DateTime<Utc>, tz: Tz) -> String {
+    match find_run(runs, query, now) {
+        Some(run) if run.start > now => {
+            let mut msg = starts_msg(&run.game, run.start, now, tz);
+            if let Some(estimate) = &run.estimate {
+                msg.push_str(&format!(" (estimate {})", estimate));
+            }
+            msg
+        }
+        Some(run) => format!(
+            "{} started on {}",
+            run.game,
+            run.start.with_timezone(&tz).format("%Y-%m-%d %H:%M")
+        ),
+        None => format!("No run matching '{}' in the GDQ schedule", query),
+    }
+}
+
+/// Timer for pinging `nick` shortly before the run starts, and the reply to the command
+fn notify_timer(
+    runs: &[Run],
+    query: &str,
+    nick: &str,
+    source: &ChatTarget,
+    now: DateTime<Utc>,
+) -> (Option<TimerEvent>, String) {
+    let run = match find_run(runs, query, now).filter(|r| r.start > now) {
+        Some(r) => r,
+        None => {
+            return (None, format!("No upcoming run matching '{}'", query));
+        }
+    };
+
+    let until_start = run.start.with_timezone(&Utc) - now;
+    let notify_in = until_start - chrono::Duration::minutes(NOTIFY_MINUTES_BEFORE);
+    if notify_in <= chrono::Duration::zero() {
+        return (
+            None,
+            format!(
+                "{} starts in {} minutes: {}",
+                run.game,
+                until_start.num_minutes(),
+                STREAM_URL
+            ),
+        );
+    }
+
+    let event = TimerEvent {
+        target: ChatTarget {
+            network: source.network.to_owned(),
+            channel: source.channel.to_owned(),
+        },
+        message: format!(
+            "{}: {} starts in {} minutes at GDQ: {}",
+            nick, run.game, NOTIFY_MINUTES_BEFORE, STREAM_URL
+        ),
+        time: notify_in,
+        nick: Some(nick.to_owned()),
+    };
+
+    (
+        Some(event),
+        format!(
+            "I'll ping you {} minutes before {} starts",
+            NOTIFY_MINUTES_BEFORE, run.game
+        ),
+    )
+}
+
+pub async fn command_gdq(
+    bot_sender: mpsc::Sender<BotAction>,
+    timer_sender: mpsc::Sender<TimerEvent>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let tz = display_timezone(get_timezone(&prefix, &source.network).await, &config);
+
+    let msg = match params.split_once(' ').unwrap_or((params, "")) {
+        ("find", game) if !game.trim().is_empty() => Some(match schedule().await {
+            Ok(runs) => find_msg(&runs, game.trim(), Utc::now(), tz),
+            Err(e) => e,
+        }),
+        ("notify", game) if !game.trim().is_empty() => {
+            let nick = match &prefix {
+                Some(Prefix::Nickname(nick, _, _)) => nick.to_owned(),
+                _ => {
+                    return;
+                }
+            };
+            Some(match schedule().await {
+                Ok(runs) => {
+                    let (event, msg) = notify_timer(&runs, game.trim(), &nick, &source, Utc::now());
+                    if let Some(event) = event {
+                        timer_sender.send(event).await.unwrap();
+                    }
+                    msg
+                }
+                Err(e) => e,
+            })
+        }
+        _ => None,
+    };
+    if let Some(msg) = msg {
+        let action = BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        };
+        bot_sender.send(action).await.unwrap();
+        return;
+    }
+
+    let upcoming = match params.split_once(' ').unwrap_or((params, "")) {
+        ("next", "") => Some(DEFAULT_UPCOMING),
+        ("next", n) => Some(
+            n.trim()
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_UPCOMING)
+                .clamp(1, MAX_UPCOMING),
+        ),
+        _ => None,
+    };
+
+    let msg = match (schedule().await, upcoming) {
+        (Ok(runs), Some(count)) => generate_upcoming_msg(&runs, count, Utc::now(), tz),
+        (Ok(runs), None) => generate_msg(&runs, &events().await, Utc::now(), tz),
+        (Err(e), _) => e,
+    };
 
     let action = BotAction {
         target: source,
@@ -58,3 +465,134 @@ pub async fn command_gdq(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel
 
     bot_sender.send(action).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEDULE_HTML: &str = r#"<html><body><table id="runTable"><tbody>
+        <tr><td class="start-time text-right">2023-01-08T16:30:00Z</td><td>Pre-Show</td><td>GDQ Staff</td></tr>
+        <tr class="second-row"><td class="text-right"><i class="fa fa-clock-o"></i> 0:30:00 </td><td>Pre-Show &mdash; Live</td></tr>
+        <tr><td class="start-time text-right">2023-01-08T17:00:00Z</td><td>Super Mario 64</td><td>Runner A</td></tr>
+        <tr class="second-row"><td class="text-right"><i class="fa fa-clock-o"></i> 0:20:00 </td><td>16 Star &mdash; N64</td></tr>
+        <tr><td class="start-time text-right">2023-01-08T17:20:00Z</td><td>Celeste</td><td>Runner B, Runner C</td></tr>
+        <tr class="second-row"><td class="text-right"><i class="fa fa-clock-o"></i> 0:40:00 </td><td>Any% &mdash; PC</td></tr>
+    </tbody></table></body></html>"#;
+
+    #[test]
+    fn now_and_next() {
+        let runs = parse_html(SCHEDULE_HTML).unwrap();
+        assert_eq!(runs.len(), 3);
+
+        let tz = chrono_tz::Europe::Helsinki;
+        let now = Utc.with_ymd_and_hms(2023, 1, 8, 17, 10, 0).unwrap();
+        assert_eq!(
+            generate_msg(&runs, &[], now, tz),
+            "Now playing: Super Mario 64 | Up next: Celeste"
+        );
+
+        assert!(parse_html("<html></html>").is_err());
+    }
+
+    #[test]
+    fn event_status() {
+        let runs = parse_html(SCHEDULE_HTML).unwrap();
+        let events = parse_events(
+            r#"{"count": 2, "results": [
+            {"type": "event", "short": "sgdq2022", "name": "Summer Games Done Quick 2022",
+             "datetime": "2022-06-26T16:30:00Z", "amount": 3016139.0},
+            {"type": "event", "short": "agdq2023", "name": "Awesome Games Done Quick 2023",
+             "datetime": "2023-01-08T16:30:00Z", "amount": 2634000.7}
+        ]}"#,
+        )
+        .unwrap();
+        let tz = chrono_tz::Europe::Helsinki;
+
+        let live = Utc.with_ymd_and_hms(2023, 1, 8, 17, 30, 0).unwrap();
+        assert_eq!(
+            generate_msg(&runs, &events, live, tz),
+            "Now playing: Celeste | Raised: $2,634,001"
+        );
+
+        let before = Utc.with_ymd_and_hms(2023, 1, 5, 12, 0, 0).unwrap();
+        assert_eq!(
+            generate_msg(&runs, &events, before, tz),
+            "Awesome Games Done Quick 2023 starts on 2023-01-08 18:30, in 3 days"
+        );
+
+        let after = Utc.with_ymd_and_hms(2023, 1, 9, 12, 0, 0).unwrap();
+        assert_eq!(
+            generate_msg(&runs, &events, after, tz),
+            "No GDQ marathon running. Awesome Games Done Quick 2023 raised $2,634,001"
+        );
+    }
+
+    #[test]
+    fn find_and_notify() {
+        let runs = parse_html(SCHEDULE_HTML).unwrap();
+        let tz = chrono_tz::Europe::Helsinki;
+        let now = Utc.with_ymd_and_hms(2023, 1, 8, 16, 45, 0).unwrap();
+
+        assert_eq!(
+            find_msg(&runs, "celeste", now, tz),
+            "Celeste starts on 2023-01-08 19:20, today (estimate 0:40:00)"
+        );
+        assert_eq!(
+            find_msg(&runs, "pre-show", now, tz),
+            "Pre-Show started on 2023-01-08 18:30"
+        );
+        assert_eq!(
+            find_msg(&runs, "zelda", now, tz),
+            "No run matching 'zelda' in the GDQ schedule"
+        );
+
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        let (event, msg) = notify_timer(&runs, "celeste", "nick", &channel, now);
+        let event = event.unwrap();
+        assert_eq!(event.time, chrono::Duration::minutes(25));
+        assert_eq!(
+            event.message,
+            "nick: Celeste starts in 10 minutes at GDQ: https://www.twitch.tv/gamesdonequick"
+        );
+        assert_eq!(msg, "I'll ping you 10 minutes before Celeste starts");
+
+        let soon = Utc.with_ymd_and_hms(2023, 1, 8, 16, 55, 0).unwrap();
+        let (event, msg) = notify_timer(&runs, "mario", "nick", &channel, soon);
+        assert!(event.is_none());
+        assert_eq!(
+            msg,
+            "Super Mario 64 starts in 5 minutes: https://www.twitch.tv/gamesdonequick"
+        );
+    }
+
+    #[test]
+    fn upcoming_runs() {
+        let runs = parse_html(SCHEDULE_HTML).unwrap();
+        assert_eq!(runs[2].runners, "Runner B, Runner C");
+        assert_eq!(runs[2].estimate.as_deref(), Some("0:40:00"));
+
+        let now = Utc.with_ymd_and_hms(2023, 1, 8, 16, 45, 0).unwrap();
+        assert_eq!(
+            generate_upcoming_msg(&runs, 2, now, chrono_tz::Europe::Helsinki),
+            "19:00 Super Mario 64 (Runner A, 0:20:00) | 19:20 Celeste (Runner B, Runner C, 0:40:00)"
+        );
+        assert_eq!(
+            generate_upcoming_msg(&runs, 1, now, chrono_tz::America::New_York),
+            "12:00 Super Mario 64 (Runner A, 0:20:00)"
+        );
+
+        let config =
+            yaml_rust::YamlLoader::load_from_str("gdq:\n  timezone: America/Denver").unwrap();
+        assert_eq!(
+            display_timezone(None, &config[0]),
+            chrono_tz::America::Denver
+        );
+        assert_eq!(
+            display_timezone(Some(chrono_tz::Asia::Tokyo), &config[0]),
+            chrono_tz::Asia::Tokyo
+        );
+    }
+}
@@ -3,25 +3,285 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use rand::prelude::*;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::IrcChannel;
+use crate::ChatTarget;
+
+// Tables can refer to each other, this stops a loop in the config
+const MAX_TABLE_DEPTH: usize = 5;
 
 struct H33h3Result {
     main_action: ActionType,
     extra_action: Option<ActionType>,
 }
 
-pub async fn handle_h33h3(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel, nick: &str) {
+#[derive(Clone, Debug, PartialEq)]
+enum Response {
+    Message(String),
+    Action(String),
+    /// Pick again from another table
+    Table(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    weight: u32,
+    response: Response,
+    extra: Option<String>,
+}
+
+/// Response tables from `h33h3:` in config.yml, the first table is used for the triggers
+#[derive(Debug, PartialEq)]
+struct Tables {
+    tables: Vec<(String, Vec<Entry>)>,
+}
+
+impl Tables {
+    fn from_config(config: &Yaml) -> Tables {
+        let mut tables = Vec::new();
+
+        if let Some(hash) = config["h33h3"]["tables"].as_hash() {
+            for (name, entries) in hash {
+                if let (Some(name), Some(entries)) = (name.as_str(), entries.as_vec()) {
+                    let entries: Vec<Entry> =
+                        entries.iter().filter_map(entry_from_config).collect();
+                    if !entries.is_empty() {
+                        tables.push((name.to_owned(), entries));
+                    }
+                }
+            }
+        }
+
+        if tables.is_empty() {
+            default_tables()
+        } else {
+            Tables { tables }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&[Entry]> {
+        self.tables
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, e)| e.as_slice())
+    }
+}
+
+fn entry_from_config(entry: &Yaml) -> Option<Entry> {
+    let response = if let Some(m) = entry["message"].as_str() {
+        Response::Message(m.to_owned())
+    } else if let Some(a) = entry["action"].as_str() {
+        Response::Action(a.to_owned())
+    } else if let Some(t) = entry["table"].as_str() {
+        Response::Table(t.to_owned())
+    } else {
+        return None;
+    };
+
+    Some(Entry {
+        weight: entry["weight"].as_i64().unwrap_or(1).max(0) as u32,
+        response,
+        extra: entry["extra"].as_str().map(|e| e.to_owned()),
+    })
+}
+
+fn entry(weight: u32, response: Response, extra: Option<&str>) -> Entry {
+    Entry {
+        weight,
+        response,
+        extra: extra.map(|e| e.to_owned()),
+    }
+}
+
+fn message(text: &str) -> Response {
+    Response::Message(text.to_owned())
+}
+
+/// The original nbotti tables
+fn default_tables() -> Tables {
+    let kasipallo = Response::Table("kasipallo".to_owned());
+
+    Tables {
+        tables: vec![
+            (
+                "h33h3".to_owned(),
+                vec![
+                    entry(
+                        2,
+                        message("GOOD DAY {nick}, YOU LOSE AT THE INTTER NETS"),
+                        None,
+                    ),
+                    entry(1, message("hngggg"), None),
+                    entry(1, message("h33h3"), None),
+                    entry(1, kasipallo.clone(), Some("<W> har har har")),
+                    entry(1, kasipallo.clone(), Some("<W> HAR VITUN HAR")),
+                    entry(95, kasipallo, None),
+                ],
+            ),
+            (
+                "kasipallo".to_owned(),
+                vec![
+                    entry(4, message("0"), None),
+                    entry(3, message("{rand:1}"), None),
+                    entry(2, message("{rand:2}"), None),
+                    entry(2, message("{rand:3}"), None),
+                    entry(2, message("{rand:4}"), None),
+                    entry(2, message("{rand:5}"), None),
+                    entry(1, message(".____________."), None),
+                    entry(1, Response::Action("am cry".to_owned()), None),
+                    entry(1, message("fail"), None),
+                    // First ':' gets eaten by something
+                    entry(1, message("::|"), None),
+                    entry(1, message("h3-- not."), None),
+                ],
+            ),
+        ],
+    }
+}
+
+/// Whether the message should get a response, `h33h3: triggers` in config.yml
+pub fn is_trigger(config: &Yaml, msg_lower: &str) -> bool {
+    match config["h33h3"]["triggers"].as_vec() {
+        Some(triggers) => triggers
+            .iter()
+            .filter_map(|t| t.as_str())
+            .any(|t| t.to_lowercase() == msg_lower),
+        None => msg_lower == "h33h3",
+    }
+}
+
+/// Chance of answering a fun trigger on the channel from `fun_triggers:` in
+/// config.yml. Channels can override the global probability or be disabled.
+fn response_probability(config: &Yaml, source: &ChatTarget) -> f64 {
+    let section = &config["fun_triggers"];
+    let as_probability = |y: &Yaml| y.as_f64().or_else(|| y.as_i64().map(|p| p as f64));
+
+    let channel = section["channels"].as_vec().and_then(|channels| {
+        channels.iter().find(|c| {
+            c["network"].as_str() == Some(&source.network)
+                && c["channel"]
+                    .as_str()
+                    .is_some_and(|ch| ch.eq_ignore_ascii_case(&source.channel))
+        })
+    });
+
+    let probability = match channel {
+        Some(c) if c["enabled"].as_bool() == Some(false) => 0.0,
+        Some(c) => as_probability(&c["probability"])
+            .or_else(|| as_probability(&section["probability"]))
+            .unwrap_or(1.0),
+        None => as_probability(&section["probability"]).unwrap_or(1.0),
+    };
+
+    probability.clamp(0.0, 1.0)
+}
+
+/// Whether to answer h33h3 or another fun trigger on the channel this time
+pub fn fun_trigger_roll(config: &Yaml, source: &ChatTarget) -> bool {
+    let probability = response_probability(config, source);
+    probability > 0.0 && thread_rng().gen_bool(probability)
+}
+
+/// Fills in {nick} and {rand:N}, a random number from 0 to N
+fn render<R: Rng + ?Sized>(rng: &mut R, text: &str, nick: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text.replace("{nick}", nick);
+
+    while let Some(start) = rest.find("{rand:") {
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => break,
+        };
+        result.push_str(&rest[..start]);
+        match rest[start + 6..end].trim().parse::<u32>() {
+            Ok(max) => result.push_str(&rng.gen_range(0..=max).to_string()),
+            Err(_) => result.push_str(&rest[start..=end]),
+        }
+        rest = rest[end + 1..].to_owned();
+    }
+    result.push_str(&rest);
+
+    result
+}
+
+fn pick<'a, R: Rng + ?Sized>(rng: &mut R, entries: &'a [Entry]) -> Option<&'a Entry> {
+    let total: u32 = entries.iter().map(|e| e.weight).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut roll = rng.gen_range(0..total);
+    for e in entries {
+        if roll < e.weight {
+            return Some(e);
+        }
+        roll -= e.weight;
+    }
+
+    None
+}
+
+fn respond<R: Rng + ?Sized>(
+    rng: &mut R,
+    tables: &Tables,
+    table: &str,
+    nick: &str,
+    depth: usize,
+) -> Option<H33h3Result> {
+    let entry = pick(rng, tables.get(table)?)?;
+
+    let extra_action = entry
+        .extra
+        .as_ref()
+        .map(|e| ActionType::Message(render(rng, e, nick)));
+    let main_action = match &entry.response {
+        Response::Message(m) => ActionType::Message(render(rng, m, nick)),
+        Response::Action(a) => ActionType::Action(render(rng, a, nick)),
+        Response::Table(t) if depth < MAX_TABLE_DEPTH => {
+            let inner = respond(rng, tables, t, nick, depth + 1)?;
+            return Some(H33h3Result {
+                main_action: inner.main_action,
+                extra_action: extra_action.or(inner.extra_action),
+            });
+        }
+        Response::Table(_) => {
+            return None;
+        }
+    };
+
+    Some(H33h3Result {
+        main_action,
+        extra_action,
+    })
+}
+
+pub async fn handle_h33h3(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    nick: &str,
+    config: Arc<Yaml>,
+) {
+    let tables = Tables::from_config(&config);
     let result = {
         // Having the rng live past bot_sender.send seems to be a problem
         let mut rng = thread_rng();
-        nbotti_h33h3(&mut rng, nick)
+        match tables.tables.first() {
+            Some((start, _)) => respond(&mut rng, &tables, start, nick, 0),
+            None => None,
+        }
+    };
+    let result = match result {
+        Some(r) => r,
+        None => {
+            return;
+        }
     };
 
     if let Some(extra) = result.extra_action {
-        let target = IrcChannel {
+        let target = ChatTarget {
             network: source.network.to_owned(),
             channel: source.channel.to_owned(),
         };
@@ -39,59 +299,98 @@ pub async fn handle_h33h3(bot_sender: mpsc::Sender<BotAction>, source: IrcChanne
     let _ = bot_sender.send(action).await;
 }
 
-fn nbotti_h33h3<R: Rng + ?Sized>(rng: &mut R, nick: &str) -> H33h3Result {
-    match rng.gen_range(0..=100) {
-        23 | 55 => H33h3Result {
-            main_action: ActionType::Message(format!(
-                "GOOD DAY {}, YOU LOSE AT THE INTTER NETS",
-                nick
-            )),
-            extra_action: None,
-        },
-        28 => H33h3Result {
-            main_action: ActionType::Message("hngggg".to_owned()),
-            extra_action: None,
-        },
-        29 => H33h3Result {
-            main_action: ActionType::Message("h33h3".to_owned()),
-            extra_action: None,
-        },
-        30 => H33h3Result {
-            main_action: nbotti_kasipallo(rng),
-            extra_action: Some(ActionType::Message("<W> har har har".to_owned())),
-        },
-        31 => H33h3Result {
-            main_action: nbotti_kasipallo(rng),
-            extra_action: Some(ActionType::Message("<W> HAR VITUN HAR".to_owned())),
-        },
-        _ => H33h3Result {
-            main_action: nbotti_kasipallo(rng),
-            extra_action: None,
-        },
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn config_tables() {
+        let config = YamlLoader::load_from_str(
+            "h33h3:
+  triggers: ['lol', 'LOL!']
+  tables:
+    lol:
+      - message: 'hello {nick}'
+        weight: 0
+      - table: numbers
+        extra: 'rolling'
+    numbers:
+      - message: '{rand:0} of {rand:x}'
+      - foo: bar",
+        )
+        .unwrap();
+        let config = &config[0];
+
+        assert!(is_trigger(config, "lol!"));
+        assert!(!is_trigger(config, "h33h3"));
+
+        let tables = Tables::from_config(config);
+        assert_eq!(tables.tables.len(), 2);
+        assert_eq!(tables.get("numbers").unwrap().len(), 1);
+
+        let mut rng = thread_rng();
+        let result = respond(&mut rng, &tables, "lol", "nick", 0).unwrap();
+        assert_eq!(
+            result.main_action,
+            ActionType::Message("0 of {rand:x}".to_owned())
+        );
+        assert_eq!(
+            result.extra_action,
+            Some(ActionType::Message("rolling".to_owned()))
+        );
     }
-}
 
-fn nbotti_kasipallo<R: Rng + ?Sized>(rng: &mut R) -> ActionType {
-    match rng.gen_range(1..=20) {
-        1 => ActionType::Message(format!("{}", rng.gen_range(0..=4))),
-        2 => ActionType::Message(".____________.".to_owned()),
-        3 => ActionType::Message(format!("{}", rng.gen_range(0..=5))),
-        4 => ActionType::Message(format!("{}", rng.gen_range(0..=2))),
-        5 => ActionType::Message(format!("{}", rng.gen_range(0..=1))),
-        6 => ActionType::Message(format!("{}", rng.gen_range(0..=3))),
-        7 => ActionType::Action("am cry".to_owned()),
-        8 => ActionType::Message("fail".to_owned()),
-        9 | 10 => ActionType::Message("0".to_owned()),
-        11 => ActionType::Message(format!("{}", rng.gen_range(0..=1))),
-        12 => ActionType::Message(format!("{}", rng.gen_range(0..=2))),
-        13 => ActionType::Message(format!("{}", rng.gen_range(0..=3))),
-        14 => ActionType::Message(format!("{}", rng.gen_range(0..=4))),
-        15 => ActionType::Message(format!("{}", rng.gen_range(0..=1))),
-        16 => ActionType::Message("0".to_owned()),
-        17 => ActionType::Message("::|".to_owned()), // First ':' gets eaten by something
-        18 => ActionType::Message("h3-- not.".to_owned()),
-        19 => ActionType::Message("0".to_owned()),
-        20 => ActionType::Message(format!("{}", rng.gen_range(0..=5))),
-        _ => panic!("RNG gen_range returned something outside the given range"),
+    #[test]
+    fn default_tables_as_before() {
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert!(is_trigger(&config[0], "h33h3"));
+
+        let tables = Tables::from_config(&config[0]);
+        assert_eq!(tables, default_tables());
+
+        let weight = |name| -> u32 { tables.get(name).unwrap().iter().map(|e| e.weight).sum() };
+        assert_eq!(weight("h33h3"), 101);
+        assert_eq!(weight("kasipallo"), 20);
+
+        let mut rng = thread_rng();
+        assert_eq!(
+            render(&mut rng, "GOOD DAY {nick}, {rand:0}", "nick"),
+            "GOOD DAY nick, 0"
+        );
+    }
+
+    #[test]
+    fn channel_probability() {
+        let channel = |c: &str| ChatTarget {
+            network: "testnet".to_owned(),
+            channel: c.to_owned(),
+        };
+
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        assert_eq!(response_probability(&config[0], &channel("#test")), 1.0);
+
+        let config = YamlLoader::load_from_str(
+            "fun_triggers:
+  probability: 0.5
+  channels:
+    - network: testnet
+      channel: '#busy'
+      probability: 0.1
+    - network: testnet
+      channel: '#serious'
+      enabled: false
+    - network: testnet
+      channel: '#h33h3'
+      probability: 3",
+        )
+        .unwrap();
+        let config = &config[0];
+
+        assert_eq!(response_probability(config, &channel("#test")), 0.5);
+        assert_eq!(response_probability(config, &channel("#Busy")), 0.1);
+        assert_eq!(response_probability(config, &channel("#serious")), 0.0);
+        assert_eq!(response_probability(config, &channel("#h33h3")), 1.0);
+        assert!(!fun_trigger_roll(config, &channel("#serious")));
     }
 }
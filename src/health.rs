@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! State of the bot for /healthz: the IRC connections, the feed refresh and
+//! the databases. The bot is degraded when a network is disconnected, feeds
+//! have not been refreshed for a while or a database can't be queried.
+//! The joined channels are kept here too, for the web admin.
+
+use chrono::prelude::*;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::db;
+
+// Feeds are refreshed every 10 minutes, allow for a couple of slow rounds
+const RSS_MAX_AGE_MINUTES: i64 = 30;
+
+lazy_static! {
+    static ref STARTED: DateTime<Utc> = Utc::now();
+    static ref NETWORKS: Mutex<BTreeMap<String, bool>> = Mutex::new(BTreeMap::new());
+    static ref RSS_REFRESHED: Mutex<Option<DateTime<Utc>>> = Mutex::new(None);
+    static ref CHANNELS: Mutex<BTreeMap<String, Vec<String>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Starts the clock for the first feed refresh
+pub fn init() {
+    lazy_static::initialize(&STARTED);
+}
+
+pub fn set_connected(network: &str, connected: bool) {
+    NETWORKS
+        .lock()
+        .unwrap()
+        .insert(network.to_owned(), connected);
+}
+
+pub fn set_channels(network: &str, mut channels: Vec<String>) {
+    channels.sort();
+    CHANNELS
+        .lock()
+        .unwrap()
+        .insert(network.to_owned(), channels);
+}
+
+/// Joined channels of every configured network, connected or not
+pub fn channels() -> BTreeMap<String, Vec<String>> {
+    let mut channels = CHANNELS.lock().unwrap().clone();
+    for network in NETWORKS.lock().unwrap().keys() {
+        channels.entry(network.to_owned()).or_default();
+    }
+
+    channels
+}
+
+pub fn rss_refreshed() {
+    *RSS_REFRESHED.lock().unwrap() = Some(Utc::now());
+}
+
+/// Whether everything is fine, and the details as JSON
+fn report(
+    networks: &BTreeMap<String, bool>,
+    rss_refreshed: Option<DateTime<Utc>>,
+    started: DateTime<Utc>,
+    databases: &[(&str, Result<(), String>)],
+    now: DateTime<Utc>,
+) -> (bool, serde_json::Value) {
+    let networks_ok = networks.values().all(|c| *c);
+    // Before the first refresh the time since startup counts
+    let rss_ok =
+        now - rss_refreshed.unwrap_or(started) < chrono::Duration::minutes(RSS_MAX_AGE_MINUTES);
+    let databases_ok = databases.iter().all(|(_, r)| r.is_ok());
+    let healthy = networks_ok && rss_ok && databases_ok;
+
+    let network_status: serde_json::Map<String, serde_json::Value> = networks
+        .iter()
+        .map(|(n, c)| {
+            let status = if *c { "connected" } else { "disconnected" };
+            (n.to_owned(), json!(status))
+        })
+        .collect();
+    let database_status: serde_json::Map<String, serde_json::Value> = databases
+        .iter()
+        .map(|(name, r)| {
+            let status = match r {
+                Ok(()) => "ok".to_owned(),
+                Err(e) => e.to_owned(),
+            };
+            ((*name).to_owned(), json!(status))
+        })
+        .collect();
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "networks": network_status,
+        "rss": {
+            "ok": rss_ok,
+            "last_refresh": rss_refreshed.map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        },
+        "databases": database_status,
+    });
+
+    (healthy, body)
+}
+
+/// Checks the databases and reports the state of the bot
+pub async fn check() -> (bool, serde_json::Value) {
+    let databases: Vec<(&str, Result<(), String>)> = db::check_all()
+        .await
+        .into_iter()
+        .map(|(name, r)| (name, r.map_err(|e| e.to_string())))
+        .collect();
+    let networks = NETWORKS.lock().unwrap().clone();
+    let rss_refreshed = *RSS_REFRESHED.lock().unwrap();
+
+    report(&networks, rss_refreshed, *STARTED, &databases, Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degraded() {
+        let started = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let now = started + chrono::Duration::minutes(20);
+        let mut networks = BTreeMap::new();
+        networks.insert("IRCnet".to_owned(), true);
+        let databases = vec![("rss.db", Ok(()))];
+
+        let (healthy, body) = report(&networks, None, started, &databases, now);
+        assert!(healthy);
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["networks"]["IRCnet"], "connected");
+        assert_eq!(body["rss"]["last_refresh"], serde_json::Value::Null);
+
+        let later = now + chrono::Duration::minutes(20);
+        assert!(!report(&networks, None, started, &databases, later).0);
+        assert!(report(&networks, Some(now), started, &databases, later).0);
+
+        networks.insert("QuakeNet".to_owned(), false);
+        let (healthy, body) = report(&networks, Some(now), started, &databases, later);
+        assert!(!healthy);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["networks"]["QuakeNet"], "disconnected");
+
+        networks.insert("QuakeNet".to_owned(), true);
+        let databases = vec![("rss.db", Err("disk I/O error".to_owned()))];
+        let (healthy, body) = report(&networks, Some(now), started, &databases, later);
+        assert!(!healthy);
+        assert_eq!(body["databases"]["rss.db"], "disk I/O error");
+    }
+}
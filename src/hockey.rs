@@ -0,0 +1,538 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const LIIGA_URL: &str = "https://liiga.fi/api/v2/games";
+const NHL_URL: &str = "https://api-web.nhle.com/v1";
+const LIIGA_TOURNAMENTS: [&str; 2] = ["runkosarja", "playoffs"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum League {
+    Liiga,
+    Nhl,
+}
+
+impl League {
+    fn key(self) -> &'static str {
+        match self {
+            League::Liiga => "liiga",
+            League::Nhl => "nhl",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Team {
+    /// Shown in results, "Tappara" or "TOR"
+    short: String,
+    /// Also matched when filtering by team, "Maple Leafs"
+    name: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Game {
+    id: i64,
+    home: Team,
+    away: Team,
+    start: DateTime<Utc>,
+    /// Goals when the game has started
+    score: Option<(i64, i64)>,
+    ended: bool,
+    /// "ja" for overtime and "vl" for shootout
+    extra: Option<&'static str>,
+}
+
+#[derive(Debug, PartialEq)]
+struct PlayerPoints {
+    id: i64,
+    name: String,
+    goals: i64,
+    assists: i64,
+}
+
+lazy_static! {
+    /// Whether an NHL player is Finnish, by player id
+    static ref FINNISH_PLAYERS: Mutex<HashMap<i64, bool>> = Mutex::new(HashMap::new());
+}
+
+async fn get_json(url: &str, query: &[(&str, &str)]) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT.get(url).query(query);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+fn parse_json(json_text: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())
+}
+
+/// Liiga seasons are named after the year they end in
+fn liiga_season(date: NaiveDate) -> i32 {
+    if date.month() >= 7 {
+        date.year() + 1
+    } else {
+        date.year()
+    }
+}
+
+fn parse_liiga_games(json_text: &str) -> Result<Vec<Game>, String> {
+    let json = parse_json(json_text)?;
+    let team = |t: &serde_json::Value| {
+        let name = t["teamName"].as_str()?.to_owned();
+        Some(Team {
+            short: name.to_owned(),
+            name,
+        })
+    };
+
+    Ok(json
+        .as_array()
+        .map(|games| {
+            games
+                .iter()
+                .filter_map(|g| {
+                    let started = g["started"].as_bool().unwrap_or(false);
+                    let score = (
+                        g["homeTeam"]["goals"].as_i64(),
+                        g["awayTeam"]["goals"].as_i64(),
+                    );
+                    Some(Game {
+                        id: g["id"].as_i64()?,
+                        home: team(&g["homeTeam"])?,
+                        away: team(&g["awayTeam"])?,
+                        start: g["start"].as_str()?.parse().ok()?,
+                        score: match score {
+                            (Some(h), Some(a)) if started => Some((h, a)),
+                            _ => None,
+                        },
+                        ended: g["ended"].as_bool().unwrap_or(false),
+                        extra: match g["finishedType"].as_str() {
+                            Some("ENDED_DURING_EXTENDED_GAME_TIME") => Some("ja"),
+                            Some("ENDED_DURING_WINNING_SHOT_COMPETITION") => Some("vl"),
+                            _ => None,
+                        },
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn parse_nhl_games(json_text: &str) -> Result<Vec<Game>, String> {
+    let json = parse_json(json_text)?;
+    let team = |t: &serde_json::Value| {
+        Some(Team {
+            short: t["abbrev"].as_str()?.to_owned(),
+            name: t["name"]["default"].as_str().unwrap_or_default().to_owned(),
+        })
+    };
+
+    Ok(json["games"]
+        .as_array()
+        .map(|games| {
+            games
+                .iter()
+                .filter_map(|g| {
+                    let state = g["gameState"].as_str()?;
+                    let score = (
+                        g["homeTeam"]["score"].as_i64(),
+                        g["awayTeam"]["score"].as_i64(),
+                    );
+                    Some(Game {
+                        id: g["id"].as_i64()?,
+                        home: team(&g["homeTeam"])?,
+                        away: team(&g["awayTeam"])?,
+                        start: g["startTimeUTC"].as_str()?.parse().ok()?,
+                        score: match score {
+                            (Some(h), Some(a)) => Some((h, a)),
+                            _ => None,
+                        },
+                        ended: state == "FINAL" || state == "OFF",
+                        extra: match g["gameOutcome"]["lastPeriodType"].as_str() {
+                            Some("OT") => Some("ja"),
+                            Some("SO") => Some("vl"),
+                            _ => None,
+                        },
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Skaters with points in an NHL game
+fn parse_boxscore(json_text: &str) -> Result<Vec<PlayerPoints>, String> {
+    let json = parse_json(json_text)?;
+    let mut players = Vec::new();
+
+    for team in ["awayTeam", "homeTeam"] {
+        for position in ["forwards", "defense", "goalies"] {
+            for p in json["playerByGameStats"][team][position]
+                .as_array()
+                .into_iter()
+                .flatten()
+            {
+                let goals = p["goals"].as_i64().unwrap_or(0);
+                let assists = p["assists"].as_i64().unwrap_or(0);
+                if let (Some(id), Some(name)) =
+                    (p["playerId"].as_i64(), p["name"]["default"].as_str())
+                {
+                    if goals + assists > 0 {
+                        players.push(PlayerPoints {
+                            id,
+                            name: name.to_owned(),
+                            goals,
+                            assists,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(players)
+}
+
+fn is_finnish(json_text: &str) -> bool {
+    match parse_json(json_text) {
+        Ok(json) => json["birthCountry"].as_str() == Some("FIN"),
+        Err(_) => false,
+    }
+}
+
+fn plays_in(game: &Game, team: &str) -> bool {
+    let team = team.to_lowercase();
+
+    [&game.home, &game.away].iter().any(|t| {
+        t.short.to_lowercase() == team
+            || t.name.to_lowercase().contains(&team)
+            || t.short.to_lowercase().contains(&team)
+    })
+}
+
+fn game_text(game: &Game) -> String {
+    let teams = format!("{}–{}", game.home.short, game.away.short);
+
+    match game.score {
+        Some((home, away)) => {
+            let mut text = format!("{} {}–{}", teams, home, away);
+            if let (true, Some(extra)) = (game.ended, game.extra) {
+                text.push_str(&format!(" {}", extra));
+            }
+            if !game.ended {
+                text.push_str(" (kesken)");
+            }
+            text
+        }
+        None => format!(
+            "{} {}",
+            teams,
+            game.start.with_timezone(&Helsinki).format("%H:%M")
+        ),
+    }
+}
+
+/// "Eilen: Tappara–Ilves 3–2 ja | Tänään: Lukko–TPS 18:30"
+fn games_msg(
+    league: League,
+    yesterday: &[Game],
+    today: &[Game],
+    team: Option<&str>,
+    finns: &[PlayerPoints],
+) -> String {
+    let list = |games: &[Game]| -> Vec<String> {
+        games
+            .iter()
+            .filter(|g| team.is_none_or(|t| plays_in(g, t)))
+            .map(game_text)
+            .collect()
+    };
+
+    let mut parts = Vec::new();
+    let yesterday = list(yesterday);
+    if !yesterday.is_empty() {
+        parts.push(format!("Eilen: {}", yesterday.join(", ")));
+    }
+    let today = list(today);
+    if !today.is_empty() {
+        parts.push(format!("Tänään: {}", today.join(", ")));
+    }
+    if !finns.is_empty() {
+        let points: Vec<String> = finns
+            .iter()
+            .map(|p| format!("{} {}+{}", p.name, p.goals, p.assists))
+            .collect();
+        parts.push(format!("Suomalaiset: {}", points.join(", ")));
+    }
+
+    let name = match league {
+        League::Liiga => "Liiga",
+        League::Nhl => "NHL",
+    };
+    if parts.is_empty() {
+        match team {
+            Some(t) => format!("{}: ei pelejä eilen eikä tänään ({})", name, t),
+            None => format!("{}: ei pelejä eilen eikä tänään", name),
+        }
+    } else {
+        format!("{}: {}", name, parts.join(" | "))
+    }
+}
+
+/// The channel's team from `hockey: channels` in config.yml
+fn favorite_team(config: &Yaml, source: &ChatTarget, league: League) -> Option<String> {
+    config["hockey"]["channels"].as_vec()?.iter().find(|c| {
+        c["network"].as_str() == Some(&source.network)
+            && c["channel"]
+                .as_str()
+                .is_some_and(|ch| ch.eq_ignore_ascii_case(&source.channel))
+    })?[league.key()]
+    .as_str()
+    .map(|t| t.to_owned())
+}
+
+async fn liiga_games(today: NaiveDate) -> Result<(Vec<Game>, Vec<Game>), String> {
+    let season = liiga_season(today).to_string();
+    let mut games = Vec::new();
+
+    for tournament in LIIGA_TOURNAMENTS {
+        match get_json(
+            LIIGA_URL,
+            &[("tournament", tournament), ("season", &season)],
+        )
+        .await
+        {
+            Ok(json) => games.extend(parse_liiga_games(&json)?),
+            Err(_) => {
+                return Err("Liiga API error".to_owned());
+            }
+        }
+    }
+
+    games.sort_by_key(|g| g.start);
+    let on =
+        |date: NaiveDate| move |g: &Game| g.start.with_timezone(&Helsinki).date_naive() == date;
+    let yesterday_date = today.pred_opt().unwrap();
+    let (yesterday, rest): (Vec<Game>, Vec<Game>) = games.into_iter().partition(on(yesterday_date));
+    let today = rest.into_iter().filter(on(today)).collect();
+
+    Ok((yesterday, today))
+}
+
+/// Finnish players with points in the games
+async fn finnish_points(games: &[Game]) -> Vec<PlayerPoints> {
+    let mut finns = Vec::new();
+
+    for game in games.iter().filter(|g| g.ended) {
+        let url = format!("{}/gamecenter/{}/boxscore", NHL_URL, game.id);
+        let players = match get_json(&url, &[]).await.map(|j| parse_boxscore(&j)) {
+            Ok(Ok(p)) => p,
+            _ => continue,
+        };
+
+        for player in players {
+            let known = FINNISH_PLAYERS.lock().unwrap().get(&player.id).copied();
+            let finnish = match known {
+                Some(f) => f,
+                None => {
+                    let url = format!("{}/player/{}/landing", NHL_URL, player.id);
+                    match get_json(&url, &[]).await {
+                        Ok(json) => {
+                            let f = is_finnish(&json);
+                            FINNISH_PLAYERS.lock().unwrap().insert(player.id, f);
+                            f
+                        }
+                        Err(_) => false,
+                    }
+                }
+            };
+            if finnish {
+                finns.push(player);
+            }
+        }
+    }
+
+    finns
+}
+
+async fn nhl_games(today: NaiveDate) -> Result<(Vec<Game>, Vec<Game>), String> {
+    // Games of the American evening are played during the Finnish night, so
+    // yesterday's results are on yesterday's date
+    let mut days = Vec::new();
+    for date in [today.pred_opt().unwrap(), today] {
+        let url = format!("{}/score/{}", NHL_URL, date.format("%Y-%m-%d"));
+        match get_json(&url, &[]).await {
+            Ok(json) => days.push(parse_nhl_games(&json)?),
+            Err(_) => {
+                return Err("NHL API error".to_owned());
+            }
+        }
+    }
+
+    let today = days.pop().unwrap_or_default();
+    let yesterday = days.pop().unwrap_or_default();
+    Ok((yesterday, today))
+}
+
+async fn hockey_msg(league: League, team: Option<&str>) -> Result<String, String> {
+    let today = Utc::now().with_timezone(&Helsinki).date_naive();
+
+    match league {
+        League::Liiga => {
+            let (yesterday, today) = liiga_games(today).await?;
+            Ok(games_msg(league, &yesterday, &today, team, &[]))
+        }
+        League::Nhl => {
+            let (yesterday, today) = nhl_games(today).await?;
+            let followed: Vec<Game> = yesterday
+                .into_iter()
+                .filter(|g| team.is_none_or(|t| plays_in(g, t)))
+                .collect();
+            let finns = finnish_points(&followed).await;
+            Ok(games_msg(league, &followed, &today, team, &finns))
+        }
+    }
+}
+
+async fn command(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+    league: League,
+) {
+    let team = match params.trim() {
+        "" => favorite_team(&config, &source, league),
+        t => Some(t.to_owned()),
+    };
+
+    let msg = match hockey_msg(league, team.as_deref()).await {
+        Ok(m) => m,
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_liiga(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    command(bot_sender, source, params, config, League::Liiga).await;
+}
+
+pub async fn command_nhl(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    command(bot_sender, source, params, config, League::Nhl).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn liiga() {
+        assert_eq!(
+            liiga_season(NaiveDate::from_ymd_opt(2023, 9, 14).unwrap()),
+            2024
+        );
+        assert_eq!(
+            liiga_season(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap()),
+            2024
+        );
+
+        let json = r#"[
+            {"id":1,"season":2024,"start":"2023-09-14T15:30:00.000Z","homeTeam":{"teamId":"1:tappara","teamName":"Tappara","goals":3},"awayTeam":{"teamId":"2:ilves","teamName":"Ilves","goals":2},"finishedType":"ENDED_DURING_EXTENDED_GAME_TIME","started":true,"ended":true},
+            {"id":2,"season":2024,"start":"2023-09-14T15:30:00.000Z","homeTeam":{"teamId":"3:hifk","teamName":"HIFK","goals":1},"awayTeam":{"teamId":"4:karpat","teamName":"Kärpät","goals":4},"finishedType":"ENDED_DURING_REGULAR_GAME_TIME","started":true,"ended":true},
+            {"id":3,"season":2024,"start":"2023-09-15T15:30:00.000Z","homeTeam":{"teamId":"5:lukko","teamName":"Lukko","goals":0},"awayTeam":{"teamId":"1:tappara","teamName":"Tappara","goals":0},"started":false,"ended":false}
+        ]"#;
+        let games = parse_liiga_games(json).unwrap();
+        assert_eq!(games.len(), 3);
+
+        assert_eq!(
+            games_msg(League::Liiga, &games[..2], &games[2..], None, &[]),
+            "Liiga: Eilen: Tappara–Ilves 3–2 ja, HIFK–Kärpät 1–4 | Tänään: Lukko–Tappara 18:30"
+        );
+        assert_eq!(
+            games_msg(League::Liiga, &games[..2], &games[2..], Some("kärpät"), &[]),
+            "Liiga: Eilen: HIFK–Kärpät 1–4"
+        );
+        assert_eq!(
+            games_msg(League::Liiga, &games[..2], &[], Some("jokerit"), &[]),
+            "Liiga: ei pelejä eilen eikä tänään (jokerit)"
+        );
+    }
+
+    #[test]
+    fn nhl() {
+        let json = r#"{"prevDate":"2024-01-09","currentDate":"2024-01-10","games":[
+            {"id":2023020650,"gameState":"OFF","startTimeUTC":"2024-01-11T00:00:00Z","awayTeam":{"id":10,"name":{"default":"Maple Leafs"},"abbrev":"TOR","score":2},"homeTeam":{"id":13,"name":{"default":"Panthers"},"abbrev":"FLA","score":3},"gameOutcome":{"lastPeriodType":"OT"}},
+            {"id":2023020651,"gameState":"FUT","startTimeUTC":"2024-01-11T02:00:00Z","awayTeam":{"id":22,"name":{"default":"Oilers"},"abbrev":"EDM"},"homeTeam":{"id":21,"name":{"default":"Avalanche"},"abbrev":"COL"}}
+        ]}"#;
+        let games = parse_nhl_games(json).unwrap();
+        assert_eq!(games.len(), 2);
+        assert!(plays_in(&games[0], "panthers"));
+        assert!(plays_in(&games[0], "tor"));
+
+        let boxscore = r#"{"playerByGameStats":{"awayTeam":{"forwards":[{"playerId":1,"name":{"default":"A. Matthews"},"goals":1,"assists":0,"points":1},{"playerId":2,"name":{"default":"M. Marner"},"goals":0,"assists":0,"points":0}],"defense":[],"goalies":[{"playerId":3,"name":{"default":"J. Woll"}}]},
+            "homeTeam":{"forwards":[{"playerId":4,"name":{"default":"A. Barkov"},"goals":1,"assists":2,"points":3}],"defense":[],"goalies":[]}}}"#;
+        let players = parse_boxscore(boxscore).unwrap();
+        assert_eq!(players.len(), 2);
+        assert!(is_finnish(r#"{"playerId":4,"birthCountry":"FIN"}"#));
+        assert!(!is_finnish(r#"{"playerId":1,"birthCountry":"USA"}"#));
+
+        let finns: Vec<PlayerPoints> = players.into_iter().filter(|p| p.id == 4).collect();
+        assert_eq!(
+            games_msg(League::Nhl, &games[..1], &games[1..], None, &finns),
+            "NHL: Eilen: FLA–TOR 3–2 ja | Tänään: COL–EDM 04:00 | Suomalaiset: A. Barkov 1+2"
+        );
+    }
+
+    #[test]
+    fn favorite_teams() {
+        let config = YamlLoader::load_from_str(
+            "hockey:\n  channels:\n    - network: testnet\n      channel: '#Tappara'\n      liiga: Tappara\n      nhl: FLA",
+        )
+        .unwrap();
+        let channel = |c: &str| ChatTarget {
+            network: "testnet".to_owned(),
+            channel: c.to_owned(),
+        };
+
+        assert_eq!(
+            favorite_team(&config[0], &channel("#tappara"), League::Liiga),
+            Some("Tappara".to_owned())
+        );
+        assert_eq!(
+            favorite_team(&config[0], &channel("#tappara"), League::Nhl),
+            Some("FLA".to_owned())
+        );
+        assert_eq!(
+            favorite_team(&config[0], &channel("#muu"), League::Liiga),
+            None
+        );
+    }
+}
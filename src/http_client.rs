@@ -2,18 +2,557 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, CACHE_CONTROL};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Instant};
+use yaml_rust::Yaml;
+
+// Expired responses are kept this long for when a site is down
+const STALE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_CACHED_RESPONSES: usize = 256;
+// However large the configured delay or however many the retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Limits for requests made with `send`, from `http:` in config.yml
+#[derive(Clone, Debug, PartialEq)]
+struct Settings {
+    /// Extra attempts for GETs that fail with a network error, 429 or 5xx
+    retries: u32,
+    /// Before the first retry, doubled for each one after it
+    retry_delay: Duration,
+    /// Requests in flight to one host at a time
+    max_per_host: usize,
+    /// Between the starts of two requests to the same host
+    min_interval: Duration,
+    /// Hosts whose certificates are not checked, e.g. internal services. Requests
+    /// to them are sent with INSECURE_CLIENT by `send`.
+    accept_invalid_certs: Vec<String>,
+}
+
+/// Proxy and TLS options from `http:` in config.yml, used when building the clients
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ClientOptions {
+    /// http://, https://, socks5:// or socks5h:// URL, system proxy settings otherwise
+    proxy: Option<String>,
+    /// PEM files of CA certificates trusted in addition to the system ones
+    ca_certificates: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            retries: 2,
+            retry_delay: Duration::from_millis(500),
+            max_per_host: 4,
+            min_interval: Duration::from_millis(100),
+            accept_invalid_certs: Vec::new(),
+        }
+    }
+}
 
 lazy_static! {
-    pub static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+    // The options have been checked in init, so building can't fail. Requests
+    // are built with HTTP_CLIENT but sent with `send`, which applies the limits
+    // and accept_invalid_certs.
+    pub static ref HTTP_CLIENT: reqwest::Client =
+        build_client(&CLIENT_OPTIONS.read().unwrap(), false).unwrap();
+    static ref INSECURE_CLIENT: reqwest::Client =
+        build_client(&CLIENT_OPTIONS.read().unwrap(), true).unwrap();
+    static ref CLIENT_OPTIONS: RwLock<ClientOptions> = RwLock::new(ClientOptions::default());
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+    static ref HOST_SLOTS: Mutex<HashMap<String, Arc<Semaphore>>> = Mutex::new(HashMap::new());
+    static ref HOST_NEXT_START: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    static ref RESPONSE_CACHE: Mutex<HashMap<String, CachedResponse>> = Mutex::new(HashMap::new());
+}
+
+struct CachedResponse {
+    body: String,
+    fetched: Instant,
+    expires: Instant,
+}
+
+fn settings_from_config(config: &Yaml) -> Settings {
+    let default = Settings::default();
+    let millis = |key: &str, default: Duration| {
+        config["http"][key]
+            .as_i64()
+            .filter(|ms| *ms >= 0)
+            .map_or(default, |ms| Duration::from_millis(ms as u64))
+    };
+
+    Settings {
+        retries: config["http"]["retries"]
+            .as_i64()
+            .filter(|r| *r >= 0)
+            .map_or(default.retries, |r| r as u32),
+        retry_delay: millis("retry_delay_ms", default.retry_delay),
+        max_per_host: config["http"]["max_per_host"]
+            .as_i64()
+            .filter(|m| *m > 0)
+            .map_or(default.max_per_host, |m| m as usize),
+        min_interval: millis("min_interval_ms", default.min_interval),
+        accept_invalid_certs: strings(&config["http"]["accept_invalid_certs"]),
+    }
+}
+
+fn strings(list: &Yaml) -> Vec<String> {
+    list.as_vec()
+        .map(|v| {
+            v.iter()
+                .filter_map(|s| s.as_str())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn client_options_from_config(config: &Yaml) -> ClientOptions {
+    ClientOptions {
+        proxy: config["http"]["proxy"].as_str().map(str::to_owned),
+        ca_certificates: strings(&config["http"]["ca_certificates"]),
+    }
+}
+
+fn configure_client(options: &ClientOptions) -> Result<reqwest::ClientBuilder, String> {
+    let mut builder = reqwest::Client::builder()
         .user_agent(format!("T-botti/{}", env!("CARGO_PKG_VERSION")))
-        .timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(10));
+
+    if let Some(proxy) = &options.proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+
+    for path in &options.ca_certificates {
+        let pem = fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| format!("Invalid certificate in {}: {}", path, e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    Ok(builder)
+}
+
+fn build_client(
+    options: &ClientOptions,
+    accept_invalid_certs: bool,
+) -> Result<reqwest::Client, String> {
+    configure_client(options)?
+        .danger_accept_invalid_certs(accept_invalid_certs)
         .build()
-        .unwrap();
+        .map_err(|e| e.to_string())
+}
+
+/// Builder with the same user agent, timeout, proxy and certificates as
+/// HTTP_CLIENT, for modules that need a client of their own
+pub fn client_builder() -> reqwest::ClientBuilder {
+    configure_client(&CLIENT_OPTIONS.read().unwrap()).unwrap()
+}
+
+/// Host of the configured proxy, which may well be on the local network
+pub fn proxy_host() -> Option<String> {
+    let proxy = CLIENT_OPTIONS.read().unwrap().proxy.clone()?;
+    Url::parse(&proxy).ok()?.host_str().map(str::to_owned)
+}
+
+/// Reads the `http:` settings. Must be called before HTTP_CLIENT is used.
+pub fn init(config: &Yaml) -> Result<(), String> {
+    let options = client_options_from_config(config);
+    build_client(&options, false)?;
+
+    *CLIENT_OPTIONS.write().unwrap() = options;
+    *SETTINGS.write().unwrap() = settings_from_config(config);
+
+    Ok(())
+}
+
+fn host_slots(host: &str, max_per_host: usize) -> Arc<Semaphore> {
+    HOST_SLOTS
+        .lock()
+        .unwrap()
+        .entry(host.to_owned())
+        .or_insert_with(|| Arc::new(Semaphore::new(max_per_host)))
+        .clone()
+}
+
+/// How long to wait before starting a request to `host`, reserving the start time
+fn reserve_start(
+    next_start: &mut HashMap<String, Instant>,
+    host: &str,
+    now: Instant,
+    min_interval: Duration,
+) -> Duration {
+    let start = next_start.get(host).map_or(now, |next| (*next).max(now));
+    next_start.insert(host.to_owned(), start + min_interval);
+
+    start - now
+}
+
+fn should_retry(result: &reqwest::Result<Response>) -> bool {
+    match result {
+        Ok(r) => r.status().is_server_error() || r.status() == StatusCode::TOO_MANY_REQUESTS,
+        Err(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+    }
+}
+
+fn accepts_invalid_certs(settings: &Settings, host: &str) -> bool {
+    settings
+        .accept_invalid_certs
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(host))
+}
+
+fn retry_delay(first: Duration, retry: u32) -> Duration {
+    first
+        .checked_mul(2u32.saturating_pow(retry))
+        .map_or(MAX_RETRY_DELAY, |d| d.min(MAX_RETRY_DELAY))
+}
+
+/// Why a request is retried, without the URL of the error
+fn failure(result: &reqwest::Result<Response>) -> String {
+    match result {
+        Ok(r) => r.status().to_string(),
+        Err(e) if e.is_timeout() => "timeout".to_owned(),
+        Err(e) if e.is_connect() => "connection error".to_owned(),
+        Err(_) => "request error".to_owned(),
+    }
+}
+
+/// Sends the request within the per-host concurrency and rate limits. GETs
+/// are retried with backoff when the host is unreachable or answers 429 or 5xx;
+/// the last response is returned as is.
+pub async fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    let settings = SETTINGS.read().unwrap().clone();
+    let built = match request.try_clone().map(|r| r.build()) {
+        Some(Ok(r)) => r,
+        // Let reqwest report the invalid request
+        _ => {
+            return request.send().await;
+        }
+    };
+    let host = built.url().host_str().unwrap_or_default().to_owned();
+    let request = match built.try_clone() {
+        Some(r) if accepts_invalid_certs(&settings, &host) => {
+            RequestBuilder::from_parts(INSECURE_CLIENT.clone(), r)
+        }
+        _ => request,
+    };
+    let retries = if built.method() == Method::GET {
+        settings.retries
+    } else {
+        0
+    };
+
+    let slots = host_slots(&host, settings.max_per_host);
+    let _permit = slots.acquire().await.unwrap();
+
+    let mut attempt = 0;
+    loop {
+        let wait = reserve_start(
+            &mut HOST_NEXT_START.lock().unwrap(),
+            &host,
+            Instant::now(),
+            settings.min_interval,
+        );
+        sleep(wait).await;
+
+        // The original request is kept for the last attempt
+        let result = match request.try_clone().filter(|_| attempt < retries) {
+            Some(r) => r.send().await,
+            None => {
+                return request.send().await;
+            }
+        };
+        if !should_retry(&result) {
+            return result;
+        }
+
+        // Only the host is logged, paths and queries can hold API keys and bot tokens
+        debug!("Retrying {} after {}", host, failure(&result));
+        sleep(retry_delay(settings.retry_delay, attempt)).await;
+        attempt += 1;
+    }
 }
 
 pub async fn get_url(url: &str) -> reqwest::Result<String> {
-    let contents = HTTP_CLIENT.get(url).send().await?.text().await?;
+    let contents = send(HTTP_CLIENT.get(url)).await?.text().await?;
 
     Ok(contents)
 }
+
+/// How long a response can be used, `default` unless Cache-Control says
+/// otherwise. None if it must not be stored at all.
+fn cache_ttl(headers: &HeaderMap, default: Duration) -> Option<Duration> {
+    let cache_control = match headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) {
+        Some(c) => c.to_lowercase(),
+        None => {
+            return Some(default);
+        }
+    };
+
+    let mut ttl = default;
+    for directive in cache_control.split(',').map(str::trim) {
+        if directive == "no-store" {
+            return None;
+        } else if directive == "no-cache" {
+            // Still kept for when the site is down
+            return Some(Duration::ZERO);
+        } else if let Some(max_age) = directive.strip_prefix("max-age=") {
+            if let Ok(secs) = max_age.trim_matches('"').parse() {
+                ttl = Duration::from_secs(secs).min(STALE_MAX_AGE);
+            }
+        }
+    }
+
+    Some(ttl)
+}
+
+/// The cached body and whether it is still fresh
+fn cache_get(
+    cache: &Mutex<HashMap<String, CachedResponse>>,
+    url: &str,
+    now: Instant,
+) -> Option<(String, bool)> {
+    cache
+        .lock()
+        .unwrap()
+        .get(url)
+        .filter(|c| now - c.fetched < STALE_MAX_AGE)
+        .map(|c| (c.body.clone(), now < c.expires))
+}
+
+fn cache_put(
+    cache: &Mutex<HashMap<String, CachedResponse>>,
+    url: &str,
+    body: String,
+    ttl: Duration,
+    now: Instant,
+) {
+    let mut cache = cache.lock().unwrap();
+    cache.retain(|_, c| now - c.fetched < STALE_MAX_AGE);
+    if cache.len() >= MAX_CACHED_RESPONSES && !cache.contains_key(url) {
+        if let Some(oldest) = cache
+            .iter()
+            .min_by_key(|(_, c)| c.fetched)
+            .map(|(k, _)| k.clone())
+        {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(
+        url.to_owned(),
+        CachedResponse {
+            body,
+            fetched: now,
+            expires: now + ttl,
+        },
+    );
+}
+
+/// Key of a value derived from the response to `url`. URLs have no spaces,
+/// so these never collide with the bodies stored by `get_cached`.
+fn derived_key(kind: &str, url: &str) -> String {
+    format!("{} {}", kind, url)
+}
+
+/// A fresh value derived from the response to `url`, like the title of a page
+pub fn cached_value(kind: &str, url: &str) -> Option<String> {
+    match cache_get(&RESPONSE_CACHE, &derived_key(kind, url), Instant::now()) {
+        Some((value, true)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Stores a value derived from a response fetched outside of `get_cached`,
+/// for `default_ttl` unless the headers of the response say otherwise
+pub fn cache_value(kind: &str, url: &str, headers: &HeaderMap, value: &str, default_ttl: Duration) {
+    if let Some(ttl) = cache_ttl(headers, default_ttl) {
+        let key = derived_key(kind, url);
+        cache_put(&RESPONSE_CACHE, &key, value.to_owned(), ttl, Instant::now());
+    }
+}
+
+/// Like `get_url`, but the body is reused by identical requests for
+/// `default_ttl` or what Cache-Control allows. An expired body is still
+/// returned if the request fails.
+pub async fn get_cached(request: RequestBuilder, default_ttl: Duration) -> reqwest::Result<String> {
+    let (url, host_path) = match request.try_clone().map(|r| r.build()) {
+        // The query can have API keys in it, so only the host and path are logged
+        Some(Ok(r)) => (
+            r.url().to_string(),
+            format!(
+                "{}{}",
+                r.url().host_str().unwrap_or_default(),
+                r.url().path()
+            ),
+        ),
+        _ => {
+            return send(request).await?.text().await;
+        }
+    };
+
+    let now = Instant::now();
+    let cached = cache_get(&RESPONSE_CACHE, &url, now);
+    if let Some((body, true)) = cached {
+        return Ok(body);
+    }
+
+    let fetched = match send(request).await {
+        Ok(r) if r.status().is_success() => {
+            let ttl = cache_ttl(r.headers(), default_ttl);
+            r.text().await.map(|body| (body, ttl))
+        }
+        // Error pages are not cached, but returned as before if there is nothing better
+        Ok(r) => match r.error_for_status_ref() {
+            Err(e) if cached.is_some() => Err(e),
+            _ => {
+                return r.text().await;
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    match fetched {
+        Ok((body, ttl)) => {
+            if let Some(ttl) = ttl {
+                cache_put(&RESPONSE_CACHE, &url, body.clone(), ttl, now);
+            }
+            Ok(body)
+        }
+        Err(e) => match cached {
+            Some((body, _)) => {
+                warn!(
+                    "Request to {} failed, using cached response: {}",
+                    host_path,
+                    e.without_url()
+                );
+                Ok(body)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn limits() {
+        let config =
+            YamlLoader::load_from_str("http:\n  retries: 0\n  min_interval_ms: 250").unwrap();
+        assert_eq!(
+            settings_from_config(&config[0]),
+            Settings {
+                retries: 0,
+                min_interval: Duration::from_millis(250),
+                ..Settings::default()
+            }
+        );
+
+        let mut next_start = HashMap::new();
+        let now = Instant::now();
+        let interval = Duration::from_millis(100);
+        assert_eq!(
+            reserve_start(&mut next_start, "a", now, interval),
+            Duration::ZERO
+        );
+        assert_eq!(reserve_start(&mut next_start, "a", now, interval), interval);
+        assert_eq!(
+            reserve_start(&mut next_start, "a", now, interval),
+            interval * 2
+        );
+        assert_eq!(
+            reserve_start(&mut next_start, "b", now, interval),
+            Duration::ZERO
+        );
+        assert_eq!(
+            reserve_start(&mut next_start, "a", now + interval * 5, interval),
+            Duration::ZERO
+        );
+
+        assert_eq!(
+            retry_delay(Duration::from_millis(500), 2),
+            Duration::from_secs(2)
+        );
+        assert_eq!(retry_delay(Duration::from_millis(500), 40), MAX_RETRY_DELAY);
+        assert_eq!(
+            retry_delay(Duration::from_millis(u64::MAX), 1),
+            MAX_RETRY_DELAY
+        );
+    }
+
+    #[test]
+    fn client_options() {
+        let config = YamlLoader::load_from_str(
+            "http:\n  proxy: 'socks5h://127.0.0.1:1080'\n  accept_invalid_certs:\n    - 'intranet.example.com'",
+        )
+        .unwrap();
+        let options = client_options_from_config(&config[0]);
+        assert_eq!(options.proxy, Some("socks5h://127.0.0.1:1080".to_owned()));
+        assert!(build_client(&options, false).is_ok());
+        let settings = settings_from_config(&config[0]);
+        assert_eq!(
+            settings.accept_invalid_certs,
+            vec!["intranet.example.com".to_owned()]
+        );
+        assert!(accepts_invalid_certs(&settings, "Intranet.example.com"));
+        assert!(!accepts_invalid_certs(&settings, "example.com"));
+
+        let missing_ca = ClientOptions {
+            proxy: None,
+            ca_certificates: vec!["/nonexistent/ca.pem".to_owned()],
+        };
+        assert!(build_client(&missing_ca, false)
+            .unwrap_err()
+            .starts_with("Could not read /nonexistent/ca.pem"));
+    }
+
+    #[test]
+    fn response_cache() {
+        let hour = Duration::from_secs(60 * 60);
+        let mut headers = HeaderMap::new();
+        assert_eq!(cache_ttl(&headers, hour), Some(hour));
+        headers.insert(CACHE_CONTROL, "public, max-age=60".parse().unwrap());
+        assert_eq!(cache_ttl(&headers, hour), Some(Duration::from_secs(60)));
+        headers.insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+        assert_eq!(cache_ttl(&headers, hour), Some(Duration::ZERO));
+        headers.insert(CACHE_CONTROL, "private, No-Store".parse().unwrap());
+        assert_eq!(cache_ttl(&headers, hour), None);
+
+        let cache = Mutex::new(HashMap::new());
+        let now = Instant::now();
+        let url = "https://api.tvmaze.com/singlesearch/shows?q=the+office";
+
+        assert_eq!(cache_get(&cache, url, now), None);
+        cache_put(&cache, url, "{}".to_owned(), 3 * hour, now);
+        assert_eq!(
+            cache_get(&cache, url, now + hour),
+            Some(("{}".to_owned(), true))
+        );
+        assert_eq!(
+            cache_get(&cache, url, now + 4 * hour),
+            Some(("{}".to_owned(), false))
+        );
+        assert_eq!(cache_get(&cache, url, now + 25 * hour), None);
+
+        cache_put(&cache, "lost", "{}".to_owned(), hour, now + 25 * hour);
+        assert_eq!(cache.lock().unwrap().len(), 1);
+
+        let page = "https://example.com/title-cache";
+        cache_value("title", page, &HeaderMap::new(), "Title: Example", hour);
+        assert_eq!(
+            cached_value("title", page),
+            Some("Title: Example".to_owned())
+        );
+        assert_eq!(cache_get(&RESPONSE_CACHE, page, Instant::now()), None);
+    }
+}
@@ -0,0 +1,206 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use log::{error, info};
+
+use tokio::sync::mpsc;
+
+use yaml_rust::yaml::Yaml;
+
+use crate::botaction::BotAction;
+use crate::health;
+use crate::rss::{websub_authentic, websub_notification, websub_verify};
+use crate::web_admin;
+use crate::webhooks;
+use crate::ClientQuery;
+
+// Pushed feeds are small, anything larger isn't read
+const MAX_WEBSUB_BODY: usize = 1024 * 1024;
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Reads the body of a request, refusing ones larger than `max_len` bytes
+/// before reading more than that
+pub async fn read_body(
+    req: Request<Body>,
+    max_len: usize,
+) -> Result<(Request<Body>, Vec<u8>), StatusCode> {
+    let content_length = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok());
+    if content_length.is_some_and(|l| l > max_len as u64) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if bytes.len() + chunk.len() > max_len {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok((Request::from_parts(parts, Body::empty()), bytes))
+}
+
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    match req.uri().query() {
+        Some(q) => url::form_urlencoded::parse(q.as_bytes())
+            .into_owned()
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+async fn handle_websub(
+    req: Request<Body>,
+    feed_id: i64,
+    sender: mpsc::Sender<BotAction>,
+) -> Response<Body> {
+    match *req.method() {
+        Method::GET => {
+            let params = query_params(&req);
+            let mode = params.get("hub.mode").map(|m| m.as_str()).unwrap_or("");
+            let topic = params.get("hub.topic").map(|t| t.as_str()).unwrap_or("");
+            let lease = params
+                .get("hub.lease_seconds")
+                .and_then(|l| l.parse::<i64>().ok());
+
+            match params.get("hub.challenge") {
+                Some(challenge) if websub_verify(feed_id, mode, topic, lease).await => {
+                    response(StatusCode::OK, challenge.to_owned())
+                }
+                _ => response(StatusCode::NOT_FOUND, "".to_owned()),
+            }
+        }
+        Method::POST => {
+            let (req, body) = match read_body(req, MAX_WEBSUB_BODY).await {
+                Ok(r) => r,
+                Err(status) => {
+                    return response(status, "".to_owned());
+                }
+            };
+            let signature = req
+                .headers()
+                .get("x-hub-signature")
+                .and_then(|s| s.to_str().ok());
+            if !websub_authentic(feed_id, signature, &body).await {
+                return response(StatusCode::FORBIDDEN, "".to_owned());
+            }
+            let body = String::from_utf8_lossy(&body).into_owned();
+            tokio::spawn(async move {
+                websub_notification(sender, feed_id, &body).await;
+            });
+            response(StatusCode::OK, "".to_owned())
+        }
+        _ => response(StatusCode::METHOD_NOT_ALLOWED, "".to_owned()),
+    }
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    sender: mpsc::Sender<BotAction>,
+    clientquery_sender: mpsc::Sender<ClientQuery>,
+    config: Arc<Yaml>,
+) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path().to_owned();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let resp = match segments.as_slice() {
+        ["healthz"] if req.method() == Method::GET => {
+            let (healthy, body) = health::check().await;
+            let status = if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            let mut resp = response(status, body.to_string());
+            resp.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            resp
+        }
+        ["admin", ..] => {
+            let action = path.trim_matches('/').trim_start_matches("admin");
+            let action = action.trim_start_matches('/').to_owned();
+            web_admin::handle(req, &action, sender, clientquery_sender, &config).await
+        }
+        ["webhook", name] if req.method() == Method::POST => {
+            let name = name.to_string();
+            webhooks::handle(req, &name, sender, &config).await
+        }
+        ["websub", id] => match id.parse::<i64>() {
+            Ok(feed_id) => handle_websub(req, feed_id, sender).await,
+            Err(_) => response(StatusCode::NOT_FOUND, "".to_owned()),
+        },
+        _ => response(StatusCode::NOT_FOUND, "".to_owned()),
+    };
+
+    Ok(resp)
+}
+
+pub async fn http_server(
+    sender: mpsc::Sender<BotAction>,
+    clientquery_sender: mpsc::Sender<ClientQuery>,
+    config: Arc<Yaml>,
+) {
+    let listen = match config["http_server"]["listen"].as_str() {
+        Some(l) => l,
+        None => {
+            info!("http_server.listen not configured, not starting HTTP server");
+            return;
+        }
+    };
+
+    let addr: SocketAddr = match listen.parse() {
+        Ok(a) => a,
+        Err(_) => {
+            error!("Invalid http_server.listen address: {}", listen);
+            return;
+        }
+    };
+
+    if web_admin::credentials(&config).is_some() {
+        info!("Web admin enabled at /admin");
+    }
+
+    let make_svc = make_service_fn(move |_conn| {
+        let sender = sender.clone();
+        let clientquery_sender = clientquery_sender.clone();
+        let config = config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(
+                    req,
+                    sender.clone(),
+                    clientquery_sender.clone(),
+                    config.clone(),
+                )
+            }))
+        }
+    });
+
+    info!("HTTP server listening on {}", addr);
+    if let Err(e) = Server::bind(&addr).serve(make_svc).await {
+        error!("HTTP server error: {}", e);
+    }
+}
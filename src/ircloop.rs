@@ -10,7 +10,13 @@ use std::sync::Arc;
 use tokio::sync::mpsc;
 use yaml_rust::yaml::Yaml;
 
+use crate::admins;
 use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::discord;
+use crate::health;
+use crate::matrix;
+use crate::telegram;
 use crate::ClientQuery;
 
 fn edit_msg_for_output(mut s: String, max_len: usize) -> String {
@@ -32,6 +38,44 @@ fn edit_msg_for_output(mut s: String, max_len: usize) -> String {
     out
 }
 
+/// IRC style mask matching, `*` matches any run of characters and `?` any one
+fn mask_matches(pattern: &str, mask: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mask: Vec<char> = mask.chars().collect();
+    let (mut p, mut m) = (0, 0);
+    // Position after the last `*` and the mask position it was tried at
+    let mut backtrack = None;
+
+    while m < mask.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == mask[m]) {
+            p += 1;
+            m += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, m));
+        } else if let Some((star_p, star_m)) = backtrack {
+            p = star_p;
+            m = star_m + 1;
+            backtrack = Some((star_p, star_m + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Admins added from the web admin, on top of the ones in the config
+async fn load_added_admins() -> HashMap<String, Vec<String>> {
+    match db::call(&admins::DB, admins::get_all).await {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Could not read admins.db: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
 pub async fn irc_loop(
     input_channel: mpsc::Sender<(String, Message)>,
     mut output_channel: mpsc::Receiver<BotAction>,
@@ -40,13 +84,17 @@ pub async fn irc_loop(
 ) {
     let (common_ircdata_tx, mut common_ircdata_rx) = mpsc::channel(100);
 
-    let networks = match config["networks"].as_vec() {
-        Some(n) => n,
-        None => {
-            error!("No networks found in configuration!");
-            return;
-        }
-    };
+    // A bot without IRC networks still answers the admin queries here
+    let no_networks = vec![];
+    let networks = config["networks"].as_vec().unwrap_or(&no_networks);
+    if networks.is_empty()
+        && config["matrix"].as_vec().is_none()
+        && config["discord"].as_vec().is_none()
+        && config["telegram"].as_vec().is_none()
+    {
+        error!("No networks found in configuration!");
+        return;
+    }
 
     let mut admins: HashMap<String, Vec<String>> = HashMap::new();
 
@@ -112,6 +160,12 @@ pub async fn irc_loop(
         configs.insert(network_name, config);
     }
 
+    admins.extend(matrix::admins(&config));
+    admins.extend(discord::admins(&config));
+    admins.extend(telegram::admins(&config));
+
+    let mut added_admins = load_added_admins().await;
+
     let mut network_mpsc_senders: HashMap<String, mpsc::Sender<BotAction>> = HashMap::new();
 
     for (network, conf) in configs {
@@ -119,17 +173,41 @@ pub async fn irc_loop(
         let (network_input_tx, mut network_input_rx) = mpsc::channel(10);
         network_mpsc_senders.insert(network.to_owned(), network_input_tx);
 
+        health::set_connected(&network, false);
         tokio::spawn(async move {
             let mut client = Client::from_config(conf).await.unwrap();
             client.identify().unwrap();
             let mut stream = client.stream().unwrap();
+            let mut stream_open = true;
 
             loop {
                 tokio::select! {
-                    Some(message) = stream.next() => {
-                        if let Ok(m) = message {
-                            debug!("Received message: {}", m);
-                            network_sender.send((network.to_owned(), m)).await.unwrap();
+                    message = stream.next(), if stream_open => {
+                        match message {
+                            Some(Ok(m)) => {
+                                debug!("Received message: {}", m);
+                                match m.command {
+                                    Command::Response(Response::RPL_WELCOME, _) => {
+                                        health::set_connected(&network, true);
+                                    }
+                                    Command::JOIN(..) | Command::PART(..) | Command::KICK(..) => {
+                                        let channels = client.list_channels().unwrap_or_default();
+                                        health::set_channels(&network, channels);
+                                    }
+                                    _ => {}
+                                }
+                                network_sender.send((network.to_owned(), m)).await.unwrap();
+                            }
+                            Some(Err(e)) => {
+                                error!("Connection to {} failed: {}", network, e);
+                                health::set_connected(&network, false);
+                            }
+                            None => {
+                                error!("Connection to {} closed", network);
+                                health::set_connected(&network, false);
+                                health::set_channels(&network, Vec::new());
+                                stream_open = false;
+                            }
                         }
                     }
                     Some(action) = network_input_rx.recv() => {
@@ -143,6 +221,11 @@ pub async fn irc_loop(
                                 debug!("sending ACTION {}", msg);
                                 client.send_action(action.target.channel, msg).unwrap();
                             }
+                            ActionType::Notice(msg) => {
+                                let out = edit_msg_for_output(msg, 450);
+                                debug!("sending NOTICE {}", out);
+                                client.send_notice(action.target.channel, out).unwrap();
+                            }
                         }
                     }
                 }
@@ -164,20 +247,47 @@ pub async fn irc_loop(
                 match query {
                     ClientQuery::IsAdmin(response_channel, network, mask) => {
                         debug!("Querying if {} is owner on {}", mask, network);
-                        let mut is_owner = false;
-                        if let Some(network_admins) = admins.get(&network) {
-                            for a in network_admins {
-                                if a == &mask {
-                                    is_owner = true;
-                                    break;
-                                }
-                            }
-                        }
+                        let is_owner = [&admins, &added_admins]
+                            .iter()
+                            .filter_map(|a| a.get(&network))
+                            .flatten()
+                            .any(|admin| mask_matches(admin, &mask));
                         debug!("is owner? {}", is_owner);
                         response_channel.send(is_owner).unwrap();
                     }
+                    ClientQuery::ReloadAdmins => {
+                        added_admins = load_added_admins().await;
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert!(mask_matches(
+            "owner!owner@example.com",
+            "owner!owner@example.com"
+        ));
+        assert!(!mask_matches(
+            "owner!owner@example.com",
+            "owner!owner@example.org"
+        ));
+        assert!(mask_matches("*!123@discord", "nelly!123@discord"));
+        assert!(!mask_matches("*!123@discord", "nelly!1234@discord"));
+        assert!(mask_matches(
+            "owner!*@*.example.com",
+            "owner!~o@host.example.com"
+        ));
+        assert!(mask_matches("own?r!*", "owner!owner@example.com"));
+        assert!(!mask_matches(
+            "owner!*@*.example.com",
+            "owner!o@example.com"
+        ));
+    }
+}
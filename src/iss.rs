@@ -0,0 +1,370 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use irc::client::prelude::Prefix;
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::blitzortung::lookup_place;
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+const POSITION_URL: &str = "http://api.open-notify.org/iss-now.json";
+const ASTROS_URL: &str = "http://api.open-notify.org/astros.json";
+const TLE_URL: &str = "https://celestrak.org/NORAD/elements/gp.php?CATNR=25544&FORMAT=TLE";
+const TLE_CACHE_HOURS: i64 = 12;
+
+// Passes are searched for a day ahead, counting from when the ISS rises
+// above MIN_ELEVATION degrees
+const PASS_SEARCH_HOURS: i64 = 24;
+const PASS_STEP_SECS: i64 = 30;
+const MIN_ELEVATION: f64 = 10.0;
+
+const EARTH_RADIUS_KM: f64 = 6378.137;
+const MU: f64 = 398600.4418;
+const J2: f64 = 1.08263e-3;
+
+/// Mean orbital elements from a two-line element set, angles in radians
+#[derive(Clone, Debug, PartialEq)]
+struct Elements {
+    epoch: DateTime<Utc>,
+    inclination: f64,
+    raan: f64,
+    eccentricity: f64,
+    arg_perigee: f64,
+    mean_anomaly: f64,
+    /// Radians per second
+    mean_motion: f64,
+}
+
+#[derive(Debug, PartialEq)]
+struct Pass {
+    start: DateTime<Utc>,
+    max_elevation: f64,
+}
+
+lazy_static! {
+    static ref TLE_CACHE: Mutex<Option<(Elements, DateTime<Utc>)>> = Mutex::new(None);
+}
+
+async fn get_text(url: &str) -> reqwest::Result<String> {
+    http_client::send(HTTP_CLIENT.get(url)).await?.text().await
+}
+
+fn parse_json(json_text: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())
+}
+
+/// (latitude, longitude) of the ISS right now
+fn parse_position(json_text: &str) -> Result<(f64, f64), String> {
+    let json = parse_json(json_text)?;
+    let coordinate =
+        |name: &str| -> Option<f64> { json["iss_position"][name].as_str()?.parse().ok() };
+
+    match (coordinate("latitude"), coordinate("longitude")) {
+        (Some(lat), Some(lon)) => Ok((lat, lon)),
+        _ => Err("ISS position not found".to_owned()),
+    }
+}
+
+/// People in space grouped by craft
+fn parse_astronauts(json_text: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+    let json = parse_json(json_text)?;
+    let mut crafts: Vec<(String, Vec<String>)> = Vec::new();
+
+    for person in json["people"].as_array().into_iter().flatten() {
+        let (craft, name) = match (person["craft"].as_str(), person["name"].as_str()) {
+            (Some(c), Some(n)) => (c, n),
+            _ => continue,
+        };
+        match crafts.iter_mut().find(|(c, _)| c == craft) {
+            Some((_, names)) => names.push(name.to_owned()),
+            None => crafts.push((craft.to_owned(), vec![name.to_owned()])),
+        }
+    }
+
+    Ok(crafts)
+}
+
+fn parse_tle(tle: &str) -> Option<Elements> {
+    let line1 = tle.lines().find(|l| l.starts_with("1 "))?;
+    let line2 = tle.lines().find(|l| l.starts_with("2 "))?;
+    let field = |line: &str, start: usize, end: usize| -> Option<f64> {
+        line.get(start - 1..end)?.trim().parse().ok()
+    };
+
+    let year = field(line1, 19, 20)? as i32;
+    let year = if year < 57 { 2000 + year } else { 1900 + year };
+    let day = field(line1, 21, 32)?;
+    let epoch = Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single()?
+        + chrono::Duration::milliseconds(((day - 1.0) * 86_400_000.0) as i64);
+
+    Some(Elements {
+        epoch,
+        inclination: field(line2, 9, 16)?.to_radians(),
+        raan: field(line2, 18, 25)?.to_radians(),
+        eccentricity: format!("0.{}", line2.get(26..33)?.trim()).parse().ok()?,
+        arg_perigee: field(line2, 35, 42)?.to_radians(),
+        mean_anomaly: field(line2, 44, 51)?.to_radians(),
+        mean_motion: field(line2, 53, 63)? * 2.0 * PI / 86400.0,
+    })
+}
+
+/// Greenwich mean sidereal time in radians
+fn gmst(time: DateTime<Utc>) -> f64 {
+    let days = (time.timestamp_millis() as f64 / 86_400_000.0) + 2440587.5 - 2451545.0;
+
+    (280.46061837 + 360.98564736629 * days)
+        .rem_euclid(360.0)
+        .to_radians()
+}
+
+/// Earth-fixed position in km. Two-body orbit with the secular effects of
+/// Earth's oblateness, which is good to a few tens of km within a day of the
+/// epoch: enough for telling when the ISS passes over, not where exactly.
+fn position_ecef(el: &Elements, time: DateTime<Utc>) -> [f64; 3] {
+    let dt = (time - el.epoch).num_milliseconds() as f64 / 1000.0;
+    let n = el.mean_motion;
+    let e = el.eccentricity;
+    let a = (MU / (n * n)).cbrt();
+    let p = a * (1.0 - e * e);
+    let k = 0.75 * n * J2 * (EARTH_RADIUS_KM / p).powi(2);
+    let cos_i = el.inclination.cos();
+
+    let raan = el.raan - 2.0 * k * cos_i * dt;
+    let arg_perigee = el.arg_perigee + k * (5.0 * cos_i * cos_i - 1.0) * dt;
+    let mean_anomaly =
+        el.mean_anomaly + (n + k * (1.0 - e * e).sqrt() * (3.0 * cos_i * cos_i - 1.0)) * dt;
+
+    let mut eccentric = mean_anomaly;
+    for _ in 0..10 {
+        eccentric -= (eccentric - e * eccentric.sin() - mean_anomaly) / (1.0 - e * eccentric.cos());
+    }
+    let true_anomaly = 2.0
+        * ((1.0 + e).sqrt() * (eccentric / 2.0).sin())
+            .atan2((1.0 - e).sqrt() * (eccentric / 2.0).cos());
+    let r = a * (1.0 - e * eccentric.cos());
+    let u = arg_perigee + true_anomaly;
+
+    let x = r * (raan.cos() * u.cos() - raan.sin() * u.sin() * cos_i);
+    let y = r * (raan.sin() * u.cos() + raan.cos() * u.sin() * cos_i);
+    let z = r * u.sin() * el.inclination.sin();
+
+    let g = gmst(time);
+    [g.cos() * x + g.sin() * y, -g.sin() * x + g.cos() * y, z]
+}
+
+fn ground_point(lat: f64, lon: f64) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+
+    [
+        EARTH_RADIUS_KM * lat.cos() * lon.cos(),
+        EARTH_RADIUS_KM * lat.cos() * lon.sin(),
+        EARTH_RADIUS_KM * lat.sin(),
+    ]
+}
+
+/// Degrees above the horizon of the observer
+fn elevation(observer: [f64; 3], satellite: [f64; 3]) -> f64 {
+    let d: Vec<f64> = (0..3).map(|i| satellite[i] - observer[i]).collect();
+    let distance = d.iter().map(|c| c * c).sum::<f64>().sqrt();
+    let up = (0..3).map(|i| d[i] * observer[i]).sum::<f64>() / EARTH_RADIUS_KM;
+
+    (up / distance).asin().to_degrees()
+}
+
+fn next_pass(el: &Elements, lat: f64, lon: f64, now: DateTime<Utc>) -> Option<Pass> {
+    let observer = ground_point(lat, lon);
+    let mut pass: Option<Pass> = None;
+
+    for step in 0..PASS_SEARCH_HOURS * 3600 / PASS_STEP_SECS {
+        let time = now + chrono::Duration::seconds(step * PASS_STEP_SECS);
+        let elev = elevation(observer, position_ecef(el, time));
+
+        match pass.as_mut() {
+            Some(p) if elev >= MIN_ELEVATION => p.max_elevation = p.max_elevation.max(elev),
+            Some(_) => break,
+            // A pass already going on is skipped
+            None if elev >= MIN_ELEVATION && step > 0 => {
+                pass = Some(Pass {
+                    start: time,
+                    max_elevation: elev,
+                })
+            }
+            None => (),
+        }
+    }
+
+    pass
+}
+
+fn coordinates(lat: f64, lon: f64) -> String {
+    format!(
+        "{:.2}°{} {:.2}°{}",
+        lat.abs(),
+        if lat >= 0.0 { "N" } else { "S" },
+        lon.abs(),
+        if lon >= 0.0 { "E" } else { "W" }
+    )
+}
+
+fn pass_msg(place: &str, pass: &Option<Pass>, tz: Tz) -> String {
+    match pass {
+        Some(p) => format!(
+            "seuraava ylilento ({}) {}, korkeimmillaan {:.0}°",
+            place,
+            p.start.with_timezone(&tz).format("%-d.%-m. %H:%M"),
+            p.max_elevation
+        ),
+        None => format!("ei ylilentoja vuorokauden sisällä ({})", place),
+    }
+}
+
+fn astronauts_msg(crafts: &[(String, Vec<String>)]) -> String {
+    let count: usize = crafts.iter().map(|(_, names)| names.len()).sum();
+    let list: Vec<String> = crafts
+        .iter()
+        .map(|(craft, names)| format!("{}: {}", craft, names.join(", ")))
+        .collect();
+
+    format!("Avaruudessa nyt {} ihmistä. {}", count, list.join(" | "))
+}
+
+async fn elements() -> Result<Elements, String> {
+    let now = Utc::now();
+    if let Some((el, fetched)) = TLE_CACHE.lock().unwrap().as_ref() {
+        if now - *fetched < chrono::Duration::hours(TLE_CACHE_HOURS) {
+            return Ok(el.clone());
+        }
+    }
+
+    let el = match get_text(TLE_URL).await {
+        Ok(tle) => parse_tle(&tle).ok_or_else(|| "Error parsing ISS orbit".to_owned())?,
+        Err(_) => {
+            return Err("Error getting ISS orbit".to_owned());
+        }
+    };
+    *TLE_CACHE.lock().unwrap() = Some((el.clone(), now));
+
+    Ok(el)
+}
+
+async fn iss_msg(place: &str, tz: Tz) -> Result<String, String> {
+    let (lat, lon) = match get_text(POSITION_URL).await {
+        Ok(json) => parse_position(&json)?,
+        Err(_) => {
+            return Err("Error getting ISS position".to_owned());
+        }
+    };
+    let mut msg = format!("ISS on nyt kohdassa {}", coordinates(lat, lon));
+
+    if !place.is_empty() {
+        let place = lookup_place(place)
+            .await
+            .map_err(|_| "Paikkaa ei löytynyt".to_owned())?;
+        let pass = next_pass(&elements().await?, place.lat, place.lon, Utc::now());
+        msg.push_str(&format!(", {}", pass_msg(&place.name, &pass, tz)));
+    }
+
+    Ok(msg)
+}
+
+pub async fn command_iss(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let tz = get_timezone(&prefix, &source.network)
+        .await
+        .unwrap_or(chrono_tz::Europe::Helsinki);
+    let msg = match iss_msg(params.trim(), tz).await {
+        Ok(m) => m,
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_astronauts(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget) {
+    let msg = match get_text(ASTROS_URL).await {
+        Ok(json) => match parse_astronauts(&json) {
+            Ok(crafts) => astronauts_msg(&crafts),
+            Err(e) => e,
+        },
+        Err(_) => "Error getting astronauts".to_owned(),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TLE: &str = "ISS (ZARYA)
+1 25544U 98067A   24001.50000000  .00016717  00000-0  30571-3 0  9991
+2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.49815311432717";
+
+    #[test]
+    fn open_notify() {
+        let json = r#"{"iss_position": {"longitude": "-38.2316", "latitude": "-46.4112"}, "timestamp": 1700000000, "message": "success"}"#;
+        let (lat, lon) = parse_position(json).unwrap();
+        assert_eq!(coordinates(lat, lon), "46.41°S 38.23°W");
+
+        let json = r#"{"people": [{"craft": "ISS", "name": "Jasmin Moghbeli"}, {"craft": "Tiangong", "name": "Tang Hongbo"}, {"craft": "ISS", "name": "Andreas Mogensen"}], "number": 3, "message": "success"}"#;
+        assert_eq!(
+            astronauts_msg(&parse_astronauts(json).unwrap()),
+            "Avaruudessa nyt 3 ihmistä. ISS: Jasmin Moghbeli, Andreas Mogensen | Tiangong: Tang Hongbo"
+        );
+    }
+
+    #[test]
+    fn orbit() {
+        let el = parse_tle(TLE).unwrap();
+        assert_eq!(
+            el.epoch,
+            Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+        );
+        assert!((el.eccentricity - 0.0006703).abs() < 1e-9);
+
+        for hours in [0, 6, 12, 24] {
+            let p = position_ecef(&el, el.epoch + chrono::Duration::hours(hours));
+            let r = p.iter().map(|c| c * c).sum::<f64>().sqrt();
+            let latitude = (p[2] / r).asin().to_degrees();
+            assert!(r - EARTH_RADIUS_KM > 380.0 && r - EARTH_RADIUS_KM < 440.0);
+            assert!(latitude.abs() <= 51.7);
+        }
+
+        // Straight below the ISS it is at the zenith
+        let p = position_ecef(&el, el.epoch);
+        let r = p.iter().map(|c| c * c).sum::<f64>().sqrt();
+        let lat = (p[2] / r).asin().to_degrees();
+        let lon = p[1].atan2(p[0]).to_degrees();
+        assert!(elevation(ground_point(lat, lon), p) > 89.0);
+
+        let pass = next_pass(&el, 60.17, 24.94, el.epoch).unwrap();
+        assert!(pass.start > el.epoch);
+        assert!(pass.max_elevation >= MIN_ELEVATION && pass.max_elevation <= 90.0);
+        assert_eq!(
+            pass_msg("Helsinki", &None, chrono_tz::Europe::Helsinki),
+            "ei ylilentoja vuorokauden sisällä (Helsinki)"
+        );
+    }
+}
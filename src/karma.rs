@@ -0,0 +1,286 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use log::{debug, error};
+use regex::Regex;
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+// Each user can change karma this many times per window
+const RATE_LIMIT_COUNT: usize = 5;
+const RATE_LIMIT_MINUTES: i64 = 10;
+const TOP_COUNT: usize = 5;
+
+/// Times of recent karma changes by (network, user@host), so changing nick doesn't reset the limit
+type RecentChanges = HashMap<(String, String), Vec<DateTime<Utc>>>;
+
+lazy_static! {
+    static ref RE_KARMA: Regex = Regex::new(r"^([^+\-].*?)(\+\+|--)[,.:;!?]*$").unwrap();
+    static ref RECENT_CHANGES: Mutex<RecentChanges> = Mutex::new(HashMap::new());
+}
+
+/// Things and score changes in a message, "rust++ c--" -> [("rust", 1), ("c", -1)]
+fn parse_changes(msg: &str) -> Vec<(String, i64)> {
+    msg.split_whitespace()
+        .filter_map(|word| RE_KARMA.captures(word))
+        .filter_map(|c| {
+            let thing = c[1].trim_end_matches([':', ',']);
+            if thing.is_empty() || !thing.chars().any(|c| c.is_alphanumeric()) {
+                return None;
+            }
+            let change = if &c[2] == "++" { 1 } else { -1 };
+            Some((thing.to_owned(), change))
+        })
+        .collect()
+}
+
+/// Nicks in the RFC 1459 case mapping most IRC networks use, where []\~ are
+/// the uppercase forms of {}|^
+fn irc_lowercase(nick: &str) -> String {
+    nick.chars()
+        .map(|c| match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Records a change for the user unless they are over the limit
+fn allow_change(recent: &mut RecentChanges, network: &str, user: &str, now: DateTime<Utc>) -> bool {
+    let window = chrono::Duration::minutes(RATE_LIMIT_MINUTES);
+    recent.retain(|_, times| {
+        times.retain(|t| now - *t < window);
+        !times.is_empty()
+    });
+
+    let times = recent
+        .entry((network.to_owned(), user.to_lowercase()))
+        .or_default();
+    if times.len() >= RATE_LIMIT_COUNT {
+        return false;
+    }
+    times.push(now);
+
+    true
+}
+
+pub const DB: db::Database = db::Database {
+    name: "karma.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS karma (
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            thing_lower TEXT NOT NULL,
+            thing TEXT NOT NULL,
+            score INTEGER NOT NULL,
+            PRIMARY KEY(network, channel, thing_lower)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn change_karma(conn: &Connection, source: &ChatTarget, thing: &str, change: i64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO karma (network, channel, thing_lower, thing, score)
+        VALUES (:network, :channel, :thing_lower, :thing, :change)
+        ON CONFLICT(network, channel, thing_lower)
+        DO UPDATE SET score = score + :change, thing = :thing",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":thing_lower": thing.to_lowercase(),
+            ":thing": thing,
+            ":change": change,
+        },
+    )?;
+
+    Ok(())
+}
+
+fn get_karma(conn: &Connection, source: &ChatTarget, thing: &str) -> Result<Option<i64>> {
+    conn.query_row(
+        "SELECT score FROM karma
+        WHERE network = :network AND channel = :channel AND thing_lower = :thing_lower",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":thing_lower": thing.to_lowercase(),
+        },
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+fn top_karma(conn: &Connection, source: &ChatTarget, count: usize) -> Result<Vec<(String, i64)>> {
+    let mut statement = conn.prepare(
+        "SELECT thing, score FROM karma WHERE network = :network AND channel = :channel
+        ORDER BY score DESC, thing_lower LIMIT :count",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": source.network,
+        ":channel": source.channel,
+        ":count": count as i64,
+    })?;
+
+    let mut top = Vec::new();
+    while let Some(row) = rows.next()? {
+        top.push((row.get(0)?, row.get(1)?));
+    }
+
+    Ok(top)
+}
+
+fn top_msg(top: &[(String, i64)]) -> String {
+    if top.is_empty() {
+        return "Ei karmaa tällä kanavalla".to_owned();
+    }
+
+    let list: Vec<String> = top.iter().map(|(t, s)| format!("{} {}", t, s)).collect();
+    format!("Karma: {}", list.join(", "))
+}
+
+/// Called for every message on a channel; applies thing++ and thing-- changes.
+/// `user` is the sender's user@host.
+pub async fn handle_karma(source: ChatTarget, nick: &str, user: &str, msg: &str) {
+    let own_nick = irc_lowercase(nick);
+    let changes: Vec<(String, i64)> = parse_changes(msg)
+        .into_iter()
+        .filter(|(thing, _)| irc_lowercase(thing) != own_nick)
+        .collect();
+    if changes.is_empty() {
+        return;
+    }
+    let changes_count = changes.len();
+
+    let allowed: Vec<(String, i64)> = {
+        let mut recent = RECENT_CHANGES.lock().unwrap();
+        changes
+            .into_iter()
+            .take_while(|_| allow_change(&mut recent, &source.network, user, Utc::now()))
+            .collect()
+    };
+    if allowed.len() < changes_count {
+        debug!("Karma rate limit reached for {} ({})", nick, user);
+    }
+
+    let result = db::call(&DB, move |c| {
+        for (thing, change) in allowed {
+            change_karma(c, &source, &thing, change)?;
+        }
+        Ok(())
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Error changing karma: {}", e);
+    }
+}
+
+pub async fn command_karma(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let msg = match params.trim() {
+        "" => "Usage: .karma <thing> | .karma top".to_owned(),
+        "top" => {
+            let target = source.clone();
+            match db::call(&DB, move |c| top_karma(c, &target, TOP_COUNT)).await {
+                Ok(top) => top_msg(&top),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        thing => {
+            let (target, wanted) = (source.clone(), thing.to_owned());
+            match db::call(&DB, move |c| get_karma(c, &target, &wanted)).await {
+                Ok(score) => format!("{}: {}", thing, score.unwrap_or(0)),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn karma_changes() {
+        assert_eq!(
+            parse_changes("rust++ c-- ++ -- a+++ i++; toimii"),
+            vec![
+                ("rust".to_owned(), 1),
+                ("c".to_owned(), -1),
+                ("a+".to_owned(), 1),
+                ("i".to_owned(), 1)
+            ]
+        );
+        assert_eq!(
+            parse_changes("nick: kiitos++"),
+            vec![("kiitos".to_owned(), 1)]
+        );
+        assert!(parse_changes("x = y++z, ---").is_empty());
+
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+
+        change_karma(&conn, &channel, "Rust", 1).unwrap();
+        change_karma(&conn, &channel, "rust", 1).unwrap();
+        change_karma(&conn, &channel, "PHP", -1).unwrap();
+        change_karma(&conn, &channel, "sauna", 1).unwrap();
+
+        assert_eq!(get_karma(&conn, &channel, "RUST").unwrap(), Some(2));
+        assert_eq!(get_karma(&conn, &channel, "python").unwrap(), None);
+        assert_eq!(
+            top_msg(&top_karma(&conn, &channel, 5).unwrap()),
+            "Karma: rust 2, sauna 1, PHP -1"
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut recent = HashMap::new();
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+
+        for _ in 0..RATE_LIMIT_COUNT {
+            assert!(allow_change(&mut recent, "net", "~nick@Example.com", now));
+        }
+        assert!(!allow_change(&mut recent, "net", "~nick@example.com", now));
+        assert!(allow_change(&mut recent, "net", "~nick@other.com", now));
+        assert!(allow_change(
+            &mut recent,
+            "net",
+            "~nick@example.com",
+            now + chrono::Duration::minutes(RATE_LIMIT_MINUTES)
+        ));
+
+        assert_eq!(irc_lowercase("Nick[Away]\\~"), "nick{away}|^");
+        assert_eq!(irc_lowercase("{nick}"), irc_lowercase("[NICK]"));
+    }
+}
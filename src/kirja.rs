@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use regex::Regex;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const SEARCH_URL: &str = "https://openlibrary.org/search.json";
+const WORK_URL: &str = "https://openlibrary.org";
+const SEARCH_FIELDS: &str = "key,title,author_name,first_publish_year,number_of_pages_median";
+// Books expanded from one chat message at most
+const MAX_ISBNS: usize = 2;
+
+#[derive(Debug, PartialEq)]
+struct Book {
+    title: String,
+    authors: Vec<String>,
+    year: Option<i64>,
+    pages: Option<i64>,
+    /// "/works/OL27448W"
+    key: String,
+}
+
+lazy_static! {
+    // ISBN-10s look like any other number, so only ones labeled as such count
+    static ref RE_ISBN: Regex =
+        Regex::new(r"(?i)(isbn(?:-1[03])?:?\s*)?\b(97[89](?:-?\d){10}|\d(?:-?\d){8}-?[\dX])\b")
+            .unwrap();
+}
+
+/// "978-0-261-10320-7" -> "9780261103207", if the check digit is right
+fn normalize_isbn(isbn: &str) -> Option<String> {
+    let isbn: String = isbn
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let digit = |c: char| c.to_digit(10);
+
+    let valid = match isbn.len() {
+        10 => {
+            let sum: Option<u32> = isbn
+                .chars()
+                .enumerate()
+                .map(|(i, c)| match c {
+                    'X' if i == 9 => Some(10),
+                    c => digit(c).map(|d| d * (10 - i as u32)),
+                })
+                .sum();
+            sum.is_some_and(|s| s % 11 == 0)
+        }
+        13 => {
+            let sum: Option<u32> = isbn
+                .chars()
+                .enumerate()
+                .map(|(i, c)| digit(c).map(|d| if i % 2 == 0 { d } else { d * 3 }))
+                .sum();
+            sum.is_some_and(|s| s % 10 == 0)
+        }
+        _ => false,
+    };
+
+    valid.then_some(isbn)
+}
+
+fn find_isbns(msg: &str) -> Vec<String> {
+    let mut isbns: Vec<String> = RE_ISBN
+        .captures_iter(msg)
+        .filter_map(|c| Some((c.get(1).is_some(), normalize_isbn(&c[2])?)))
+        .filter(|(labeled, isbn)| *labeled || isbn.len() == 13)
+        .map(|(_, isbn)| isbn)
+        .collect();
+    isbns.dedup();
+    isbns.truncate(MAX_ISBNS);
+
+    isbns
+}
+
+async fn search_json(params: &[(&str, &str)]) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT
+        .get(SEARCH_URL)
+        .query(params)
+        .query(&[("limit", "1"), ("fields", SEARCH_FIELDS)]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+fn parse_book(json_text: &str) -> Result<Option<Book>, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())?;
+
+    let doc = &json["docs"][0];
+    let (title, key) = match (doc["title"].as_str(), doc["key"].as_str()) {
+        (Some(t), Some(k)) => (t, k),
+        _ => {
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(Book {
+        title: title.to_owned(),
+        authors: doc["author_name"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|a| a.as_str())
+            .map(|a| a.to_owned())
+            .collect(),
+        year: doc["first_publish_year"].as_i64(),
+        pages: doc["number_of_pages_median"].as_i64(),
+        key: key.to_owned(),
+    }))
+}
+
+fn book_msg(book: &Book) -> String {
+    let mut msg = book.title.to_owned();
+    if !book.authors.is_empty() {
+        msg.push_str(&format!(" – {}", book.authors.join(", ")));
+    }
+    if let Some(year) = book.year {
+        msg.push_str(&format!(" ({})", year));
+    }
+    if let Some(pages) = book.pages {
+        msg.push_str(&format!(", {} sivua", pages));
+    }
+
+    format!("{} | {}{}", msg, WORK_URL, book.key)
+}
+
+/// Title search, or an ISBN lookup if the query is one
+async fn find_book(query: &str) -> Result<Option<Book>, String> {
+    let json = match normalize_isbn(query) {
+        Some(isbn) => search_json(&[("isbn", &isbn)]).await,
+        None => search_json(&[("q", query)]).await,
+    };
+
+    match json {
+        Ok(j) => parse_book(&j),
+        Err(_) => Err("Open Library API error".to_owned()),
+    }
+}
+
+pub async fn command_kirja(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let msg = match params.trim() {
+        "" => "Usage: .kirja <nimi tai ISBN>".to_owned(),
+        query => match find_book(query).await {
+            Ok(Some(book)) => book_msg(&book),
+            Ok(None) => "Kirjaa ei löytynyt".to_owned(),
+            Err(e) => e,
+        },
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+/// Expands ISBNs posted on a channel; books that are not found stay quiet
+pub async fn handle_isbns(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, msg: &str) {
+    for isbn in find_isbns(msg) {
+        if let Ok(Some(book)) = find_book(&isbn).await {
+            let action = BotAction {
+                target: ChatTarget {
+                    network: source.network.to_owned(),
+                    channel: source.channel.to_owned(),
+                },
+                action_type: ActionType::Message(book_msg(&book)),
+            };
+            bot_sender.send(action).await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isbn_detection() {
+        assert_eq!(
+            normalize_isbn("978-0-261-10320-7"),
+            Some("9780261103207".to_owned())
+        );
+        assert_eq!(
+            normalize_isbn("0-261-10320-2"),
+            Some("0261103202".to_owned())
+        );
+        assert_eq!(normalize_isbn("080442957x"), Some("080442957X".to_owned()));
+        assert_eq!(normalize_isbn("9780261103208"), None);
+        assert_eq!(normalize_isbn("Hobitti"), None);
+
+        assert_eq!(
+            find_isbns("luin 9780261103207 ja ISBN: 0-261-10320-2, puh. 0401234567"),
+            vec!["9780261103207", "0261103202"]
+        );
+        assert!(find_isbns("tilinumero 0261103202 ja 1234567890123").is_empty());
+    }
+
+    #[test]
+    fn open_library() {
+        let json = r#"{"numFound": 1, "start": 0, "docs": [{"key": "/works/OL27448W",
+            "title": "The Lord of the Rings", "author_name": ["J.R.R. Tolkien"],
+            "first_publish_year": 1954, "number_of_pages_median": 1216}]}"#;
+        assert_eq!(
+            book_msg(&parse_book(json).unwrap().unwrap()),
+            "The Lord of the Rings – J.R.R. Tolkien (1954), 1216 sivua | \
+            https://openlibrary.org/works/OL27448W"
+        );
+        assert_eq!(
+            parse_book(r#"{"numFound": 0, "start": 0, "docs": []}"#),
+            Ok(None)
+        );
+    }
+}
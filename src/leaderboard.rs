@@ -0,0 +1,228 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use log::error;
+use rusqlite::{named_params, Connection, Result};
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+const TOP_COUNT: usize = 5;
+
+/// A game keeping score here. Each game module has its own `GAME` which is
+/// listed in `GAMES` so that `.top` knows about it.
+pub struct Game {
+    /// Stored in the database and used in `.top <id>`
+    pub id: &'static str,
+    pub name: &'static str,
+}
+
+const GAMES: [&Game; 2] = [&crate::trivia::GAME, &crate::sanuli::GAME];
+
+pub const DB: db::Database = db::Database {
+    name: "leaderboard.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS scores (
+            game TEXT NOT NULL,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            nick_lower TEXT NOT NULL,
+            nick TEXT NOT NULL,
+            points INTEGER NOT NULL,
+            PRIMARY KEY(game, network, channel, nick_lower)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Points scored in private messages count only towards the network-wide totals
+fn scope(source: &ChatTarget) -> String {
+    if source.is_channel() {
+        source.channel.to_lowercase()
+    } else {
+        String::new()
+    }
+}
+
+fn add_points(
+    conn: &Connection,
+    game: &Game,
+    source: &ChatTarget,
+    nick: &str,
+    points: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO scores (game, network, channel, nick_lower, nick, points)
+        VALUES (:game, :network, :channel, :nick_lower, :nick, :points)
+        ON CONFLICT(game, network, channel, nick_lower)
+        DO UPDATE SET points = points + :points, nick = :nick",
+        named_params! {
+            ":game": game.id,
+            ":network": source.network,
+            ":channel": scope(source),
+            ":nick_lower": nick.to_lowercase(),
+            ":nick": nick,
+            ":points": points,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Best players of the channel, or of the whole network when asked in a private message
+fn top(
+    conn: &Connection,
+    game: &Game,
+    source: &ChatTarget,
+    count: usize,
+) -> Result<Vec<(String, i64)>> {
+    let channel = scope(source);
+    let mut statement = conn.prepare(
+        "SELECT max(nick), sum(points) AS total FROM scores
+        WHERE game = :game AND network = :network AND (:channel = '' OR channel = :channel)
+        GROUP BY nick_lower ORDER BY total DESC, nick_lower LIMIT :count",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":game": game.id,
+        ":network": source.network,
+        ":channel": channel,
+        ":count": count as i64,
+    })?;
+
+    let mut top = Vec::new();
+    while let Some(row) = rows.next()? {
+        top.push((row.get(0)?, row.get(1)?));
+    }
+
+    Ok(top)
+}
+
+fn top_msg(game: &Game, top: &[(String, i64)]) -> String {
+    if top.is_empty() {
+        return format!("{}: ei vielä pisteitä", game.name);
+    }
+
+    let list: Vec<String> = top.iter().map(|(n, p)| format!("{} {}", n, p)).collect();
+    format!("{}: {}", game.name, list.join(", "))
+}
+
+/// Leaders of every game, "Trivia: nick 12 | Sanuli: toinen 30"
+fn leaders_msg(conn: &Connection, source: &ChatTarget) -> Result<String> {
+    let mut leaders = Vec::new();
+    for game in GAMES {
+        if let Some((nick, points)) = top(conn, game, source, 1)?.first() {
+            leaders.push(format!("{}: {} {}", game.name, nick, points));
+        }
+    }
+
+    if leaders.is_empty() {
+        Ok("Kukaan ei ole vielä saanut pisteitä".to_owned())
+    } else {
+        Ok(leaders.join(" | "))
+    }
+}
+
+fn find_game(name: &str) -> Option<&'static Game> {
+    GAMES
+        .iter()
+        .find(|g| g.id.eq_ignore_ascii_case(name) || g.name.to_lowercase() == name.to_lowercase())
+        .copied()
+}
+
+/// Adds points for the nick in the game, errors are only logged
+pub async fn record_points(game: &'static Game, source: &ChatTarget, nick: &str, points: i64) {
+    let (source, nick) = (source.clone(), nick.to_owned());
+    if let Err(e) = db::call(&DB, move |c| add_points(c, game, &source, &nick, points)).await {
+        error!("Error saving {} points: {}", game.id, e);
+    }
+}
+
+/// The leaderboard message of a single game
+pub async fn leaderboard_msg(game: &'static Game, source: &ChatTarget) -> String {
+    let source = source.clone();
+    match db::call(&DB, move |c| top(c, game, &source, TOP_COUNT)).await {
+        Ok(t) => top_msg(game, &t),
+        Err(_) => "Database error".to_owned(),
+    }
+}
+
+pub async fn command_top(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let msg = match params.trim() {
+        "" => {
+            let source = source.clone();
+            match db::call(&DB, move |c| leaders_msg(c, &source)).await {
+                Ok(m) => m,
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        name => match find_game(name) {
+            Some(game) => leaderboard_msg(game, &source).await,
+            None => {
+                let ids: Vec<&str> = GAMES.iter().map(|g| g.id).collect();
+                format!("Usage: .top [{}]", ids.join("|"))
+            }
+        },
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn scores_per_game() {
+        let conn = open_db(true).unwrap();
+        let channel = |c: &str| ChatTarget {
+            network: "testnet".to_owned(),
+            channel: c.to_owned(),
+        };
+        let trivia = &crate::trivia::GAME;
+        let sanuli = &crate::sanuli::GAME;
+
+        add_points(&conn, trivia, &channel("#Trivia"), "Nick", 1).unwrap();
+        add_points(&conn, trivia, &channel("#trivia"), "nick", 1).unwrap();
+        add_points(&conn, trivia, &channel("#trivia"), "toinen", 1).unwrap();
+        add_points(&conn, trivia, &channel("#muu"), "toinen", 2).unwrap();
+        add_points(&conn, sanuli, &channel("toinen"), "toinen", 5).unwrap();
+
+        assert_eq!(
+            top_msg(trivia, &top(&conn, trivia, &channel("#trivia"), 5).unwrap()),
+            "Trivia: nick 2, toinen 1"
+        );
+        assert_eq!(
+            top_msg(trivia, &top(&conn, trivia, &channel("nick"), 5).unwrap()),
+            "Trivia: toinen 3, nick 2"
+        );
+        assert_eq!(
+            top_msg(sanuli, &top(&conn, sanuli, &channel("#trivia"), 5).unwrap()),
+            "Sanuli: ei vielä pisteitä"
+        );
+        assert_eq!(
+            leaders_msg(&conn, &channel("nick")).unwrap(),
+            "Trivia: toinen 3 | Sanuli: toinen 5"
+        );
+
+        assert_eq!(find_game("SANULI").map(|g| g.id), Some("sanuli"));
+        assert!(find_game("hirsipuu").is_none());
+    }
+}
@@ -0,0 +1,300 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! The bot without the binary around it: IRC connections, Matrix accounts,
+//! Discord and Telegram bots, background tasks and the command engine. `run` starts everything the way
+//! tbotti does, `message_handler::handle_command` runs a single command.
+
+use tokio::sync::{mpsc, oneshot};
+
+use yaml_rust::yaml::Yaml;
+
+use std::sync::Arc;
+
+use log::{error, info};
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod botaction;
+pub mod config;
+mod db;
+
+mod admins;
+mod bensa;
+mod birthday;
+use birthday::birthday_manager;
+mod blitzortung;
+use blitzortung::lightning_manager;
+mod calc;
+mod chatlog;
+use chatlog::chatlog_manager;
+mod digitraffic;
+mod epic;
+use epic::epic_manager;
+mod f1;
+mod factoids;
+mod finnkino;
+mod fmi;
+mod fmi_warnings;
+use fmi_warnings::fmi_warnings_manager;
+mod free_games;
+mod gdq;
+mod h33h3;
+mod hockey;
+mod iss;
+mod karma;
+mod kirja;
+mod leaderboard;
+mod links;
+mod lotto;
+use links::links_manager;
+mod openweathermap;
+mod poll;
+use poll::poll_manager;
+mod ts3;
+mod twitch;
+use twitch::twitch_manager;
+mod weather;
+mod weather_db;
+mod wolfram_alpha;
+
+mod health;
+mod http_client;
+mod http_server;
+mod web_admin;
+mod webhooks;
+use http_server::http_server;
+
+mod rss;
+use rss::rss_manager;
+
+mod chat;
+mod ircloop;
+use ircloop::irc_loop;
+mod matrix;
+use matrix::matrix_loop;
+mod discord;
+use discord::discord_loop;
+mod telegram;
+use telegram::telegram_loop;
+
+mod timer;
+use timer::timer_manager;
+
+mod timezone;
+
+mod tell;
+
+pub mod message_handler;
+use message_handler::message_handler;
+
+mod urltitle;
+
+mod eightball;
+mod flip;
+mod roll;
+
+mod sahko;
+use sahko::sahko_manager;
+mod sana;
+mod sanuli;
+mod seen;
+mod sun;
+mod tutka;
+
+mod tmdb;
+mod trivia;
+mod tvmaze;
+use tvmaze::tvmaze_manager;
+
+mod wikipedia;
+
+pub use timer::TimerEvent;
+
+/// Where a message came from or goes to. `network` is the name of an IRC
+/// network, a Matrix account or a Discord or Telegram bot in the config,
+/// `channel` an IRC channel or nick, a Matrix room id, or a Discord channel
+/// or Telegram chat id with `#` for guild channels and groups.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatTarget {
+    pub network: String,
+    pub channel: String,
+}
+
+impl ChatTarget {
+    /// IRC channels and Matrix rooms, as opposed to private messages
+    pub fn is_channel_name(name: &str) -> bool {
+        name.starts_with('#') || name.starts_with('&') || name.starts_with('!')
+    }
+
+    pub fn is_channel(&self) -> bool {
+        ChatTarget::is_channel_name(&self.channel)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientQuery {
+    IsAdmin(oneshot::Sender<bool>, String, String), // (sender, network, mask)
+    ReloadAdmins,                                   // admins.db has changed
+}
+
+/// Connects to the networks in `config` and runs the bot until all tasks finish
+pub async fn run(config: Arc<Yaml>) {
+    if let Err(e) = db::init(&config) {
+        error!("Could not create the data directory: {}", e);
+        return;
+    }
+    if let Err(e) = db::migrate_all() {
+        error!("Could not migrate the databases: {}", e);
+        return;
+    }
+    health::init();
+    if let Err(e) = http_client::init(&config) {
+        error!("Invalid HTTP client settings: {}", e);
+        return;
+    }
+
+    let (botaction_tx, botaction_rx) = mpsc::channel(10);
+    let (ircdata_tx, ircdata_rx) = mpsc::channel(10);
+    let (timer_tx, timer_rx) = mpsc::channel(10);
+    let (clientquery_tx, clientquery_rx) = mpsc::channel(10);
+
+    let mut tasks = vec![];
+
+    let (irc_tx, irc_rx) = mpsc::channel(10);
+    let (matrix_tx, matrix_rx) = mpsc::channel(10);
+    let (discord_tx, discord_rx) = mpsc::channel(10);
+    let (telegram_tx, telegram_rx) = mpsc::channel(10);
+    let routes = chat::routes(
+        &config,
+        &[
+            ("networks", irc_tx),
+            ("matrix", matrix_tx),
+            ("discord", discord_tx),
+            ("telegram", telegram_tx),
+        ],
+    );
+    tasks.push(tokio::spawn(async move {
+        chat::route_actions(botaction_rx, routes).await
+    }));
+
+    let c1 = config.clone();
+    let irc_input_tx = ircdata_tx.clone();
+    tasks.push(tokio::spawn(async move {
+        irc_loop(irc_input_tx, irc_rx, clientquery_rx, c1).await
+    }));
+    info!("Started irc_loop");
+
+    let c11 = config.clone();
+    let matrix_input_tx = ircdata_tx.clone();
+    tasks.push(tokio::spawn(async move {
+        matrix_loop(matrix_input_tx, matrix_rx, c11).await
+    }));
+    info!("Started matrix_loop");
+
+    let c12 = config.clone();
+    let discord_input_tx = ircdata_tx.clone();
+    tasks.push(tokio::spawn(async move {
+        discord_loop(discord_input_tx, discord_rx, c12).await
+    }));
+    info!("Started discord_loop");
+
+    let c13 = config.clone();
+    tasks.push(tokio::spawn(async move {
+        telegram_loop(ircdata_tx, telegram_rx, c13).await
+    }));
+    info!("Started telegram_loop");
+
+    let rssbot_tx = botaction_tx.clone();
+    let c3 = config.clone();
+    tasks.push(tokio::spawn(
+        async move { rss_manager(rssbot_tx, c3).await },
+    ));
+    info!("Started rss_manager");
+
+    let tvmaze_tx = botaction_tx.clone();
+    tasks.push(tokio::spawn(async move { tvmaze_manager(tvmaze_tx).await }));
+    info!("Started tvmaze_manager");
+
+    let httpserver_tx = botaction_tx.clone();
+    let httpserver_cq_tx = clientquery_tx.clone();
+    let c4 = config.clone();
+    tasks.push(tokio::spawn(async move {
+        http_server(httpserver_tx, httpserver_cq_tx, c4).await
+    }));
+    info!("Started http_server");
+
+    let fmiwarnings_tx = botaction_tx.clone();
+    let c5 = config.clone();
+    tasks.push(tokio::spawn(async move {
+        fmi_warnings_manager(fmiwarnings_tx, c5).await
+    }));
+    info!("Started fmi_warnings_manager");
+
+    let sahko_tx = botaction_tx.clone();
+    let c6 = config.clone();
+    tasks.push(tokio::spawn(
+        async move { sahko_manager(sahko_tx, c6).await },
+    ));
+    info!("Started sahko_manager");
+
+    let lightning_tx = botaction_tx.clone();
+    tasks.push(tokio::spawn(async move {
+        lightning_manager(lightning_tx).await
+    }));
+    info!("Started lightning_manager");
+
+    let epic_tx = botaction_tx.clone();
+    let c7 = config.clone();
+    tasks.push(tokio::spawn(async move { epic_manager(epic_tx, c7).await }));
+    info!("Started epic_manager");
+
+    let c8 = config.clone();
+    tasks.push(tokio::spawn(async move { chatlog_manager(c8).await }));
+    info!("Started chatlog_manager");
+
+    let links_tx = botaction_tx.clone();
+    let c9 = config.clone();
+    tasks.push(tokio::spawn(
+        async move { links_manager(links_tx, c9).await },
+    ));
+    info!("Started links_manager");
+
+    let poll_tx = botaction_tx.clone();
+    tasks.push(tokio::spawn(async move { poll_manager(poll_tx).await }));
+    info!("Started poll_manager");
+
+    let birthday_tx = botaction_tx.clone();
+    tasks.push(tokio::spawn(
+        async move { birthday_manager(birthday_tx).await },
+    ));
+    info!("Started birthday_manager");
+
+    let twitch_tx = botaction_tx.clone();
+    let c10 = config.clone();
+    tasks.push(tokio::spawn(
+        async move { twitch_manager(twitch_tx, c10).await },
+    ));
+    info!("Started twitch_manager");
+
+    let t_tx = botaction_tx.clone();
+    tasks.push(tokio::spawn(
+        async move { timer_manager(timer_rx, t_tx).await },
+    ));
+    info!("Started timer_manager");
+
+    let messagehandler_tx = botaction_tx.clone();
+    let c2 = config.clone();
+    tasks.push(tokio::spawn(async move {
+        message_handler(ircdata_rx, messagehandler_tx, timer_tx, clientquery_tx, c2).await
+    }));
+    info!("Started message_handler");
+
+    for task in tasks {
+        let _ = tokio::join!(task);
+    }
+
+    info!("All tasks finished");
+}
@@ -0,0 +1,421 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use core::time::Duration;
+use log::{error, info};
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+const TOP_COUNT: usize = 5;
+// The weekly digest is posted on Monday morning
+const DIGEST_WEEKDAY: Weekday = Weekday::Mon;
+const DIGEST_HOUR: u32 = 9;
+
+#[derive(Debug, PartialEq)]
+struct TopLink {
+    url: String,
+    title: Option<String>,
+    count: i64,
+    first_nick: String,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "links.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS links (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            url TEXT NOT NULL,
+            domain TEXT NOT NULL,
+            title TEXT,
+            nick TEXT NOT NULL,
+            time INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// "https://www.youtube.com/watch?v=x" -> "youtube.com"
+fn domain(url: &str) -> Option<String> {
+    let host = reqwest::Url::parse(url).ok()?.host_str()?.to_lowercase();
+
+    Some(host.trim_start_matches("www.").to_owned())
+}
+
+fn insert(
+    conn: &Connection,
+    source: &ChatTarget,
+    nick: &str,
+    url: &str,
+    title: Option<&str>,
+    time: i64,
+) -> Result<()> {
+    let domain = match domain(url) {
+        Some(d) => d,
+        None => {
+            return Ok(());
+        }
+    };
+
+    conn.execute(
+        "INSERT INTO links (network, channel, url, domain, title, nick, time)
+        VALUES (:network, :channel, :url, :domain, :title, :nick, :time)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+            ":url": url,
+            ":domain": domain,
+            ":title": title,
+            ":nick": nick,
+            ":time": time,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Most posted links since `since`, with the latest known title and who posted it first
+fn top_links(
+    conn: &Connection,
+    source: &ChatTarget,
+    since: i64,
+    count: usize,
+) -> Result<Vec<TopLink>> {
+    let mut statement = conn.prepare(
+        "SELECT url, count(*) AS posts,
+            (SELECT title FROM links t WHERE t.url = l.url AND t.title IS NOT NULL
+                ORDER BY t.time DESC LIMIT 1),
+            (SELECT nick FROM links f WHERE f.url = l.url
+                AND f.network = l.network AND f.channel = l.channel
+                ORDER BY f.time, f.id LIMIT 1)
+        FROM links l
+        WHERE network = :network AND channel = :channel AND time >= :since
+        GROUP BY url ORDER BY posts DESC, max(time) DESC LIMIT :count",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": source.network,
+        ":channel": source.channel.to_lowercase(),
+        ":since": since,
+        ":count": count as i64,
+    })?;
+
+    let mut links = Vec::new();
+    while let Some(row) = rows.next()? {
+        links.push(TopLink {
+            url: row.get(0)?,
+            count: row.get(1)?,
+            title: row.get(2)?,
+            first_nick: row.get(3)?,
+        });
+    }
+
+    Ok(links)
+}
+
+fn top_domains(
+    conn: &Connection,
+    source: &ChatTarget,
+    since: i64,
+    count: usize,
+) -> Result<Vec<(String, i64)>> {
+    let mut statement = conn.prepare(
+        "SELECT domain, count(*) AS posts FROM links
+        WHERE network = :network AND channel = :channel AND time >= :since
+        GROUP BY domain ORDER BY posts DESC, domain LIMIT :count",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": source.network,
+        ":channel": source.channel.to_lowercase(),
+        ":since": since,
+        ":count": count as i64,
+    })?;
+
+    let mut domains = Vec::new();
+    while let Some(row) = rows.next()? {
+        domains.push((row.get(0)?, row.get(1)?));
+    }
+
+    Ok(domains)
+}
+
+fn format_links(links: &[TopLink]) -> String {
+    links
+        .iter()
+        .map(|l| {
+            let name = match &l.title {
+                Some(t) => format!("{} ({})", t, l.url),
+                None => l.url.to_owned(),
+            };
+            format!("{} {}× by {}", name, l.count, l.first_nick)
+        })
+        .collect::<Vec<String>>()
+        .join(" | ")
+}
+
+fn top_msg(links: &[TopLink], week: bool) -> String {
+    let period = if week { " this week" } else { "" };
+
+    if links.is_empty() {
+        format!("No links posted{}", period)
+    } else {
+        format!("Top links{}: {}", period, format_links(links))
+    }
+}
+
+fn digest_msg(domains: &[(String, i64)], links: &[TopLink]) -> Option<String> {
+    if links.is_empty() {
+        return None;
+    }
+
+    let domains: Vec<String> = domains
+        .iter()
+        .map(|(d, c)| format!("{} {}", d, c))
+        .collect();
+
+    Some(format!(
+        "Links of the week: {} | Top domains: {}",
+        format_links(links),
+        domains.join(", ")
+    ))
+}
+
+/// Called with every URL posted on a channel, `title` as found by urltitle
+pub async fn record_link(source: &ChatTarget, nick: &str, url: &str, title: Option<&str>) {
+    if !source.is_channel() {
+        return;
+    }
+
+    let (source, nick, url) = (source.clone(), nick.to_owned(), url.to_owned());
+    let title = title.map(|t| t.to_owned());
+    let now = Utc::now().timestamp();
+    let result = db::call(&DB, move |c| {
+        insert(c, &source, &nick, &url, title.as_deref(), now)
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Error recording link: {}", e);
+    }
+}
+
+fn latest(conn: &Connection, source: &ChatTarget) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT url FROM links WHERE network = :network AND channel = :channel
+        ORDER BY time DESC, id DESC LIMIT 1",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+        },
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// The last URL posted on the channel
+pub async fn latest_link(source: &ChatTarget) -> Option<String> {
+    let source = source.clone();
+    match db::call(&DB, move |c| latest(c, &source)).await {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Error reading links: {}", e);
+            None
+        }
+    }
+}
+
+fn week_ago(now: DateTime<Utc>) -> i64 {
+    (now - chrono::Duration::days(7)).timestamp()
+}
+
+fn subscriptions_from_config(config: &Yaml) -> Vec<ChatTarget> {
+    let mut subscriptions = Vec::new();
+
+    if let Some(channels) = config["links"]["digest_channels"].as_vec() {
+        for c in channels {
+            if let (Some(network), Some(channel)) = (c["network"].as_str(), c["channel"].as_str()) {
+                subscriptions.push(ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                });
+            }
+        }
+    }
+
+    subscriptions
+}
+
+/// Whether it is time for the weekly digest, once per week
+fn digest_due(now: DateTime<Utc>, last_posted: Option<NaiveDate>) -> bool {
+    let local = now.with_timezone(&Helsinki);
+
+    local.weekday() == DIGEST_WEEKDAY
+        && local.hour() >= DIGEST_HOUR
+        && last_posted != Some(local.date_naive())
+}
+
+/// Posts the most linked URLs and domains of the week to `links: digest_channels`
+pub async fn links_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(15 * 60);
+    let subscriptions = subscriptions_from_config(&config);
+    let mut last_posted = None;
+
+    if subscriptions.is_empty() {
+        info!("No link digest subscriptions configured");
+        return;
+    }
+
+    loop {
+        let now = Utc::now();
+
+        if digest_due(now, last_posted) {
+            last_posted = Some(now.with_timezone(&Helsinki).date_naive());
+
+            let targets = subscriptions.clone();
+            let since = week_ago(now);
+            let messages = db::call(&DB, move |c| {
+                let mut messages = Vec::new();
+                for s in targets {
+                    let links = top_links(c, &s, since, TOP_COUNT);
+                    let domains = top_domains(c, &s, since, TOP_COUNT);
+                    match (links, domains) {
+                        (Ok(l), Ok(d)) => {
+                            if let Some(msg) = digest_msg(&d, &l) {
+                                messages.push((s, msg));
+                            }
+                        }
+                        (Err(e), _) | (_, Err(e)) => error!("Error reading links: {}", e),
+                    }
+                }
+                Ok(messages)
+            })
+            .await
+            .unwrap_or_default();
+
+            for (s, msg) in messages {
+                let action = BotAction {
+                    target: s,
+                    action_type: ActionType::Message(msg),
+                };
+                sender.send(action).await.unwrap();
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+pub async fn command_links(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let mut words = params.split_whitespace();
+
+    let msg = match (words.next(), words.next()) {
+        (Some("top"), period) if period.is_none() || period == Some("week") => {
+            let week = period.is_some();
+            let since = if week { week_ago(Utc::now()) } else { 0 };
+
+            let target = source.clone();
+            match db::call(&DB, move |c| top_links(c, &target, since, TOP_COUNT)).await {
+                Ok(links) => top_msg(&links, week),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        _ => "Usage: .links top [week]".to_owned(),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn top_links_and_domains() {
+        assert_eq!(
+            domain("https://www.YouTube.com/watch?v=x"),
+            Some("youtube.com".to_owned())
+        );
+        assert_eq!(domain("https://"), None);
+
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        let yle = "https://yle.fi/a/74-1";
+        let yt = "https://www.youtube.com/watch?v=x";
+
+        insert(&conn, &channel, "vanha", yt, None, 0).unwrap();
+        insert(&conn, &channel, "nick", yle, None, 100).unwrap();
+        insert(&conn, &channel, "toinen", yle, Some("Uutinen"), 200).unwrap();
+        insert(&conn, &channel, "toinen", yt, None, 300).unwrap();
+        insert(
+            &conn,
+            &channel,
+            "toinen",
+            "https://yle.fi/a/74-2",
+            None,
+            400,
+        )
+        .unwrap();
+
+        assert_eq!(
+            top_msg(&top_links(&conn, &channel, 0, 2).unwrap(), false),
+            "Top links: https://www.youtube.com/watch?v=x 2× by vanha | \
+            Uutinen (https://yle.fi/a/74-1) 2× by nick"
+        );
+
+        let links = top_links(&conn, &channel, 50, 1).unwrap();
+        let domains = top_domains(&conn, &channel, 50, 5).unwrap();
+        assert_eq!(
+            digest_msg(&domains, &links).unwrap(),
+            "Links of the week: Uutinen (https://yle.fi/a/74-1) 2× by nick | \
+            Top domains: yle.fi 3, youtube.com 1"
+        );
+        assert_eq!(
+            latest(&conn, &channel).unwrap().as_deref(),
+            Some("https://yle.fi/a/74-2")
+        );
+        assert_eq!(
+            top_msg(&top_links(&conn, &channel, 500, 5).unwrap(), true),
+            "No links posted this week"
+        );
+    }
+
+    #[test]
+    fn weekly_digest() {
+        // Monday 9:30 in Helsinki
+        let monday = Utc.with_ymd_and_hms(2023, 6, 5, 6, 30, 0).unwrap();
+        assert!(digest_due(monday, None));
+        assert!(!digest_due(
+            monday,
+            Some(NaiveDate::from_ymd_opt(2023, 6, 5).unwrap())
+        ));
+        assert!(!digest_due(monday - chrono::Duration::hours(1), None));
+        assert!(!digest_due(monday + chrono::Duration::days(1), None));
+    }
+}
@@ -0,0 +1,305 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const RESULTS_URL: &str = "https://www.veikkaus.fi/api/draw-results/v1/games";
+const OPEN_DRAWS_URL: &str = "https://www.veikkaus.fi/api/draw-games/v1/games";
+// Results are kept until the next draw, or this long if it is not known
+const DEFAULT_CACHE_HOURS: i64 = 6;
+const DISCLAIMER: &str = "Pelaa maltilla. Apua rahapeliongelmiin: Peluuri 0800 100 101";
+
+struct Game {
+    id: &'static str,
+    name: &'static str,
+    secondary: &'static str,
+    tertiary: &'static str,
+}
+
+const LOTTO: Game = Game {
+    id: "LOTTO",
+    name: "Lotto",
+    secondary: "lisänumero",
+    tertiary: "plusnumero",
+};
+
+const EUROJACKPOT: Game = Game {
+    id: "EJACKPOT",
+    name: "Eurojackpot",
+    secondary: "tähtinumerot",
+    tertiary: "",
+};
+
+#[derive(Clone, Debug, PartialEq)]
+struct Draw {
+    time: DateTime<Utc>,
+    primary: Vec<String>,
+    secondary: Vec<String>,
+    tertiary: Vec<String>,
+    /// Winners of the top prize
+    jackpot_winners: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct NextDraw {
+    time: DateTime<Utc>,
+    /// Euro cents
+    jackpot: Option<i64>,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<&'static str, (String, DateTime<Utc>)>> =
+        Mutex::new(HashMap::new());
+}
+
+async fn get_json(url: &str) -> reqwest::Result<String> {
+    http_client::send(HTTP_CLIENT.get(url)).await?.text().await
+}
+
+fn parse_json(json_text: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())
+}
+
+fn draw_time(json: &serde_json::Value) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(json["drawTime"].as_i64()?)
+        .single()
+}
+
+fn numbers(json: &serde_json::Value) -> Vec<String> {
+    json.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|n| n.as_str())
+        .map(|n| n.trim_start_matches('0').to_owned())
+        .collect()
+}
+
+/// The latest draw with results
+fn parse_results(json_text: &str) -> Result<Option<Draw>, String> {
+    let json = parse_json(json_text)?;
+
+    let draw = json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|d| {
+            let results = &d["results"][0];
+            if results.is_null() {
+                return None;
+            }
+            Some(Draw {
+                time: draw_time(d)?,
+                primary: numbers(&results["primary"]),
+                secondary: numbers(&results["secondary"]),
+                tertiary: numbers(&results["tertiary"]),
+                jackpot_winners: d["prizeTiers"][0]["shareCount"].as_i64(),
+            })
+        })
+        .max_by_key(|d| d.time);
+
+    Ok(draw)
+}
+
+/// The first upcoming draw
+fn parse_next_draw(json_text: &str) -> Result<Option<NextDraw>, String> {
+    let json = parse_json(json_text)?;
+
+    let draw = json
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|d| {
+            Some(NextDraw {
+                time: draw_time(d)?,
+                jackpot: d["jackpots"][0]["amount"].as_i64(),
+            })
+        })
+        .min_by_key(|d| d.time);
+
+    Ok(draw)
+}
+
+/// 123456789 cents -> "1,2 milj. €"
+fn euros(cents: i64) -> String {
+    let euros = cents / 100;
+
+    if euros >= 1_000_000 {
+        format!(
+            "{} milj. €",
+            format!("{:.1}", euros as f64 / 1e6).replace('.', ",")
+        )
+    } else {
+        let digits = euros.to_string();
+        let mut grouped = String::new();
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i).is_multiple_of(3) {
+                grouped.push(' ');
+            }
+            grouped.push(c);
+        }
+        format!("{} €", grouped)
+    }
+}
+
+fn day(time: DateTime<Utc>) -> String {
+    const WEEKDAYS: [&str; 7] = ["ma", "ti", "ke", "to", "pe", "la", "su"];
+    let local = time.with_timezone(&Helsinki);
+
+    format!(
+        "{} {}",
+        WEEKDAYS[local.weekday().num_days_from_monday() as usize],
+        local.format("%-d.%-m.")
+    )
+}
+
+fn lotto_msg(game: &Game, draw: &Option<Draw>, next: &Option<NextDraw>) -> String {
+    let mut msg = match draw {
+        Some(d) => {
+            let mut msg = format!("{} {}: {}", game.name, day(d.time), d.primary.join(" "));
+            if !d.secondary.is_empty() {
+                msg.push_str(&format!(", {} {}", game.secondary, d.secondary.join(" ")));
+            }
+            if !d.tertiary.is_empty() && !game.tertiary.is_empty() {
+                msg.push_str(&format!(", {} {}", game.tertiary, d.tertiary.join(" ")));
+            }
+            match d.jackpot_winners {
+                Some(0) => msg.push_str(" | Pääpalkinto jäi jakamatta"),
+                Some(n) => msg.push_str(&format!(" | Pääpalkinnon voittajia {}", n)),
+                None => (),
+            }
+            msg
+        }
+        None => format!("{}: ei tuloksia", game.name),
+    };
+
+    if let Some(n) = next {
+        msg.push_str(&format!(" | Seuraava arvonta {}", day(n.time)));
+        if let Some(jackpot) = n.jackpot {
+            msg.push_str(&format!(", potti {}", euros(jackpot)));
+        }
+    }
+
+    msg
+}
+
+/// "2024-W01" for the week of `time` and the one before it
+fn weeks(time: DateTime<Utc>) -> [String; 2] {
+    let week = |t: DateTime<Utc>| {
+        let iso = t.with_timezone(&Helsinki).iso_week();
+        format!("{}-W{:02}", iso.year(), iso.week())
+    };
+
+    [week(time), week(time - chrono::Duration::days(7))]
+}
+
+async fn results_msg(game: &'static Game, now: DateTime<Utc>) -> Result<String, String> {
+    if let Some((msg, until)) = CACHE.lock().unwrap().get(game.id) {
+        if now < *until {
+            return Ok(msg.to_owned());
+        }
+    }
+
+    let mut draw = None;
+    for week in weeks(now) {
+        let url = format!("{}/{}/draws/by-week/{}", RESULTS_URL, game.id, week);
+        draw = match get_json(&url).await {
+            Ok(json) => parse_results(&json)?,
+            Err(_) => {
+                return Err("Veikkaus API error".to_owned());
+            }
+        };
+        if draw.is_some() {
+            break;
+        }
+    }
+
+    // Without the jackpot there is still something to show
+    let url = format!("{}/{}/draws", OPEN_DRAWS_URL, game.id);
+    let next = match get_json(&url).await {
+        Ok(json) => parse_next_draw(&json).unwrap_or(None),
+        Err(_) => None,
+    };
+
+    let msg = lotto_msg(game, &draw, &next);
+    let default = now + chrono::Duration::hours(DEFAULT_CACHE_HOURS);
+    let until = next.map_or(default, |n| n.time.max(now).min(default));
+    CACHE.lock().unwrap().insert(game.id, (msg.clone(), until));
+
+    Ok(msg)
+}
+
+pub async fn command_lotto(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let game = match params.trim().to_lowercase().as_str() {
+        "" | "lotto" => Some(&LOTTO),
+        "ej" | "eurojackpot" => Some(&EUROJACKPOT),
+        _ => None,
+    };
+
+    let messages = match game {
+        Some(g) => match results_msg(g, Utc::now()).await {
+            Ok(m) => vec![m, DISCLAIMER.to_owned()],
+            Err(e) => vec![e],
+        },
+        None => vec!["Usage: .lotto [eurojackpot]".to_owned()],
+    };
+
+    for msg in messages {
+        let action = BotAction {
+            target: ChatTarget {
+                network: source.network.to_owned(),
+                channel: source.channel.to_owned(),
+            },
+            action_type: ActionType::Message(msg),
+        };
+        bot_sender.send(action).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lotto_results() {
+        let json = r#"[
+            {"id": 1, "gameName": "LOTTO", "drawTime": 1704571200000, "results": [],
+                "prizeTiers": []},
+            {"id": 2, "gameName": "LOTTO", "drawTime": 1703966400000,
+                "results": [{"primary": ["03", "10", "15", "22", "30", "31", "37"],
+                    "secondary": ["12"], "tertiary": ["28"]}],
+                "prizeTiers": [{"name": "7 oikein", "shareCount": 0, "shareAmount": 0}]}
+        ]"#;
+        let draw = parse_results(json).unwrap();
+        assert_eq!(draw.as_ref().unwrap().primary[0], "3");
+
+        let next = parse_next_draw(
+            r#"[{"drawTime": 1704571200000, "jackpots": [{"amount": 420000000}]}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            lotto_msg(&LOTTO, &draw, &next),
+            "Lotto la 30.12.: 3 10 15 22 30 31 37, lisänumero 12, plusnumero 28 | \
+            Pääpalkinto jäi jakamatta | Seuraava arvonta la 6.1., potti 4,2 milj. €"
+        );
+        assert_eq!(
+            lotto_msg(&EUROJACKPOT, &None, &None),
+            "Eurojackpot: ei tuloksia"
+        );
+
+        assert_eq!(euros(12345678), "123 456 €");
+        assert_eq!(euros(99900), "999 €");
+        assert_eq!(
+            weeks(Utc.with_ymd_and_hms(2024, 1, 3, 12, 0, 0).unwrap()),
+            ["2024-W01".to_owned(), "2023-W52".to_owned()]
+        );
+    }
+}
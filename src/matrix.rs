@@ -0,0 +1,432 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Matrix accounts from the `matrix:` section of the config. Text messages
+//! in the joined rooms go to message_handler as IRC PRIVMSGs from
+//! `localpart!localpart@server` to the room id, so Matrix users get the same
+//! commands, and admins are listed as Matrix user ids.
+
+use irc::client::prelude::{Command, Message, Prefix};
+use log::{error, info, warn};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use url::Url;
+use yaml_rust::yaml::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::health;
+use crate::http_client::{self, HTTP_CLIENT};
+
+const SYNC_TIMEOUT_MS: u64 = 30000;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const SYNC_FILTER: &str = r#"{"presence":{"types":[]},"account_data":{"types":[]},"room":{"timeline":{"types":["m.room.message"],"limit":50},"state":{"types":[]},"ephemeral":{"types":[]}}}"#;
+
+struct Account {
+    network: String,
+    homeserver: String,
+    access_token: String,
+    user_id: String,
+    rooms: Vec<String>,
+}
+
+impl Account {
+    fn from_config(account: &Yaml) -> Option<Account> {
+        let string = |key: &str| match account[key].as_str() {
+            Some(s) => Some(s.to_owned()),
+            None => {
+                error!("Matrix account is missing {}", key);
+                None
+            }
+        };
+
+        Some(Account {
+            network: string("network")?,
+            homeserver: string("homeserver")?,
+            access_token: string("access_token")?,
+            user_id: string("user_id")?,
+            rooms: account["rooms"]
+                .as_vec()
+                .map(|r| {
+                    r.iter()
+                        .filter_map(|r| r.as_str())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Client-server API endpoint, with the path segments percent-encoded
+    fn endpoint(&self, segments: &[&str]) -> Option<Url> {
+        let mut url = Url::parse(&self.homeserver).ok()?;
+        url.path_segments_mut()
+            .ok()?
+            .pop_if_empty()
+            .extend(&["_matrix", "client", "v3"])
+            .extend(segments);
+        Some(url)
+    }
+}
+
+/// ("alice", "example.org") from "@alice:example.org"
+fn split_user_id(user_id: &str) -> Option<(&str, &str)> {
+    user_id.strip_prefix('@')?.split_once(':')
+}
+
+/// The mask message_handler sees for a Matrix user
+fn user_mask(user_id: &str) -> Option<String> {
+    let (localpart, server) = split_user_id(user_id)?;
+    Some(format!("{}!{}@{}", localpart, localpart, server))
+}
+
+/// Admin masks of the Matrix accounts, by network
+pub fn admins(config: &Yaml) -> HashMap<String, Vec<String>> {
+    let mut admins = HashMap::new();
+    for account in config["matrix"].as_vec().into_iter().flatten() {
+        if let Some(network) = account["network"].as_str() {
+            let masks = account["admins"]
+                .as_vec()
+                .into_iter()
+                .flatten()
+                .filter_map(|a| a.as_str())
+                .filter_map(|a| {
+                    let mask = user_mask(a);
+                    if mask.is_none() {
+                        warn!("Invalid Matrix user id {}", a);
+                    }
+                    mask
+                })
+                .collect();
+            admins.insert(network.to_owned(), masks);
+        }
+    }
+
+    admins
+}
+
+/// Body of a reply without the quote of the original message
+fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while let Some(line_end) = rest.find('\n') {
+        if !rest.starts_with('>') {
+            break;
+        }
+        rest = &rest[line_end + 1..];
+    }
+
+    rest.trim_start_matches('\n')
+}
+
+/// next_batch and the text messages of other users as IRC messages
+fn parse_sync(body: &serde_json::Value, own_user_id: &str) -> (Option<String>, Vec<Message>) {
+    let next_batch = body["next_batch"].as_str().map(str::to_owned);
+
+    let mut messages = vec![];
+    if let Some(rooms) = body["rooms"]["join"].as_object() {
+        for (room_id, room) in rooms {
+            for event in room["timeline"]["events"].as_array().into_iter().flatten() {
+                let content = &event["content"];
+                if event["type"] != "m.room.message" || content["msgtype"] != "m.text" {
+                    continue;
+                }
+                let sender = match event["sender"].as_str() {
+                    Some(s) if s != own_user_id => s,
+                    _ => continue,
+                };
+                let (localpart, server) = match split_user_id(sender) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let body = match content["body"].as_str() {
+                    Some(b) => b,
+                    None => continue,
+                };
+                let body = if content["m.relates_to"]["m.in_reply_to"].is_object() {
+                    strip_reply_fallback(body)
+                } else {
+                    body
+                };
+
+                messages.push(Message {
+                    tags: None,
+                    prefix: Some(Prefix::Nickname(
+                        localpart.to_owned(),
+                        localpart.to_owned(),
+                        server.to_owned(),
+                    )),
+                    command: Command::PRIVMSG(room_id.to_owned(), body.replace('\n', " ")),
+                });
+            }
+        }
+    }
+
+    (next_batch, messages)
+}
+
+async fn matrix_request(
+    request: reqwest::RequestBuilder,
+    account: &Account,
+    content: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    let request = match content {
+        Some(c) => request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(c.to_string()),
+        None => request,
+    };
+    let response = http_client::send(request.bearer_auth(&account.access_token))
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let body: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!(
+            "{} {}",
+            status,
+            body["error"].as_str().unwrap_or_default()
+        ));
+    }
+
+    Ok(body)
+}
+
+/// Joins the configured rooms, returns room ids by the configured names
+async fn join_rooms(account: &Account) -> HashMap<String, String> {
+    let mut joined = HashMap::new();
+    for room in &account.rooms {
+        let url = match account.endpoint(&["join", room]) {
+            Some(u) => u,
+            None => {
+                error!("Invalid Matrix homeserver {}", account.homeserver);
+                break;
+            }
+        };
+        match matrix_request(HTTP_CLIENT.post(url), account, Some(json!({}))).await {
+            Ok(body) => {
+                if let Some(room_id) = body["room_id"].as_str() {
+                    info!("Joined {} on {}", room, account.network);
+                    joined.insert(room.to_owned(), room_id.to_owned());
+                }
+            }
+            Err(e) => error!("Could not join {} on {}: {}", room, account.network, e),
+        }
+    }
+
+    joined
+}
+
+async fn sync_loop(account: Arc<Account>, input: mpsc::Sender<(String, Message)>) {
+    let mut since: Option<String> = None;
+    loop {
+        let url = match account.endpoint(&["sync"]) {
+            Some(u) => u,
+            None => return,
+        };
+        // The first sync only finds out where to start, old messages are skipped
+        let mut query = vec![("filter", SYNC_FILTER.to_owned())];
+        if let Some(since) = &since {
+            query.push(("since", since.to_owned()));
+            query.push(("timeout", SYNC_TIMEOUT_MS.to_string()));
+        }
+        let request = HTTP_CLIENT
+            .get(url)
+            .query(&query)
+            .timeout(Duration::from_millis(SYNC_TIMEOUT_MS) + Duration::from_secs(30));
+
+        match matrix_request(request, &account, None).await {
+            Ok(body) => {
+                health::set_connected(&account.network, true);
+                let (next_batch, messages) = parse_sync(&body, &account.user_id);
+                if since.is_some() {
+                    for message in messages {
+                        if input
+                            .send((account.network.to_owned(), message))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+                if next_batch.is_some() {
+                    since = next_batch;
+                }
+            }
+            Err(e) => {
+                error!("Matrix sync on {} failed: {}", account.network, e);
+                health::set_connected(&account.network, false);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn send_loop(
+    account: Arc<Account>,
+    mut actions: mpsc::Receiver<BotAction>,
+    rooms: HashMap<String, String>,
+) {
+    static TRANSACTION: AtomicU64 = AtomicU64::new(0);
+    let started = chrono::Utc::now().timestamp_millis();
+
+    while let Some(action) = actions.recv().await {
+        let channel = &action.target.channel;
+        let room_id = match rooms.get(channel) {
+            Some(id) => id,
+            None if channel.starts_with('!') => channel,
+            None => {
+                warn!("Not in Matrix room {} on {}", channel, account.network);
+                continue;
+            }
+        };
+        let (msgtype, text) = match &action.action_type {
+            ActionType::Message(t) => ("m.text", t),
+            ActionType::Action(t) => ("m.emote", t),
+            ActionType::Notice(t) => ("m.notice", t),
+        };
+        let txn = format!(
+            "tbotti{}.{}",
+            started,
+            TRANSACTION.fetch_add(1, Ordering::Relaxed)
+        );
+        let url = match account.endpoint(&["rooms", room_id, "send", "m.room.message", &txn]) {
+            Some(u) => u,
+            None => continue,
+        };
+        let content = json!({"msgtype": msgtype, "body": text});
+        if let Err(e) = matrix_request(HTTP_CLIENT.put(url), &account, Some(content)).await {
+            error!(
+                "Could not send to {} on {}: {}",
+                room_id, account.network, e
+            );
+        }
+    }
+}
+
+async fn account_loop(
+    account: Account,
+    input: mpsc::Sender<(String, Message)>,
+    actions: mpsc::Receiver<BotAction>,
+) {
+    let rooms = join_rooms(&account).await;
+    let mut room_ids: Vec<String> = rooms.values().cloned().collect();
+    room_ids.dedup();
+    health::set_channels(&account.network, room_ids);
+
+    let account = Arc::new(account);
+    let sync_account = account.clone();
+    let sync = tokio::spawn(async move { sync_loop(sync_account, input).await });
+    send_loop(account, actions, rooms).await;
+    sync.abort();
+}
+
+pub async fn matrix_loop(
+    input: mpsc::Sender<(String, Message)>,
+    mut actions: mpsc::Receiver<BotAction>,
+    config: Arc<Yaml>,
+) {
+    let mut senders: HashMap<String, mpsc::Sender<BotAction>> = HashMap::new();
+    for account in config["matrix"].as_vec().into_iter().flatten() {
+        let account = match Account::from_config(account) {
+            Some(a) => a,
+            None => continue,
+        };
+        health::set_connected(&account.network, false);
+        let (tx, rx) = mpsc::channel(10);
+        senders.insert(account.network.to_owned(), tx);
+        let account_input = input.clone();
+        tokio::spawn(async move { account_loop(account, account_input, rx).await });
+    }
+
+    while let Some(action) = actions.recv().await {
+        if let Some(sender) = senders.get(&action.target.network) {
+            let _ = sender.send(action).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        assert_eq!(
+            user_mask("@alice:example.org"),
+            Some("alice!alice@example.org".to_owned())
+        );
+        assert_eq!(user_mask("alice"), None);
+
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "matrix:\n  - network: matrix\n    admins:\n      - '@owner:example.org'",
+        )
+        .unwrap();
+        assert_eq!(
+            admins(&config[0])["matrix"],
+            vec!["owner!owner@example.org".to_owned()]
+        );
+    }
+
+    #[test]
+    fn sync_messages() {
+        let body = json!({
+            "next_batch": "s72595_4483_1934",
+            "rooms": {"join": {"!abc:example.org": {"timeline": {"events": [
+                {"type": "m.room.message", "sender": "@alice:example.org",
+                 "content": {"msgtype": "m.text", "body": ".sää Tampere"}},
+                {"type": "m.room.message", "sender": "@bot:example.org",
+                 "content": {"msgtype": "m.text", "body": "Tampere: 3 °C"}},
+                {"type": "m.room.message", "sender": "@bob:matrix.org",
+                 "content": {"msgtype": "m.image", "body": "cat.jpg"}},
+                {"type": "m.room.message", "sender": "@bob:matrix.org",
+                 "content": {"msgtype": "m.text",
+                             "body": "> <@alice:example.org> .sää Tampere\n\nkiitti",
+                             "m.relates_to": {"m.in_reply_to": {"event_id": "$1"}}}},
+            ]}}}}
+        });
+
+        let (next_batch, messages) = parse_sync(&body, "@bot:example.org");
+        assert_eq!(next_batch.as_deref(), Some("s72595_4483_1934"));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].prefix,
+            Some(Prefix::Nickname(
+                "alice".to_owned(),
+                "alice".to_owned(),
+                "example.org".to_owned()
+            ))
+        );
+        assert_eq!(
+            messages[0].command,
+            Command::PRIVMSG("!abc:example.org".to_owned(), ".sää Tampere".to_owned())
+        );
+        assert_eq!(
+            messages[1].command,
+            Command::PRIVMSG("!abc:example.org".to_owned(), "kiitti".to_owned())
+        );
+    }
+
+    #[test]
+    fn endpoints() {
+        let account = Account {
+            network: "matrix".to_owned(),
+            homeserver: "https://matrix.example.org/".to_owned(),
+            access_token: String::new(),
+            user_id: "@bot:example.org".to_owned(),
+            rooms: vec![],
+        };
+        assert_eq!(
+            account
+                .endpoint(&["join", "#tbotti:example.org"])
+                .unwrap()
+                .as_str(),
+            "https://matrix.example.org/_matrix/client/v3/join/%23tbotti:example.org"
+        );
+    }
+}
@@ -14,24 +14,54 @@ use tokio::sync::{mpsc, oneshot};
 
 use yaml_rust::yaml::Yaml;
 
+use crate::bensa::command_bensa;
+use crate::birthday::command_birthday;
 use crate::blitzortung::command_ukkostutka;
 use crate::botaction::{ActionType, BotAction};
+use crate::calc::command_calc;
+use crate::chatlog::log_message;
+use crate::digitraffic::{command_juna, command_tiesaa};
+use crate::eightball::command_8ball;
 use crate::epic::command_epic;
-use crate::fmi::command_fmi;
+use crate::f1::command_f1;
+use crate::factoids::{command_factoids, command_forget, command_learn, handle_factoid};
+use crate::finnkino::command_leffat;
+use crate::flip::command_flip;
+use crate::fmi::{command_fmi, command_meri, command_minmax};
+use crate::fmi_warnings::command_varoitukset;
+use crate::free_games::command_ilmaispelit;
 use crate::gdq::command_gdq;
-use crate::h33h3::handle_h33h3;
-use crate::openweathermap::command_openweathermap;
-use crate::roll::command_roll;
+use crate::h33h3::{fun_trigger_roll, handle_h33h3, is_trigger};
+use crate::hockey::{command_liiga, command_nhl};
+use crate::iss::{command_astronauts, command_iss};
+use crate::karma::{command_karma, handle_karma};
+use crate::kirja::{command_kirja, handle_isbns};
+use crate::leaderboard::command_top;
+use crate::links::command_links;
+use crate::lotto::command_lotto;
+use crate::openweathermap::{command_forecast, command_openweathermap};
+use crate::poll::{command_poll, command_vote};
+use crate::roll::{command_choose, command_roll};
 use crate::rss::command_rss;
 use crate::sahko::command_sahko;
-use crate::timer::{command_bigone, command_pizza, command_timer, TimerEvent};
+use crate::sana::command_sana;
+use crate::sanuli::{command_sanuli, command_wordle};
+use crate::seen::{command_seen, track_activity};
+use crate::sun::command_aurinko;
+use crate::tell::{command_tell, deliver_tells};
+use crate::timer::{command_bigone, command_pizza, command_snooze, command_timer, TimerEvent};
+use crate::timezone::command_tz;
+use crate::tmdb::command_movie;
+use crate::trivia::{command_trivia, handle_trivia_answer};
 use crate::ts3::command_ts;
+use crate::tutka::command_tutka;
 use crate::tvmaze::command_ep;
-use crate::urltitle::handle_url_titles;
+use crate::twitch::{command_live, command_twitch};
+use crate::urltitle::{command_title, handle_url_titles};
 use crate::weather_db::command_weatherset;
 use crate::wikipedia::{command_wikipedia, command_wikipediafi};
 use crate::wolfram_alpha::command_wa;
-use crate::{ClientQuery, IrcChannel};
+use crate::{ChatTarget, ClientQuery};
 
 const COMMAND_PREFIX: char = '.';
 
@@ -41,7 +71,7 @@ lazy_static! {
 
 async fn command_echo(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
     prefix: Option<Prefix>,
 ) {
@@ -61,8 +91,8 @@ async fn command_echo(
 }
 
 async fn is_admin(
-    clientquery_sender: mpsc::Sender<ClientQuery>,
-    prefix: Option<Prefix>,
+    clientquery_sender: &mpsc::Sender<ClientQuery>,
+    prefix: &Option<Prefix>,
     network: &str,
 ) -> bool {
     let mask = match prefix {
@@ -89,11 +119,12 @@ async fn is_admin(
     ret
 }
 
-async fn handle_command(
+/// Runs `message` as a command, e.g. ".sää Tampere", and sends the replies to `bot_sender`
+pub async fn handle_command(
     bot_sender: mpsc::Sender<BotAction>,
     timer_sender: mpsc::Sender<TimerEvent>,
     clientquery_sender: mpsc::Sender<ClientQuery>,
-    source: IrcChannel,
+    source: ChatTarget,
     message: &str,
     prefix: Option<Prefix>,
     config: Arc<Yaml>,
@@ -114,59 +145,199 @@ async fn handle_command(
             command_echo(bot_sender, source, params, prefix).await;
         }
         "timer" => {
-            command_timer(bot_sender, timer_sender, source, params, prefix).await;
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_timer(bot_sender, timer_sender, source, params, prefix, admin).await;
+        }
+        "snooze" => {
+            command_snooze(bot_sender, timer_sender, source, params, prefix).await;
         }
         "pizza" => {
             command_pizza(bot_sender, timer_sender, source, prefix).await;
         }
+        "tz" => {
+            command_tz(bot_sender, source, prefix, params).await;
+        }
+        "tell" | "remind" => {
+            command_tell(bot_sender, source, prefix, params).await;
+        }
         "bigone" => {
             command_bigone(bot_sender, timer_sender, source, prefix).await;
         }
         "rss" => {
-            if is_admin(clientquery_sender, prefix, &source.network).await {
-                command_rss(bot_sender, source, params).await;
+            if is_admin(&clientquery_sender, &prefix, &source.network).await {
+                command_rss(bot_sender, source, params, prefix).await;
+            }
+        }
+        "twitch" => {
+            if is_admin(&clientquery_sender, &prefix, &source.network).await {
+                command_twitch(bot_sender, source, params).await;
             }
         }
+        "live" => {
+            command_live(bot_sender, source, config).await;
+        }
         "sää" | "saa" | "fmi" => {
-            command_fmi(bot_sender, source, prefix, params).await;
+            command_fmi(bot_sender, source, prefix, params, config).await;
+        }
+        "lämpöennätys" | "lampoennatys" | "minmax" => {
+            command_minmax(bot_sender, source, config).await;
+        }
+        "meri" => {
+            command_meri(bot_sender, source, params).await;
+        }
+        "varoitukset" => {
+            command_varoitukset(bot_sender, source, params).await;
         }
         "weather" | "owm" => {
             command_openweathermap(bot_sender, source, prefix, params, config).await;
         }
+        "forecast" => {
+            command_forecast(bot_sender, source, prefix, params, config).await;
+        }
+        "tiesää" | "tiesaa" => {
+            command_tiesaa(bot_sender, source, params).await;
+        }
+        "bensa" => {
+            command_bensa(bot_sender, source, params, config).await;
+        }
+        "juna" => {
+            command_juna(bot_sender, source, params).await;
+        }
+        "aurinko" => {
+            command_aurinko(bot_sender, source, prefix, params).await;
+        }
         "weatherset" => {
-            command_weatherset(bot_sender, source, prefix, params).await;
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_weatherset(bot_sender, source, prefix, params, admin).await;
         }
         "roll" => {
             command_roll(bot_sender, source, params).await;
         }
+        "choose" => {
+            command_choose(bot_sender, source, params).await;
+        }
+        "flip" => {
+            command_flip(bot_sender, source).await;
+        }
+        "8ball" => {
+            command_8ball(bot_sender, source, params, config).await;
+        }
         "ep" => {
-            command_ep(bot_sender, source, params).await;
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_ep(bot_sender, source, prefix, params, admin).await;
+        }
+        "movie" => {
+            command_movie(bot_sender, source, params, config).await;
+        }
+        "leffat" => {
+            command_leffat(bot_sender, source, params, config).await;
+        }
+        "calc" => {
+            command_calc(bot_sender, source, params, config).await;
         }
         "wa" => {
             command_wa(bot_sender, source, params, config).await;
         }
         "wikipedia" => {
-            command_wikipedia(bot_sender, source, params).await;
+            command_wikipedia(bot_sender, source, params, config).await;
         }
         "wikipediafi" => {
-            command_wikipediafi(bot_sender, source, params).await;
+            command_wikipediafi(bot_sender, source, params, config).await;
         }
         "epic" => {
-            command_epic(bot_sender, source).await;
+            command_epic(bot_sender, source, config).await;
+        }
+        "ilmaispelit" => {
+            command_ilmaispelit(bot_sender, source, config).await;
         }
         "ts" => {
-            command_ts(bot_sender, source, config).await;
+            command_ts(bot_sender, source, params, config).await;
+        }
+        "tutka" => {
+            command_tutka(bot_sender, source, prefix, params).await;
         }
         "ukkostutka" | "blitzortung" => {
-            command_ukkostutka(bot_sender, source, params).await;
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_ukkostutka(bot_sender, source, params, config, admin).await;
+        }
+        "liiga" => {
+            command_liiga(bot_sender, source, params, config).await;
+        }
+        "nhl" => {
+            command_nhl(bot_sender, source, params, config).await;
+        }
+        "kirja" => {
+            command_kirja(bot_sender, source, params).await;
+        }
+        "sana" => {
+            command_sana(bot_sender, source, params).await;
+        }
+        "lotto" => {
+            command_lotto(bot_sender, source, params).await;
+        }
+        "iss" => {
+            command_iss(bot_sender, source, prefix, params).await;
+        }
+        "astronauts" | "astronautit" => {
+            command_astronauts(bot_sender, source).await;
+        }
+        "f1" => {
+            command_f1(bot_sender, source, prefix, params).await;
         }
         "agdq" | "sgdq" | "gdq" => {
-            command_gdq(bot_sender, source).await;
+            command_gdq(bot_sender, timer_sender, source, prefix, params, config).await;
+        }
+        "karma" => {
+            command_karma(bot_sender, source, params).await;
+        }
+        "learn" => {
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_learn(bot_sender, source, prefix, params, config, admin).await;
+        }
+        "forget" => {
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_forget(bot_sender, source, params, config, admin).await;
+        }
+        "factoids" => {
+            command_factoids(bot_sender, source, params).await;
+        }
+        "title" => {
+            command_title(bot_sender, source, params).await;
+        }
+        "links" => {
+            command_links(bot_sender, source, params).await;
+        }
+        "poll" => {
+            let admin = is_admin(&clientquery_sender, &prefix, &source.network).await;
+            command_poll(bot_sender, source, prefix, params, admin).await;
+        }
+        "vote" => {
+            command_vote(bot_sender, source, prefix, params).await;
+        }
+        "trivia" => {
+            command_trivia(bot_sender, source, params, config).await;
+        }
+        "top" => {
+            command_top(bot_sender, source, params).await;
+        }
+        "sanuli" => {
+            command_sanuli(bot_sender, source, prefix, params, config).await;
+        }
+        "wordle" => {
+            command_wordle(bot_sender, source, prefix, params, config).await;
+        }
+        "birthday" | "synttärit" => {
+            command_birthday(bot_sender, source, prefix, params).await;
+        }
+        "seen" => {
+            command_seen(bot_sender, source, prefix, params).await;
         }
         "sähkö" | "sahko" => {
-            command_sahko(bot_sender, source, config).await;
+            command_sahko(bot_sender, source, params, config).await;
+        }
+        _ => {
+            handle_factoid(bot_sender, source, command, false).await;
         }
-        _ => {}
     }
 }
 
@@ -178,6 +349,12 @@ pub async fn message_handler(
     config: Arc<Yaml>,
 ) {
     while let Some((network, message)) = receiver.recv().await {
+        let seen_network = network.to_owned();
+        let seen_message = message.clone();
+        tokio::spawn(async move {
+            track_activity(seen_network, seen_message).await;
+        });
+
         if let Command::PRIVMSG(_, msg) = &message.command {
             let msg_lower = msg.to_lowercase();
             let channel = match message.response_target() {
@@ -187,15 +364,93 @@ pub async fn message_handler(
                 }
             };
 
-            if RE_URL.is_match(msg) {
+            if let Some(Prefix::Nickname(nick, user, host)) = &message.prefix {
+                let prefix = Some(Prefix::Nickname(
+                    nick.to_owned(),
+                    user.to_owned(),
+                    host.to_owned(),
+                ));
+                let new_sender = sender.clone();
+                let source = ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                };
+                tokio::spawn(async move {
+                    deliver_tells(new_sender, source, prefix).await;
+                });
+            }
+
+            // .title fetches the title itself
+            let title_command = msg_lower.split_whitespace().next() == Some(".title");
+            if RE_URL.is_match(msg) && !title_command {
                 let snd = sender.clone();
                 let msg_copy = String::from(msg);
-                let source = IrcChannel {
+                let source = ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                };
+                let nick = match &message.prefix {
+                    Some(Prefix::Nickname(nick, _, _)) => Some(nick.to_owned()),
+                    _ => None,
+                };
+                tokio::spawn(async move {
+                    handle_url_titles(snd, source, nick, &msg_copy).await;
+                });
+            }
+
+            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
+                let cfg = config.clone();
+                let nick_copy = nick.to_owned();
+                let msg_copy = String::from(msg);
+                let source = ChatTarget {
                     network: network.to_owned(),
                     channel: channel.to_owned(),
                 };
                 tokio::spawn(async move {
-                    handle_url_titles(snd, source, &msg_copy).await;
+                    log_message(cfg, source, &nick_copy, &msg_copy).await;
+                });
+            }
+
+            if ChatTarget::is_channel_name(channel) && !msg_lower.starts_with(COMMAND_PREFIX) {
+                if let Some(Prefix::Nickname(nick, user, host)) = &message.prefix {
+                    let nick_copy = nick.to_owned();
+                    let user_host = format!("{}@{}", user, host);
+                    let msg_copy = String::from(msg);
+                    let source = ChatTarget {
+                        network: network.to_owned(),
+                        channel: channel.to_owned(),
+                    };
+                    let new_sender = sender.clone();
+                    let trivia_source = ChatTarget {
+                        network: source.network.to_owned(),
+                        channel: source.channel.to_owned(),
+                    };
+                    let isbn_sender = sender.clone();
+                    let isbn_source = ChatTarget {
+                        network: source.network.to_owned(),
+                        channel: source.channel.to_owned(),
+                    };
+                    let isbn_msg = String::from(msg);
+                    tokio::spawn(async move {
+                        handle_trivia_answer(new_sender, trivia_source, &nick_copy, &msg_copy)
+                            .await;
+                        handle_karma(source, &nick_copy, &user_host, &msg_copy).await;
+                    });
+                    tokio::spawn(async move {
+                        handle_isbns(isbn_sender, isbn_source, &isbn_msg).await;
+                    });
+                }
+            }
+
+            if let Some(key) = msg.strip_prefix("??") {
+                let new_sender = sender.clone();
+                let key = key.to_owned();
+                let source = ChatTarget {
+                    network: network.to_owned(),
+                    channel: channel.to_owned(),
+                };
+                tokio::spawn(async move {
+                    handle_factoid(new_sender, source, &key, true).await;
                 });
             }
 
@@ -212,7 +467,7 @@ pub async fn message_handler(
                 let new_timer_sender = timer_sender.clone();
                 let new_cq_sender = clientquery_sender.clone();
                 let msg_copy = String::from(msg);
-                let source = IrcChannel {
+                let source = ChatTarget {
                     network: network.to_owned(),
                     channel: channel.to_owned(),
                 };
@@ -231,26 +486,28 @@ pub async fn message_handler(
                 });
             }
 
-            if msg_lower == "h33h3" {
+            let source = ChatTarget {
+                network: network.to_owned(),
+                channel: channel.to_owned(),
+            };
+
+            if is_trigger(&config, &msg_lower) && fun_trigger_roll(&config, &source) {
                 if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
                     let nick_copy = nick.to_owned();
                     let new_sender = sender.clone();
-                    let source = IrcChannel {
+                    let source = ChatTarget {
                         network: network.to_owned(),
                         channel: channel.to_owned(),
                     };
+                    let cfg = config.clone();
                     tokio::spawn(async move {
-                        handle_h33h3(new_sender, source, &nick_copy).await;
+                        handle_h33h3(new_sender, source, &nick_copy, cfg).await;
                     });
                 }
             }
 
-            if msg_lower.contains("matt damon") {
+            if msg_lower.contains("matt damon") && fun_trigger_roll(&config, &source) {
                 let s = sender.clone();
-                let source = IrcChannel {
-                    network: network.to_owned(),
-                    channel: channel.to_owned(),
-                };
                 let mattdamon = "MATT DAMON".to_owned();
                 let action = BotAction {
                     action_type: ActionType::Message(mattdamon),
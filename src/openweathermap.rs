@@ -2,15 +2,19 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use chrono::prelude::*;
 use irc::client::prelude::Prefix;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use yaml_rust::yaml::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::weather_db::get_location;
-use crate::IrcChannel;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::weather::{summer_humidex, WeatherError};
+use crate::weather_db::{get_location, get_units, Units};
+use crate::ChatTarget;
+
+const LOCATION_NOT_FOUND: &str = "Location not found";
 
 #[derive(Debug)]
 struct WeatherData {
@@ -21,22 +25,223 @@ struct WeatherData {
     humidity: Option<String>,
     cloudiness: Option<String>,
     description: Option<String>,
+    uv_index: Option<String>,
+    temp_min: Option<String>,
+    temp_max: Option<String>,
+    sunrise: Option<String>,
+    sunset: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct GeoLocation {
+    name: String,
+    country: String,
+    state: Option<String>,
+    lat: f64,
+    lon: f64,
 }
 
-async fn get_json(city: &str, apikey: &str) -> reqwest::Result<String> {
+#[derive(Debug, PartialEq)]
+struct DailyForecast {
+    day: String,
+    temp_min: String,
+    temp_max: String,
+    description: Option<String>,
+}
+
+async fn get_json(location: &GeoLocation, apikey: &str, units: Units) -> reqwest::Result<String> {
     let baseurl = "https://api.openweathermap.org/data/2.5/weather";
 
-    let json = HTTP_CLIENT
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("units", units.as_str()),
+        ("lat", location.lat.to_string().as_str()),
+        ("lon", location.lon.to_string().as_str()),
+        ("appid", apikey),
+    ]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+async fn get_geocoding_json(city: &str, apikey: &str) -> reqwest::Result<String> {
+    let baseurl = "https://api.openweathermap.org/geo/1.0/direct";
+
+    let request = HTTP_CLIENT
         .get(baseurl)
-        .query(&[("units", "metric"), ("q", city), ("appid", apikey)])
-        .send()
-        .await?
-        .text()
-        .await?;
+        .query(&[("q", city), ("limit", "5"), ("appid", apikey)]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+async fn get_onecall_json(
+    location: &GeoLocation,
+    apikey: &str,
+    units: Units,
+) -> reqwest::Result<String> {
+    let baseurl = "https://api.openweathermap.org/data/3.0/onecall";
+
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("lat", location.lat.to_string().as_str()),
+        ("lon", location.lon.to_string().as_str()),
+        ("units", units.as_str()),
+        ("exclude", "minutely,hourly,alerts"),
+        ("appid", apikey),
+    ]);
+    let json = http_client::send(request).await?.text().await?;
 
     Ok(json)
 }
 
+fn parse_geocoding(json_text: &str) -> Result<Vec<GeoLocation>, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let locations: Vec<GeoLocation> = json
+        .as_array()
+        .map(|a| {
+            a.iter()
+                .filter_map(|l| {
+                    Some(GeoLocation {
+                        name: l["name"].as_str()?.to_owned(),
+                        country: l["country"].as_str()?.to_owned(),
+                        state: l["state"].as_str().map(|s| s.to_owned()),
+                        lat: l["lat"].as_f64()?,
+                        lon: l["lon"].as_f64()?,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if locations.is_empty() {
+        return Err(LOCATION_NOT_FOUND.to_owned());
+    }
+
+    Ok(locations)
+}
+
+/// Pick the location the user meant, or list the alternatives if the
+/// query is ambiguous and has no country hint like "Paris,US"
+fn choose_location(query: &str, mut locations: Vec<GeoLocation>) -> Result<GeoLocation, String> {
+    if !query.contains(',') {
+        let mut alternatives: Vec<String> = Vec::new();
+        for l in &locations {
+            let alternative = match &l.state {
+                Some(state) => format!("{}, {} ({})", l.name, l.country, state),
+                None => format!("{}, {}", l.name, l.country),
+            };
+            if !alternatives.contains(&alternative) {
+                alternatives.push(alternative);
+            }
+        }
+
+        let countries: Vec<&str> = locations.iter().map(|l| l.country.as_str()).collect();
+        if alternatives.len() > 1 && countries.iter().any(|c| *c != countries[0]) {
+            return Err(format!(
+                "Multiple matches: {}. Try e.g. {},{}",
+                alternatives.join("; "),
+                query,
+                locations[1].country
+            ));
+        }
+    }
+
+    Ok(locations.remove(0))
+}
+
+async fn geocode(city: &str, apikey: &str) -> Result<GeoLocation, String> {
+    match get_geocoding_json(city, apikey).await {
+        Ok(json) => choose_location(city, parse_geocoding(&json)?),
+        Err(_) => Err("Unable to get location".to_owned()),
+    }
+}
+
+fn parse_onecall(
+    json_text: &str,
+    location: &GeoLocation,
+) -> Result<(WeatherData, Vec<DailyForecast>), String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let current = &json["current"];
+    if current.is_null() {
+        return Err("No data found".to_owned());
+    }
+
+    let offset = json["timezone_offset"]
+        .as_i64()
+        .and_then(|o| FixedOffset::east_opt(o as i32))
+        .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    let local_time = |ts: Option<i64>| -> Option<DateTime<FixedOffset>> {
+        Utc.timestamp_opt(ts?, 0)
+            .single()
+            .map(|t| t.with_timezone(&offset))
+    };
+
+    let today = &json["daily"][0];
+
+    let data = WeatherData {
+        place: Some(format!("{}, {}", location.name, location.country)),
+        temperature: current["temp"].as_f64().map(|t| format!("{:.1}", t)),
+        wind: current["wind_speed"].as_f64().map(|w| format!("{:.1}", w)),
+        feels_like: current["feels_like"].as_f64().map(|f| format!("{:.1}", f)),
+        humidity: current["humidity"].as_i64().map(|h| format!("{}", h)),
+        cloudiness: current["clouds"].as_i64().map(|c| format!("{}", c)),
+        description: current["weather"][0]["description"]
+            .as_str()
+            .map(|d| d.to_string()),
+        uv_index: current["uvi"].as_f64().map(|u| format!("{:.1}", u)),
+        temp_min: today["temp"]["min"].as_f64().map(|t| format!("{:.1}", t)),
+        temp_max: today["temp"]["max"].as_f64().map(|t| format!("{:.1}", t)),
+        sunrise: local_time(current["sunrise"].as_i64()).map(|t| t.format("%H:%M").to_string()),
+        sunset: local_time(current["sunset"].as_i64()).map(|t| t.format("%H:%M").to_string()),
+    };
+
+    let mut forecasts = Vec::new();
+    if let Some(daily) = json["daily"].as_array() {
+        for d in daily.iter().skip(1).take(3) {
+            if let (Some(day), Some(min), Some(max)) = (
+                local_time(d["dt"].as_i64()),
+                d["temp"]["min"].as_f64(),
+                d["temp"]["max"].as_f64(),
+            ) {
+                forecasts.push(DailyForecast {
+                    day: day.format("%a").to_string(),
+                    temp_min: format!("{:.0}", min),
+                    temp_max: format!("{:.0}", max),
+                    description: d["weather"][0]["description"]
+                        .as_str()
+                        .map(|s| s.to_string()),
+                });
+            }
+        }
+    }
+
+    Ok((data, forecasts))
+}
+
+async fn get_onecall(
+    city: &str,
+    apikey: &str,
+    units: Units,
+) -> Result<(WeatherData, Vec<DailyForecast>), String> {
+    let location = geocode(city, apikey).await?;
+
+    match get_onecall_json(&location, apikey, units).await {
+        Ok(json) => parse_onecall(&json, &location),
+        Err(_) => Err("Unable to get weather data".to_owned()),
+    }
+}
+
 fn parse_json(json_text: &str) -> Result<WeatherData, String> {
     let mut place = None;
     let mut temperature = None;
@@ -104,23 +309,53 @@ fn parse_json(json_text: &str) -> Result<WeatherData, String> {
         humidity,
         cloudiness,
         description,
+        uv_index: None,
+        temp_min: None,
+        temp_max: None,
+        sunrise: None,
+        sunset: None,
     })
 }
 
-fn generate_msg(data: WeatherData) -> String {
+/// Temperature and speed units OWM uses for each unit system
+fn unit_labels(units: Units) -> (&'static str, &'static str) {
+    match units {
+        Units::Metric => ("°C", "m/s"),
+        Units::Imperial => ("°F", "mph"),
+    }
+}
+
+fn generate_msg(data: WeatherData, units: Units) -> String {
+    let (deg, speed) = unit_labels(units);
     let mut msg = String::new();
 
     if let Some(p) = data.place {
         msg.push_str(&format!("{}: ", p));
     }
-    if let Some(t) = data.temperature {
-        msg.push_str(&format!("temperature: {}°C, ", t));
+    if let Some(t) = &data.temperature {
+        msg.push_str(&format!("temperature: {}{}, ", t, deg));
     }
     if let Some(f) = data.feels_like {
-        msg.push_str(&format!("feels like: {}°C, ", f));
+        msg.push_str(&format!("feels like: {}{}, ", f, deg));
+    }
+    if let (Some(Ok(t)), Some(Ok(h))) = (
+        data.temperature.as_ref().map(|t| t.parse::<f64>()),
+        data.humidity.as_ref().map(|h| h.parse::<f64>()),
+    ) {
+        let celsius = match units {
+            Units::Metric => t,
+            Units::Imperial => (t - 32.0) * 5.0 / 9.0,
+        };
+        if let Some(humidex) = summer_humidex(celsius, h) {
+            let humidex = match units {
+                Units::Metric => humidex,
+                Units::Imperial => humidex * 9.0 / 5.0 + 32.0,
+            };
+            msg.push_str(&format!("humidex: {:.1}{}, ", humidex, deg));
+        }
     }
     if let Some(w) = data.wind {
-        msg.push_str(&format!("wind speed: {}m/s, ", w));
+        msg.push_str(&format!("wind speed: {}{}, ", w, speed));
     }
     if let Some(h) = data.humidity {
         msg.push_str(&format!("humidity: {}%, ", h));
@@ -128,6 +363,15 @@ fn generate_msg(data: WeatherData) -> String {
     if let Some(c) = data.cloudiness {
         msg.push_str(&format!("cloudiness: {}%, ", c));
     }
+    if let (Some(min), Some(max)) = (data.temp_min, data.temp_max) {
+        msg.push_str(&format!("today: {}…{}{}, ", min, max, deg));
+    }
+    if let Some(u) = data.uv_index {
+        msg.push_str(&format!("UV index: {}, ", u));
+    }
+    if let (Some(rise), Some(set)) = (data.sunrise, data.sunset) {
+        msg.push_str(&format!("sun: {}–{}, ", rise, set));
+    }
     if let Some(d) = data.description {
         msg.push_str(&d);
     }
@@ -139,15 +383,40 @@ fn generate_msg(data: WeatherData) -> String {
     msg
 }
 
-pub async fn command_openweathermap(
+fn generate_forecast_msg(
+    place: Option<String>,
+    forecasts: &[DailyForecast],
+    units: Units,
+) -> String {
+    let (deg, _) = unit_labels(units);
+
+    if forecasts.is_empty() {
+        return "No forecast found".to_owned();
+    }
+
+    let days: Vec<String> = forecasts
+        .iter()
+        .map(|f| match &f.description {
+            Some(d) => format!("{} {}…{}{} {}", f.day, f.temp_min, f.temp_max, deg, d),
+            None => format!("{} {}…{}{}", f.day, f.temp_min, f.temp_max, deg),
+        })
+        .collect();
+
+    match place {
+        Some(p) => format!("{}: {}", p, days.join(", ")),
+        None => days.join(", "),
+    }
+}
+
+pub async fn command_forecast(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     prefix: Option<Prefix>,
     params: &str,
     config: Arc<Yaml>,
 ) {
     let location = match params {
-        "" => get_location(&prefix, &source.network),
+        "" => get_location(&prefix, &source).await,
         _ => params.to_owned(),
     };
 
@@ -158,13 +427,87 @@ pub async fn command_openweathermap(
         }
     };
 
-    let msg = if let Ok(json) = get_json(&location, apikey).await {
-        match parse_json(&json) {
-            Ok(data) => generate_msg(data),
-            Err(_) => "Unable to get weather data".to_owned(),
+    let units = get_units(&prefix, &source).await;
+
+    let msg = match get_onecall(&location, apikey, units).await {
+        Ok((data, forecasts)) => generate_forecast_msg(data.place, &forecasts, units),
+        Err(e) => e,
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+/// Only an unknown place lets another weather provider try
+fn weather_error(e: String) -> WeatherError {
+    match e.as_str() {
+        LOCATION_NOT_FOUND => WeatherError::NotFound(e),
+        _ => WeatherError::Failed(e),
+    }
+}
+
+/// Current weather for `location`, or an error telling whether OWM knows the place at all
+pub async fn current_weather(
+    location: &str,
+    config: &Yaml,
+    units: Units,
+) -> Result<String, WeatherError> {
+    let apikey = match config["openweathermap"]["apikey"].as_str() {
+        Some(a) => a,
+        _ => {
+            return Err(WeatherError::Failed(
+                "OpenWeatherMap is not configured".to_owned(),
+            ));
+        }
+    };
+
+    // One Call needs a separate subscription, so it is opt-in
+    if config["openweathermap"]["onecall"].as_bool() == Some(true) {
+        match get_onecall(location, apikey, units).await {
+            Ok((data, _)) => Ok(generate_msg(data, units)),
+            Err(e) => Err(weather_error(e)),
         }
     } else {
-        "Unable to get weather data".to_owned()
+        let geo = geocode(location, apikey).await.map_err(weather_error)?;
+        match get_json(&geo, apikey, units).await {
+            Ok(json) => match parse_json(&json) {
+                Ok(data) => Ok(generate_msg(data, units)),
+                Err(_) => Err(WeatherError::Failed(
+                    "Unable to get weather data".to_owned(),
+                )),
+            },
+            Err(_) => Err(WeatherError::Failed(
+                "Unable to get weather data".to_owned(),
+            )),
+        }
+    }
+}
+
+pub async fn command_openweathermap(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let location = match params {
+        "" => get_location(&prefix, &source).await,
+        _ => params.to_owned(),
+    };
+
+    if config["openweathermap"]["apikey"].as_str().is_none() {
+        return;
+    }
+
+    let units = get_units(&prefix, &source).await;
+
+    let msg = match current_weather(&location, &config, units).await {
+        Ok(m) => m,
+        Err(e) => e.message(),
     };
 
     let action = BotAction {
@@ -178,6 +521,7 @@ pub async fn command_openweathermap(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::weather::first_weather;
 
     const TESTJSON: &str = r###"{"coord":{"lon":8.55,"lat":47.3667},"weather":[{"id":800,"main":"Clear","description":"clear sky","icon":"01d"}],"base":"stations","main":{"temp":10.76,"feels_like":7.57,"temp_min":9,"temp_max":12.78,"pressure":1029,"humidity":53},"visibility":10000,"wind":{"speed":2.06,"deg":350},"clouds":{"all":0},"dt":1614604333,"sys":{"type":1,"id":6932,"country":"CH","sunrise":1614578776,"sunset":1614618620},"timezone":3600,"id":2657896,"name":"Zurich","cod":200}"###;
 
@@ -192,7 +536,89 @@ mod tests {
         assert_eq!(data.cloudiness, Some("0".to_owned()));
         assert_eq!(data.description, Some("clear sky".to_owned()));
 
-        let msg = generate_msg(data);
+        let msg = generate_msg(data, Units::Metric);
         assert_eq!(msg, "Zurich, CH: temperature: 10.8°C, feels like: 7.6°C, wind speed: 2.1m/s, humidity: 53%, cloudiness: 0%, clear sky".to_owned());
+
+        let msg = generate_msg(parse_json(TESTJSON).unwrap(), Units::Imperial);
+        assert_eq!(msg, "Zurich, CH: temperature: 10.8°F, feels like: 7.6°F, wind speed: 2.1mph, humidity: 53%, cloudiness: 0%, clear sky".to_owned());
+    }
+
+    const GEOCODING_JSON: &str = r###"[{"name":"Zurich","local_names":{"de":"Zürich"},"lat":47.3744489,"lon":8.5410422,"country":"CH","state":"Zurich"}]"###;
+
+    const ONECALL_JSON: &str = r###"{"lat":47.3744,"lon":8.541,"timezone":"Europe/Zurich","timezone_offset":3600,"current":{"dt":1614604333,"sunrise":1614578776,"sunset":1614618620,"temp":10.76,"feels_like":7.57,"pressure":1029,"humidity":53,"uvi":2.31,"clouds":0,"wind_speed":2.06,"weather":[{"id":800,"main":"Clear","description":"clear sky","icon":"01d"}]},"daily":[{"dt":1614596400,"temp":{"min":1.2,"max":12.78},"weather":[{"description":"clear sky"}]},{"dt":1614682800,"temp":{"min":2.4,"max":11.1},"weather":[{"description":"light rain"}]},{"dt":1614769200,"temp":{"min":0.6,"max":8.5},"weather":[{"description":"overcast clouds"}]},{"dt":1614855600,"temp":{"min":-1.2,"max":6.4},"weather":[{"description":"snow"}]},{"dt":1614942000,"temp":{"min":-2.0,"max":4.0},"weather":[{"description":"snow"}]}]}"###;
+
+    #[test]
+    fn owm_onecall() {
+        let locations = parse_geocoding(GEOCODING_JSON).unwrap();
+        assert_eq!(locations[0].name, "Zurich");
+        assert_eq!(locations[0].country, "CH");
+        assert_eq!(parse_geocoding("[]"), Err("Location not found".to_owned()));
+
+        let (data, forecasts) = parse_onecall(ONECALL_JSON, &locations[0]).unwrap();
+        assert_eq!(data.uv_index, Some("2.3".to_owned()));
+        assert_eq!(data.sunrise, Some("07:06".to_owned()));
+        assert_eq!(data.sunset, Some("18:10".to_owned()));
+
+        let msg = generate_msg(data, Units::Metric);
+        assert_eq!(msg, "Zurich, CH: temperature: 10.8°C, feels like: 7.6°C, wind speed: 2.1m/s, humidity: 53%, cloudiness: 0%, today: 1.2…12.8°C, UV index: 2.3, sun: 07:06–18:10, clear sky");
+
+        assert_eq!(forecasts.len(), 3);
+        assert_eq!(
+            generate_forecast_msg(Some("Zurich, CH".to_owned()), &forecasts, Units::Metric),
+            "Zurich, CH: Tue 2…11°C light rain, Wed 1…8°C overcast clouds, Thu -1…6°C snow"
+        );
+    }
+
+    const PARIS_JSON: &str = r###"[{"name":"Paris","lat":48.8588897,"lon":2.3200410,"country":"FR","state":"Ile-de-France"},{"name":"Paris","lat":33.6617962,"lon":-95.5555130,"country":"US","state":"Texas"},{"name":"Paris","lat":38.2097987,"lon":-84.2529869,"country":"US","state":"Kentucky"}]"###;
+
+    #[test]
+    fn owm_disambiguation() {
+        assert_eq!(
+            choose_location("Paris", parse_geocoding(PARIS_JSON).unwrap()),
+            Err("Multiple matches: Paris, FR (Ile-de-France); Paris, US (Texas); Paris, US (Kentucky). Try e.g. Paris,US".to_owned())
+        );
+        // Same country only, e.g. the results for "Paris,US"
+        let json_us = r###"[{"name":"Paris","lat":33.6617962,"lon":-95.5555130,"country":"US","state":"Texas"},{"name":"Paris","lat":38.2097987,"lon":-84.2529869,"country":"US","state":"Kentucky"}]"###;
+        assert_eq!(
+            choose_location("Paris,US", parse_geocoding(json_us).unwrap())
+                .unwrap()
+                .state,
+            Some("Texas".to_owned())
+        );
+        assert_eq!(
+            choose_location("Zurich", parse_geocoding(GEOCODING_JSON).unwrap())
+                .unwrap()
+                .name,
+            "Zurich"
+        );
+    }
+
+    #[tokio::test]
+    async fn owm_error_after_fmi() {
+        let providers = ["FMI", "OpenWeatherMap"];
+        let fetch = |json: &'static str| {
+            move |provider| async move {
+                match provider {
+                    "FMI" => Err(WeatherError::NotFound("Tietoja ei löytynyt".to_owned())),
+                    _ => parse_geocoding(json)
+                        .and_then(|l| choose_location("Paris", l))
+                        .map(|l| l.name)
+                        .map_err(weather_error),
+                }
+            }
+        };
+
+        assert_eq!(
+            first_weather(&providers, fetch(PARIS_JSON)).await,
+            Err("Multiple matches: Paris, FR (Ile-de-France); Paris, US (Texas); Paris, US (Kentucky). Try e.g. Paris,US".to_owned())
+        );
+        assert_eq!(
+            first_weather(&providers, fetch("[]")).await,
+            Err(LOCATION_NOT_FOUND.to_owned())
+        );
+        assert_eq!(
+            first_weather(&providers, fetch(GEOCODING_JSON)).await,
+            Ok(("OpenWeatherMap", "Zurich".to_owned()))
+        );
     }
 }
@@ -0,0 +1,527 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::Utc;
+use core::time::Duration;
+use irc::client::prelude::Prefix;
+use log::{error, info};
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::timer::parse_duration;
+use crate::ChatTarget;
+
+const MAX_OPTIONS: usize = 10;
+
+#[derive(Debug, PartialEq)]
+struct Poll {
+    id: i64,
+    question: String,
+    options: Vec<String>,
+    creator: String,
+    closes: Option<i64>,
+}
+
+/// A poll to start, parsed from `[duration] "question" option | option`
+#[derive(Debug, PartialEq)]
+struct NewPoll {
+    question: String,
+    options: Vec<String>,
+    duration: Option<i64>,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "polls.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS polls (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            question TEXT NOT NULL,
+            options TEXT NOT NULL,
+            creator TEXT NOT NULL,
+            closes INTEGER,
+            open INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS votes (
+            poll_id INTEGER NOT NULL,
+            nick_lower TEXT NOT NULL,
+            option INTEGER NOT NULL,
+            PRIMARY KEY(poll_id, nick_lower)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// `10m "Mitä syödään?" pizza | kebab` -> question, options and duration in seconds
+fn parse_poll(params: &str) -> Option<NewPoll> {
+    let params = params.trim();
+    let (duration, rest) = match params.split_once(char::is_whitespace) {
+        Some((first, rest)) if !first.starts_with('"') => {
+            (Some(parse_duration(first)?.num_seconds()), rest.trim())
+        }
+        _ => (None, params),
+    };
+
+    let (question, options) = rest.strip_prefix('"')?.split_once('"')?;
+    let options: Vec<String> = options
+        .split('|')
+        .map(|o| o.trim().to_owned())
+        .filter(|o| !o.is_empty())
+        .collect();
+
+    if question.trim().is_empty()
+        || options.len() < 2
+        || options.len() > MAX_OPTIONS
+        || duration == Some(0)
+    {
+        return None;
+    }
+
+    Some(NewPoll {
+        question: question.trim().to_owned(),
+        options,
+        duration,
+    })
+}
+
+fn poll_from_row(row: &rusqlite::Row) -> Result<Poll> {
+    let options: String = row.get(2)?;
+
+    Ok(Poll {
+        id: row.get(0)?,
+        question: row.get(1)?,
+        options: options.split('\n').map(|o| o.to_owned()).collect(),
+        creator: row.get(3)?,
+        closes: row.get(4)?,
+    })
+}
+
+fn open_poll(conn: &Connection, source: &ChatTarget) -> Result<Option<Poll>> {
+    conn.query_row(
+        "SELECT id, question, options, creator, closes FROM polls
+        WHERE network = :network AND channel = :channel COLLATE NOCASE AND open",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+        },
+        poll_from_row,
+    )
+    .optional()
+}
+
+/// Returns the poll already open on the channel instead of starting another one
+fn start_poll(
+    conn: &Connection,
+    source: &ChatTarget,
+    creator: &str,
+    poll: &NewPoll,
+    now: i64,
+) -> Result<std::result::Result<Poll, Poll>> {
+    if let Some(existing) = open_poll(conn, source)? {
+        return Ok(Err(existing));
+    }
+
+    let closes = poll.duration.map(|d| now + d);
+    conn.execute(
+        "INSERT INTO polls (network, channel, question, options, creator, closes, open)
+        VALUES (:network, :channel, :question, :options, :creator, :closes, 1)",
+        named_params! {
+            ":network": source.network,
+            // Kept as given, Matrix room ids are case-sensitive
+            ":channel": source.channel,
+            ":question": poll.question,
+            ":options": poll.options.join("\n"),
+            ":creator": creator,
+            ":closes": closes,
+        },
+    )?;
+
+    Ok(Ok(Poll {
+        id: conn.last_insert_rowid(),
+        question: poll.question.to_owned(),
+        options: poll.options.clone(),
+        creator: creator.to_owned(),
+        closes,
+    }))
+}
+
+/// A later vote by the same nick replaces the earlier one
+fn vote(conn: &Connection, poll: &Poll, nick: &str, option: usize) -> Result<()> {
+    conn.execute(
+        "INSERT INTO votes (poll_id, nick_lower, option) VALUES (:poll_id, :nick_lower, :option)
+        ON CONFLICT(poll_id, nick_lower) DO UPDATE SET option = :option",
+        named_params! {
+            ":poll_id": poll.id,
+            ":nick_lower": nick.to_lowercase(),
+            ":option": option as i64,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Number of votes for each option
+fn results(conn: &Connection, poll: &Poll) -> Result<Vec<i64>> {
+    let mut counts = vec![0; poll.options.len()];
+
+    let mut statement = conn
+        .prepare("SELECT option, count(*) FROM votes WHERE poll_id = :poll_id GROUP BY option")?;
+    let mut rows = statement.query(named_params! { ":poll_id": poll.id })?;
+    while let Some(row) = rows.next()? {
+        let option: i64 = row.get(0)?;
+        if let Some(count) = counts.get_mut(option as usize) {
+            *count = row.get(1)?;
+        }
+    }
+
+    Ok(counts)
+}
+
+fn close(conn: &Connection, poll: &Poll) -> Result<()> {
+    conn.execute(
+        "UPDATE polls SET open = 0 WHERE id = :id",
+        named_params! { ":id": poll.id },
+    )?;
+
+    Ok(())
+}
+
+/// Open polls past their time limit with the channels they are on
+fn expired_polls(conn: &Connection, now: i64) -> Result<Vec<(ChatTarget, Poll)>> {
+    let mut statement = conn.prepare(
+        "SELECT id, question, options, creator, closes, network, channel FROM polls
+        WHERE open AND closes <= :now",
+    )?;
+    let mut rows = statement.query(named_params! { ":now": now })?;
+
+    let mut polls = Vec::new();
+    while let Some(row) = rows.next()? {
+        let source = ChatTarget {
+            network: row.get(5)?,
+            channel: row.get(6)?,
+        };
+        polls.push((source, poll_from_row(row)?));
+    }
+
+    Ok(polls)
+}
+
+fn start_msg(poll: &Poll, now: i64) -> String {
+    let options: Vec<String> = poll
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, o)| format!("{}) {}", i + 1, o))
+        .collect();
+    let mut msg = format!(
+        "Äänestys: {} {} – äänestä .vote <numero>",
+        poll.question,
+        options.join(" ")
+    );
+
+    if let Some(closes) = poll.closes {
+        msg.push_str(&format!(
+            " (sulkeutuu {} min kuluttua)",
+            ((closes - now) as f64 / 60.0).ceil()
+        ));
+    }
+
+    msg
+}
+
+fn results_msg(poll: &Poll, counts: &[i64], closed: bool) -> String {
+    let total: i64 = counts.iter().sum();
+    let list: Vec<String> = poll
+        .options
+        .iter()
+        .zip(counts)
+        .map(|(o, c)| format!("{} {}", o, c))
+        .collect();
+
+    format!(
+        "{}{}: {} ({} ääntä)",
+        if closed { "Äänestys päättyi! " } else { "" },
+        poll.question,
+        list.join(", "),
+        total
+    )
+}
+
+fn close_and_report(conn: &Connection, poll: &Poll) -> Result<String> {
+    close(conn, poll)?;
+
+    Ok(results_msg(poll, &results(conn, poll)?, true))
+}
+
+/// Closes polls whose time limit has passed, also those that ran out while
+/// the bot was down
+pub async fn poll_manager(sender: mpsc::Sender<BotAction>) {
+    let update_interval = Duration::from_secs(30);
+
+    loop {
+        let now = Utc::now().timestamp();
+        let messages = match db::call(&DB, move |c| {
+            let mut messages = Vec::new();
+            for (source, poll) in expired_polls(c, now)? {
+                messages.push((source, close_and_report(c, &poll)?));
+            }
+            Ok(messages)
+        })
+        .await
+        {
+            Ok(messages) => {
+                if !messages.is_empty() {
+                    info!("Closed {} polls", messages.len());
+                }
+                messages
+            }
+            Err(e) => {
+                error!("Error closing polls: {}", e);
+                Vec::new()
+            }
+        };
+
+        for (source, msg) in messages {
+            let action = BotAction {
+                target: source,
+                action_type: ActionType::Message(msg),
+            };
+            sender.send(action).await.unwrap();
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+fn poll_command(
+    conn: &Connection,
+    source: &ChatTarget,
+    nick: &str,
+    params: &str,
+    admin: bool,
+    now: i64,
+) -> Result<String> {
+    let (subcommand, rest) = params.split_once(' ').unwrap_or((params, ""));
+
+    Ok(match subcommand {
+        "start" => match parse_poll(rest) {
+            Some(new_poll) => match start_poll(conn, source, nick, &new_poll, now)? {
+                Ok(poll) => start_msg(&poll, now),
+                Err(existing) => format!(
+                    "Äänestys on jo käynnissä: {}. Sulje se ensin komennolla .poll close",
+                    existing.question
+                ),
+            },
+            None => "Usage: .poll start [aika] \"kysymys\" vaihtoehto | vaihtoehto".to_owned(),
+        },
+        "results" => match open_poll(conn, source)? {
+            Some(poll) => results_msg(&poll, &results(conn, &poll)?, false),
+            None => "Ei käynnissä olevaa äänestystä".to_owned(),
+        },
+        "close" => match open_poll(conn, source)? {
+            Some(poll) if admin || poll.creator.eq_ignore_ascii_case(nick) => {
+                close_and_report(conn, &poll)?
+            }
+            Some(_) => "Vain äänestyksen aloittaja voi sulkea sen".to_owned(),
+            None => "Ei käynnissä olevaa äänestystä".to_owned(),
+        },
+        _ => "Usage: .poll start|results|close".to_owned(),
+    })
+}
+
+fn vote_command(
+    conn: &Connection,
+    source: &ChatTarget,
+    nick: &str,
+    params: &str,
+) -> Result<String> {
+    let poll = match open_poll(conn, source)? {
+        Some(p) => p,
+        None => {
+            return Ok("Ei käynnissä olevaa äänestystä".to_owned());
+        }
+    };
+
+    Ok(match params.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= poll.options.len() => {
+            vote(conn, &poll, nick, n - 1)?;
+            format!("{} äänesti: {}", nick, poll.options[n - 1])
+        }
+        _ => format!("Usage: .vote <1-{}>", poll.options.len()),
+    })
+}
+
+async fn send(bot_sender: &mpsc::Sender<BotAction>, source: ChatTarget, msg: String) {
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_poll(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    admin: bool,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let now = Utc::now().timestamp();
+    let (target, params) = (source.clone(), params.to_owned());
+    let msg = match db::call(&DB, move |c| {
+        poll_command(c, &target, &nick, &params, admin, now)
+    })
+    .await
+    {
+        Ok(m) => m,
+        Err(_) => "Database error".to_owned(),
+    };
+
+    send(&bot_sender, source, msg).await;
+}
+
+pub async fn command_vote(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let (target, params) = (source.clone(), params.to_owned());
+    let msg = match db::call(&DB, move |c| vote_command(c, &target, &nick, &params)).await {
+        Ok(m) => m,
+        Err(_) => "Database error".to_owned(),
+    };
+
+    send(&bot_sender, source, msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_poll("\"Mitä syödään?\" pizza | kebab |"),
+            Some(NewPoll {
+                question: "Mitä syödään?".to_owned(),
+                options: vec!["pizza".to_owned(), "kebab".to_owned()],
+                duration: None,
+            })
+        );
+        assert_eq!(
+            parse_poll("1h30m \"Kahvia?\" kyllä | ei").unwrap().duration,
+            Some(90 * 60)
+        );
+        assert_eq!(parse_poll("\"Kahvia?\" kyllä"), None);
+        assert_eq!(parse_poll("huomenna \"Kahvia?\" kyllä | ei"), None);
+        assert_eq!(parse_poll("Kahvia? kyllä | ei"), None);
+    }
+
+    #[test]
+    fn voting() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#Testing".to_owned(),
+        };
+
+        assert_eq!(
+            vote_command(&conn, &channel, "nick", "1").unwrap(),
+            "Ei käynnissä olevaa äänestystä"
+        );
+        assert_eq!(
+            poll_command(
+                &conn,
+                &channel,
+                "nick",
+                "start 10m \"Kahvia?\" kyllä | ei",
+                false,
+                0
+            )
+            .unwrap(),
+            "Äänestys: Kahvia? 1) kyllä 2) ei – äänestä .vote <numero> (sulkeutuu 10 min kuluttua)"
+        );
+        assert!(
+            poll_command(&conn, &channel, "nick", "start \"Teetä?\" a | b", false, 0)
+                .unwrap()
+                .starts_with("Äänestys on jo käynnissä: Kahvia?")
+        );
+
+        assert_eq!(
+            vote_command(&conn, &channel, "nick", "2").unwrap(),
+            "nick äänesti: ei"
+        );
+        vote_command(&conn, &channel, "NICK", "1").unwrap();
+        vote_command(&conn, &channel, "toinen", "1").unwrap();
+        assert_eq!(
+            vote_command(&conn, &channel, "toinen", "3").unwrap(),
+            "Usage: .vote <1-2>"
+        );
+        assert_eq!(
+            poll_command(&conn, &channel, "x", "results", false, 0).unwrap(),
+            "Kahvia?: kyllä 2, ei 0 (2 ääntä)"
+        );
+
+        assert_eq!(
+            poll_command(&conn, &channel, "toinen", "close", false, 0).unwrap(),
+            "Vain äänestyksen aloittaja voi sulkea sen"
+        );
+        assert!(expired_polls(&conn, 599).unwrap().is_empty());
+        let expired = expired_polls(&conn, 600).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.channel, "#Testing");
+        let lowercase = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        assert_eq!(
+            open_poll(&conn, &lowercase).unwrap().map(|p| p.id),
+            Some(expired[0].1.id)
+        );
+        assert_eq!(
+            close_and_report(&conn, &expired[0].1).unwrap(),
+            "Äänestys päättyi! Kahvia?: kyllä 2, ei 0 (2 ääntä)"
+        );
+        assert!(open_poll(&conn, &channel).unwrap().is_none());
+
+        poll_command(&conn, &channel, "nick", "start \"Teetä?\" a | b", false, 0).unwrap();
+        assert_eq!(
+            poll_command(&conn, &channel, "toinen", "close", true, 0).unwrap(),
+            "Äänestys päättyi! Teetä?: a 0, b 0 (0 ääntä)"
+        );
+    }
+}
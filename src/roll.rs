@@ -6,7 +6,106 @@ use rand::prelude::*;
 use tokio::sync::mpsc;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::IrcChannel;
+use crate::ChatTarget;
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_MODIFIER: i64 = 1000;
+
+/// Dice notation like "4d20k3+2": count, sides, dice kept and modifier
+#[derive(Debug, PartialEq)]
+struct Dice {
+    count: u32,
+    sides: u32,
+    keep: Option<u32>,
+    modifier: i64,
+}
+
+fn parse_dice(params: &str) -> Result<Dice, ()> {
+    let spec = params.trim().to_lowercase();
+    if spec.contains(char::is_whitespace) {
+        return Err(());
+    }
+
+    let (count, rest) = spec.split_once('d').ok_or(())?;
+    let count = match count {
+        "" => 1,
+        c => c.parse::<u32>().map_err(|_| ())?,
+    };
+
+    let modifier_at = rest.find(['+', '-']);
+    let (dice, modifier) = match modifier_at {
+        Some(i) => (&rest[..i], rest[i..].parse::<i64>().map_err(|_| ())?),
+        None => (rest, 0),
+    };
+
+    let (sides, keep) = match dice.split_once('k') {
+        Some((s, k)) => (s, Some(k.parse::<u32>().map_err(|_| ())?)),
+        None => (dice, None),
+    };
+    let sides = sides.parse::<u32>().map_err(|_| ())?;
+
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) {
+        return Err(());
+    }
+    if keep.is_some_and(|k| k == 0 || k > count) {
+        return Err(());
+    }
+    if !(-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier) {
+        return Err(());
+    }
+
+    Ok(Dice {
+        count,
+        sides,
+        keep,
+        modifier,
+    })
+}
+
+/// Rolled dice in order, whether each one is kept, and the total
+fn roll_dice<R: Rng>(dice: &Dice, rng: &mut R) -> (Vec<(u32, bool)>, i64) {
+    let rolls: Vec<u32> = (0..dice.count)
+        .map(|_| rng.gen_range(1..=dice.sides))
+        .collect();
+
+    // Keep the highest dice; ties are broken by roll order
+    let mut order: Vec<usize> = (0..rolls.len()).collect();
+    order.sort_by(|a, b| rolls[*b].cmp(&rolls[*a]));
+    let keep = dice.keep.unwrap_or(dice.count) as usize;
+    let mut kept = vec![false; rolls.len()];
+    for i in order.into_iter().take(keep) {
+        kept[i] = true;
+    }
+
+    let total = rolls
+        .iter()
+        .zip(&kept)
+        .filter(|(_, k)| **k)
+        .map(|(r, _)| *r as i64)
+        .sum::<i64>()
+        + dice.modifier;
+
+    (rolls.into_iter().zip(kept).collect(), total)
+}
+
+fn dice_msg(spec: &str, rolls: &[(u32, bool)], modifier: i64, total: i64) -> String {
+    let dice: Vec<String> = rolls
+        .iter()
+        .map(|(r, kept)| match kept {
+            true => r.to_string(),
+            false => format!("({})", r),
+        })
+        .collect();
+
+    let modifier = match modifier {
+        0 => "".to_owned(),
+        m if m > 0 => format!(" + {}", m),
+        m => format!(" - {}", -m),
+    };
+
+    format!("{}: [{}]{} = {}", spec, dice.join(", "), modifier, total)
+}
 
 fn split_params(params: &str) -> Result<(i64, i64), ()> {
     let mut iter = params.split_whitespace();
@@ -30,13 +129,46 @@ fn roll(min: i64, max: i64) -> i64 {
     rng.gen_range(min..=max)
 }
 
-pub async fn command_roll(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel, params: &str) {
-    let msg = match split_params(params) {
-        Ok((min, max)) => {
+/// Options separated by "|", or by commas if there is no "|"
+fn split_choices(params: &str) -> Vec<&str> {
+    let separator = if params.contains('|') { '|' } else { ',' };
+
+    params
+        .split(separator)
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn choose<'a, R: Rng>(choices: &[&'a str], rng: &mut R) -> Option<&'a str> {
+    choices.choose(rng).copied()
+}
+
+pub async fn command_choose(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let choices = split_choices(params);
+    let msg = match choose(&choices, &mut thread_rng()) {
+        Some(c) if choices.len() > 1 => c.to_owned(),
+        _ => "Usage: .choose option1 | option2 | option3".to_owned(),
+    };
+
+    let a = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+    bot_sender.send(a).await.unwrap();
+}
+
+pub async fn command_roll(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let msg = match (split_params(params), parse_dice(params)) {
+        (Ok((min, max)), _) => {
             let rolled = roll(min, max);
             format!("{}", rolled)
         }
-        Err(()) => "Usage: .roll <min> <max>".to_owned(),
+        (_, Ok(dice)) => {
+            let (rolls, total) = roll_dice(&dice, &mut thread_rng());
+            dice_msg(params.trim(), &rolls, dice.modifier, total)
+        }
+        _ => "Usage: .roll <min> <max> or .roll 2d6+3".to_owned(),
     };
     let a = BotAction {
         target: source,
@@ -46,6 +178,7 @@ pub async fn command_roll(bot_sender: mpsc::Sender<BotAction>, source: IrcChanne
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
@@ -67,4 +200,73 @@ mod tests {
         assert_eq!(split_params(&"1 10 100"), Err(()));
         assert_eq!(split_params(&""), Err(()));
     }
+
+    #[test]
+    fn choices() {
+        assert_eq!(
+            split_choices("pizza | kebab, falafel"),
+            vec!["pizza", "kebab, falafel"]
+        );
+        assert_eq!(
+            split_choices("pizza, kebab ,falafel"),
+            vec!["pizza", "kebab", "falafel"]
+        );
+        assert_eq!(split_choices(" | "), Vec::<&str>::new());
+
+        let choices = ["a", "b", "c"];
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            assert!(choices.contains(&choose(&choices, &mut rng).unwrap()));
+        }
+        assert_eq!(choose(&[], &mut rng), None);
+    }
+
+    #[test]
+    fn dice_notation() {
+        assert_eq!(
+            parse_dice("2d6+3"),
+            Ok(Dice {
+                count: 2,
+                sides: 6,
+                keep: None,
+                modifier: 3
+            })
+        );
+        assert_eq!(
+            parse_dice("4D20k3-1"),
+            Ok(Dice {
+                count: 4,
+                sides: 20,
+                keep: Some(3),
+                modifier: -1
+            })
+        );
+        assert_eq!(parse_dice("d20").map(|d| d.count), Ok(1));
+        assert_eq!(parse_dice("2d1"), Err(()));
+        assert_eq!(parse_dice("2d6k3"), Err(()));
+        assert_eq!(parse_dice("1000d6"), Err(()));
+        assert_eq!(parse_dice("1 10"), Err(()));
+        assert_eq!(parse_dice("1d6+1001"), Err(()));
+        assert_eq!(parse_dice("1d6-9223372036854775808"), Err(()));
+
+        let dice = parse_dice("4d20k3+2").unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let (rolls, total) = roll_dice(&dice, &mut rng);
+            assert_eq!(rolls.iter().filter(|(_, k)| *k).count(), 3);
+            let lowest = rolls.iter().map(|(r, _)| *r).min().unwrap();
+            let sum: u32 = rolls.iter().map(|(r, _)| *r).sum();
+            assert_eq!(total, (sum - lowest) as i64 + 2);
+        }
+
+        assert_eq!(
+            dice_msg(
+                "4d20k3+2",
+                &[(17, true), (3, false), (12, true), (9, true)],
+                2,
+                40
+            ),
+            "4d20k3+2: [17, (3), 12, 9] + 2 = 40"
+        );
+    }
 }
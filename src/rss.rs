@@ -4,26 +4,39 @@
 
 use core::time::Duration;
 
+use chrono::prelude::*;
+
 use feed_rs::parser;
 
+use hmac::{Hmac, Mac};
+
+use irc::client::prelude::Prefix;
+
 use log::{debug, info, warn};
 
 use rusqlite::{named_params, params};
 
+use std::sync::Arc;
+
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 
 use url::Url;
 
+use yaml_rust::yaml::Yaml;
+
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::get_url;
-use crate::IrcChannel;
+use crate::db::{self, add_column_if_missing};
+use crate::health;
+use crate::http_client::{self, get_url, HTTP_CLIENT};
+use crate::ChatTarget;
 
 #[derive(Debug)]
 pub enum RssCommand {
     Add(String),
     Remove(i64),
     List,
+    ListAll,
 }
 
 #[derive(Debug)]
@@ -31,17 +44,30 @@ struct FeedData {
     title: String,
     url: String,
     entries: Vec<feed_rs::model::Entry>,
+    hub: Option<String>,
+    topic: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct FeedInfo {
-    id: i64,
-    title: String,
-    url: String,
-    target: IrcChannel,
+    pub id: i64,
+    pub title: String,
+    pub url: String,
+    pub target: ChatTarget,
+    pub last_fetched: Option<i64>,
+    pub errors: i64,
+    hub: Option<String>,
+    topic: Option<String>,
+    websub_expires: Option<i64>,
+    websub_secret: Option<String>,
 }
 
-pub async fn command_rss(sender: mpsc::Sender<BotAction>, source: IrcChannel, params: &str) {
+pub async fn command_rss(
+    sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    prefix: Option<Prefix>,
+) {
     match rsscommand_from_params(params) {
         Some(RssCommand::Add(url)) => {
             info!(
@@ -50,40 +76,51 @@ pub async fn command_rss(sender: mpsc::Sender<BotAction>, source: IrcChannel, pa
             );
             add_feed(sender, &source, &url).await;
         }
-        Some(RssCommand::Remove(id)) => {
-            let conn = open_db(false).unwrap();
-            let res = remove_feed(&conn, &source, id);
-            match res {
-                Ok(()) => {
-                    info!(
-                        "Removed feed id {} from {}/{}",
-                        id, source.network, source.channel
-                    );
-                    sender
-                        .send(BotAction {
-                            target: source,
-                            action_type: ActionType::Message(format!("Removed feed id {}", id)),
-                        })
-                        .await
-                        .unwrap();
-                }
-                Err(e) => {
-                    warn!("Error when removing feed: {}", e);
-                    sender
-                        .send(BotAction {
-                            target: source,
-                            action_type: ActionType::Message(e),
-                        })
-                        .await
-                        .unwrap();
-                }
+        Some(RssCommand::Remove(id)) => match unsubscribe(&source, id).await {
+            Ok(()) => {
+                info!(
+                    "Removed feed id {} from {}/{}",
+                    id, source.network, source.channel
+                );
+                sender
+                    .send(BotAction {
+                        target: source,
+                        action_type: ActionType::Message(format!("Removed feed id {}", id)),
+                    })
+                    .await
+                    .unwrap();
             }
-        }
+            Err(e) => {
+                warn!("Error when removing feed: {}", e);
+                sender
+                    .send(BotAction {
+                        target: source,
+                        action_type: ActionType::Message(e),
+                    })
+                    .await
+                    .unwrap();
+            }
+        },
         Some(RssCommand::List) => {
-            let conn = open_db(false).unwrap();
-            let feeds = get_feeds_for_channel(&conn, &source).unwrap();
+            let target = source.clone();
+            let feeds = db::call(&DB, move |c| get_feeds_for_channel(c, &target))
+                .await
+                .unwrap();
             list_feeds(sender, &source, feeds).await;
         }
+        Some(RssCommand::ListAll) => {
+            if let Some(Prefix::Nickname(nick, _, _)) = prefix {
+                let network = source.network.to_owned();
+                let feeds = db::call(&DB, move |c| get_feeds_for_network(c, &network))
+                    .await
+                    .unwrap();
+                let target = ChatTarget {
+                    network: source.network,
+                    channel: nick,
+                };
+                list_all_feeds(sender, &target, feeds).await;
+            }
+        }
         None => {}
     };
 }
@@ -108,24 +145,31 @@ fn rsscommand_from_params(s: &str) -> Option<RssCommand> {
         return None;
     } else if s == "list" {
         return Some(RssCommand::List);
+    } else if s == "listall" {
+        return Some(RssCommand::ListAll);
     }
 
     None
 }
 
-fn open_db(testing: bool) -> rusqlite::Result<rusqlite::Connection> {
-    let conn = match testing {
-        true => rusqlite::Connection::open(":memory:")?,
-        false => rusqlite::Connection::open("db/rss.db")?,
-    };
+pub const DB: db::Database = db::Database {
+    name: "rss.db",
+    migrations: &[create_tables, add_websub_secret],
+};
 
+fn create_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
     conn.execute(
         "create table if not exists feeds (
             id integer primary key,
             url text not null,
             name text not null,
             network text not null,
-            channel text not null
+            channel text not null,
+            last_fetched integer,
+            errors integer not null default 0,
+            hub text,
+            topic text,
+            websub_expires integer
         )",
         [],
     )?;
@@ -139,11 +183,34 @@ fn open_db(testing: bool) -> rusqlite::Result<rusqlite::Connection> {
         [],
     )?;
 
-    Ok(conn)
+    // Databases created by older versions lack the newer columns
+    add_column_if_missing(conn, "feeds", "last_fetched", "integer")?;
+    add_column_if_missing(conn, "feeds", "errors", "integer not null default 0")?;
+    add_column_if_missing(conn, "feeds", "hub", "text")?;
+    add_column_if_missing(conn, "feeds", "topic", "text")?;
+    add_column_if_missing(conn, "feeds", "websub_expires", "integer")?;
+
+    Ok(())
+}
+
+fn add_websub_secret(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "feeds", "websub_secret", "text")
 }
 
 fn parse_feed(feed: &str, url: &str) -> parser::ParseFeedResult<FeedData> {
     let feed = parser::parse(feed.as_bytes())?;
+
+    // WebSub hubs are advertised with <link rel="hub">, and the topic is the
+    // feed's own <link rel="self">
+    let link_with_rel = |rel: &str| {
+        feed.links
+            .iter()
+            .find(|l| l.rel.as_deref() == Some(rel))
+            .map(|l| l.href.to_owned())
+    };
+    let hub = link_with_rel("hub");
+    let topic = link_with_rel("self");
+
     let title = match feed.title {
         Some(t) => t.content,
         None => "NoTitle".to_owned(),
@@ -156,28 +223,18 @@ fn parse_feed(feed: &str, url: &str) -> parser::ParseFeedResult<FeedData> {
         title,
         url: url.to_owned(),
         entries: feed.entries,
+        hub,
+        topic,
     })
 }
 
-async fn add_feed(sender: mpsc::Sender<BotAction>, target: &IrcChannel, url: &str) {
+/// Adds the feed at `url` to the channel, returning its title or an error message
+pub async fn subscribe(target: &ChatTarget, url: &str) -> Result<String, String> {
     let feed_body = match get_url(url).await {
         Ok(r) => r,
         Err(_) => {
             warn!("Could not fetch url: {}", url);
-            sender
-                .send(BotAction {
-                    target: IrcChannel {
-                        network: target.network.to_owned(),
-                        channel: target.channel.to_owned(),
-                    },
-                    action_type: ActionType::Message(format!(
-                        "Error adding feed: Unable to get URL {}",
-                        url
-                    )),
-                })
-                .await
-                .unwrap();
-            return;
+            return Err(format!("Error adding feed: Unable to get URL {}", url));
         }
     };
 
@@ -185,60 +242,50 @@ async fn add_feed(sender: mpsc::Sender<BotAction>, target: &IrcChannel, url: &st
         Ok(p) => p,
         Err(e) => {
             warn!("Could not parse feed: {:?}", e);
-            sender
-                .send(BotAction {
-                    target: IrcChannel {
-                        network: target.network.to_owned(),
-                        channel: target.channel.to_owned(),
-                    },
-                    action_type: ActionType::Message(
-                        "Error adding feed: Unable to parse feed.".to_owned(),
-                    ),
-                })
-                .await
-                .unwrap();
-            return;
+            return Err("Error adding feed: Unable to parse feed.".to_owned());
         }
     };
 
     let title = parsed.title.to_owned();
 
-    let conn = open_db(false).unwrap();
-    let result = add_feed_to_db(&conn, parsed, target);
+    let db_target = target.clone();
+    let result = db::call(&DB, move |c| add_feed_to_db(c, parsed, &db_target)).await;
     match result {
         Ok(_) => {
             info!("Successfully added feed {}", url);
-            sender
-                .send(BotAction {
-                    target: IrcChannel {
-                        network: target.network.to_owned(),
-                        channel: target.channel.to_owned(),
-                    },
-                    action_type: ActionType::Message(format!("Successfully added feed {}", title)),
-                })
-                .await
-                .unwrap();
+            Ok(title)
         }
         Err(e) => {
             warn!("Database error when adding feed: {:?}", e);
-            sender
-                .send(BotAction {
-                    target: IrcChannel {
-                        network: target.network.to_owned(),
-                        channel: target.channel.to_owned(),
-                    },
-                    action_type: ActionType::Message(format!(
-                        "Error adding feed {}: Database error",
-                        title
-                    )),
-                })
-                .await
-                .unwrap();
+            Err(format!("Error adding feed {}: Database error", title))
         }
     }
 }
 
-fn remove_feed(conn: &rusqlite::Connection, source: &IrcChannel, id: i64) -> Result<(), String> {
+async fn add_feed(sender: mpsc::Sender<BotAction>, target: &ChatTarget, url: &str) {
+    let msg = match subscribe(target, url).await {
+        Ok(title) => format!("Successfully added feed {}", title),
+        Err(e) => e,
+    };
+
+    sender
+        .send(BotAction {
+            target: target.clone(),
+            action_type: ActionType::Message(msg),
+        })
+        .await
+        .unwrap();
+}
+
+/// Removes the feed from the channel it is posted to
+pub async fn unsubscribe(target: &ChatTarget, id: i64) -> Result<(), String> {
+    let target = target.clone();
+    db::call(&DB, move |c| Ok(remove_feed(c, &target, id)))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+}
+
+fn remove_feed(conn: &rusqlite::Connection, source: &ChatTarget, id: i64) -> Result<(), String> {
     let mut check_feed_stmt = conn
         .prepare(
             "SELECT * FROM feeds WHERE
@@ -289,15 +336,17 @@ fn remove_feed(conn: &rusqlite::Connection, source: &IrcChannel, id: i64) -> Res
 fn add_feed_to_db(
     conn: &rusqlite::Connection,
     feed_data: FeedData,
-    target: &IrcChannel,
+    target: &ChatTarget,
 ) -> rusqlite::Result<()> {
     conn.execute(
-        "INSERT INTO feeds (url, name, network, channel) VALUES (?1, ?2, ?3, ?4)",
+        "INSERT INTO feeds (url, name, network, channel, hub, topic) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
         params![
             feed_data.url,
             feed_data.title,
             target.network,
-            target.channel
+            target.channel,
+            feed_data.hub,
+            feed_data.topic
         ],
     )?;
 
@@ -332,9 +381,9 @@ fn add_feed_to_db(
     Ok(())
 }
 
-async fn list_feeds(sender: mpsc::Sender<BotAction>, source: &IrcChannel, feeds: Vec<FeedInfo>) {
+async fn list_feeds(sender: mpsc::Sender<BotAction>, source: &ChatTarget, feeds: Vec<FeedInfo>) {
     for feed in feeds {
-        let source_copy = IrcChannel {
+        let source_copy = ChatTarget {
             network: source.network.to_owned(),
             channel: source.channel.to_owned(),
         };
@@ -349,56 +398,138 @@ async fn list_feeds(sender: mpsc::Sender<BotAction>, source: &IrcChannel, feeds:
     }
 }
 
+async fn list_all_feeds(
+    sender: mpsc::Sender<BotAction>,
+    target: &ChatTarget,
+    feeds: Vec<FeedInfo>,
+) {
+    if feeds.is_empty() {
+        let _ = sender
+            .send(BotAction {
+                target: ChatTarget {
+                    network: target.network.to_owned(),
+                    channel: target.channel.to_owned(),
+                },
+                action_type: ActionType::Notice("No feeds on this network".to_owned()),
+            })
+            .await;
+        return;
+    }
+
+    for feed in feeds {
+        let target_copy = ChatTarget {
+            network: target.network.to_owned(),
+            channel: target.channel.to_owned(),
+        };
+        let msg = format!(
+            "{} | {}: {} | {} | last fetched: {} | errors: {}",
+            feed.target.channel,
+            feed.id,
+            feed.title,
+            feed.url,
+            format_last_fetched(feed.last_fetched),
+            feed.errors
+        );
+        sender
+            .send(BotAction {
+                target: target_copy,
+                action_type: ActionType::Notice(msg),
+            })
+            .await
+            .unwrap();
+    }
+}
+
+pub fn format_last_fetched(last_fetched: Option<i64>) -> String {
+    match last_fetched.and_then(|t| Local.timestamp_opt(t, 0).single()) {
+        Some(dt) => dt.format("%Y-%m-%d %H:%M").to_string(),
+        None => "never".to_owned(),
+    }
+}
+
 fn get_feeds_for_channel(
     conn: &rusqlite::Connection,
-    target: &IrcChannel,
+    target: &ChatTarget,
 ) -> rusqlite::Result<Vec<FeedInfo>> {
-    let mut feeds = vec![];
     let mut stmt = conn.prepare(
         "SELECT * FROM feeds WHERE
          network = :network AND
          channel = :channel",
     )?;
-    let mut rows = stmt.query(&[(":network", &target.network), (":channel", &target.channel)])?;
+    let rows = stmt.query(&[(":network", &target.network), (":channel", &target.channel)])?;
+    feeds_from_rows(rows)
+}
+
+fn feeds_from_rows(mut rows: rusqlite::Rows) -> rusqlite::Result<Vec<FeedInfo>> {
+    let mut feeds = vec![];
     while let Some(row) = rows.next()? {
         let id = row.get(0)?;
         let url = row.get(1)?;
         let title = row.get(2)?;
+        let network = row.get(3)?;
+        let channel = row.get(4)?;
+        let last_fetched = row.get(5)?;
+        let errors = row.get(6)?;
+        let hub = row.get(7)?;
+        let topic = row.get(8)?;
+        let websub_expires = row.get(9)?;
+        let websub_secret = row.get(10)?;
 
         feeds.push(FeedInfo {
             id,
             url,
             title,
-            target: IrcChannel {
-                network: target.network.to_owned(),
-                channel: target.channel.to_owned(),
-            },
+            target: ChatTarget { network, channel },
+            last_fetched,
+            errors,
+            hub,
+            topic,
+            websub_expires,
+            websub_secret,
         });
     }
 
     Ok(feeds)
 }
 
+/// Every feed, for the web admin
+pub async fn all_feeds() -> rusqlite::Result<Vec<FeedInfo>> {
+    db::call(&DB, get_all_feeds).await
+}
+
 fn get_all_feeds(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<FeedInfo>> {
-    let mut feeds = vec![];
     let mut stmt = conn.prepare("SELECT * FROM feeds")?;
-    let mut rows = stmt.query([])?;
-    while let Some(row) = rows.next()? {
-        let id = row.get(0)?;
-        let url = row.get(1)?;
-        let title = row.get(2)?;
-        let network = row.get(3)?;
-        let channel = row.get(4)?;
+    let rows = stmt.query([])?;
+    feeds_from_rows(rows)
+}
 
-        feeds.push(FeedInfo {
-            id,
-            url,
-            title,
-            target: IrcChannel { network, channel },
-        });
-    }
+fn get_feeds_for_network(
+    conn: &rusqlite::Connection,
+    network: &str,
+) -> rusqlite::Result<Vec<FeedInfo>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM feeds WHERE
+         network = :network
+         ORDER BY channel, id",
+    )?;
+    let rows = stmt.query(&[(":network", network)])?;
+    feeds_from_rows(rows)
+}
 
-    Ok(feeds)
+fn mark_feed_fetched(conn: &rusqlite::Connection, feed_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE feeds SET last_fetched = ?1, errors = 0 WHERE id = ?2",
+        params![Utc::now().timestamp(), feed_id],
+    )?;
+    Ok(())
+}
+
+fn mark_feed_error(conn: &rusqlite::Connection, feed_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE feeds SET errors = errors + 1 WHERE id = ?1",
+        params![feed_id],
+    )?;
+    Ok(())
 }
 
 fn entry_is_posted(
@@ -429,78 +560,422 @@ fn add_entry_to_db(conn: &rusqlite::Connection, entry: &feed_rs::model::Entry, f
     .unwrap();
 }
 
-async fn refresh_feeds(sender: mpsc::Sender<BotAction>) {
+fn format_duration(duration: &Duration) -> String {
+    let total_secs = duration.as_secs();
+    let h = total_secs / 3600;
+    let m = (total_secs / 60) % 60;
+    let s = total_secs % 60;
+
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{}:{:02}", m, s)
+    }
+}
+
+fn enclosure_from_entry(entry: &feed_rs::model::Entry) -> Option<(String, Option<Duration>)> {
+    for media in &entry.media {
+        for content in &media.content {
+            let is_audio_or_video = match content.content_type {
+                Some(ref t) => t.type_() == "audio" || t.type_() == "video",
+                None => false,
+            };
+            if !is_audio_or_video {
+                continue;
+            }
+            if let Some(ref url) = content.url {
+                let duration = content.duration.or(media.duration);
+                return Some((url.to_string(), duration));
+            }
+        }
+    }
+
+    None
+}
+
+fn entry_msg(feed_title: &str, entry: &feed_rs::model::Entry) -> String {
+    let title = match entry.title {
+        Some(ref t) => t.content.to_owned(),
+        _ => "".to_owned(),
+    };
+
+    let mut msg = format!("[{}] {} <{}>", feed_title, title, entry.links[0].href);
+
+    if let Some((url, duration)) = enclosure_from_entry(entry) {
+        match duration {
+            Some(d) => msg.push_str(&format!(" | Media: <{}> ({})", url, format_duration(&d))),
+            None => msg.push_str(&format!(" | Media: <{}>", url)),
+        }
+    }
+
+    msg
+}
+
+/// Marks new entries as posted and returns the announcements for them.
+fn take_new_entries(
+    conn: &rusqlite::Connection,
+    feed: &FeedInfo,
+    parsed: FeedData,
+) -> Vec<BotAction> {
+    let mut actions = vec![];
+
+    for entry in parsed.entries {
+        if entry.links.is_empty() || entry_is_posted(conn, &entry, feed.id) {
+            continue;
+        }
+
+        info!(
+            "New feed item from feed {} for {}/{}: {}",
+            feed.title, feed.target.network, feed.target.channel, feed.title
+        );
+        let output_target = ChatTarget {
+            network: feed.target.network.to_owned(),
+            channel: feed.target.channel.to_owned(),
+        };
+        debug!("Entry URL before format!: {}", entry.links[0].href);
+
+        actions.push(BotAction {
+            target: output_target,
+            action_type: ActionType::Message(entry_msg(&feed.title, &entry)),
+        });
+
+        add_entry_to_db(conn, &entry, feed.id);
+    }
+
+    actions
+}
+
+async fn announce(sender: &mpsc::Sender<BotAction>, actions: Vec<BotAction>) {
+    for action in actions {
+        let _ = sender.send(action).await;
+    }
+}
+
+// Subscriptions made before pushes were signed count as inactive, so the
+// feed is polled and subscribed again
+fn websub_active(feed: &FeedInfo) -> bool {
+    match feed.websub_expires {
+        Some(expires) => feed.websub_secret.is_some() && expires > Utc::now().timestamp(),
+        None => false,
+    }
+}
+
+/// Random hub.secret for signing the pushes of a subscription
+fn new_websub_secret() -> String {
+    let bytes: [u8; 24] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Checks an X-Hub-Signature header ("sha256=<hex>") against the body
+fn verify_signature(secret: &str, header: &str, body: &[u8]) -> bool {
+    let (method, signature) = match header.split_once('=') {
+        Some(s) => s,
+        None => {
+            return false;
+        }
+    };
+    let signature = match from_hex(signature) {
+        Some(s) => s,
+        None => {
+            return false;
+        }
+    };
+
+    macro_rules! verify {
+        ($hash:ty) => {{
+            let mut mac = Hmac::<$hash>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(body);
+            mac.verify_slice(&signature).is_ok()
+        }};
+    }
+    match method {
+        "sha1" => verify!(sha1::Sha1),
+        "sha256" => verify!(sha2::Sha256),
+        "sha384" => verify!(sha2::Sha384),
+        "sha512" => verify!(sha2::Sha512),
+        _ => false,
+    }
+}
+
+fn websub_callback_url(public_url: &str, feed_id: i64) -> String {
+    format!("{}/websub/{}", public_url.trim_end_matches('/'), feed_id)
+}
+
+async fn websub_subscribe(feed: &FeedInfo, public_url: &str) {
+    let hub = match feed.hub {
+        Some(ref h) => h,
+        None => {
+            return;
+        }
+    };
+    let topic = feed.topic.as_ref().unwrap_or(&feed.url);
+    let callback = websub_callback_url(public_url, feed.id);
+
+    // Pushes signed with the previous secret may still arrive while the hub
+    // verifies the new subscription, so an existing secret is kept
+    let secret = match &feed.websub_secret {
+        Some(s) => s.to_owned(),
+        None => {
+            let secret = new_websub_secret();
+            let (id, s) = (feed.id, secret.clone());
+            if let Err(e) = db::call(&DB, move |c| set_websub_secret(c, id, &s)).await {
+                warn!("Could not save WebSub secret for {}: {}", feed.url, e);
+                return;
+            }
+            secret
+        }
+    };
+
+    info!("Subscribing to {} via WebSub hub {}", topic, hub);
+
+    let request = HTTP_CLIENT.post(hub).form(&[
+        ("hub.mode", "subscribe"),
+        ("hub.topic", topic),
+        ("hub.callback", &callback),
+        ("hub.secret", &secret),
+    ]);
+    let res = http_client::send(request).await;
+
+    match res {
+        Ok(r) if r.status().is_success() => {
+            debug!("WebSub subscription request for {} accepted", topic);
+        }
+        Ok(r) => {
+            warn!(
+                "WebSub hub {} rejected subscription for {}: {}",
+                hub,
+                topic,
+                r.status()
+            );
+        }
+        Err(e) => {
+            warn!("Could not contact WebSub hub {}: {}", hub, e);
+        }
+    }
+}
+
+/// Handles the hub's verification of intent. Returns true if the request
+/// matches a feed we want to be subscribed to.
+pub async fn websub_verify(
+    feed_id: i64,
+    mode: &str,
+    topic: &str,
+    lease_seconds: Option<i64>,
+) -> bool {
+    let (mode, topic) = (mode.to_owned(), topic.to_owned());
+    db::call(&DB, move |c| {
+        Ok(verify_subscription(
+            c,
+            feed_id,
+            &mode,
+            &topic,
+            lease_seconds,
+        ))
+    })
+    .await
+    .unwrap_or(false)
+}
+
+fn verify_subscription(
+    conn: &rusqlite::Connection,
+    feed_id: i64,
+    mode: &str,
+    topic: &str,
+    lease_seconds: Option<i64>,
+) -> bool {
+    let feed = match get_feed_by_id(conn, feed_id) {
+        Ok(Some(f)) => f,
+        _ => {
+            return mode == "unsubscribe";
+        }
+    };
+
+    if feed.topic.as_ref().unwrap_or(&feed.url) != topic {
+        return false;
+    }
+
+    match mode {
+        "subscribe" => {
+            let lease = lease_seconds.unwrap_or(24 * 60 * 60);
+            let expires = Utc::now().timestamp() + lease;
+            info!("WebSub subscription for feed {} verified", feed_id);
+            set_websub_expires(conn, feed_id, Some(expires)).is_ok()
+        }
+        "unsubscribe" => set_websub_expires(conn, feed_id, None).is_ok(),
+        _ => false,
+    }
+}
+
+/// Whether a push is signed with the secret of the feed's subscription.
+/// Unsigned pushes are never accepted.
+pub async fn websub_authentic(feed_id: i64, signature: Option<&str>, body: &[u8]) -> bool {
+    let signature = match signature {
+        Some(s) => s.to_owned(),
+        None => {
+            warn!("Unsigned WebSub notification for feed {}", feed_id);
+            return false;
+        }
+    };
+    let secret = match db::call(&DB, move |c| get_feed_by_id(c, feed_id)).await {
+        Ok(Some(FeedInfo {
+            websub_secret: Some(s),
+            ..
+        })) => s,
+        _ => {
+            return false;
+        }
+    };
+
+    let valid = verify_signature(&secret, &signature, body);
+    if !valid {
+        warn!("Invalid WebSub signature for feed {}", feed_id);
+    }
+    valid
+}
+
+/// Handles content pushed by a WebSub hub.
+pub async fn websub_notification(sender: mpsc::Sender<BotAction>, feed_id: i64, body: &str) {
+    let feed = match db::call(&DB, move |c| get_feed_by_id(c, feed_id)).await {
+        Ok(Some(f)) => f,
+        _ => {
+            warn!("WebSub notification for unknown feed {}", feed_id);
+            return;
+        }
+    };
+
+    match parse_feed(body, &feed.url) {
+        Ok(parsed) => {
+            debug!("WebSub notification for feed {}", feed.url);
+            let actions = db::call(&DB, move |c| {
+                let _ = mark_feed_fetched(c, feed.id);
+                Ok(take_new_entries(c, &feed, parsed))
+            })
+            .await
+            .unwrap_or_default();
+            announce(&sender, actions).await;
+        }
+        Err(e) => {
+            warn!(
+                "Could not parse WebSub notification for {}: {:?}",
+                feed.url, e
+            );
+        }
+    }
+}
+
+fn get_feed_by_id(conn: &rusqlite::Connection, feed_id: i64) -> rusqlite::Result<Option<FeedInfo>> {
+    let mut stmt = conn.prepare("SELECT * FROM feeds WHERE id = :id")?;
+    let rows = stmt.query(&[(":id", &feed_id)])?;
+    Ok(feeds_from_rows(rows)?.pop())
+}
+
+fn set_websub_secret(
+    conn: &rusqlite::Connection,
+    feed_id: i64,
+    secret: &str,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE feeds SET websub_secret = ?1 WHERE id = ?2",
+        params![secret, feed_id],
+    )?;
+    Ok(())
+}
+
+fn set_websub_expires(
+    conn: &rusqlite::Connection,
+    feed_id: i64,
+    expires: Option<i64>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE feeds SET websub_expires = ?1 WHERE id = ?2",
+        params![expires, feed_id],
+    )?;
+    Ok(())
+}
+
+async fn refresh_feeds(sender: mpsc::Sender<BotAction>, websub_public_url: Option<&str>) {
     info!("Starting feed refresh");
-    let conn = open_db(false).unwrap();
-    let feeds = get_all_feeds(&conn).unwrap();
+    let feeds = db::call(&DB, get_all_feeds).await.unwrap();
     for feed in feeds {
+        if let Some(public_url) = websub_public_url {
+            // Renew well before the lease runs out
+            let renew_at = Utc::now().timestamp() + 60 * 60;
+            if feed.hub.is_some()
+                && (feed.websub_expires.unwrap_or(0) < renew_at || feed.websub_secret.is_none())
+            {
+                websub_subscribe(&feed, public_url).await;
+            }
+            if websub_active(&feed) {
+                debug!("Feed {} is pushed via WebSub, not polling", feed.url);
+                continue;
+            }
+        }
+
         let feed_body = match get_url(&feed.url).await {
             Ok(b) => b,
             _ => {
-                return;
+                warn!("Could not fetch feed {}", feed.url);
+                let id = feed.id;
+                let _ = db::call(&DB, move |c| mark_feed_error(c, id)).await;
+                continue;
             }
         };
         let parsed = match parse_feed(&feed_body, &feed.url) {
             Ok(p) => p,
             _ => {
-                return;
+                warn!("Could not parse feed {}", feed.url);
+                let id = feed.id;
+                let _ = db::call(&DB, move |c| mark_feed_error(c, id)).await;
+                continue;
             }
         };
-        let mut to_output = vec![];
-
-        for entry in parsed.entries {
-            if !entry.links.is_empty() && !entry_is_posted(&conn, &entry, feed.id) {
-                to_output.push(entry);
-            }
-        }
-
-        for entry in to_output {
-            info!(
-                "New feed item from feed {} for {}/{}: {}",
-                feed.title, feed.target.network, feed.target.channel, feed.title
-            );
-            let title = match entry.title {
-                Some(ref t) => t.content.to_owned(),
-                _ => "".to_owned(),
-            };
-            let output_target = IrcChannel {
-                network: feed.target.network.to_owned(),
-                channel: feed.target.channel.to_owned(),
-            };
-            debug!("Entry URL before format!: {}", entry.links[0].href);
-
-            let msg = format!("[{}] {} <{}>", feed.title, title, entry.links[0].href);
-            let _ = sender
-                .send(BotAction {
-                    target: output_target,
-                    action_type: ActionType::Message(msg),
-                })
-                .await;
-
-            add_entry_to_db(&conn, &entry, feed.id);
-        }
+        let actions = db::call(&DB, move |c| {
+            let _ = mark_feed_fetched(c, feed.id);
+            Ok(take_new_entries(c, &feed, parsed))
+        })
+        .await
+        .unwrap_or_default();
+        announce(&sender, actions).await;
     }
 
+    health::rss_refreshed();
     info!("Feed refresh finished");
 }
 
-pub async fn rss_manager(sender: mpsc::Sender<BotAction>) {
+pub async fn rss_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
     let update_interval = Duration::from_secs(10 * 60);
+    let websub_public_url = config["http_server"]["public_url"].as_str();
 
     loop {
         tokio::select! {
             _ = sleep(update_interval) => {
                 let sender_copy = sender.clone();
-                refresh_feeds(sender_copy).await;
+                refresh_feeds(sender_copy, websub_public_url).await;
             }
         }
     }
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::assertions_on_constants,
+    clippy::needless_borrow,
+    clippy::redundant_field_names
+)]
 mod tests {
     use super::*;
 
+    fn open_db(testing: bool) -> rusqlite::Result<rusqlite::Connection> {
+        db::open(&DB, testing)
+    }
+
     #[derive(Debug)]
     struct FeedEntry {
         url: String,
@@ -519,10 +994,7 @@ mod tests {
             let url = row.get(1)?;
             let title = row.get(2)?;
 
-            entries.push(FeedEntry {
-                url,
-                title,
-            });
+            entries.push(FeedEntry { url, title });
         }
 
         Ok(entries)
@@ -581,6 +1053,12 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rss_command_parsing_listall() {
+        let c1 = rsscommand_from_params("listall");
+        assert!(matches!(c1, Some(RssCommand::ListAll)));
+    }
+
     #[test]
     fn rss_command_parsing_nocommand() {
         let s1 = "Just a line";
@@ -598,7 +1076,7 @@ mod tests {
         assert!(c.is_ok());
     }
 
-    fn rss_add_example_feed(conn: &rusqlite::Connection, target: &IrcChannel) {
+    fn rss_add_example_feed(conn: &rusqlite::Connection, target: &ChatTarget) {
         const TESTFEED: &str = r#"<feed>
             <id>
             https://example.com/rss
@@ -623,7 +1101,7 @@ mod tests {
     #[test]
     fn rss_add_feed() {
         let conn = open_db(true).unwrap();
-        let target = IrcChannel {
+        let target = ChatTarget {
             network: "testnetwork".to_owned(),
             channel: "#testing".to_owned(),
         };
@@ -643,10 +1121,30 @@ mod tests {
         assert_eq!(entries[0].title, "Test entry 01");
     }
 
+    #[test]
+    fn rss_upgrade_v1() {
+        const V1: db::Database = db::Database {
+            name: "rss.db",
+            migrations: &[create_tables],
+        };
+        let conn = db::open(&V1, true).unwrap();
+        assert!(conn.prepare("SELECT websub_secret FROM feeds").is_err());
+
+        db::migrate(&conn, &DB).unwrap();
+        let target = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        rss_add_example_feed(&conn, &target);
+        let feeds = get_all_feeds(&conn).unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].websub_secret, None);
+    }
+
     #[tokio::test]
     async fn rss_list_feeds() {
         let (bot_tx, mut bot_rx) = mpsc::channel(10);
-        let target = IrcChannel {
+        let target = ChatTarget {
             network: "testnetwork".to_owned(),
             channel: "#testing".to_owned(),
         };
@@ -671,9 +1169,125 @@ mod tests {
         }
     }
 
+    #[test]
+    fn rss_network_feeds() {
+        let conn = open_db(true).unwrap();
+        let target = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        rss_add_example_feed(&conn, &target);
+        conn.execute(
+            "INSERT INTO feeds (url, name, network, channel) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                "https://example.com/other",
+                "Other feed",
+                "secondnetwork",
+                "#testing"
+            ],
+        )
+        .unwrap();
+
+        let feeds = get_feeds_for_network(&conn, "testnetwork").unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].last_fetched, None);
+        assert_eq!(feeds[0].errors, 0);
+
+        mark_feed_error(&conn, feeds[0].id).unwrap();
+        mark_feed_error(&conn, feeds[0].id).unwrap();
+        let feeds = get_feeds_for_network(&conn, "testnetwork").unwrap();
+        assert_eq!(feeds[0].errors, 2);
+
+        mark_feed_fetched(&conn, feeds[0].id).unwrap();
+        let feeds = get_feeds_for_network(&conn, "testnetwork").unwrap();
+        assert!(feeds[0].last_fetched.is_some());
+        assert_eq!(feeds[0].errors, 0);
+    }
+
+    #[test]
+    fn rss_websub_links() {
+        const HUBFEED: &str = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <id>https://example.com/atom</id>
+            <title>T-botti hub feed</title>
+            <updated>2021-01-26T11:31:04.605378+00:00</updated>
+            <link rel="hub" href="https://pubsubhubbub.example.com/"/>
+            <link rel="self" href="https://example.com/atom.xml"/>
+            </feed>"#;
+
+        let parsed = parse_feed(HUBFEED, "https://example.com/atom").unwrap();
+        assert_eq!(
+            parsed.hub,
+            Some("https://pubsubhubbub.example.com/".to_owned())
+        );
+        assert_eq!(
+            parsed.topic,
+            Some("https://example.com/atom.xml".to_owned())
+        );
+
+        assert_eq!(
+            websub_callback_url("https://bot.example.com/", 3),
+            "https://bot.example.com/websub/3"
+        );
+    }
+
+    #[test]
+    fn rss_websub_signatures() {
+        let body = b"<feed/>";
+        let sha1 = "sha1=d0f17a456159bccb28b7f244db7aadc2ccbc8f1d";
+        let sha256 = "sha256=1b9591b5322baa7463554820f41fe27e650c767e2fbc4a934c5aa90e9cc27c7c";
+
+        assert!(verify_signature("s3cret", sha1, body));
+        assert!(verify_signature("s3cret", sha256, body));
+        assert!(!verify_signature("other", sha256, body));
+        assert!(!verify_signature(
+            "s3cret",
+            sha256,
+            b"<feed>injected</feed>"
+        ));
+        assert!(!verify_signature("s3cret", "md5=d0f17a45", body));
+        assert!(!verify_signature("s3cret", "sha256=zz", body));
+        assert_eq!(new_websub_secret().len(), 48);
+        assert_ne!(new_websub_secret(), new_websub_secret());
+    }
+
+    #[test]
+    fn rss_podcast_enclosure() {
+        const PODCASTFEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+            <channel>
+            <title>T-botti test podcast</title>
+            <link>https://example.com/podcast</link>
+            <item>
+            <title>Episode 01</title>
+            <link>https://example.com/podcast/01</link>
+            <guid>https://example.com/podcast/01</guid>
+            <enclosure url="https://example.com/podcast/01.mp3" length="1234" type="audio/mpeg"/>
+            <itunes:duration>01:02:03</itunes:duration>
+            </item>
+            <item>
+            <title>Episode 02</title>
+            <link>https://example.com/podcast/02</link>
+            <guid>https://example.com/podcast/02</guid>
+            </item>
+            </channel>
+            </rss>"#;
+
+        let parsed = parse_feed(PODCASTFEED, "https://example.com/podcast.xml").unwrap();
+        assert_eq!(parsed.entries.len(), 2);
+
+        assert_eq!(
+            entry_msg(&parsed.title, &parsed.entries[0]),
+            "[T-botti test podcast] Episode 01 <https://example.com/podcast/01> | Media: <https://example.com/podcast/01.mp3> (1:02:03)"
+        );
+        assert_eq!(
+            entry_msg(&parsed.title, &parsed.entries[1]),
+            "[T-botti test podcast] Episode 02 <https://example.com/podcast/02>"
+        );
+    }
+
     #[tokio::test]
     async fn rss_remove_feed() {
-        let target = IrcChannel {
+        let target = ChatTarget {
             network: "testnetwork".to_owned(),
             channel: "#testing".to_owned(),
         };
@@ -683,7 +1297,7 @@ mod tests {
         let feeds_before = get_feeds_for_channel(&conn, &target).unwrap();
         assert_eq!(feeds_before.len(), 1);
 
-        let wrong_channel = IrcChannel {
+        let wrong_channel = ChatTarget {
             network: "secondnetwork".to_owned(),
             channel: "#testing".to_owned(),
         };
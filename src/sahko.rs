@@ -3,39 +3,100 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use chrono::prelude::*;
-use std::sync::Arc;
+use chrono_tz::Europe::Helsinki;
+use core::time::Duration;
+use log::{error, info, warn};
+use rusqlite::{named_params, Connection};
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
+use tokio::time::sleep;
 use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::db;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
 
-async fn get_json(fingrid_api_key: &str) -> Result<(String, String), reqwest::Error> {
-    let priceurl = "https://api.spot-hinta.fi/Today";
-    let fingridurl = "https://api.fingrid.fi/v1/variable/event/json/192%2C193%2C194%2C209";
+const TODAY_URL: &str = "https://api.spot-hinta.fi/Today";
+const TOMORROW_URL: &str = "https://api.spot-hinta.fi/DayForward";
+const ENTSOE_URL: &str = "https://web-api.tp.entsoe.eu/api";
+const ENTSOE_FINLAND: &str = "10YFI-1--------U";
+// ENTSO-E prices are without VAT, which is not added to negative prices
+const VAT: f64 = 0.255;
+const EXPENSIVE_HOURS_IN_MSG: usize = 3;
+const GRAPH_BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// IRC bold, used to highlight the current hour in the graph
+const HIGHLIGHT: char = '\x02';
+// Tomorrow's prices are published around 14:00 Finnish time
+const TOMORROW_PUBLISHED_HOUR: u32 = 14;
 
-    let price_req = HTTP_CLIENT.get(priceurl).send(); //.await?.text().await?;
-    let fingrid_req = HTTP_CLIENT
-        .get(fingridurl)
-        .header("x-api-key", fingrid_api_key)
-        .send();
+// Busy channels would otherwise hit the APIs on every .sähkö
+const PRICE_TTL_MINUTES: i64 = 15;
+const PRICE_TTL: Duration = Duration::from_secs(PRICE_TTL_MINUTES as u64 * 60);
+// Fingrid's real-time datasets update every three minutes
+const FINGRID_TTL_MINUTES: i64 = 3;
 
-    let price_json = price_req.await?.text().await?;
-    let fingrid_json = fingrid_req.await?.text().await?;
+struct Cached<T> {
+    value: T,
+    fetched: DateTime<Utc>,
+}
 
-    Ok((price_json, fingrid_json))
+lazy_static! {
+    static ref TODAY_CACHE: Mutex<Option<Cached<Vec<HourlyPrice>>>> = Mutex::new(None);
+    static ref TOMORROW_CACHE: Mutex<Option<Cached<Vec<HourlyPrice>>>> = Mutex::new(None);
+    static ref FINGRID_CACHE: Mutex<Option<Cached<[String; 4]>>> = Mutex::new(None);
 }
 
-struct ElecData {
+/// The cached value if it is younger than `ttl_minutes`
+fn cache_get<T: Clone>(
+    cache: &Mutex<Option<Cached<T>>>,
+    ttl_minutes: i64,
+    now: DateTime<Utc>,
+) -> Option<T> {
+    cache
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|c| now - c.fetched < chrono::Duration::minutes(ttl_minutes))
+        .map(|c| c.value.clone())
+}
+
+/// The cached value and when it was fetched, however old
+fn cache_get_stale<T: Clone>(cache: &Mutex<Option<Cached<T>>>) -> Option<(T, DateTime<Utc>)> {
+    cache
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|c| (c.value.clone(), c.fetched))
+}
+
+fn cache_put<T>(cache: &Mutex<Option<Cached<T>>>, value: T, now: DateTime<Utc>) {
+    *cache.lock().unwrap() = Some(Cached {
+        value,
+        fetched: now,
+    });
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct HourlyPrice {
+    time: DateTime<FixedOffset>,
+    // c/kWh including VAT
     price: f64,
-    consumption: f64,
-    production: f64,
-    importexport: f64,
-    state: u64,
 }
 
-fn parse_json(price_json: &str, fingrid_json: &str) -> Result<ElecData, String> {
+async fn get_prices_json(url: &str) -> Result<Option<String>, reqwest::Error> {
+    let response = http_client::send(HTTP_CLIENT.get(url)).await?;
+
+    // spot-hinta.fi answers 404 until the next day's prices are published
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    Ok(Some(response.text().await?))
+}
+
+/// Prices per hour; quarter-hour prices are averaged into their hour
+fn parse_prices(price_json: &str) -> Result<Vec<HourlyPrice>, String> {
     let prices: serde_json::Value = match serde_json::from_str(price_json) {
         Ok(j) => j,
         Err(_) => {
@@ -43,56 +104,615 @@ fn parse_json(price_json: &str, fingrid_json: &str) -> Result<ElecData, String>
         }
     };
 
-    let hour = Local::now().hour();
+    let points = prices
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|p| {
+            let time = DateTime::parse_from_rfc3339(p["DateTime"].as_str()?).ok()?;
+            Some((time, p["PriceWithTax"].as_f64()? * 100.0))
+        })
+        .collect();
 
-    let price_with_tax = {
-        if let Some(d) = prices.as_array() {
-            let hourly = &d[hour as usize];
-            hourly["PriceWithTax"].as_f64()
-        } else {
-            return Err("No price found".to_string());
+    hourly_prices(points)
+}
+
+/// Average (time, c/kWh) points into hours
+fn hourly_prices(points: Vec<(DateTime<FixedOffset>, f64)>) -> Result<Vec<HourlyPrice>, String> {
+    let mut hourly: Vec<(DateTime<FixedOffset>, f64, u32)> = Vec::new();
+
+    for (time, price) in points {
+        let hour = time
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .unwrap_or(time);
+        match hourly.iter_mut().find(|(t, _, _)| *t == hour) {
+            Some((_, sum, count)) => {
+                *sum += price;
+                *count += 1;
+            }
+            None => hourly.push((hour, price, 1)),
         }
-    };
+    }
 
-    let fg: serde_json::Value = match serde_json::from_str(fingrid_json) {
-        Ok(j) => j,
+    if hourly.is_empty() {
+        return Err("No price found".to_string());
+    }
+
+    Ok(hourly
+        .into_iter()
+        .map(|(time, sum, count)| HourlyPrice {
+            time,
+            price: sum / count as f64,
+        })
+        .collect())
+}
+
+fn helsinki_time(time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    let local = time.with_timezone(&Helsinki);
+    local.with_timezone(&local.offset().fix())
+}
+
+/// Start and end of today in Finland
+fn today_range(now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let date = now.with_timezone(&Helsinki).date_naive();
+    let start = Helsinki
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .unwrap()
+        .with_timezone(&Utc);
+    let next = Helsinki
+        .from_local_datetime(&date.succ_opt().unwrap().and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .unwrap()
+        .with_timezone(&Utc);
+
+    (start, next)
+}
+
+async fn get_entsoe_xml(
+    token: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> reqwest::Result<String> {
+    let period_start = start.format("%Y%m%d%H%M").to_string();
+    let period_end = end.format("%Y%m%d%H%M").to_string();
+
+    let request = HTTP_CLIENT.get(ENTSOE_URL).query(&[
+        ("securityToken", token),
+        ("documentType", "A44"),
+        ("in_Domain", ENTSOE_FINLAND),
+        ("out_Domain", ENTSOE_FINLAND),
+        ("periodStart", &period_start),
+        ("periodEnd", &period_end),
+    ]);
+    http_client::get_cached(request, PRICE_TTL).await
+}
+
+/// Day-ahead prices from an ENTSO-E Publication_MarketDocument, in c/kWh with VAT
+fn parse_entsoe(xml: &str) -> Result<Vec<HourlyPrice>, String> {
+    let root = match xmltree::Element::parse(xml.as_bytes()) {
+        Ok(r) => r,
         Err(_) => {
-            return Err("Error parsing JSON".to_owned());
+            return Err("Error parsing xml".to_owned());
         }
     };
 
-    let mut consumption: Option<f64> = None;
-    let mut production: Option<f64> = None;
-    let mut importexport: Option<f64> = None;
-    let mut state: Option<u64> = None;
+    let text = |e: &xmltree::Element, name: &str| -> Option<String> {
+        Some(e.get_child(name)?.get_text()?.to_string())
+    };
 
-    if let Some(d) = fg.as_array() {
-        for info in d {
-            match info["variable_id"].as_i64() {
-                Some(192) => {
-                    production = info["value"].as_f64();
-                }
-                Some(193) => {
-                    consumption = info["value"].as_f64();
-                }
-                Some(194) => {
-                    importexport = info["value"].as_f64();
+    let mut points = Vec::new();
+
+    for series in root.children.iter().filter_map(|c| c.as_element()) {
+        if series.name != "TimeSeries" {
+            continue;
+        }
+        for period in series.children.iter().filter_map(|c| c.as_element()) {
+            if period.name != "Period" {
+                continue;
+            }
+            let start = period
+                .get_child("timeInterval")
+                .and_then(|i| text(i, "start"))
+                .and_then(|s| NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%MZ").ok())
+                .map(|s| Utc.from_utc_datetime(&s));
+            let minutes = match text(period, "resolution").as_deref() {
+                Some("PT15M") => 15,
+                Some("PT30M") => 30,
+                _ => 60,
+            };
+            let start = match start {
+                Some(s) => s,
+                None => continue,
+            };
+
+            for point in period.children.iter().filter_map(|c| c.as_element()) {
+                if point.name != "Point" {
+                    continue;
                 }
-                Some(209) => {
-                    state = info["value"].as_u64();
+                let position = text(point, "position").and_then(|p| p.parse::<i64>().ok());
+                let amount = text(point, "price.amount").and_then(|p| p.parse::<f64>().ok());
+                if let (Some(position), Some(amount)) = (position, amount) {
+                    let time = start + chrono::Duration::minutes((position - 1) * minutes);
+                    // EUR/MWh to c/kWh
+                    let price = amount / 10.0;
+                    let price = if price > 0.0 {
+                        price * (1.0 + VAT)
+                    } else {
+                        price
+                    };
+                    points.push((helsinki_time(time), price));
                 }
-                _ => {}
             }
         }
     }
 
+    hourly_prices(points)
+}
+
+pub const DB: db::Database = db::Database {
+    name: "sahko.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS prices (
+            timestamp INTEGER PRIMARY KEY,
+            time TEXT NOT NULL,
+            price REAL NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn store_prices(conn: &Connection, prices: &[HourlyPrice]) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO prices (timestamp, time, price) VALUES (:timestamp, :time, :price)",
+    )?;
+    for p in prices {
+        statement.execute(named_params! {
+            ":timestamp": p.time.timestamp(),
+            ":time": p.time.to_rfc3339(),
+            ":price": p.price,
+        })?;
+    }
+
+    Ok(())
+}
+
+fn cached_prices(
+    conn: &Connection,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> rusqlite::Result<Vec<HourlyPrice>> {
+    let mut statement = conn.prepare(
+        "SELECT time, price FROM prices WHERE timestamp >= :start AND timestamp < :end
+        ORDER BY timestamp",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":start": start.timestamp(),
+        ":end": end.timestamp(),
+    })?;
+
+    let mut prices = Vec::new();
+    while let Some(row) = rows.next()? {
+        let time: String = row.get(0)?;
+        if let Ok(time) = DateTime::parse_from_rfc3339(&time) {
+            prices.push(HourlyPrice {
+                time,
+                price: row.get(1)?,
+            });
+        }
+    }
+
+    Ok(prices)
+}
+
+/// Today's prices from spot-hinta.fi, or from ENTSO-E or the local cache when it is down
+async fn today_prices(config: &Yaml) -> Result<Vec<HourlyPrice>, String> {
+    let now = Utc::now();
+
+    // Cached prices are no use after midnight
+    if let Some(prices) = cache_get(&TODAY_CACHE, PRICE_TTL_MINUTES, now)
+        .filter(|p: &Vec<HourlyPrice>| current_price(p, now).is_some())
+    {
+        return Ok(prices);
+    }
+
+    let prices = fetch_today_prices(config).await?;
+    cache_put(&TODAY_CACHE, prices.clone(), now);
+
+    Ok(prices)
+}
+
+/// Tomorrow's prices, or None until they are published
+async fn tomorrow_prices() -> Result<Option<Vec<HourlyPrice>>, String> {
+    let now = Utc::now();
+    let tomorrow_start = today_range(now).1;
+
+    if let Some(prices) = cache_get(&TOMORROW_CACHE, PRICE_TTL_MINUTES, now)
+        .filter(|p: &Vec<HourlyPrice>| p.first().is_some_and(|p| p.time >= tomorrow_start))
+    {
+        return Ok(Some(prices));
+    }
+
+    match get_prices_json(TOMORROW_URL).await {
+        Ok(Some(json)) => {
+            let prices = parse_prices(&json)?;
+            cache_put(&TOMORROW_CACHE, prices.clone(), now);
+            Ok(Some(prices))
+        }
+        Ok(None) => Ok(None),
+        Err(_) => Err("Virhe datan haussa".to_owned()),
+    }
+}
+
+async fn fetch_today_prices(config: &Yaml) -> Result<Vec<HourlyPrice>, String> {
+    if let Ok(Some(json)) = get_prices_json(TODAY_URL).await {
+        if let Ok(prices) = parse_prices(&json) {
+            let stored = prices.clone();
+            if let Err(e) = db::call(&DB, move |c| store_prices(c, &stored)).await {
+                error!("Error caching electricity prices: {}", e);
+            }
+            return Ok(prices);
+        }
+    }
+
+    warn!("spot-hinta.fi unavailable, falling back");
+    let (start, end) = today_range(Utc::now());
+
+    if let Some(token) = config["entsoe"]["token"].as_str() {
+        if let Ok(xml) = get_entsoe_xml(token, start, end).await {
+            match parse_entsoe(&xml) {
+                Ok(prices) => return Ok(prices),
+                Err(e) => warn!("ENTSO-E: {}", e),
+            }
+        }
+    }
+
+    match db::call(&DB, move |c| cached_prices(c, start, end)).await {
+        Ok(prices) if !prices.is_empty() => Ok(prices),
+        _ => Err("Virhe datan haussa".to_owned()),
+    }
+}
+
+/// (min, max, average) of the prices
+fn summary(prices: &[HourlyPrice]) -> (f64, f64, f64) {
+    let min = prices.iter().map(|p| p.price).fold(f64::INFINITY, f64::min);
+    let max = prices
+        .iter()
+        .map(|p| p.price)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let avg = prices.iter().map(|p| p.price).sum::<f64>() / prices.len() as f64;
+
+    (min, max, avg)
+}
+
+/// The price of the hour `now` falls in
+fn current_price(prices: &[HourlyPrice], now: DateTime<Utc>) -> Option<f64> {
+    prices
+        .iter()
+        .find(|p| p.time <= now && now < p.time + chrono::Duration::hours(1))
+        .map(|p| p.price)
+}
+
+/// Start index and average price of the cheapest run of `hours` consecutive hours
+fn cheapest_window(prices: &[HourlyPrice], hours: usize) -> Option<(usize, f64)> {
+    if hours == 0 || prices.len() < hours {
+        return None;
+    }
+
+    prices
+        .windows(hours)
+        .enumerate()
+        .filter(|(_, w)| w[hours - 1].time - w[0].time == chrono::Duration::hours(hours as i64 - 1))
+        .map(|(i, w)| (i, w.iter().map(|p| p.price).sum::<f64>() / hours as f64))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+fn generate_cheapest_msg(prices: &[HourlyPrice], hours: usize) -> String {
+    match cheapest_window(prices, hours) {
+        Some((start, avg)) => {
+            let first = &prices[start];
+            let end = first.time + chrono::Duration::hours(hours as i64);
+            format!(
+                "Halvin {}h jakso: {} klo {}–{}, keskihinta {:.2} snt/kWh",
+                hours,
+                first.time.format("%d.%m."),
+                first.time.format("%H"),
+                end.format("%H"),
+                avg
+            )
+        }
+        None => "Hintoja ei ole tarpeeksi".to_owned(),
+    }
+}
+
+/// Today's prices, and the prices from the current hour onwards including
+/// tomorrow once published
+async fn today_and_upcoming_prices(
+    config: &Yaml,
+) -> Result<(Vec<HourlyPrice>, Vec<HourlyPrice>), String> {
+    let (today, tomorrow) = tokio::join!(today_prices(config), tomorrow_prices());
+
+    let today = today?;
+    let mut prices = today.clone();
+    if let Ok(Some(p)) = tomorrow {
+        prices.extend(p);
+    }
+
+    let now = Utc::now();
+    prices.retain(|p| p.time + chrono::Duration::hours(1) > now);
+
+    Ok((today, prices))
+}
+
+async fn cheapest_msg(params: &str, config: &Yaml) -> String {
+    let hours = match params.trim().parse::<usize>() {
+        Ok(h) if (1..=24).contains(&h) => h,
+        _ => {
+            return "Käyttö: .sähkö halvin <tunnit 1-24>".to_owned();
+        }
+    };
+
+    match today_and_upcoming_prices(config).await {
+        Ok((_, upcoming)) => generate_cheapest_msg(&upcoming, hours),
+        Err(e) => e,
+    }
+}
+
+/// One bar per hour, scaled between the day's cheapest and most expensive hour
+fn generate_graph_msg(prices: &[HourlyPrice], now: DateTime<Utc>) -> String {
+    let (min, max, _) = summary(prices);
+
+    let graph: String = prices
+        .iter()
+        .map(|p| {
+            let level = if max > min {
+                ((p.price - min) / (max - min) * (GRAPH_BARS.len() - 1) as f64).round() as usize
+            } else {
+                GRAPH_BARS.len() / 2
+            };
+            let bar = GRAPH_BARS[level.min(GRAPH_BARS.len() - 1)];
+            if p.time <= now && now < p.time + chrono::Duration::hours(1) {
+                format!("{}{}{}", HIGHLIGHT, bar, HIGHLIGHT)
+            } else {
+                bar.to_string()
+            }
+        })
+        .collect();
+
+    format!(
+        "Sähkö tänään klo {}–{} ({:.2}…{:.2} snt/kWh): {}",
+        prices[0].time.format("%H"),
+        prices[prices.len() - 1].time.format("%H"),
+        min,
+        max,
+        graph
+    )
+}
+
+async fn graph_msg(config: &Yaml) -> String {
+    match today_prices(config).await {
+        Ok(prices) => generate_graph_msg(&prices, Utc::now()),
+        Err(e) => e,
+    }
+}
+
+/// Cost in euros of using `kwh` spread evenly over `hours` now, in the
+/// cheapest upcoming window and at today's average price
+fn generate_consumption_msg(
+    today: &[HourlyPrice],
+    upcoming: &[HourlyPrice],
+    kwh: f64,
+    hours: usize,
+) -> String {
+    let cost = |price: f64| kwh * price / 100.0;
+    let mut parts = Vec::new();
+
+    if upcoming.len() >= hours {
+        let now = upcoming[..hours].iter().map(|p| p.price).sum::<f64>() / hours as f64;
+        parts.push(format!("nyt {:.2} €", cost(now)));
+    }
+    if let Some((start, avg)) = cheapest_window(upcoming, hours) {
+        let first = &upcoming[start];
+        let end = first.time + chrono::Duration::hours(hours as i64);
+        parts.push(format!(
+            "halvimmillaan {:.2} € (klo {}–{})",
+            cost(avg),
+            first.time.format("%H"),
+            end.format("%H")
+        ));
+    }
+    if !today.is_empty() {
+        let (_, _, avg) = summary(today);
+        parts.push(format!("päivän keskihinnalla {:.2} €", cost(avg)));
+    }
+
+    if parts.is_empty() {
+        return "Hintoja ei ole tarpeeksi".to_owned();
+    }
+
+    format!("{} kWh ({}h): {}", kwh, hours, parts.join(", "))
+}
+
+async fn consumption_msg(params: &str, config: &Yaml) -> String {
+    let usage = "Käyttö: .sähkö kulutus <kWh> [tunnit 1-24]";
+    let mut params = params.split_whitespace();
+
+    let kwh = match params.next().map(|k| k.replace(',', ".").parse::<f64>()) {
+        Some(Ok(k)) if k > 0.0 => k,
+        _ => {
+            return usage.to_owned();
+        }
+    };
+    let hours = match params.next().map(|h| h.parse::<usize>()) {
+        None => 1,
+        Some(Ok(h)) if (1..=24).contains(&h) => h,
+        _ => {
+            return usage.to_owned();
+        }
+    };
+
+    match today_and_upcoming_prices(config).await {
+        Ok((today, upcoming)) => generate_consumption_msg(&today, &upcoming, kwh, hours),
+        Err(e) => e,
+    }
+}
+
+fn generate_day_msg(title: &str, prices: &[HourlyPrice]) -> String {
+    let (min, max, avg) = summary(prices);
+
+    let mut expensive: Vec<&HourlyPrice> = prices.iter().collect();
+    expensive.sort_by(|a, b| b.price.total_cmp(&a.price));
+    let expensive: Vec<String> = expensive
+        .iter()
+        .take(EXPENSIVE_HOURS_IN_MSG)
+        .map(|p| format!("klo {} ({:.2})", p.time.format("%H"), p.price))
+        .collect();
+
+    format!(
+        "{}: min {:.2}, max {:.2}, keskiarvo {:.2} snt/kWh | Kalleimmat tunnit: {}",
+        title,
+        min,
+        max,
+        avg,
+        expensive.join(", ")
+    )
+}
+
+async fn tomorrow_msg() -> String {
+    match tomorrow_prices().await {
+        Ok(Some(prices)) => generate_day_msg("Sähkö huomenna", &prices),
+        Ok(None) => "Huomisen hintoja ei ole vielä julkaistu (yleensä klo 14 jälkeen)".to_owned(),
+        Err(e) => e,
+    }
+}
+
+const FINGRID_URL: &str = "https://data.fingrid.fi/api/datasets";
+
+/// Fingrid open data dataset ids, overridable under `fingrid: datasets:`
+struct FingridDatasets {
+    production: i64,
+    consumption: i64,
+    importexport: i64,
+    state: i64,
+}
+
+impl FingridDatasets {
+    fn from_config(config: &Yaml) -> FingridDatasets {
+        let datasets = &config["fingrid"]["datasets"];
+
+        FingridDatasets {
+            production: datasets["production"].as_i64().unwrap_or(192),
+            consumption: datasets["consumption"].as_i64().unwrap_or(193),
+            importexport: datasets["importexport"].as_i64().unwrap_or(194),
+            state: datasets["state"].as_i64().unwrap_or(209),
+        }
+    }
+}
+
+async fn get_fingrid_json(dataset: i64, fingrid_api_key: &str) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT
+        .get(format!("{}/{}/data/latest", FINGRID_URL, dataset))
+        .header("x-api-key", fingrid_api_key);
+    http_client::send(request).await?.text().await
+}
+
+/// The latest value of each Fingrid dataset, in the order production,
+/// consumption, import/export, system state
+async fn get_json(
+    fingrid_api_key: &str,
+    datasets: &FingridDatasets,
+) -> Result<[String; 4], reqwest::Error> {
+    let (production, consumption, importexport, state) = tokio::join!(
+        get_fingrid_json(datasets.production, fingrid_api_key),
+        get_fingrid_json(datasets.consumption, fingrid_api_key),
+        get_fingrid_json(datasets.importexport, fingrid_api_key),
+        get_fingrid_json(datasets.state, fingrid_api_key)
+    );
+
+    Ok([production?, consumption?, importexport?, state?])
+}
+
+/// Fingrid values through the cache. When fetching fails, older values are
+/// returned along with the time they were fetched.
+async fn fingrid_values(
+    fingrid_api_key: &str,
+    datasets: &FingridDatasets,
+) -> Option<([String; 4], Option<DateTime<Utc>>)> {
+    let now = Utc::now();
+
+    if let Some(values) = cache_get(&FINGRID_CACHE, FINGRID_TTL_MINUTES, now) {
+        return Some((values, None));
+    }
+
+    match get_json(fingrid_api_key, datasets).await {
+        Ok(values) if values.iter().all(|v| parse_fingrid_value(v).is_some()) => {
+            cache_put(&FINGRID_CACHE, values.clone(), now);
+            Some((values, None))
+        }
+        _ => {
+            warn!("Fetching Fingrid data failed, using cached values");
+            cache_get_stale(&FINGRID_CACHE).map(|(values, fetched)| (values, Some(fetched)))
+        }
+    }
+}
+
+struct ElecData {
+    price: f64,
+    next_price: Option<f64>,
+    // 1 for the cheapest hour of the day
+    rank: usize,
+    // (min, max, average) for today
+    today: (f64, f64, f64),
+    consumption: f64,
+    production: f64,
+    importexport: f64,
+    state: u64,
+}
+
+fn parse_fingrid_value(fingrid_json: &str) -> Option<f64> {
+    let json: serde_json::Value = serde_json::from_str(fingrid_json).ok()?;
+
+    json["value"].as_f64()
+}
+
+/// Position of `price` among the day's prices, cheapest first
+fn cheapness_rank(prices: &[HourlyPrice], price: f64) -> usize {
+    prices.iter().filter(|p| p.price < price).count() + 1
+}
+
+fn parse_json(
+    today: &[HourlyPrice],
+    upcoming: &[HourlyPrice],
+    fingrid_jsons: &[String; 4],
+    now: DateTime<Utc>,
+) -> Result<ElecData, String> {
+    let price = match current_price(today, now) {
+        Some(p) => p,
+        None => {
+            return Err("No price found".to_string());
+        }
+    };
+
+    let [production, consumption, importexport, state] =
+        fingrid_jsons.each_ref().map(|j| parse_fingrid_value(j));
+
     if let (Some(c), Some(p), Some(i), Some(s)) = (consumption, production, importexport, state) {
         Ok(ElecData {
-            price: price_with_tax.unwrap() * 100.0,
+            price,
+            next_price: current_price(upcoming, now + chrono::Duration::hours(1)),
+            rank: cheapness_rank(today, price),
+            today: summary(today),
             consumption: c,
             production: p,
             importexport: i,
-            state: s,
+            state: s as u64,
         })
     } else {
         Err("Fingrid-tietojen hakemisessa virhe".to_string())
@@ -108,17 +728,44 @@ fn generate_msg(data: ElecData) -> String {
         5 => " | Sähköjärjestelmän käyttötila: Vakavan häiriön käytönpalautus on menossa.",
         _ => " | Sähköjärjestelmän käyttötila: Tuntematon",
     };
+    let next_msg = match data.next_price {
+        Some(next) if next > data.price => {
+            format!(", seuraava tunti {:.2} ↑ +{:.2}", next, next - data.price)
+        }
+        Some(next) if next < data.price => {
+            format!(", seuraava tunti {:.2} ↓ -{:.2}", next, data.price - next)
+        }
+        Some(next) => format!(", seuraava tunti {:.2} →", next),
+        None => String::new(),
+    };
     format!(
-        "Sähkön spot-hinta: {:.2} snt/kWh | Tuotanto: {} MW | Kulutus: {} MW | Tuonti-/vienti+: {} MW{}",
-        data.price, data.production, data.consumption, data.importexport, state_msg
+        "Sähkön spot-hinta: {:.2} snt/kWh{} (päivän {}. halvin tunti; tänään min {:.2}, max {:.2}, ka {:.2}) | Tuotanto: {} MW | Kulutus: {} MW | Tuonti-/vienti+: {} MW{}",
+        data.price, next_msg, data.rank, data.today.0, data.today.1, data.today.2, data.production, data.consumption, data.importexport, state_msg
     )
 }
 
 pub async fn command_sahko(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
+    params: &str,
     config: Arc<Yaml>,
 ) {
+    let subcommand_msg = match params.split_once(' ').unwrap_or((params, "")) {
+        ("huomenna", _) => Some(tomorrow_msg().await),
+        ("halvin", hours) => Some(cheapest_msg(hours, &config).await),
+        ("kulutus", usage) => Some(consumption_msg(usage, &config).await),
+        ("graafi", _) => Some(graph_msg(&config).await),
+        _ => None,
+    };
+    if let Some(msg) = subcommand_msg {
+        let action = BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        };
+        bot_sender.send(action).await.unwrap();
+        return;
+    }
+
     let fingrid_apikey = match config["fingrid"]["apikey"].as_str() {
         Some(a) => a,
         _ => {
@@ -126,9 +773,23 @@ pub async fn command_sahko(
         }
     };
 
-    let msg = if let Ok((price_json, fingrid_json)) = get_json(fingrid_apikey).await {
-        match parse_json(&price_json, &fingrid_json) {
-            Ok(data) => generate_msg(data),
+    let datasets = FingridDatasets::from_config(&config);
+
+    let (prices, fingrid) = tokio::join!(
+        today_and_upcoming_prices(&config),
+        fingrid_values(fingrid_apikey, &datasets)
+    );
+
+    let msg = if let (Ok((today, upcoming)), Some((fingrid_jsons, stale))) = (prices, fingrid) {
+        match parse_json(&today, &upcoming, &fingrid_jsons, Utc::now()) {
+            Ok(data) => match stale {
+                Some(fetched) => format!(
+                    "{} (Fingrid-tiedot klo {})",
+                    generate_msg(data),
+                    fetched.with_timezone(&Helsinki).format("%H:%M")
+                ),
+                None => generate_msg(data),
+            },
             Err(_) => "Virhe datan haussa".to_owned(),
         }
     } else {
@@ -142,3 +803,314 @@ pub async fn command_sahko(
 
     bot_sender.send(action).await.unwrap();
 }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PriceLevel {
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    target: ChatTarget,
+    above: Option<f64>,
+    below: Option<f64>,
+    tomorrow: bool,
+}
+
+impl Subscription {
+    fn level(&self, price: f64) -> PriceLevel {
+        match (self.above, self.below) {
+            (Some(above), _) if price > above => PriceLevel::High,
+            (_, Some(below)) if price < below => PriceLevel::Low,
+            _ => PriceLevel::Normal,
+        }
+    }
+
+    /// Message for the price moving from `previous` to a new level
+    fn threshold_msg(&self, previous: PriceLevel, price: f64) -> Option<String> {
+        let current = self.level(price);
+        if current == previous {
+            return None;
+        }
+
+        match current {
+            PriceLevel::High => Some(format!(
+                "Sähkön hinta nousi yli {} snt/kWh: nyt {:.2} snt/kWh",
+                self.above?, price
+            )),
+            PriceLevel::Low => Some(format!(
+                "Sähkön hinta laski alle {} snt/kWh: nyt {:.2} snt/kWh",
+                self.below?, price
+            )),
+            PriceLevel::Normal => None,
+        }
+    }
+}
+
+fn subscriptions_from_config(config: &Yaml) -> Vec<Subscription> {
+    let number = |y: &Yaml| y.as_f64().or_else(|| y.as_i64().map(|i| i as f64));
+    let mut subscriptions = Vec::new();
+
+    if let Some(channels) = config["sahko_alerts"]["channels"].as_vec() {
+        for c in channels {
+            if let (Some(network), Some(channel)) = (c["network"].as_str(), c["channel"].as_str()) {
+                subscriptions.push(Subscription {
+                    target: ChatTarget {
+                        network: network.to_owned(),
+                        channel: channel.to_owned(),
+                    },
+                    above: number(&c["above"]),
+                    below: number(&c["below"]),
+                    tomorrow: c["tomorrow"].as_bool().unwrap_or(false),
+                });
+            }
+        }
+    }
+
+    subscriptions
+}
+
+async fn announce(sender: &mpsc::Sender<BotAction>, target: &ChatTarget, msg: String) {
+    let action = BotAction {
+        target: ChatTarget {
+            network: target.network.to_owned(),
+            channel: target.channel.to_owned(),
+        },
+        action_type: ActionType::Message(msg),
+    };
+    sender.send(action).await.unwrap();
+}
+
+pub async fn sahko_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(5 * 60);
+    let subscriptions = subscriptions_from_config(&config);
+
+    if subscriptions.is_empty() {
+        info!("No electricity price subscriptions configured");
+        return;
+    }
+
+    // The level at startup is only remembered, not announced
+    let mut levels: Option<Vec<PriceLevel>> = None;
+    let mut tomorrow_announced: Option<NaiveDate> = None;
+
+    loop {
+        match today_prices(&config).await {
+            Ok(prices) => match current_price(&prices, Utc::now()) {
+                Some(price) => {
+                    if let Some(previous) = &levels {
+                        for (s, level) in subscriptions.iter().zip(previous) {
+                            if let Some(msg) = s.threshold_msg(*level, price) {
+                                announce(&sender, &s.target, msg).await;
+                            }
+                        }
+                    }
+                    levels = Some(subscriptions.iter().map(|s| s.level(price)).collect());
+                }
+                None => warn!("No current electricity price found"),
+            },
+            Err(_) => warn!("Error fetching electricity prices"),
+        }
+
+        let today = Local::now().date_naive();
+        if Local::now().hour() >= TOMORROW_PUBLISHED_HOUR && tomorrow_announced != Some(today) {
+            if let Ok(Some(prices)) = tomorrow_prices().await {
+                let msg = generate_day_msg("Sähkö huomenna", &prices);
+                for s in subscriptions.iter().filter(|s| s.tomorrow) {
+                    announce(&sender, &s.target, msg.to_owned()).await;
+                }
+                tomorrow_announced = Some(today);
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> rusqlite::Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    const DAY_JSON: &str = r#"[
+        {"Rank":3,"DateTime":"2023-11-02T00:00:00+02:00","PriceNoTax":0.0400,"PriceWithTax":0.0502},
+        {"Rank":1,"DateTime":"2023-11-02T01:00:00+02:00","PriceNoTax":0.0100,"PriceWithTax":0.0126},
+        {"Rank":4,"DateTime":"2023-11-02T02:00:00+02:00","PriceNoTax":0.1500,"PriceWithTax":0.1500},
+        {"Rank":4,"DateTime":"2023-11-02T02:15:00+02:00","PriceNoTax":0.1500,"PriceWithTax":0.2500},
+        {"Rank":2,"DateTime":"2023-11-02T03:00:00+02:00","PriceNoTax":0.0200,"PriceWithTax":0.0251}
+    ]"#;
+
+    #[test]
+    fn sahko_day_summary() {
+        let prices = parse_prices(DAY_JSON).unwrap();
+        assert_eq!(prices.len(), 4);
+        assert!((prices[2].price - 20.0).abs() < 0.001);
+
+        assert_eq!(
+            generate_day_msg("Sähkö huomenna", &prices),
+            "Sähkö huomenna: min 1.26, max 20.00, keskiarvo 7.20 snt/kWh | Kalleimmat tunnit: klo 02 (20.00), klo 00 (5.02), klo 03 (2.51)"
+        );
+        assert!(parse_prices("[]").is_err());
+    }
+
+    #[test]
+    fn sahko_cheapest() {
+        let prices = parse_prices(DAY_JSON).unwrap();
+
+        let now = Utc.with_ymd_and_hms(2023, 11, 1, 22, 30, 0).unwrap();
+        assert!((current_price(&prices, now).unwrap() - 5.02).abs() < 0.001);
+
+        assert_eq!(
+            generate_cheapest_msg(&prices, 2),
+            "Halvin 2h jakso: 02.11. klo 00–02, keskihinta 3.14 snt/kWh"
+        );
+        assert_eq!(cheapest_window(&prices, 1).unwrap().0, 1);
+        assert_eq!(cheapest_window(&prices, 5), None);
+    }
+
+    #[test]
+    fn sahko_alerts() {
+        let subscription = Subscription {
+            target: ChatTarget {
+                network: "testnetwork".to_owned(),
+                channel: "#testing".to_owned(),
+            },
+            above: Some(30.0),
+            below: Some(1.0),
+            tomorrow: false,
+        };
+
+        assert_eq!(subscription.level(12.0), PriceLevel::Normal);
+        assert_eq!(
+            subscription.threshold_msg(PriceLevel::Normal, 31.5),
+            Some("Sähkön hinta nousi yli 30 snt/kWh: nyt 31.50 snt/kWh".to_owned())
+        );
+        assert_eq!(subscription.threshold_msg(PriceLevel::High, 35.0), None);
+        assert_eq!(
+            subscription.threshold_msg(PriceLevel::High, 0.5),
+            Some("Sähkön hinta laski alle 1 snt/kWh: nyt 0.50 snt/kWh".to_owned())
+        );
+        assert_eq!(subscription.threshold_msg(PriceLevel::Low, 5.0), None);
+    }
+
+    #[test]
+    fn sahko_fingrid() {
+        let latest = r#"{"datasetId":192,"startTime":"2024-03-01T10:00:00.000Z","endTime":"2024-03-01T10:03:00.000Z","value":9876.5}"#;
+        assert_eq!(parse_fingrid_value(latest), Some(9876.5));
+        assert_eq!(parse_fingrid_value(r#"{"message":"Unauthorized"}"#), None);
+
+        let datasets = FingridDatasets::from_config(&Yaml::Null);
+        assert_eq!(datasets.production, 192);
+        assert_eq!(datasets.state, 209);
+    }
+
+    const ENTSOE_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<Publication_MarketDocument xmlns="urn:iec62325.351:tc57wg16:451-3:publicationdocument:7:3">
+  <mRID>1</mRID>
+  <TimeSeries>
+    <mRID>1</mRID>
+    <Period>
+      <timeInterval>
+        <start>2023-11-01T22:00Z</start>
+        <end>2023-11-02T00:00Z</end>
+      </timeInterval>
+      <resolution>PT15M</resolution>
+      <Point><position>1</position><price.amount>40.00</price.amount></Point>
+      <Point><position>2</position><price.amount>40.00</price.amount></Point>
+      <Point><position>3</position><price.amount>40.00</price.amount></Point>
+      <Point><position>4</position><price.amount>40.00</price.amount></Point>
+      <Point><position>5</position><price.amount>-10.00</price.amount></Point>
+    </Period>
+  </TimeSeries>
+</Publication_MarketDocument>"#;
+
+    #[test]
+    fn sahko_entsoe_and_cache() {
+        let prices = parse_entsoe(ENTSOE_XML).unwrap();
+        assert_eq!(prices.len(), 2);
+        assert_eq!(prices[0].time.to_rfc3339(), "2023-11-02T00:00:00+02:00");
+        assert!((prices[0].price - 5.02).abs() < 0.001);
+        assert!((prices[1].price + 1.0).abs() < 0.001);
+
+        let conn = open_db(true).unwrap();
+        store_prices(&conn, &prices).unwrap();
+        store_prices(&conn, &parse_prices(DAY_JSON).unwrap()).unwrap();
+
+        let (start, end) = today_range(Utc.with_ymd_and_hms(2023, 11, 2, 10, 0, 0).unwrap());
+        assert_eq!(start, Utc.with_ymd_and_hms(2023, 11, 1, 22, 0, 0).unwrap());
+        let cached = cached_prices(&conn, start, end).unwrap();
+        assert_eq!(cached.len(), 4);
+        assert!((cached[1].price - 1.26).abs() < 0.001);
+    }
+
+    #[test]
+    fn sahko_consumption() {
+        let prices = parse_prices(DAY_JSON).unwrap();
+
+        assert_eq!(
+            generate_consumption_msg(&prices, &prices[1..], 10.0, 2),
+            "10 kWh (2h): nyt 1.06 €, halvimmillaan 1.06 € (klo 01–03), päivän keskihinnalla 0.72 €"
+        );
+        assert_eq!(
+            generate_consumption_msg(&[], &[], 10.0, 2),
+            "Hintoja ei ole tarpeeksi"
+        );
+    }
+
+    #[test]
+    fn sahko_trend() {
+        let prices = parse_prices(DAY_JSON).unwrap();
+        let fingrid = [
+            r#"{"value":9000.0}"#.to_owned(),
+            r#"{"value":10000.0}"#.to_owned(),
+            r#"{"value":1000.0}"#.to_owned(),
+            r#"{"value":1.0}"#.to_owned(),
+        ];
+
+        let now = Utc.with_ymd_and_hms(2023, 11, 1, 22, 30, 0).unwrap();
+        let data = parse_json(&prices, &prices, &fingrid, now).unwrap();
+        assert_eq!(data.rank, 3);
+        assert_eq!(
+            generate_msg(data),
+            "Sähkön spot-hinta: 5.02 snt/kWh, seuraava tunti 1.26 ↓ -3.76 (päivän 3. halvin tunti; tänään min 1.26, max 20.00, ka 7.20) | Tuotanto: 9000 MW | Kulutus: 10000 MW | Tuonti-/vienti+: 1000 MW"
+        );
+
+        let now = Utc.with_ymd_and_hms(2023, 11, 2, 1, 30, 0).unwrap();
+        let data = parse_json(&prices, &prices, &fingrid, now).unwrap();
+        assert_eq!(data.next_price, None);
+    }
+
+    #[test]
+    fn sahko_graph() {
+        let prices = parse_prices(DAY_JSON).unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 11, 2, 0, 10, 0).unwrap();
+
+        assert_eq!(
+            generate_graph_msg(&prices, now),
+            "Sähkö tänään klo 00–03 (1.26…20.00 snt/kWh): ▂▁\x02█\x02▁"
+        );
+    }
+
+    #[test]
+    fn sahko_cache() {
+        let cache: Mutex<Option<Cached<u32>>> = Mutex::new(None);
+        let now = Utc.with_ymd_and_hms(2023, 11, 2, 10, 0, 0).unwrap();
+
+        assert_eq!(cache_get(&cache, 3, now), None);
+        cache_put(&cache, 42, now);
+        assert_eq!(
+            cache_get(&cache, 3, now + chrono::Duration::minutes(2)),
+            Some(42)
+        );
+        assert_eq!(
+            cache_get(&cache, 3, now + chrono::Duration::minutes(5)),
+            None
+        );
+        assert_eq!(cache_get_stale(&cache), Some((42, now)));
+    }
+}
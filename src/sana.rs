@@ -0,0 +1,264 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use regex::Regex;
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const WIKTIONARY_URL: &str = "https://fi.wiktionary.org/w/api.php";
+const PAGE_URL: &str = "https://fi.wiktionary.org/wiki/";
+// Per word class, and at most this many word classes
+const MAX_DEFINITIONS: usize = 3;
+const MAX_CLASSES: usize = 2;
+
+/// Section headings of word classes in fi.wiktionary
+const WORD_CLASSES: [&str; 14] = [
+    "Substantiivi",
+    "Verbi",
+    "Adjektiivi",
+    "Adverbi",
+    "Pronomini",
+    "Numeraali",
+    "Erisnimi",
+    "Interjektio",
+    "Konjunktio",
+    "Postpositio",
+    "Prepositio",
+    "Partikkeli",
+    "Lyhenne",
+    "Fraasi",
+];
+
+#[derive(Debug, PartialEq)]
+struct WordClass {
+    name: String,
+    definitions: Vec<String>,
+    /// The model word of the inflection type, e.g. "valo" for "talo"
+    inflection: Option<String>,
+}
+
+lazy_static! {
+    static ref RE_LINK: Regex = Regex::new(r"\[\[(?:[^|\]]*\|)?([^\]]*)\]\]").unwrap();
+    static ref RE_TEMPLATE: Regex = Regex::new(r"\{\{[^{}]*\}\}").unwrap();
+    static ref RE_REF: Regex = Regex::new(r"<ref[^>]*/>|<ref[^>]*>.*?</ref>|<[^>]*>").unwrap();
+    static ref RE_INFLECTION: Regex =
+        Regex::new(r"\{\{fi-(?:subs|adj|verbi|pron|num)-([^|}]+)").unwrap();
+}
+
+async fn get_json(word: &str) -> reqwest::Result<String> {
+    let request = HTTP_CLIENT.get(WIKTIONARY_URL).query(&[
+        ("action", "query"),
+        ("prop", "revisions"),
+        ("rvprop", "content"),
+        ("rvslots", "main"),
+        ("redirects", "1"),
+        ("titles", word),
+        ("formatversion", "2"),
+        ("format", "json"),
+    ]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+/// The page title and wikitext, None if there is no such page
+fn parse_page(json_text: &str) -> Result<Option<(String, String)>, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())?;
+
+    let page = &json["query"]["pages"][0];
+    match (
+        page["title"].as_str(),
+        page["revisions"][0]["slots"]["main"]["content"].as_str(),
+    ) {
+        (Some(title), Some(text)) => Ok(Some((title.to_owned(), text.to_owned()))),
+        _ => Ok(None),
+    }
+}
+
+/// "[[rakennus]], jossa {{k|fi}}asutaan" -> "rakennus, jossa asutaan"
+fn plain_text(wikitext: &str) -> String {
+    let mut text = RE_REF.replace_all(wikitext, "").into_owned();
+    // Templates can be nested, so the innermost ones go first
+    while RE_TEMPLATE.is_match(&text) {
+        text = RE_TEMPLATE.replace_all(&text, "").into_owned();
+    }
+    let text = RE_LINK
+        .replace_all(&text, "$1")
+        .replace("'''", "")
+        .replace("''", "");
+
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let line = line.trim();
+    let level = line.chars().take_while(|c| *c == '=').count();
+    if level < 2 || !line.ends_with('=') {
+        return None;
+    }
+
+    Some((level, line.trim_matches('=').trim()))
+}
+
+/// Word classes of the Finnish section with their definitions
+fn parse_wikitext(wikitext: &str) -> Vec<WordClass> {
+    let mut classes: Vec<WordClass> = Vec::new();
+    let mut finnish = false;
+    let mut current: Option<usize> = None;
+
+    for line in wikitext.lines() {
+        if let Some((level, name)) = heading(line) {
+            if level == 2 {
+                finnish = name == "Suomi";
+                current = None;
+            } else if finnish && WORD_CLASSES.contains(&name) {
+                classes.push(WordClass {
+                    name: name.to_lowercase(),
+                    definitions: Vec::new(),
+                    inflection: None,
+                });
+                current = Some(classes.len() - 1);
+            }
+            continue;
+        }
+
+        let class = match current {
+            Some(i) if finnish => &mut classes[i],
+            _ => continue,
+        };
+        if let Some(definition) = line.strip_prefix('#') {
+            // "#:" examples, "#*" quotes and "##" sub-definitions are skipped
+            if !definition.starts_with([':', '*', '#']) {
+                let definition = plain_text(definition);
+                if !definition.is_empty() {
+                    class.definitions.push(definition);
+                }
+            }
+        } else if class.inflection.is_none() {
+            class.inflection = RE_INFLECTION.captures(line).map(|c| c[1].trim().to_owned());
+        }
+    }
+
+    classes.retain(|c| !c.definitions.is_empty());
+    classes
+}
+
+fn sana_msg(title: &str, classes: &[WordClass]) -> String {
+    if classes.is_empty() {
+        return format!("Sanalle {} ei löytynyt suomenkielistä määritelmää", title);
+    }
+
+    let list: Vec<String> = classes
+        .iter()
+        .take(MAX_CLASSES)
+        .map(|c| {
+            let definitions: Vec<String> = c
+                .definitions
+                .iter()
+                .take(MAX_DEFINITIONS)
+                .enumerate()
+                .map(|(i, d)| format!("{}. {}", i + 1, d))
+                .collect();
+            let mut msg = format!("({}) {}", c.name, definitions.join(" "));
+            if let Some(model) = &c.inflection {
+                msg.push_str(&format!(" [taipuu kuten {}]", model));
+            }
+            msg
+        })
+        .collect();
+
+    format!(
+        "{} {} | {}{}",
+        title,
+        list.join(" | "),
+        PAGE_URL,
+        title.replace(' ', "_")
+    )
+}
+
+async fn lookup(word: &str) -> Result<Option<(String, String)>, String> {
+    match get_json(word).await {
+        Ok(json) => parse_page(&json),
+        Err(_) => Err("Wiktionary API error".to_owned()),
+    }
+}
+
+pub async fn command_sana(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let word = params.trim();
+
+    let msg = if word.is_empty() {
+        "Usage: .sana <hakusana>".to_owned()
+    } else {
+        // Sentence-case searches find the lowercase entry
+        let mut page = lookup(word).await;
+        if matches!(page, Ok(None)) && word.to_lowercase() != word {
+            page = lookup(&word.to_lowercase()).await;
+        }
+        match page {
+            Ok(Some((title, text))) => sana_msg(&title, &parse_wikitext(&text)),
+            Ok(None) => format!("Sanaa {} ei löytynyt", word),
+            Err(e) => e,
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definitions() {
+        let wikitext = "==Suomi==
+===Substantiivi===
+{{fi-subs|talo|1}}
+# [[rakennus]], jossa [[asua|asutaan]]<ref>Kielitoimiston sanakirja</ref>
+#: ''Talossa on kolme huonetta.''
+# {{kuvaannollisesti|fi}} [[suku]], ''[[dynastia]]''
+## alamerkitys
+====Taivutus====
+{{fi-subs-valo|ta|l|o|a}}
+===Verbi===
+# ei ole
+==Viro==
+===Substantiivi===
+# maja";
+
+        let classes = parse_wikitext(wikitext);
+        assert_eq!(
+            classes[0],
+            WordClass {
+                name: "substantiivi".to_owned(),
+                definitions: vec![
+                    "rakennus, jossa asutaan".to_owned(),
+                    "suku, dynastia".to_owned()
+                ],
+                inflection: Some("valo".to_owned()),
+            }
+        );
+        assert_eq!(
+            sana_msg("talo", &classes),
+            "talo (substantiivi) 1. rakennus, jossa asutaan 2. suku, dynastia [taipuu kuten valo] \
+            | (verbi) 1. ei ole | https://fi.wiktionary.org/wiki/talo"
+        );
+        assert_eq!(
+            sana_msg("hus", &parse_wikitext("==Viro==\n===Verbi===\n# x")),
+            "Sanalle hus ei löytynyt suomenkielistä määritelmää"
+        );
+
+        let json =
+            r#"{"batchcomplete":true,"query":{"pages":[{"ns":0,"title":"xyz","missing":true}]}}"#;
+        assert_eq!(parse_page(json), Ok(None));
+    }
+}
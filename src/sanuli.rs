@@ -0,0 +1,498 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono_tz::Europe::Helsinki;
+use irc::client::prelude::Prefix;
+use log::warn;
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::leaderboard::{self, record_points};
+use crate::ChatTarget;
+
+const WORD_LENGTH: usize = 5;
+const MAX_GUESSES: i64 = 6;
+
+const WORDS_FI: [&str; 66] = [
+    "kissa", "koira", "talvi", "kirja", "pallo", "valas", "kukka", "metsä", "järvi", "saari",
+    "joulu", "kuusi", "tuuli", "sauna", "kylmä", "kello", "ranta", "laiva", "pyörä", "torni",
+    "katto", "tuoli", "pöytä", "kahvi", "leipä", "maito", "omena", "marja", "sieni", "hauki",
+    "ahven", "kettu", "jänis", "hirvi", "karhu", "ilves", "pöllö", "varis", "tähti", "pilvi",
+    "routa", "hanki", "kevät", "syksy", "päivä", "vuosi", "silta", "polku", "vuori", "aalto",
+    "laine", "kirje", "posti", "lehti", "radio", "laulu", "rumpu", "huilu", "noppa", "rakas",
+    "perhe", "sisko", "lapsi", "vauva", "mummo", "pappa",
+];
+
+const WORDS_EN: [&str; 60] = [
+    "apple", "beach", "chair", "dance", "eagle", "flame", "grape", "house", "input", "joker",
+    "knife", "lemon", "mango", "night", "ocean", "piano", "queen", "river", "stone", "table",
+    "uncle", "voice", "water", "youth", "zebra", "bread", "cloud", "dream", "earth", "field",
+    "ghost", "heart", "index", "jelly", "koala", "light", "money", "north", "order", "plant",
+    "quiet", "radio", "smile", "tiger", "unity", "value", "whale", "yacht", "brick", "crane",
+    "drink", "fruit", "globe", "honey", "ivory", "juice", "lunar", "magic", "novel", "olive",
+];
+
+pub const GAME: leaderboard::Game = leaderboard::Game {
+    id: "sanuli",
+    name: "Sanuli",
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lang {
+    Fi,
+    En,
+}
+
+impl Lang {
+    fn code(self) -> &'static str {
+        match self {
+            Lang::Fi => "fi",
+            Lang::En => "en",
+        }
+    }
+
+    /// Words from `sanuli: words_fi/words_en` files in config.yml, one per line,
+    /// or the bundled lists
+    fn words(self, config: &Yaml) -> Vec<String> {
+        let (key, bundled): (&str, &[&str]) = match self {
+            Lang::Fi => ("words_fi", &WORDS_FI),
+            Lang::En => ("words_en", &WORDS_EN),
+        };
+
+        if let Some(file) = config["sanuli"][key].as_str() {
+            match std::fs::read_to_string(file) {
+                Ok(contents) => {
+                    let words: Vec<String> = contents
+                        .lines()
+                        .map(|w| w.trim().to_lowercase())
+                        .filter(|w| w.chars().count() == WORD_LENGTH)
+                        .collect();
+                    if !words.is_empty() {
+                        return words;
+                    }
+                }
+                Err(e) => warn!("Could not read word list {}: {}", file, e),
+            }
+        }
+
+        bundled.iter().map(|w| (*w).to_owned()).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mark {
+    Correct,
+    Present,
+    Absent,
+}
+
+/// The word of the day is the same for everyone, changing at midnight in Finland
+fn daily_word(words: &[String], date: NaiveDate) -> &str {
+    let epoch = NaiveDate::from_ymd_opt(2022, 1, 1).unwrap();
+    let day = (date - epoch).num_days().max(0) as usize;
+
+    // Stepping with a prime keeps consecutive days from getting neighbouring words
+    &words[(day * 7919) % words.len()]
+}
+
+fn check_guess(guess: &str, answer: &str) -> Vec<Mark> {
+    let guess: Vec<char> = guess.chars().collect();
+    let answer: Vec<char> = answer.chars().collect();
+    let mut marks = vec![Mark::Absent; guess.len()];
+    let mut unmatched: Vec<Option<char>> = answer.iter().map(|c| Some(*c)).collect();
+
+    for (i, c) in guess.iter().enumerate() {
+        if answer.get(i) == Some(c) {
+            marks[i] = Mark::Correct;
+            unmatched[i] = None;
+        }
+    }
+    for (i, c) in guess.iter().enumerate() {
+        if marks[i] == Mark::Correct {
+            continue;
+        }
+        if let Some(slot) = unmatched.iter_mut().find(|u| **u == Some(*c)) {
+            marks[i] = Mark::Present;
+            *slot = None;
+        }
+    }
+
+    marks
+}
+
+/// Letters with IRC colours: green for the right place, yellow for a wrong
+/// place. The brackets keep it readable without colours.
+fn render(guess: &str, marks: &[Mark]) -> String {
+    guess
+        .chars()
+        .zip(marks)
+        .map(|(c, m)| {
+            let c = c.to_uppercase();
+            match m {
+                Mark::Correct => format!("\x0301,09[{}]\x03", c),
+                Mark::Present => format!("\x0301,08({})\x03", c),
+                Mark::Absent => format!(" {} ", c),
+            }
+        })
+        .collect()
+}
+
+pub const DB: db::Database = db::Database {
+    name: "sanuli.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS guesses (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            nick_lower TEXT NOT NULL,
+            lang TEXT NOT NULL,
+            date TEXT NOT NULL,
+            guess TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS streaks (
+            network TEXT NOT NULL,
+            nick_lower TEXT NOT NULL,
+            lang TEXT NOT NULL,
+            streak INTEGER NOT NULL,
+            max_streak INTEGER NOT NULL,
+            wins INTEGER NOT NULL,
+            played INTEGER NOT NULL,
+            last_win TEXT,
+            PRIMARY KEY(network, nick_lower, lang)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Today's guesses of the player in order
+fn guesses_today(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    lang: Lang,
+    date: NaiveDate,
+) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT guess FROM guesses WHERE network = :network AND nick_lower = :nick_lower
+        AND lang = :lang AND date = :date ORDER BY id",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": network,
+        ":nick_lower": nick.to_lowercase(),
+        ":lang": lang.code(),
+        ":date": date.to_string(),
+    })?;
+
+    let mut guesses = Vec::new();
+    while let Some(row) = rows.next()? {
+        guesses.push(row.get(0)?);
+    }
+
+    Ok(guesses)
+}
+
+fn add_guess(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    lang: Lang,
+    date: NaiveDate,
+    guess: &str,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO guesses (network, nick_lower, lang, date, guess)
+        VALUES (:network, :nick_lower, :lang, :date, :guess)",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+            ":lang": lang.code(),
+            ":date": date.to_string(),
+            ":guess": guess,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Updates the player's streak after a finished game and returns it
+fn finish_game(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    lang: Lang,
+    date: NaiveDate,
+    won: bool,
+) -> Result<i64> {
+    let previous: Option<(i64, Option<String>)> = conn
+        .query_row(
+            "SELECT streak, last_win FROM streaks
+            WHERE network = :network AND nick_lower = :nick_lower AND lang = :lang",
+            named_params! {
+                ":network": network,
+                ":nick_lower": nick.to_lowercase(),
+                ":lang": lang.code(),
+            },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    let yesterday = date.pred_opt().map(|d| d.to_string());
+    let streak = match (won, previous) {
+        (false, _) => 0,
+        (true, Some((streak, last_win))) if last_win.is_some() && last_win == yesterday => {
+            streak + 1
+        }
+        (true, _) => 1,
+    };
+
+    conn.execute(
+        "INSERT INTO streaks (network, nick_lower, lang, streak, max_streak, wins, played, last_win)
+        VALUES (:network, :nick_lower, :lang, :streak, :streak, :win, 1, :last_win)
+        ON CONFLICT(network, nick_lower, lang) DO UPDATE SET
+            streak = :streak,
+            max_streak = max(max_streak, :streak),
+            wins = wins + :win,
+            played = played + 1,
+            last_win = coalesce(:last_win, last_win)",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+            ":lang": lang.code(),
+            ":streak": streak,
+            ":win": won as i64,
+            ":last_win": if won { Some(date.to_string()) } else { None },
+        },
+    )?;
+
+    Ok(streak)
+}
+
+/// Returns the reply and the leaderboard points earned: a win with fewer
+/// guesses is worth more
+fn play(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    lang: Lang,
+    words: &[String],
+    guess: &str,
+    date: NaiveDate,
+) -> Result<(String, i64)> {
+    let answer = daily_word(words, date);
+    let guesses = guesses_today(conn, network, nick, lang, date)?;
+
+    if guesses.iter().any(|g| g == answer) || guesses.len() as i64 >= MAX_GUESSES {
+        let msg = match lang {
+            Lang::Fi => "Olet jo pelannut tämän päivän sanulin".to_owned(),
+            Lang::En => "You have already played today's word".to_owned(),
+        };
+        return Ok((msg, 0));
+    }
+
+    let guess = guess.to_lowercase();
+    if guess.chars().count() != WORD_LENGTH || !guess.chars().all(|c| c.is_alphabetic()) {
+        let msg = match lang {
+            Lang::Fi => format!("Arvauksen pitää olla {}-kirjaiminen sana", WORD_LENGTH),
+            Lang::En => format!("The guess must be a {}-letter word", WORD_LENGTH),
+        };
+        return Ok((msg, 0));
+    }
+
+    add_guess(conn, network, nick, lang, date, &guess)?;
+    let count = guesses.len() as i64 + 1;
+    let feedback = format!(
+        "{} {}/{}",
+        render(&guess, &check_guess(&guess, answer)),
+        count,
+        MAX_GUESSES
+    );
+
+    if guess == answer {
+        let streak = finish_game(conn, network, nick, lang, date, true)?;
+        let msg = match lang {
+            Lang::Fi => format!("{} Oikein! Putki: {}", feedback, streak),
+            Lang::En => format!("{} Correct! Streak: {}", feedback, streak),
+        };
+        Ok((msg, MAX_GUESSES + 1 - count))
+    } else if count >= MAX_GUESSES {
+        finish_game(conn, network, nick, lang, date, false)?;
+        let msg = match lang {
+            Lang::Fi => format!("{} Sana oli {}", feedback, answer.to_uppercase()),
+            Lang::En => format!("{} The word was {}", feedback, answer.to_uppercase()),
+        };
+        Ok((msg, 0))
+    } else {
+        Ok((feedback, 0))
+    }
+}
+
+async fn command(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+    lang: Lang,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let msg = if params.trim().is_empty() {
+        match lang {
+            Lang::Fi => "Usage: .sanuli <arvaus>".to_owned(),
+            Lang::En => "Usage: .wordle <guess>".to_owned(),
+        }
+    } else {
+        let words = lang.words(&config);
+        let today = Utc::now().with_timezone(&Helsinki).date_naive();
+        let (network, player, guess) = (
+            source.network.clone(),
+            nick.clone(),
+            params.trim().to_owned(),
+        );
+        match db::call(&DB, move |c| {
+            play(c, &network, &player, lang, &words, &guess, today)
+        })
+        .await
+        {
+            Ok((m, points)) => {
+                if points > 0 {
+                    record_points(&GAME, &source, &nick, points).await;
+                }
+                format!("{}: {}", nick, m)
+            }
+            Err(_) => "Database error".to_owned(),
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_sanuli(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    command(bot_sender, source, prefix, params, config, Lang::Fi).await;
+}
+
+pub async fn command_wordle(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    command(bot_sender, source, prefix, params, config, Lang::En).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn marks() {
+        use Mark::*;
+
+        assert_eq!(
+            check_guess("kissa", "kassi"),
+            vec![Correct, Present, Correct, Correct, Present]
+        );
+        assert_eq!(
+            check_guess("aaaaa", "pallo"),
+            vec![Absent, Correct, Absent, Absent, Absent]
+        );
+        assert_eq!(
+            render("pöytä", &check_guess("pöytä", "pöllö")),
+            "\x0301,09[P]\x03\x0301,09[Ö]\x03 Y  T  Ä "
+        );
+    }
+
+    #[test]
+    fn daily_game_and_streak() {
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        let words = Lang::Fi.words(&config[0]);
+        assert!(words.iter().all(|w| w.chars().count() == WORD_LENGTH));
+
+        let day = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+        let next_day = day.succ_opt().unwrap();
+        assert_ne!(daily_word(&words, day), daily_word(&words, next_day));
+
+        let conn = open_db(true).unwrap();
+        let answer = daily_word(&words, day).to_owned();
+        let play_day = |guess: &str, date| {
+            play(&conn, "testnet", "Nick", Lang::Fi, &words, guess, date)
+                .unwrap()
+                .0
+        };
+
+        assert_eq!(
+            play_day("liian pitkä", day),
+            "Arvauksen pitää olla 5-kirjaiminen sana"
+        );
+        assert!(play_day(&answer, day).ends_with("1/6 Oikein! Putki: 1"));
+        assert_eq!(
+            play_day(&answer, day),
+            "Olet jo pelannut tämän päivän sanulin"
+        );
+
+        let answer = daily_word(&words, next_day).to_owned();
+        let (msg, points) = play(
+            &conn,
+            "testnet",
+            "nick",
+            Lang::Fi,
+            &words,
+            &answer.to_uppercase(),
+            next_day,
+        )
+        .unwrap();
+        assert!(msg.ends_with("Putki: 2"));
+        assert_eq!(points, 6);
+
+        let last_day = next_day.succ_opt().unwrap();
+        let wrong = if daily_word(&words, last_day) == "xxxxx" {
+            "yyyyy"
+        } else {
+            "xxxxx"
+        };
+        for _ in 0..5 {
+            play_day(wrong, last_day);
+        }
+        assert!(play_day(wrong, last_day).contains("6/6 Sana oli"));
+        assert_eq!(
+            finish_game(&conn, "testnet", "nick", Lang::Fi, last_day, true).unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,420 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::Utc;
+use irc::client::prelude::{Command, Message, Prefix};
+use log::error;
+use rusqlite::{named_params, Connection, OptionalExtension, Result};
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+#[derive(Debug, PartialEq)]
+enum Activity {
+    Message(String),
+    Join,
+    Part(Option<String>),
+    Quit(Option<String>),
+}
+
+impl Activity {
+    fn event(&self) -> &'static str {
+        match self {
+            Activity::Message(_) => "message",
+            Activity::Join => "join",
+            Activity::Part(_) => "part",
+            Activity::Quit(_) => "quit",
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Activity::Message(m) => Some(m),
+            Activity::Part(r) | Activity::Quit(r) => r.as_deref(),
+            Activity::Join => None,
+        }
+    }
+
+    fn from_row(event: &str, text: Option<String>) -> Activity {
+        match event {
+            "join" => Activity::Join,
+            "part" => Activity::Part(text),
+            "quit" => Activity::Quit(text),
+            _ => Activity::Message(text.unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Seen {
+    nick: String,
+    channel: String,
+    activity: Activity,
+    time: i64,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "seen.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS seen (
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            nick_lower TEXT NOT NULL,
+            nick TEXT NOT NULL,
+            event TEXT NOT NULL,
+            message TEXT,
+            time INTEGER NOT NULL,
+            PRIMARY KEY(network, channel, nick_lower)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn record(
+    conn: &Connection,
+    source: &ChatTarget,
+    nick: &str,
+    activity: &Activity,
+    time: i64,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO seen (network, channel, nick_lower, nick, event, message, time)
+        VALUES (:network, :channel, :nick_lower, :nick, :event, :message, :time)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":nick_lower": nick.to_lowercase(),
+            ":nick": nick,
+            ":event": activity.event(),
+            ":message": activity.text(),
+            ":time": time,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// A quit has no channel, so it is recorded on every channel the nick was seen on
+fn record_quit(
+    conn: &Connection,
+    network: &str,
+    nick: &str,
+    reason: Option<&str>,
+    time: i64,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE seen SET nick = :nick, event = 'quit', message = :message, time = :time
+        WHERE network = :network AND nick_lower = :nick_lower",
+        named_params! {
+            ":network": network,
+            ":nick_lower": nick.to_lowercase(),
+            ":nick": nick,
+            ":message": reason,
+            ":time": time,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Latest activity of `nick` on the channel, or on any channel of the network
+/// when asked in a private message. Only the time of the latter is shown, as
+/// the asker may not be on that channel.
+fn last_seen(conn: &Connection, source: &ChatTarget, nick: &str) -> Result<Option<Seen>> {
+    let any_channel = !is_channel(&source.channel);
+
+    conn.query_row(
+        "SELECT nick, channel, event, message, time FROM seen
+        WHERE network = :network AND nick_lower = :nick_lower
+        AND (channel = :channel OR :any_channel)
+        ORDER BY time DESC LIMIT 1",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":nick_lower": nick.to_lowercase(),
+            ":any_channel": any_channel,
+        },
+        |row| {
+            let event: String = row.get(2)?;
+            Ok(Seen {
+                nick: row.get(0)?,
+                channel: row.get(1)?,
+                activity: Activity::from_row(&event, row.get(3)?),
+                time: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+fn channels_since(conn: &Connection, network: &str, nick: &str, since: i64) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT channel FROM seen WHERE network = :network AND nick_lower = :nick_lower
+        AND event != 'part' AND time >= :since ORDER BY channel",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": network,
+        ":nick_lower": nick.to_lowercase(),
+        ":since": since,
+    })?;
+
+    let mut channels = Vec::new();
+    while let Some(row) = rows.next()? {
+        let channel: String = row.get(0)?;
+        if is_channel(&channel) {
+            channels.push(channel);
+        }
+    }
+
+    Ok(channels)
+}
+
+/// Channels the nick has been on since `since` and not left
+pub async fn recent_channels(network: &str, nick: &str, since: i64) -> Vec<String> {
+    let (network, nick) = (network.to_owned(), nick.to_owned());
+    match db::call(&DB, move |c| channels_since(c, &network, &nick, since)).await {
+        Ok(channels) => channels,
+        Err(e) => {
+            error!("Error reading seen: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn is_channel(target: &str) -> bool {
+    ChatTarget::is_channel_name(target)
+}
+
+/// "3 päivää sitten"
+fn time_ago(seconds: i64) -> String {
+    let (amount, singular, plural) = match seconds {
+        s if s < 60 => {
+            return "juuri äsken".to_owned();
+        }
+        s if s < 60 * 60 => (s / 60, "minuutti", "minuuttia"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "tunti", "tuntia"),
+        s => (s / (24 * 60 * 60), "päivä", "päivää"),
+    };
+
+    match amount {
+        1 => format!("{} sitten", singular),
+        n => format!("{} {} sitten", n, plural),
+    }
+}
+
+fn generate_msg(seen: &Seen, now: i64, time_only: bool) -> String {
+    let ago = time_ago(now - seen.time);
+    if time_only {
+        return format!("{} nähtiin viimeksi {}", seen.nick, ago);
+    }
+    let reason = |r: &Option<String>| match r {
+        Some(r) if !r.is_empty() => format!(" ({})", r),
+        _ => String::new(),
+    };
+
+    match &seen.activity {
+        Activity::Message(m) => match m
+            .strip_prefix("\u{1}ACTION ")
+            .map(|a| a.trim_end_matches('\u{1}'))
+        {
+            Some(action) => format!(
+                "{} nähtiin viimeksi {}: * {} {}",
+                seen.nick, ago, seen.nick, action
+            ),
+            None => format!("{} nähtiin viimeksi {} sanomassa: {}", seen.nick, ago, m),
+        },
+        Activity::Join => format!(
+            "{} nähtiin viimeksi {} liittymässä kanavalle {}",
+            seen.nick, ago, seen.channel
+        ),
+        Activity::Part(r) => format!(
+            "{} nähtiin viimeksi {} poistumassa kanavalta {}{}",
+            seen.nick,
+            ago,
+            seen.channel,
+            reason(r)
+        ),
+        Activity::Quit(r) => format!(
+            "{} nähtiin viimeksi {} lopettamassa{}",
+            seen.nick,
+            ago,
+            reason(r)
+        ),
+    }
+}
+
+/// JOIN and PART can list several channels
+fn record_channels(
+    conn: &Connection,
+    network: &str,
+    channels: &str,
+    nick: &str,
+    activity: &Activity,
+    time: i64,
+) -> Result<()> {
+    for channel in channels.split(',') {
+        let source = ChatTarget {
+            network: network.to_owned(),
+            channel: channel.to_owned(),
+        };
+        record(conn, &source, nick, activity, time)?;
+    }
+
+    Ok(())
+}
+
+/// Called for every message from the server; records when nicks speak, join, part and quit
+pub async fn track_activity(network: String, message: Message) {
+    let nick = match &message.prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick.to_owned(),
+        _ => {
+            return;
+        }
+    };
+
+    let activity = match &message.command {
+        Command::PRIVMSG(target, msg) if is_channel(target) => Activity::Message(msg.to_owned()),
+        Command::JOIN(_, _, _) => Activity::Join,
+        Command::PART(_, reason) => Activity::Part(reason.to_owned()),
+        Command::QUIT(reason) => Activity::Quit(reason.to_owned()),
+        _ => {
+            return;
+        }
+    };
+
+    let time = Utc::now().timestamp();
+    let result = db::call(&DB, move |conn| match (&message.command, &activity) {
+        (Command::PRIVMSG(channels, _), _)
+        | (Command::JOIN(channels, _, _), _)
+        | (Command::PART(channels, _), _) => {
+            record_channels(conn, &network, channels, &nick, &activity, time)
+        }
+        (_, Activity::Quit(reason)) => record_quit(conn, &network, &nick, reason.as_deref(), time),
+        _ => Ok(()),
+    })
+    .await;
+
+    if let Err(e) = result {
+        error!("Error recording seen: {}", e);
+    }
+}
+
+pub async fn command_seen(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let nick = params.trim();
+
+    let msg = if nick.is_empty() || nick.contains(char::is_whitespace) {
+        "Usage: .seen <nick>".to_owned()
+    } else if matches!(&prefix, Some(Prefix::Nickname(n, _, _)) if n.eq_ignore_ascii_case(nick)) {
+        "Olet tässä.".to_owned()
+    } else {
+        let (target, wanted) = (source.clone(), nick.to_owned());
+        match db::call(&DB, move |c| last_seen(c, &target, &wanted)).await {
+            Ok(Some(seen)) => {
+                generate_msg(&seen, Utc::now().timestamp(), !is_channel(&source.channel))
+            }
+            Ok(None) => format!("En ole nähnyt nickiä {}", nick),
+            Err(_) => "Database error".to_owned(),
+        }
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn seen_activity() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        let other = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#other".to_owned(),
+        };
+        let private = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "asker".to_owned(),
+        };
+
+        record(&conn, &channel, "Nick", &Activity::Join, 1000).unwrap();
+        record(
+            &conn,
+            &channel,
+            "Nick",
+            &Activity::Message("moi".to_owned()),
+            2000,
+        )
+        .unwrap();
+        record(
+            &conn,
+            &other,
+            "Nick",
+            &Activity::Message("\u{1}ACTION heiluttaa\u{1}".to_owned()),
+            3000,
+        )
+        .unwrap();
+
+        assert_eq!(last_seen(&conn, &channel, "someoneelse").unwrap(), None);
+        assert_eq!(
+            channels_since(&conn, "testnetwork", "nick", 1500).unwrap(),
+            vec!["#other", "#testing"]
+        );
+        assert_eq!(
+            channels_since(&conn, "testnetwork", "nick", 2500).unwrap(),
+            vec!["#other"]
+        );
+
+        let seen = last_seen(&conn, &channel, "nick").unwrap().unwrap();
+        assert_eq!(
+            generate_msg(&seen, 2000 + 3 * 24 * 60 * 60 + 100, false),
+            "Nick nähtiin viimeksi 3 päivää sitten sanomassa: moi"
+        );
+
+        let seen = last_seen(&conn, &other, "nick").unwrap().unwrap();
+        assert_eq!(
+            generate_msg(&seen, 3000 + 60 * 60, false),
+            "Nick nähtiin viimeksi tunti sitten: * Nick heiluttaa"
+        );
+
+        // Private messages don't tell where or what
+        let seen = last_seen(&conn, &private, "NICK").unwrap().unwrap();
+        assert_eq!(
+            generate_msg(&seen, 3000 + 60 * 60, true),
+            "Nick nähtiin viimeksi tunti sitten"
+        );
+
+        record_quit(&conn, "testnetwork", "nick", Some("Ping timeout"), 5000).unwrap();
+        let seen = last_seen(&conn, &channel, "nick").unwrap().unwrap();
+        assert_eq!(
+            generate_msg(&seen, 5000 + 5 * 60, false),
+            "nick nähtiin viimeksi 5 minuuttia sitten lopettamassa (Ping timeout)"
+        );
+    }
+}
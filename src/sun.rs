@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use chrono::Duration;
+use irc::client::prelude::Prefix;
+use tokio::sync::mpsc;
+
+use crate::blitzortung::geocode;
+use crate::botaction::{ActionType, BotAction};
+use crate::timezone::get_timezone;
+use crate::weather_db::get_location;
+use crate::ChatTarget;
+
+#[derive(Debug, PartialEq)]
+enum SunTimes {
+    Normal(DateTime<Utc>, DateTime<Utc>),
+    MidnightSun,
+    PolarNight,
+}
+
+fn julian_to_utc(j: f64) -> DateTime<Utc> {
+    let secs = (j - 2440587.5) * 86400.0;
+    Utc.timestamp_opt(secs.round() as i64, 0).unwrap()
+}
+
+/// Sunrise and sunset for the given date
+///
+/// https://en.wikipedia.org/wiki/Sunrise_equation#Complete_calculation_on_Earth
+fn sun_times(date: NaiveDate, lat: f64, lon: f64) -> SunTimes {
+    let days_since_epoch = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()).num_days();
+    let n = days_since_epoch as f64 + 0.0008;
+    let j_star = n - lon / 360.0;
+
+    let m = (357.5291 + 0.98560028 * j_star).rem_euclid(360.0);
+    let m_rad = m.to_radians();
+    let c = 1.9148 * m_rad.sin() + 0.0200 * (2.0 * m_rad).sin() + 0.0003 * (3.0 * m_rad).sin();
+    let lambda = (m + c + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+
+    let j_transit = 2451545.0 + j_star + 0.0053 * m_rad.sin() - 0.0069 * (2.0 * lambda).sin();
+
+    let sin_delta = lambda.sin() * 23.4397_f64.to_radians().sin();
+    let cos_delta = sin_delta.asin().cos();
+    let phi = lat.to_radians();
+
+    let cos_omega =
+        ((-0.833_f64).to_radians().sin() - phi.sin() * sin_delta) / (phi.cos() * cos_delta);
+
+    if cos_omega < -1.0 {
+        return SunTimes::MidnightSun;
+    }
+    if cos_omega > 1.0 {
+        return SunTimes::PolarNight;
+    }
+
+    let omega = cos_omega.acos().to_degrees();
+
+    SunTimes::Normal(
+        julian_to_utc(j_transit - omega / 360.0),
+        julian_to_utc(j_transit + omega / 360.0),
+    )
+}
+
+fn format_day_length(d: &Duration) -> String {
+    format!("{}h{:02}m", d.num_hours(), d.num_minutes() % 60)
+}
+
+fn format_change(d: &Duration) -> String {
+    let sign = if d.num_seconds() < 0 { "-" } else { "+" };
+    let secs = d.num_seconds().abs();
+
+    format!("{}{}m{:02}s", sign, secs / 60, secs % 60)
+}
+
+fn generate_msg<T: TimeZone>(place: &str, date: NaiveDate, lat: f64, lon: f64, tz: &T) -> String
+where
+    T::Offset: std::fmt::Display,
+{
+    match sun_times(date, lat, lon) {
+        SunTimes::Normal(rise, set) => {
+            let length = set - rise;
+            let mut msg = format!(
+                "{}: aurinko nousee {}, laskee {}, päivän pituus {}",
+                place,
+                rise.with_timezone(tz).format("%H:%M"),
+                set.with_timezone(tz).format("%H:%M"),
+                format_day_length(&length)
+            );
+
+            if let SunTimes::Normal(y_rise, y_set) = sun_times(date.pred_opt().unwrap(), lat, lon) {
+                let change = length - (y_set - y_rise);
+                msg.push_str(&format!(" ({} eilisestä)", format_change(&change)));
+            }
+
+            msg
+        }
+        SunTimes::MidnightSun => format!("{}: aurinko ei laske tänään", place),
+        SunTimes::PolarNight => format!("{}: aurinko ei nouse tänään", place),
+    }
+}
+
+pub async fn command_aurinko(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let location = match params {
+        "" => get_location(&prefix, &source).await,
+        _ => params.to_owned(),
+    };
+
+    let msg = match geocode(&location).await {
+        Ok((lat, lon)) => match get_timezone(&prefix, &source.network).await {
+            Some(tz) => {
+                let today = Utc::now().with_timezone(&tz).date_naive();
+                generate_msg(&location, today, lat, lon, &tz)
+            }
+            None => {
+                let today = Local::now().date_naive();
+                generate_msg(&location, today, lat, lon, &Local)
+            }
+        },
+        Err(_) => "Paikkaa ei löytynyt".to_owned(),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_helsinki_midsummer() {
+        let date = NaiveDate::from_ymd_opt(2023, 6, 21).unwrap();
+        let tz = chrono_tz::Europe::Helsinki;
+
+        if let SunTimes::Normal(rise, set) = sun_times(date, 60.17, 24.94) {
+            let expected_rise = tz.with_ymd_and_hms(2023, 6, 21, 3, 54, 0).unwrap();
+            let expected_set = tz.with_ymd_and_hms(2023, 6, 21, 22, 50, 0).unwrap();
+            assert!(
+                (rise - expected_rise.with_timezone(&Utc))
+                    .num_minutes()
+                    .abs()
+                    <= 3
+            );
+            assert!((set - expected_set.with_timezone(&Utc)).num_minutes().abs() <= 3);
+        } else {
+            panic!();
+        }
+
+        assert_eq!(sun_times(date, 69.9, 27.0), SunTimes::MidnightSun);
+        assert_eq!(
+            sun_times(NaiveDate::from_ymd_opt(2023, 12, 21).unwrap(), 69.9, 27.0),
+            SunTimes::PolarNight
+        );
+
+        let msg = generate_msg(
+            "Helsinki",
+            NaiveDate::from_ymd_opt(2023, 12, 1).unwrap(),
+            60.17,
+            24.94,
+            &tz,
+        );
+        assert_eq!(
+            msg,
+            "Helsinki: aurinko nousee 08:56, laskee 15:24, päivän pituus 6h27m (-3m29s eilisestä)"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Telegram bots from the `telegram:` section of the config, polled with
+//! getUpdates. Messages go to message_handler as IRC PRIVMSGs from
+//! `username!userid@telegram`. Groups are `#<chat id>` and private chats
+//! `<chat id>`, so URL titles, feeds and the rest work like on IRC channels.
+//! Admins are listed as Telegram user ids.
+
+use irc::client::prelude::{Command, Message, Prefix};
+use log::{error, info, warn};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use yaml_rust::yaml::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::chat;
+use crate::health;
+use crate::http_client::{self, HTTP_CLIENT};
+
+const API_URL: &str = "https://api.telegram.org";
+const POLL_TIMEOUT_SECS: u64 = 30;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// In characters
+const MAX_MESSAGE_LEN: usize = 4096;
+const USER_HOST: &str = "telegram";
+
+struct Bot {
+    network: String,
+    token: String,
+}
+
+impl Bot {
+    fn method_url(&self, method: &str) -> String {
+        format!("{}/bot{}/{}", API_URL, self.token, method)
+    }
+}
+
+/// Admin masks of the Telegram bots, by network
+pub fn admins(config: &Yaml) -> HashMap<String, Vec<String>> {
+    let mut admins = HashMap::new();
+    for bot in config["telegram"].as_vec().into_iter().flatten() {
+        if let Some(network) = bot["network"].as_str() {
+            let masks = bot["admins"]
+                .as_vec()
+                .into_iter()
+                .flatten()
+                .filter_map(|a| match a {
+                    Yaml::String(s) => Some(s.to_owned()),
+                    Yaml::Integer(i) => Some(i.to_string()),
+                    _ => None,
+                })
+                .map(|id| format!("*!{}@{}", id, USER_HOST))
+                .collect();
+            admins.insert(network.to_owned(), masks);
+        }
+    }
+
+    admins
+}
+
+/// A text message from a user as an IRC message
+fn parse_message(message: &serde_json::Value) -> Option<Message> {
+    let from = &message["from"];
+    if from["is_bot"].as_bool().unwrap_or(false) {
+        return None;
+    }
+    let text = message["text"].as_str()?;
+    let chat_id = message["chat"]["id"].as_i64()?;
+    let channel = match message["chat"]["type"].as_str()? {
+        "private" => chat_id.to_string(),
+        _ => format!("#{}", chat_id),
+    };
+    let nick = from["username"]
+        .as_str()
+        .or_else(|| from["first_name"].as_str())?;
+
+    Some(Message {
+        tags: None,
+        prefix: Some(Prefix::Nickname(
+            nick.to_owned(),
+            from["id"].as_i64()?.to_string(),
+            USER_HOST.to_owned(),
+        )),
+        command: Command::PRIVMSG(channel, text.replace('\n', " ")),
+    })
+}
+
+/// Offset of the next getUpdates and the messages of a response
+fn parse_updates(body: &serde_json::Value) -> (Option<i64>, Vec<Message>) {
+    let updates = body["result"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let offset = updates
+        .iter()
+        .filter_map(|u| u["update_id"].as_i64())
+        .max()
+        .map(|id| id + 1);
+    let messages = updates
+        .iter()
+        .filter_map(|u| parse_message(&u["message"]))
+        .collect();
+
+    (offset, messages)
+}
+
+async fn get_updates(bot: &Bot, offset: i64, timeout: u64) -> Result<serde_json::Value, String> {
+    let request = HTTP_CLIENT
+        .get(bot.method_url("getUpdates"))
+        .query(&[
+            ("offset", offset.to_string()),
+            ("timeout", timeout.to_string()),
+            ("allowed_updates", r#"["message"]"#.to_owned()),
+        ])
+        .timeout(Duration::from_secs(timeout + 30));
+    // The URL has the token in it, keep it out of the logs
+    let response = http_client::send(request)
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let text = response
+        .text()
+        .await
+        .map_err(|e| e.without_url().to_string())?;
+    let body: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    if body["ok"] != true {
+        return Err(body["description"].as_str().unwrap_or_default().to_owned());
+    }
+
+    Ok(body)
+}
+
+async fn poll_loop(bot: Arc<Bot>, input: mpsc::Sender<(String, Message)>) {
+    // Only the latest pending update is fetched and skipped at startup, which
+    // confirms the older ones too
+    let mut offset = -1;
+    let mut started = false;
+    let mut chats = BTreeSet::new();
+
+    while !input.is_closed() {
+        let timeout = if started { POLL_TIMEOUT_SECS } else { 0 };
+        match get_updates(&bot, offset, timeout).await {
+            Ok(body) => {
+                health::set_connected(&bot.network, true);
+                let (next_offset, messages) = parse_updates(&body);
+                if let Some(o) = next_offset {
+                    offset = o;
+                } else if !started {
+                    offset = 0;
+                }
+                if started {
+                    for message in messages {
+                        if let Command::PRIVMSG(channel, _) = &message.command {
+                            if chats.insert(channel.to_owned()) {
+                                health::set_channels(&bot.network, chats.iter().cloned().collect());
+                            }
+                        }
+                        if input.send((bot.network.to_owned(), message)).await.is_err() {
+                            return;
+                        }
+                    }
+                } else {
+                    info!("Connected to Telegram as {}", bot.network);
+                    started = true;
+                }
+            }
+            Err(e) => {
+                error!("Telegram getUpdates of {} failed: {}", bot.network, e);
+                health::set_connected(&bot.network, false);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+async fn send_loop(bot: Arc<Bot>, mut actions: mpsc::Receiver<BotAction>) {
+    while let Some(action) = actions.recv().await {
+        let chat_id = action.target.channel.trim_start_matches('#');
+        let content = match &action.action_type {
+            ActionType::Message(t) | ActionType::Notice(t) => json!({
+                "chat_id": chat_id,
+                "text": chat::truncate(t, MAX_MESSAGE_LEN),
+            }),
+            ActionType::Action(t) => json!({
+                "chat_id": chat_id,
+                "text": format!("<i>{}</i>", escape_html(&chat::truncate(t, MAX_MESSAGE_LEN - 7))),
+                "parse_mode": "HTML",
+            }),
+        };
+
+        let request = HTTP_CLIENT
+            .post(bot.method_url("sendMessage"))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(content.to_string());
+        match http_client::send(request).await {
+            Ok(r) if !r.status().is_success() => warn!(
+                "Could not send to {} on {}: {}",
+                chat_id,
+                bot.network,
+                r.status()
+            ),
+            Ok(_) => {}
+            Err(e) => error!(
+                "Could not send to {} on {}: {}",
+                chat_id,
+                bot.network,
+                e.without_url()
+            ),
+        }
+    }
+}
+
+pub async fn telegram_loop(
+    input: mpsc::Sender<(String, Message)>,
+    mut actions: mpsc::Receiver<BotAction>,
+    config: Arc<Yaml>,
+) {
+    let mut senders: HashMap<String, mpsc::Sender<BotAction>> = HashMap::new();
+    for bot in config["telegram"].as_vec().into_iter().flatten() {
+        let (network, token) = match (bot["network"].as_str(), bot["token"].as_str()) {
+            (Some(n), Some(t)) => (n.to_owned(), t.to_owned()),
+            _ => {
+                error!("Telegram bot needs a network name and a token");
+                continue;
+            }
+        };
+        health::set_connected(&network, false);
+        let bot = Arc::new(Bot { network, token });
+
+        let (tx, rx) = mpsc::channel(10);
+        senders.insert(bot.network.to_owned(), tx);
+        let poll_bot = bot.clone();
+        let bot_input = input.clone();
+        tokio::spawn(async move { poll_loop(poll_bot, bot_input).await });
+        tokio::spawn(async move { send_loop(bot, rx).await });
+    }
+
+    while let Some(action) = actions.recv().await {
+        if let Some(sender) = senders.get(&action.target.network) {
+            let _ = sender.send(action).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let body = json!({"ok": true, "result": [
+            {"update_id": 4001, "message": {
+                "chat": {"id": -1001234567890i64, "type": "supergroup"},
+                "from": {"id": 11111111, "is_bot": false, "first_name": "Nelli", "username": "nelly"},
+                "text": "https://www.example.com/",
+            }},
+            {"update_id": 4002, "message": {
+                "chat": {"id": 22222222, "type": "private"},
+                "from": {"id": 22222222, "is_bot": false, "first_name": "Olli"},
+                "text": ".flip",
+            }},
+            {"update_id": 4003, "message": {
+                "chat": {"id": 22222222, "type": "private"},
+                "from": {"id": 22222222, "is_bot": false, "first_name": "Olli"},
+                "sticker": {},
+            }},
+        ]});
+
+        let (offset, messages) = parse_updates(&body);
+        assert_eq!(offset, Some(4004));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].prefix,
+            Some(Prefix::Nickname(
+                "nelly".to_owned(),
+                "11111111".to_owned(),
+                "telegram".to_owned()
+            ))
+        );
+        assert_eq!(
+            messages[0].command,
+            Command::PRIVMSG(
+                "#-1001234567890".to_owned(),
+                "https://www.example.com/".to_owned()
+            )
+        );
+        assert_eq!(
+            messages[1].command,
+            Command::PRIVMSG("22222222".to_owned(), ".flip".to_owned())
+        );
+
+        assert_eq!(parse_updates(&json!({"ok": true, "result": []})).0, None);
+    }
+
+    #[test]
+    fn telegram_admins() {
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "telegram:\n  - network: telegram\n    admins:\n      - 11111111",
+        )
+        .unwrap();
+        assert_eq!(
+            admins(&config[0])["telegram"],
+            vec!["*!11111111@telegram".to_owned()]
+        );
+    }
+}
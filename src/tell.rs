@@ -0,0 +1,285 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::{TimeZone, Utc};
+use irc::client::prelude::Prefix;
+use log::error;
+use rusqlite::{named_params, Connection, Result, Transaction, TransactionBehavior};
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+#[derive(Debug, PartialEq)]
+struct Tell {
+    sender: String,
+    message: String,
+    time: i64,
+    /// Left in a private message, so not to be shown on a channel
+    private: bool,
+}
+
+pub async fn command_tell(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let sender = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let (recipient, message) = match params.split_once(char::is_whitespace) {
+        Some((r, m)) if !m.trim().is_empty() => (r, m.trim()),
+        _ => {
+            let a = BotAction {
+                target: source,
+                action_type: ActionType::Message("Usage: .tell <nick> <message>".to_owned()),
+            };
+            bot_sender.send(a).await.unwrap();
+            return;
+        }
+    };
+
+    let (target, to, message) = (source.clone(), recipient.to_owned(), message.to_owned());
+    let reply = match db::call(&DB, move |c| add_tell(c, &target, &to, &sender, &message)).await {
+        Ok(()) => format!("Välitän viestin {}:lle kun näen hänet.", recipient),
+        Err(_) => "Database error".to_owned(),
+    };
+
+    let a = BotAction {
+        target: source,
+        action_type: ActionType::Message(reply),
+    };
+    bot_sender.send(a).await.unwrap();
+}
+
+/// Called for every message on a channel; delivers any notes left for the speaker
+pub async fn deliver_tells(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+) {
+    let nick = match &prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick.to_owned(),
+        _ => {
+            return;
+        }
+    };
+
+    let (target, recipient) = (source.clone(), nick.clone());
+    let tells = match db::call(&DB, move |c| take_tells(c, &target, &recipient)).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Error reading tells: {:?}", e);
+            return;
+        }
+    };
+
+    if tells.is_empty() {
+        return;
+    }
+
+    let timezone = get_timezone(&prefix, &source.network).await;
+
+    for tell in tells {
+        let time = match timezone {
+            Some(tz) => tz
+                .timestamp_opt(tell.time, 0)
+                .unwrap()
+                .format("%d.%m. %H:%M")
+                .to_string(),
+            None => chrono::Local
+                .timestamp_opt(tell.time, 0)
+                .unwrap()
+                .format("%d.%m. %H:%M")
+                .to_string(),
+        };
+        bot_sender
+            .send(tell_action(&source, &nick, &tell, &time))
+            .await
+            .unwrap();
+    }
+}
+
+/// Private notes are sent to the recipient as a notice, others to the channel
+fn tell_action(source: &ChatTarget, nick: &str, tell: &Tell, time: &str) -> BotAction {
+    if tell.private {
+        BotAction {
+            target: ChatTarget {
+                network: source.network.to_owned(),
+                channel: nick.to_owned(),
+            },
+            action_type: ActionType::Notice(format!(
+                "<{}> {} ({})",
+                tell.sender, tell.message, time
+            )),
+        }
+    } else {
+        BotAction {
+            target: source.clone(),
+            action_type: ActionType::Message(format!(
+                "{}: <{}> {} ({})",
+                nick, tell.sender, tell.message, time
+            )),
+        }
+    }
+}
+
+pub const DB: db::Database = db::Database {
+    name: "tell.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tells (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT,
+            recipient TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            message TEXT NOT NULL,
+            time INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn add_tell(
+    conn: &Connection,
+    source: &ChatTarget,
+    recipient: &str,
+    sender: &str,
+    message: &str,
+) -> Result<()> {
+    // Notes left in a private message are delivered privately when the
+    // recipient speaks on any channel
+    let channel = if source.is_channel() {
+        Some(source.channel.as_str())
+    } else {
+        None
+    };
+
+    let mut statement = conn.prepare(
+        "INSERT INTO tells (network, channel, recipient, sender, message, time)
+        VALUES (:network, :channel, :recipient, :sender, :message, :time)",
+    )?;
+    statement.execute(named_params! {
+        ":network": source.network,
+        ":channel": channel,
+        ":recipient": recipient.to_lowercase(),
+        ":sender": sender,
+        ":message": message,
+        ":time": Utc::now().timestamp(),
+    })?;
+
+    Ok(())
+}
+
+/// Fetch and remove the notes waiting for `nick` on the source channel. The
+/// write lock is taken first, so two messages at once can't both get a note.
+fn take_tells(conn: &Connection, source: &ChatTarget, nick: &str) -> Result<Vec<Tell>> {
+    let transaction = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let params = named_params! {
+        ":network": source.network,
+        ":channel": source.channel,
+        ":recipient": nick.to_lowercase(),
+    };
+    let condition = "network = :network AND recipient = :recipient
+        AND (channel = :channel OR channel IS NULL)";
+
+    let mut statement = conn.prepare(&format!(
+        "SELECT sender, message, time, channel IS NULL FROM tells WHERE {} ORDER BY id",
+        condition
+    ))?;
+    let mut rows = statement.query(params)?;
+
+    let mut tells = Vec::new();
+    while let Some(row) = rows.next()? {
+        tells.push(Tell {
+            sender: row.get(0)?,
+            message: row.get(1)?,
+            time: row.get(2)?,
+            private: row.get(3)?,
+        });
+    }
+
+    if !tells.is_empty() {
+        transaction.execute(&format!("DELETE FROM tells WHERE {}", condition), params)?;
+    }
+    transaction.commit()?;
+
+    Ok(tells)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn tell_store_and_take() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+        let other_channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#other".to_owned(),
+        };
+        let private = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "sender".to_owned(),
+        };
+
+        add_tell(&conn, &channel, "Recipient", "sender", "moi").unwrap();
+        add_tell(&conn, &private, "recipient", "sender", "salaista").unwrap();
+
+        assert_eq!(take_tells(&conn, &channel, "someoneelse").unwrap(), vec![]);
+
+        let tells = take_tells(&conn, &other_channel, "RECIPIENT").unwrap();
+        assert_eq!(tells.len(), 1);
+        assert_eq!(tells[0].message, "salaista");
+        assert!(tells[0].private);
+        assert_eq!(
+            tell_action(&other_channel, "Recipient", &tells[0], "01.02. 12:00"),
+            BotAction {
+                target: ChatTarget {
+                    network: "testnetwork".to_owned(),
+                    channel: "Recipient".to_owned(),
+                },
+                action_type: ActionType::Notice("<sender> salaista (01.02. 12:00)".to_owned()),
+            }
+        );
+
+        let tells = take_tells(&conn, &channel, "recipient").unwrap();
+        assert_eq!(tells.len(), 1);
+        assert_eq!(tells[0].sender, "sender");
+        assert_eq!(tells[0].message, "moi");
+        assert!(!tells[0].private);
+        assert_eq!(
+            tell_action(&channel, "recipient", &tells[0], "01.02. 12:00"),
+            BotAction {
+                target: channel.clone(),
+                action_type: ActionType::Message(
+                    "recipient: <sender> moi (01.02. 12:00)".to_owned()
+                ),
+            }
+        );
+
+        assert_eq!(take_tells(&conn, &channel, "recipient").unwrap(), vec![]);
+    }
+}
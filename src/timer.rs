@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
 
 use irc::client::prelude::*;
 
@@ -10,29 +10,56 @@ use log::{debug, error, info};
 
 use regex::Regex;
 
+use std::collections::HashMap;
+use std::sync::Mutex;
+
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::IrcChannel;
+use crate::db::{self, add_column_if_missing};
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+lazy_static! {
+    // Sleeping timer tasks by database id, kept so they can be cancelled
+    static ref TIMER_TASKS: Mutex<HashMap<i64, JoinHandle<()>>> = Mutex::new(HashMap::new());
+    // Recently fired timers by (network, channel, nick), for .snooze
+    static ref FIRED_TIMERS: Mutex<HashMap<FiredTimerKey, (DateTime<Utc>, String)>> =
+        Mutex::new(HashMap::new());
+}
+
+type FiredTimerKey = (String, String, String);
 
-#[derive(Debug)]
+/// How long after a timer fires its owner can still snooze it
+const SNOOZE_WINDOW_MINUTES: i64 = 5;
+const SNOOZE_DEFAULT_MINUTES: i64 = 10;
+// Longer times are typos or attempts to overflow the date arithmetic
+const MAX_TIMER_SECONDS: i64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(Clone, Debug)]
 pub struct TimerEvent {
-    pub target: IrcChannel,
+    pub target: ChatTarget,
     pub message: String,
     pub time: Duration,
+    pub nick: Option<String>,
 }
 
 pub async fn command_pizza(
     bot_sender: mpsc::Sender<BotAction>,
     timer_sender: mpsc::Sender<TimerEvent>,
-    source: IrcChannel,
+    source: ChatTarget,
     prefix: Option<Prefix>,
 ) {
     let mins = 12;
     let duration = Duration::minutes(mins);
 
-    let msg_to_send = if let Some(Prefix::Nickname(nick, _user, _host)) = prefix {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _user, _host)) => Some(nick),
+        _ => None,
+    };
+    let msg_to_send = if let Some(ref nick) = nick {
         format!("Apua {}! Pikku pizza palaa!", nick)
     } else {
         "Apua! Pikku pizza palaa!".to_owned()
@@ -42,7 +69,7 @@ pub async fn command_pizza(
 
     bot_sender
         .send(BotAction {
-            target: IrcChannel {
+            target: ChatTarget {
                 network: source.network.to_owned(),
                 channel: source.channel.to_owned(),
             },
@@ -56,6 +83,7 @@ pub async fn command_pizza(
             target: source,
             message: msg_to_send,
             time: duration,
+            nick,
         })
         .await
         .unwrap();
@@ -64,13 +92,17 @@ pub async fn command_pizza(
 pub async fn command_bigone(
     bot_sender: mpsc::Sender<BotAction>,
     timer_sender: mpsc::Sender<TimerEvent>,
-    source: IrcChannel,
+    source: ChatTarget,
     prefix: Option<Prefix>,
 ) {
     let mins = 15;
     let duration = Duration::minutes(mins);
 
-    let msg_to_send = if let Some(Prefix::Nickname(nick, _user, _host)) = prefix {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _user, _host)) => Some(nick),
+        _ => None,
+    };
+    let msg_to_send = if let Some(ref nick) = nick {
         format!("Apua {}! Iso pizza palaa!", nick)
     } else {
         "Apua! Iso pizza palaa!".to_owned()
@@ -80,7 +112,7 @@ pub async fn command_bigone(
 
     bot_sender
         .send(BotAction {
-            target: IrcChannel {
+            target: ChatTarget {
                 network: source.network.to_owned(),
                 channel: source.channel.to_owned(),
             },
@@ -94,6 +126,7 @@ pub async fn command_bigone(
             target: source,
             message: msg_to_send,
             time: duration,
+            nick,
         })
         .await
         .unwrap();
@@ -101,22 +134,42 @@ pub async fn command_bigone(
 pub async fn command_timer(
     bot_sender: mpsc::Sender<BotAction>,
     timer_sender: mpsc::Sender<TimerEvent>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
     prefix: Option<Prefix>,
+    is_admin: bool,
 ) {
     lazy_static! {
         static ref RE_HHMM: Regex =
             Regex::new(r"^(?:(?P<hour>\d\d?)[:\.](?P<minute>\d\d))$").unwrap();
-        static ref RE_HMS: Regex =
-            Regex::new(r"^(?:(?P<hour>\d+)h)?(?:(?P<minute>\d+)(?:m|min))?(?:(?P<second>\d+)s)?$")
-                .unwrap();
-        static ref RE_MINUTES: Regex = Regex::new(r"^(?:(?P<minute>\d+))?$").unwrap();
     }
 
+    if params == "list" {
+        list_timers(bot_sender, source, prefix, is_admin).await;
+        return;
+    }
+
+    if let Some(what) = params.strip_prefix("cancel ") {
+        cancel_timers(bot_sender, source, prefix, what.trim(), is_admin).await;
+        return;
+    }
+
+    // -p delivers the reminder as a private message instead of on the channel
+    let (private, params) = match params.strip_prefix("-p ") {
+        Some(p) => (true, p.trim_start()),
+        None => (false, params),
+    };
+
+    let natural_time;
     let time_part;
     let message_part;
-    if let Some((t, m)) = params.split_once(char::is_whitespace) {
+    let mut tomorrow = false;
+    if let Some((t, tmrw, m)) = parse_natural_time(params) {
+        natural_time = t;
+        time_part = natural_time.as_str();
+        message_part = m;
+        tomorrow = tmrw;
+    } else if let Some((t, m)) = params.split_once(char::is_whitespace) {
         time_part = t;
         message_part = m;
     } else {
@@ -124,6 +177,7 @@ pub async fn command_timer(
     }
 
     let duration;
+    let mut clock = None;
 
     if RE_HHMM.is_match(time_part) {
         let captures = RE_HHMM.captures(time_part).unwrap();
@@ -136,17 +190,19 @@ pub async fn command_timer(
             .map(|h| h.as_str().parse::<u32>().unwrap())
             .unwrap();
 
-        let now = chrono::Local::now();
-        let today = now.date_naive();
-
-        if let Some(mut timer_datetime) = today.and_hms_opt(hour, minute, 0) {
-            let diff = timer_datetime - now.naive_local();
-            if diff < Duration::seconds(0) {
-                let one_day = Duration::days(1);
-                timer_datetime += one_day;
-            }
+        let timezone = get_timezone(&prefix, &source.network).await;
+        let now = Utc::now();
+        let until = match timezone {
+            Some(tz) => duration_until(&tz, &now, hour, minute, tomorrow),
+            None => duration_until(&chrono::Local, &now, hour, minute, tomorrow),
+        };
 
-            duration = timer_datetime - now.naive_local();
+        if let Some(d) = until {
+            duration = d;
+            clock = Some(match timezone {
+                Some(tz) => format!("{:02}:{:02} ({})", hour, minute, tz.name()),
+                None => format!("{:02}:{:02}", hour, minute),
+            });
         } else {
             bot_sender
                 .send(BotAction {
@@ -160,36 +216,8 @@ pub async fn command_timer(
                 .unwrap();
             return;
         }
-    } else if RE_HMS.is_match(time_part) {
-        let captures = RE_HMS.captures(time_part).unwrap();
-        let mut dur = Duration::seconds(0);
-        if let Some(hour) = captures
-            .name("hour")
-            .map(|h| h.as_str().parse::<i64>().unwrap())
-        {
-            dur = dur + Duration::hours(hour);
-        }
-        if let Some(minute) = captures
-            .name("minute")
-            .map(|h| h.as_str().parse::<i64>().unwrap())
-        {
-            dur = dur + Duration::minutes(minute);
-        }
-        if let Some(second) = captures
-            .name("second")
-            .map(|h| h.as_str().parse::<i64>().unwrap())
-        {
-            dur = dur + Duration::seconds(second);
-        }
-
-        duration = dur;
-    } else if RE_MINUTES.is_match(time_part) {
-        let captures = RE_MINUTES.captures(time_part).unwrap();
-        let minute = captures
-            .name("minute")
-            .map(|h| h.as_str().parse::<i64>().unwrap())
-            .unwrap();
-        duration = Duration::minutes(minute);
+    } else if let Some(d) = parse_duration(time_part) {
+        duration = d;
     } else {
         return;
     }
@@ -207,68 +235,533 @@ pub async fn command_timer(
         return;
     }
 
-    let msg_to_send = if let Some(Prefix::Nickname(nick, _user, _host)) = prefix {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _user, _host)) => Some(nick),
+        _ => None,
+    };
+    let msg_to_send = if let Some(ref nick) = nick {
         format!("{}: {}", nick, message_part)
     } else {
         format!("Timer: {}", message_part)
     };
 
+    let mut confirmation_msg = match clock {
+        Some(clock) => format!(
+            "Huudan sitten klo {} eli {} päästä asiasta",
+            clock,
+            format_hms(&duration)
+        ),
+        None => format!("Huudan sitten {} päästä asiasta", format_hms(&duration)),
+    };
+    let target = match &nick {
+        Some(nick) if private => {
+            confirmation_msg.push_str(" yksityisviestillä");
+            ChatTarget {
+                network: source.network.to_owned(),
+                channel: nick.to_owned(),
+            }
+        }
+        _ => ChatTarget {
+            network: source.network.to_owned(),
+            channel: source.channel.to_owned(),
+        },
+    };
+    confirmation_msg.push('.');
+
+    bot_sender
+        .send(BotAction {
+            target: source,
+            action_type: ActionType::Message(confirmation_msg),
+        })
+        .await
+        .unwrap();
+
+    timer_sender
+        .send(TimerEvent {
+            target,
+            message: msg_to_send,
+            time: duration,
+            nick,
+        })
+        .await
+        .unwrap();
+}
+
+pub async fn command_snooze(
+    bot_sender: mpsc::Sender<BotAction>,
+    timer_sender: mpsc::Sender<TimerEvent>,
+    source: ChatTarget,
+    params: &str,
+    prefix: Option<Prefix>,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _user, _host)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let duration = if params.is_empty() {
+        Duration::minutes(SNOOZE_DEFAULT_MINUTES)
+    } else if let Some(d) = parse_duration(params) {
+        d
+    } else {
+        return;
+    };
+
+    let message = match take_fired_timer(&source, &nick, Utc::now()) {
+        Some(m) => m,
+        None => {
+            bot_sender
+                .send(BotAction {
+                    target: source,
+                    action_type: ActionType::Message("Ei torkutettavaa ajastinta.".to_owned()),
+                })
+                .await
+                .unwrap();
+            return;
+        }
+    };
+
+    bot_sender
+        .send(BotAction {
+            target: ChatTarget {
+                network: source.network.to_owned(),
+                channel: source.channel.to_owned(),
+            },
+            action_type: ActionType::Message(format!(
+                "Huudan uudestaan {} päästä.",
+                format_hms(&duration)
+            )),
+        })
+        .await
+        .unwrap();
+
+    timer_sender
+        .send(TimerEvent {
+            target: source,
+            message,
+            time: duration,
+            nick: Some(nick),
+        })
+        .await
+        .unwrap();
+}
+
+fn fired_timer_key(target: &ChatTarget, nick: &str) -> FiredTimerKey {
+    (
+        target.network.to_owned(),
+        target.channel.to_owned(),
+        nick.to_lowercase(),
+    )
+}
+
+fn remember_fired_timer(target: &ChatTarget, nick: &str, message: &str, now: DateTime<Utc>) {
+    let mut fired = FIRED_TIMERS.lock().unwrap();
+    fired.retain(|_, (time, _)| now - *time < Duration::minutes(SNOOZE_WINDOW_MINUTES));
+    fired.insert(fired_timer_key(target, nick), (now, message.to_owned()));
+}
+
+/// The message of the timer that last fired for `nick`, if still within the snooze window
+fn take_fired_timer(target: &ChatTarget, nick: &str, now: DateTime<Utc>) -> Option<String> {
+    let (time, message) = FIRED_TIMERS
+        .lock()
+        .unwrap()
+        .remove(&fired_timer_key(target, nick))?;
+
+    if now - time < Duration::minutes(SNOOZE_WINDOW_MINUTES) {
+        Some(message)
+    } else {
+        None
+    }
+}
+
+/// Parse durations like "1h30m", "45s" and plain minutes "20"
+pub fn parse_duration(time_part: &str) -> Option<Duration> {
+    lazy_static! {
+        static ref RE_HMS: Regex =
+            Regex::new(r"^(?:(?P<hour>\d+)h)?(?:(?P<minute>\d+)(?:m|min))?(?:(?P<second>\d+)s)?$")
+                .unwrap();
+        static ref RE_MINUTES: Regex = Regex::new(r"^(?:(?P<minute>\d+))?$").unwrap();
+    }
+
+    // Seconds in a unit, None if there are too many of them
+    let seconds = |amount: &str, unit: i64| {
+        let amount = amount.parse::<i64>().ok()?;
+        amount.checked_mul(unit).filter(|s| *s <= MAX_TIMER_SECONDS)
+    };
+
+    let total = if RE_HMS.is_match(time_part) {
+        let captures = RE_HMS.captures(time_part).unwrap();
+        let mut total = 0;
+        for (name, unit) in [("hour", 60 * 60), ("minute", 60), ("second", 1)] {
+            if let Some(amount) = captures.name(name) {
+                total += seconds(amount.as_str(), unit)?;
+            }
+        }
+        total
+    } else if RE_MINUTES.is_match(time_part) {
+        let captures = RE_MINUTES.captures(time_part).unwrap();
+        seconds(captures.name("minute")?.as_str(), 60)?
+    } else {
+        return None;
+    };
+
+    if total > MAX_TIMER_SECONDS {
+        return None;
+    }
+    Some(Duration::seconds(total))
+}
+
+/// Time from `now` until the next `hour`:`minute` on the wall clock of `tz`,
+/// or until that time on the following day if `tomorrow` is set
+fn duration_until<T: TimeZone>(
+    tz: &T,
+    now: &DateTime<Utc>,
+    hour: u32,
+    minute: u32,
+    tomorrow: bool,
+) -> Option<Duration> {
+    let local_now = now.with_timezone(tz).naive_local();
+    let mut timer_datetime = local_now.date().and_hms_opt(hour, minute, 0)?;
+    if tomorrow || timer_datetime < local_now {
+        timer_datetime += Duration::days(1);
+    }
+
+    let timer_datetime = tz.from_local_datetime(&timer_datetime).earliest()?;
+
+    Some(timer_datetime.with_timezone(&Utc) - *now)
+}
+
+fn natural_amount(word: &str) -> Option<f64> {
+    match word {
+        "a" | "an" | "one" | "yksi" | "yhden" => Some(1.0),
+        "half" | "puoli" | "puolen" => Some(0.5),
+        "two" | "kaksi" | "kahden" => Some(2.0),
+        _ => word.replace(',', ".").parse::<f64>().ok(),
+    }
+}
+
+fn natural_unit(word: &str) -> Option<i64> {
+    match word.trim_end_matches(',') {
+        "s" | "sec" | "secs" | "second" | "seconds" | "sekunti" | "sekuntia" | "sekunnin" => {
+            Some(1)
+        }
+        "m" | "min" | "mins" | "minute" | "minutes" | "minuutti" | "minuuttia" | "minuutin" => {
+            Some(60)
+        }
+        "h" | "hr" | "hrs" | "hour" | "hours" | "tunti" | "tuntia" | "tunnin" => Some(3600),
+        "d" | "day" | "days" | "päivä" | "päivää" | "päivän" => Some(86400),
+        _ => None,
+    }
+}
+
+/// Forgiving parser for written-out times such as "1 hour 30 min",
+/// "puolen tunnin päästä" and "tomorrow 9:00".
+///
+/// Returns the time in a form the regular timer syntax understands, whether
+/// the clock time refers to tomorrow, and the rest of the message.
+fn parse_natural_time(params: &str) -> Option<(String, bool, &str)> {
+    let words: Vec<&str> = params.split_whitespace().collect();
+    let lower: Vec<String> = words.iter().map(|w| w.to_lowercase()).collect();
+
+    let mut consumed = 0;
+    let mut tomorrow = false;
+    let mut time_part = String::new();
+
+    if matches!(
+        lower.first().map(|w| w.as_str()),
+        Some("tomorrow" | "huomenna")
+    ) {
+        let clock = words.get(1)?;
+        if !clock.contains([':', '.']) {
+            return None;
+        }
+        tomorrow = true;
+        time_part.push_str(clock);
+        consumed = 2;
+    } else {
+        if matches!(lower.first().map(|w| w.as_str()), Some("in")) {
+            consumed = 1;
+        }
+
+        let mut seconds = 0.0;
+        let mut found = false;
+        while consumed < lower.len() {
+            let word = lower[consumed].as_str();
+            if found && matches!(word, "and" | "ja") {
+                consumed += 1;
+                continue;
+            }
+
+            let mut next = consumed;
+            let amount = match natural_amount(word) {
+                Some(a) => {
+                    next += 1;
+                    // "half an hour"
+                    if matches!(lower.get(next).map(|w| w.as_str()), Some("a" | "an")) {
+                        next += 1;
+                    }
+                    a
+                }
+                None => 1.0,
+            };
+
+            // A bare unit only counts in the Finnish genitive, "tunnin päästä"
+            let unit_word = lower.get(next)?;
+            if next == consumed && !unit_word.ends_with('n') {
+                break;
+            }
+
+            match natural_unit(unit_word) {
+                Some(unit) => {
+                    seconds += amount * unit as f64;
+                    consumed = next + 1;
+                    found = true;
+                }
+                None => break,
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        if matches!(lower.get(consumed).map(|w| w.as_str()), Some("päästä")) {
+            consumed += 1;
+        }
+
+        // "inf hours" and "1e300 days" parse as numbers too
+        if !seconds.is_finite() || seconds > MAX_TIMER_SECONDS as f64 {
+            return None;
+        }
+        let duration = Duration::seconds(seconds.round() as i64);
+        if duration.num_seconds() <= 0 {
+            return None;
+        }
+        time_part = format_hms(&duration);
+    }
+
+    let mut message = params;
+    for word in &words[..consumed] {
+        message = message.trim_start().strip_prefix(word)?;
+    }
+    let message = message.trim_start();
+    if message.is_empty() {
+        return None;
+    }
+
+    Some((time_part, tomorrow, message))
+}
+
+fn format_hms(duration: &Duration) -> String {
     let total_secs = duration.num_seconds();
     let s = total_secs % 60;
     let m_temp = total_secs / 60;
     let m = m_temp % 60;
     let h = m_temp / 60;
 
-    let mut confirmation_msg = "Huudan sitten ".to_owned();
+    let mut out = String::new();
     if h > 0 {
-        let h_str = format!("{}h", h);
-        confirmation_msg.push_str(&h_str);
+        out.push_str(&format!("{}h", h));
     }
     if m > 0 {
-        let m_str = format!("{}m", m);
-        confirmation_msg.push_str(&m_str);
+        out.push_str(&format!("{}m", m));
     }
     if s > 0 {
-        let s_str = format!("{}s", s);
-        confirmation_msg.push_str(&s_str);
+        out.push_str(&format!("{}s", s));
+    }
+
+    out
+}
+
+async fn list_timers(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    is_admin: bool,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let nick_filter = if is_admin { None } else { Some(nick) };
+    let target = source.clone();
+    let timers = match db::call(&DB, move |c| {
+        get_pending_timers(c, &target, nick_filter.as_deref())
+    })
+    .await
+    {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Could not read timer db: {:?}", e);
+            return;
+        }
+    };
+
+    let messages = if timers.is_empty() {
+        vec!["Ei ajastimia.".to_owned()]
+    } else {
+        timers
+            .iter()
+            .map(|(id, event)| format!("{}: {} | {}", id, format_hms(&event.time), event.message))
+            .collect()
+    };
+
+    for msg in messages {
+        bot_sender
+            .send(BotAction {
+                target: ChatTarget {
+                    network: source.network.to_owned(),
+                    channel: source.channel.to_owned(),
+                },
+                action_type: ActionType::Message(msg),
+            })
+            .await
+            .unwrap();
     }
-    confirmation_msg.push_str(" päästä asiasta.");
+}
+
+async fn cancel_timers(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    what: &str,
+    is_admin: bool,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    // Admins may cancel anyone's timer by id, but "all" only ever means one's own
+    let (id, nick_filter) = match what {
+        "all" => (None, Some(nick.as_str())),
+        _ => match what.parse::<i64>() {
+            Ok(i) if is_admin => (Some(i), None),
+            Ok(i) => (Some(i), Some(nick.as_str())),
+            Err(_) => {
+                return;
+            }
+        },
+    };
+
+    let target = source.clone();
+    let nick_filter = nick_filter.map(|n| n.to_owned());
+    let removed = db::call(&DB, move |c| {
+        remove_pending_timers(c, &target, nick_filter.as_deref(), id)
+    })
+    .await;
+
+    let msg = match removed {
+        Ok(ids) if ids.is_empty() => "Ajastinta ei löytynyt.".to_owned(),
+        Ok(ids) => {
+            abort_timer_tasks(&ids);
+            let id_strs: Vec<String> = ids.iter().map(|i| i.to_string()).collect();
+            format!("Peruttu: {}", id_strs.join(", "))
+        }
+        Err(e) => {
+            error!("Error when cancelling timers: {:?}", e);
+            "Database error".to_owned()
+        }
+    };
 
     bot_sender
         .send(BotAction {
-            target: IrcChannel {
-                network: source.network.to_owned(),
-                channel: source.channel.to_owned(),
-            },
-            action_type: ActionType::Message(confirmation_msg),
-        })
-        .await
-        .unwrap();
-
-    timer_sender
-        .send(TimerEvent {
             target: source,
-            message: msg_to_send,
-            time: duration,
+            action_type: ActionType::Message(msg),
         })
         .await
         .unwrap();
 }
 
-fn open_db() -> rusqlite::Result<rusqlite::Connection> {
-    let conn = rusqlite::Connection::open("db/timer.db")?;
+fn abort_timer_tasks(ids: &[i64]) {
+    let mut tasks = TIMER_TASKS.lock().unwrap();
+    for id in ids {
+        if let Some(handle) = tasks.remove(id) {
+            debug!("Aborting timer task {}", id);
+            handle.abort();
+        }
+    }
+}
+
+/// Deletes pending timers in the channel, optionally limited to one id and/or
+/// to timers set by `nick`. Returns the ids of the removed timers.
+fn remove_pending_timers(
+    conn: &rusqlite::Connection,
+    target: &ChatTarget,
+    nick: Option<&str>,
+    id: Option<i64>,
+) -> rusqlite::Result<Vec<i64>> {
+    let ids: Vec<i64> = get_pending_timers(conn, target, nick)?
+        .into_iter()
+        .map(|(i, _)| i)
+        .filter(|i| id.is_none() || id == Some(*i))
+        .collect();
+
+    for i in &ids {
+        remove_from_db(conn, *i)?;
+    }
+
+    Ok(ids)
+}
+
+/// Every pending timer on all networks, soonest first
+pub async fn all_pending_timers() -> rusqlite::Result<Vec<(i64, TimerEvent)>> {
+    db::call(&DB, |c| {
+        let mut statement = c.prepare("SELECT * FROM timers WHERE time >= :now ORDER BY time")?;
+        let rows = statement.query(rusqlite::named_params! {":now": Utc::now().timestamp()})?;
+        timers_from_rows(rows)
+    })
+    .await
+}
+
+/// Cancels any pending timer by id. Returns whether there was one.
+pub async fn cancel_timer(id: i64) -> rusqlite::Result<bool> {
+    let removed = db::call(&DB, move |c| {
+        let exists = c
+            .prepare("SELECT id FROM timers WHERE id = :id")?
+            .exists(rusqlite::named_params! {":id": id})?;
+        if exists {
+            remove_from_db(c, id)?;
+        }
+        Ok(exists)
+    })
+    .await?;
+
+    if removed {
+        abort_timer_tasks(&[id]);
+    }
+
+    Ok(removed)
+}
+
+pub const DB: db::Database = db::Database {
+    name: "timer.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS timers (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             time INTEGER NOT NULL,
             message TEXT,
             channel TEXT NOT NULL,
-            network TEXT NOT NULL
+            network TEXT NOT NULL,
+            nick TEXT
         )",
         [],
     )?;
 
-    Ok(conn)
+    add_column_if_missing(conn, "timers", "nick", "TEXT")?;
+
+    Ok(())
 }
 
 fn remove_old_timers(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
@@ -291,8 +784,34 @@ fn remove_old_timers(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
 
 fn get_timers_from_db(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(i64, TimerEvent)>> {
     let mut statement = conn.prepare("SELECT * FROM timers")?;
-    let mut rows = statement.query([])?;
+    let rows = statement.query([])?;
+    timers_from_rows(rows)
+}
+
+/// Timers still waiting in the given channel, optionally only those set by `nick`
+fn get_pending_timers(
+    conn: &rusqlite::Connection,
+    target: &ChatTarget,
+    nick: Option<&str>,
+) -> rusqlite::Result<Vec<(i64, TimerEvent)>> {
+    let mut statement = conn.prepare(
+        "SELECT * FROM timers WHERE
+         network = :network AND
+         (channel = :channel OR channel = :nick) AND
+         time >= :now AND
+         (:nick IS NULL OR nick = :nick)
+         ORDER BY time",
+    )?;
+    let rows = statement.query(rusqlite::named_params! {
+        ":network": target.network,
+        ":channel": target.channel,
+        ":now": Utc::now().timestamp(),
+        ":nick": nick,
+    })?;
+    timers_from_rows(rows)
+}
 
+fn timers_from_rows(mut rows: rusqlite::Rows) -> rusqlite::Result<Vec<(i64, TimerEvent)>> {
     let mut results = Vec::new();
 
     while let Some(row) = rows.next()? {
@@ -301,6 +820,7 @@ fn get_timers_from_db(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(i64,
         let message: String = row.get(2)?;
         let channel: String = row.get(3)?;
         let network: String = row.get(4)?;
+        let nick: Option<String> = row.get(5)?;
 
         let target_dt = DateTime::<Utc>::from_utc(
             NaiveDateTime::from_timestamp_opt(timestamp, 0).unwrap(),
@@ -309,12 +829,13 @@ fn get_timers_from_db(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(i64,
         let now = Utc::now();
         let time = target_dt - now;
 
-        let target = IrcChannel { channel, network };
+        let target = ChatTarget { channel, network };
 
         let event = TimerEvent {
             target,
             message,
             time,
+            nick,
         };
         results.push((id, event));
     }
@@ -340,20 +861,30 @@ fn remove_from_db(conn: &rusqlite::Connection, id: i64) -> rusqlite::Result<()>
 }
 
 fn start_timer(event: TimerEvent, sender: mpsc::Sender<BotAction>, db_id: Option<i64>) {
-    let action = BotAction {
-        target: event.target,
-        action_type: ActionType::Message(event.message),
-    };
     let time = event.time;
-    tokio::spawn(async move {
+    // Held until the handle is stored, so that a timer that fires at once
+    // can't remove its entry before there is one
+    let mut tasks = TIMER_TASKS.lock().unwrap();
+    let handle = tokio::spawn(async move {
         sleep(time.to_std().unwrap()).await;
+        if let Some(nick) = &event.nick {
+            remember_fired_timer(&event.target, nick, &event.message, Utc::now());
+        }
+        let action = BotAction {
+            target: event.target,
+            action_type: ActionType::Message(event.message),
+        };
         sender.send(action).await.unwrap();
         if let Some(id) = db_id {
-            if let Ok(conn) = open_db() {
-                remove_from_db(&conn, id).unwrap();
-            }
+            TIMER_TASKS.lock().unwrap().remove(&id);
+            // remove_from_db logs its errors
+            let _ = db::call(&DB, move |c| remove_from_db(c, id)).await;
         }
     });
+
+    if let Some(id) = db_id {
+        tasks.insert(id, handle);
+    }
 }
 
 fn add_timer_to_db(conn: &rusqlite::Connection, event: &TimerEvent) -> rusqlite::Result<i64> {
@@ -363,12 +894,13 @@ fn add_timer_to_db(conn: &rusqlite::Connection, event: &TimerEvent) -> rusqlite:
     let channel = event.target.channel.to_owned();
     let network = event.target.network.to_owned();
 
-    let mut statement = conn.prepare("INSERT INTO timers (time, message, channel, network) VALUES (:time, :message, :channel, :network)")?;
+    let mut statement = conn.prepare("INSERT INTO timers (time, message, channel, network, nick) VALUES (:time, :message, :channel, :network, :nick)")?;
     let id = statement.insert(rusqlite::named_params! {
         ":time": timestamp,
         ":message": message,
         ":channel": channel,
         ":network": network,
+        ":nick": event.nick,
     });
 
     debug!(
@@ -382,33 +914,33 @@ pub async fn timer_manager(
     mut receiver: mpsc::Receiver<TimerEvent>,
     sender: mpsc::Sender<BotAction>,
 ) {
-    let db_conn = open_db();
-
-    if let Ok(c) = &db_conn {
+    let old_timers = db::call(&DB, |c| {
         let _ = remove_old_timers(c);
+        get_timers_from_db(c)
+    })
+    .await;
 
-        if let Ok(old_timers) = get_timers_from_db(c) {
+    match old_timers {
+        Ok(old_timers) => {
             info!("Adding {} old timers from db", old_timers.len());
             for (id, event) in old_timers {
                 let new_sender = sender.clone();
                 start_timer(event, new_sender, Some(id));
             }
         }
-    } else {
-        error!("Could not open timer db");
+        Err(e) => error!("Could not read timer db: {:?}", e),
     }
 
     while let Some(event) = receiver.recv().await {
         let mut id = None;
-        if let Ok(c) = &db_conn {
-            let r = add_timer_to_db(c, &event);
-            match r {
-                Ok(i) => {
-                    id = Some(i);
-                }
-                Err(_) => {
-                    error!("Error when adding timer to db: {:?}", r);
-                }
+        let db_event = event.clone();
+        let r = db::call(&DB, move |c| add_timer_to_db(c, &db_event)).await;
+        match r {
+            Ok(i) => {
+                id = Some(i);
+            }
+            Err(_) => {
+                error!("Error when adding timer to db: {:?}", r);
             }
         }
         let new_sender = sender.clone();
@@ -417,10 +949,105 @@ pub async fn timer_manager(
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
     use chrono::prelude::*;
 
+    fn open_db(testing: bool) -> rusqlite::Result<rusqlite::Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn timer_duration_until_timezone() {
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 10, 0, 0).unwrap();
+
+        // Stockholm is UTC+2 in summer
+        let tz = chrono_tz::Europe::Stockholm;
+        assert_eq!(
+            duration_until(&tz, &now, 13, 30, false),
+            Some(Duration::minutes(90))
+        );
+        assert_eq!(
+            duration_until(&tz, &now, 11, 0, false),
+            Some(Duration::hours(23))
+        );
+        assert_eq!(
+            duration_until(&tz, &now, 13, 30, true),
+            Some(Duration::minutes(90 + 24 * 60))
+        );
+        assert_eq!(duration_until(&tz, &now, 25, 0, false), None);
+        assert_eq!(
+            duration_until(&Utc, &now, 13, 30, false),
+            Some(Duration::minutes(210))
+        );
+    }
+
+    #[test]
+    fn timer_snooze_window() {
+        let target = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#snooze".to_owned(),
+        };
+        let fired = Utc::now();
+
+        remember_fired_timer(&target, "TestNick", "TestNick: sauna", fired);
+        assert_eq!(take_fired_timer(&target, "othernick", fired), None);
+        assert_eq!(
+            take_fired_timer(&target, "testnick", fired + Duration::minutes(1)),
+            Some("TestNick: sauna".to_owned())
+        );
+        assert_eq!(take_fired_timer(&target, "testnick", fired), None);
+
+        remember_fired_timer(&target, "testnick", "testnick: sauna", fired);
+        assert_eq!(
+            take_fired_timer(&target, "testnick", fired + Duration::minutes(6)),
+            None
+        );
+    }
+
+    #[test]
+    fn timer_parse_duration() {
+        assert_eq!(parse_duration("1h30m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("45"), Some(Duration::minutes(45)));
+        assert_eq!(parse_duration("99999999999999999999h"), None);
+        assert_eq!(parse_duration("9223372036854775807h"), None);
+        assert_eq!(parse_duration("87000h"), Some(Duration::hours(87000)));
+        assert_eq!(parse_duration("80000h500000m"), None);
+        assert_eq!(parse_duration("9999999999999999"), None);
+    }
+
+    #[test]
+    fn timer_natural_time() {
+        assert_eq!(
+            parse_natural_time("1 hour 30 min check oven"),
+            Some(("1h30m".to_owned(), false, "check oven"))
+        );
+        assert_eq!(
+            parse_natural_time("puolen tunnin päästä sauna"),
+            Some(("30m".to_owned(), false, "sauna"))
+        );
+        assert_eq!(
+            parse_natural_time("tomorrow 9:00 standup"),
+            Some(("9:00".to_owned(), true, "standup"))
+        );
+        assert_eq!(
+            parse_natural_time("in half an hour and 10 seconds tea"),
+            Some(("30m10s".to_owned(), false, "tea"))
+        );
+        assert_eq!(
+            parse_natural_time("2 tuntia ja 5 minuuttia  pizza   valmis"),
+            Some(("2h5m".to_owned(), false, "pizza   valmis"))
+        );
+        assert_eq!(parse_natural_time("20m pizza"), None);
+        assert_eq!(parse_natural_time("1 hour"), None);
+        assert_eq!(parse_natural_time("tomorrow standup"), None);
+        assert_eq!(parse_natural_time("inf hours x"), None);
+        assert_eq!(parse_natural_time("NaN hours x"), None);
+        assert_eq!(parse_natural_time("1e300 days x"), None);
+        assert_eq!(parse_natural_time("4000 days x"), None);
+    }
+
     #[tokio::test]
     async fn timer_hhmm() {
         let (timer_tx, mut timer_rx) = mpsc::channel(10);
@@ -435,7 +1062,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 network: "testnetwork".to_owned(),
                 channel: "#testing".to_owned(),
             },
@@ -445,6 +1072,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -463,7 +1091,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -473,6 +1101,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -499,7 +1128,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -509,6 +1138,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -529,7 +1159,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -539,6 +1169,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -556,7 +1187,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -566,6 +1197,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -583,7 +1215,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -593,6 +1225,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -605,6 +1238,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn timer_pending_list() {
+        let conn = open_db(true).unwrap();
+        let target = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+
+        for (nick, mins) in [("testnick", 30), ("othernick", 10), ("testnick", 5)] {
+            add_timer_to_db(
+                &conn,
+                &TimerEvent {
+                    target: ChatTarget {
+                        network: target.network.to_owned(),
+                        channel: target.channel.to_owned(),
+                    },
+                    message: format!("{}: moi", nick),
+                    time: Duration::minutes(mins),
+                    nick: Some(nick.to_owned()),
+                },
+            )
+            .unwrap();
+        }
+
+        let own = get_pending_timers(&conn, &target, Some("testnick")).unwrap();
+        assert_eq!(own.len(), 2);
+        assert!(own[0].1.time < own[1].1.time);
+        assert_eq!(own[0].1.nick, Some("testnick".to_owned()));
+
+        let all = get_pending_timers(&conn, &target, None).unwrap();
+        assert_eq!(all.len(), 3);
+
+        let other_id = all
+            .iter()
+            .find(|(_, e)| e.nick == Some("othernick".to_owned()))
+            .unwrap()
+            .0;
+        let not_removed = remove_pending_timers(&conn, &target, Some("testnick"), Some(other_id));
+        assert_eq!(not_removed, Ok(vec![]));
+
+        let removed = remove_pending_timers(&conn, &target, Some("testnick"), None).unwrap();
+        assert_eq!(removed.len(), 2);
+        let left = get_pending_timers(&conn, &target, None).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].0, other_id);
+
+        assert_eq!(
+            format_hms(&(Duration::hours(1) + Duration::seconds(5))),
+            "1h5s"
+        );
+    }
+
     #[tokio::test]
     async fn timer_minutes() {
         let (timer_tx, mut timer_rx) = mpsc::channel(10);
@@ -613,7 +1298,7 @@ mod tests {
         command_timer(
             bot_tx,
             timer_tx,
-            IrcChannel {
+            ChatTarget {
                 channel: "#testing".to_owned(),
                 network: "testnetwork".to_owned(),
             },
@@ -623,6 +1308,7 @@ mod tests {
                 "testuser".to_owned(),
                 "testhost".to_owned(),
             )),
+            false,
         )
         .await;
 
@@ -634,4 +1320,47 @@ mod tests {
             assert!(false);
         }
     }
+
+    #[tokio::test]
+    async fn timer_private() {
+        let (timer_tx, mut timer_rx) = mpsc::channel(10);
+        let (bot_tx, mut bot_rx) = mpsc::channel(10);
+
+        command_timer(
+            bot_tx,
+            timer_tx,
+            ChatTarget {
+                channel: "#testing".to_owned(),
+                network: "testnetwork".to_owned(),
+            },
+            "-p 20m take meds",
+            Some(Prefix::Nickname(
+                "testnick".to_owned(),
+                "testuser".to_owned(),
+                "testhost".to_owned(),
+            )),
+            false,
+        )
+        .await;
+
+        if let Some(result) = bot_rx.recv().await {
+            assert_eq!(result.target.channel, "#testing".to_owned());
+            assert_eq!(
+                result.action_type,
+                ActionType::Message(
+                    "Huudan sitten 20m päästä asiasta yksityisviestillä.".to_owned()
+                )
+            );
+        } else {
+            panic!();
+        }
+
+        if let Some(result) = timer_rx.recv().await {
+            assert_eq!(result.target.channel, "testnick".to_owned());
+            assert_eq!(result.message, "testnick: take meds".to_owned());
+            assert_eq!(result.time, Duration::minutes(20));
+        } else {
+            panic!();
+        }
+    }
 }
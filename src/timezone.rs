@@ -0,0 +1,147 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono_tz::Tz;
+use irc::client::prelude::Prefix;
+use rusqlite::{named_params, Connection, Result};
+use tokio::sync::mpsc;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::ChatTarget;
+
+pub async fn command_tz(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => nick,
+        _ => {
+            return;
+        }
+    };
+
+    let message = if let Some(tz_name) = params.strip_prefix("set ") {
+        match tz_name.trim().parse::<Tz>() {
+            Ok(tz) => {
+                let network = source.network.clone();
+                match db::call(&DB, move |c| set_timezone(c, &nick, &network, &tz)).await {
+                    Ok(()) => format!("Timezone set to {}", tz.name()),
+                    Err(_) => "Database error".to_owned(),
+                }
+            }
+            Err(_) => format!("Unknown timezone {}", tz_name.trim()),
+        }
+    } else if params.is_empty() {
+        match get_timezone(
+            &Some(Prefix::Nickname(nick, "".to_owned(), "".to_owned())),
+            &source.network,
+        )
+        .await
+        {
+            Some(tz) => format!("Your timezone is {}", tz.name()),
+            None => "No timezone set, using the bot's local time".to_owned(),
+        }
+    } else {
+        "Usage: .tz set <Area/City>".to_owned()
+    };
+
+    let a = BotAction {
+        target: source,
+        action_type: ActionType::Message(message),
+    };
+
+    bot_sender.send(a).await.unwrap();
+}
+
+pub const DB: db::Database = db::Database {
+    name: "timezones.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS timezones (
+            id INTEGER PRIMARY KEY,
+            network TEXT NOT NULL,
+            nick TEXT NOT NULL,
+            timezone TEXT NOT NULL,
+            UNIQUE(network, nick) ON CONFLICT REPLACE
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn get_stored_timezone(conn: &Connection, nick: &str, network: &str) -> Result<Option<Tz>> {
+    let mut statement =
+        conn.prepare("SELECT timezone FROM timezones WHERE nick = :nick AND network = :network")?;
+    let params = named_params! {":nick": nick, ":network": network};
+    let mut rows = statement.query(params)?;
+
+    if let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        return Ok(name.parse::<Tz>().ok());
+    }
+
+    Ok(None)
+}
+
+/// The timezone the user has set, or None if the bot's local time should be used
+pub async fn get_timezone(prefix: &Option<Prefix>, network: &str) -> Option<Tz> {
+    if let Some(Prefix::Nickname(nick, _, _)) = prefix {
+        let (nick, network) = (nick.clone(), network.to_owned());
+        if let Ok(tz) = db::call(&DB, move |c| get_stored_timezone(c, &nick, &network)).await {
+            return tz;
+        }
+    }
+
+    None
+}
+
+pub fn set_timezone(conn: &Connection, nick: &str, network: &str, tz: &Tz) -> Result<()> {
+    let mut statement = conn.prepare(
+        "INSERT INTO timezones (network, nick, timezone) VALUES (:network, :nick, :timezone)",
+    )?;
+    statement.execute(named_params! {
+        ":network": network,
+        ":nick": nick,
+        ":timezone": tz.name(),
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn timezone_setget() {
+        let conn = open_db(true).unwrap();
+
+        assert_eq!(
+            get_stored_timezone(&conn, "testnick", "testnetwork"),
+            Ok(None)
+        );
+
+        let tz: Tz = "Europe/Stockholm".parse().unwrap();
+        assert_eq!(set_timezone(&conn, "testnick", "testnetwork", &tz), Ok(()));
+        assert_eq!(
+            get_stored_timezone(&conn, "testnick", "testnetwork"),
+            Ok(Some(chrono_tz::Europe::Stockholm))
+        );
+        assert_eq!(
+            get_stored_timezone(&conn, "testnick", "othernetwork"),
+            Ok(None)
+        );
+    }
+}
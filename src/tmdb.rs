@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use yaml_rust::yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const RELEASE_COUNTRY: &str = "FI";
+
+#[derive(Debug, PartialEq)]
+struct MovieData {
+    title: String,
+    year: Option<i32>,
+    rating: Option<f64>,
+    runtime: Option<i64>,
+    release_date: Option<NaiveDate>,
+}
+
+async fn search_json(query: &str, apikey: &str) -> reqwest::Result<String> {
+    let baseurl = "https://api.themoviedb.org/3/search/movie";
+
+    let request = HTTP_CLIENT
+        .get(baseurl)
+        .query(&[("api_key", apikey), ("query", query)]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+async fn movie_json(id: i64, apikey: &str) -> reqwest::Result<String> {
+    let url = format!("https://api.themoviedb.org/3/movie/{}", id);
+
+    let request = HTTP_CLIENT
+        .get(url)
+        .query(&[("api_key", apikey), ("append_to_response", "release_dates")]);
+    let json = http_client::send(request).await?.text().await?;
+
+    Ok(json)
+}
+
+/// Id of the best match in the search results
+fn first_result_id(json_text: &str) -> Result<i64, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    json["results"][0]["id"]
+        .as_i64()
+        .ok_or_else(|| "Movie not found".to_owned())
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    // Release dates have a time part, e.g. "2024-02-28T00:00:00.000Z"
+    NaiveDate::parse_from_str(date.get(..10)?, "%Y-%m-%d").ok()
+}
+
+/// Earliest theatrical or digital release in `country`
+fn country_release(json: &serde_json::Value, country: &str) -> Option<NaiveDate> {
+    json["release_dates"]["results"]
+        .as_array()?
+        .iter()
+        .find(|r| r["iso_3166_1"].as_str() == Some(country))?["release_dates"]
+        .as_array()?
+        .iter()
+        .filter_map(|r| r["release_date"].as_str().and_then(parse_date))
+        .min()
+}
+
+fn parse_movie(json_text: &str) -> Result<MovieData, String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return Err("Error parsing JSON".to_owned());
+        }
+    };
+
+    let title = match json["title"].as_str() {
+        Some(t) => t.to_owned(),
+        None => {
+            return Err("Movie not found".to_owned());
+        }
+    };
+
+    let premiere = json["release_date"].as_str().and_then(parse_date);
+
+    Ok(MovieData {
+        title,
+        year: premiere.map(|d| d.year()),
+        // Unrated movies have an average of 0
+        rating: json["vote_average"]
+            .as_f64()
+            .filter(|_| json["vote_count"].as_i64().unwrap_or(0) > 0),
+        runtime: json["runtime"].as_i64().filter(|r| *r > 0),
+        release_date: country_release(&json, RELEASE_COUNTRY),
+    })
+}
+
+fn generate_msg(data: &MovieData) -> String {
+    let mut msg = data.title.to_owned();
+
+    if let Some(year) = data.year {
+        msg.push_str(&format!(" ({})", year));
+    }
+    if let Some(rating) = data.rating {
+        msg.push_str(&format!(", rating {:.1}/10", rating));
+    }
+    if let Some(runtime) = data.runtime {
+        msg.push_str(&format!(", {}h {}min", runtime / 60, runtime % 60));
+    }
+    if let Some(date) = data.release_date {
+        msg.push_str(&format!(
+            ", released in Finland on {}",
+            date.format("%Y-%m-%d")
+        ));
+    }
+
+    msg
+}
+
+async fn movie_msg(query: &str, apikey: &str) -> String {
+    let id = match search_json(query, apikey).await {
+        Ok(json) => match first_result_id(&json) {
+            Ok(id) => id,
+            Err(e) => {
+                return e;
+            }
+        },
+        Err(_) => {
+            return "TMDB API error".to_owned();
+        }
+    };
+
+    match movie_json(id, apikey).await {
+        Ok(json) => match parse_movie(&json) {
+            Ok(data) => generate_msg(&data),
+            Err(e) => e,
+        },
+        Err(_) => "TMDB API error".to_owned(),
+    }
+}
+
+pub async fn command_movie(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<yaml::Yaml>,
+) {
+    let apikey = match config["tmdb"]["apikey"].as_str() {
+        Some(k) => k,
+        None => {
+            return;
+        }
+    };
+
+    let msg = if params.is_empty() {
+        "Usage: .movie <title>".to_owned()
+    } else {
+        movie_msg(params, apikey).await
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn movie_details() {
+        let search = r#"{"page":1,"results":[{"id":693134,"title":"Dune: Part Two"}]}"#;
+        assert_eq!(first_result_id(search), Ok(693134));
+        assert_eq!(
+            first_result_id(r#"{"page":1,"results":[]}"#),
+            Err("Movie not found".to_owned())
+        );
+
+        let movie = r#"{
+            "title": "Dune: Part Two",
+            "release_date": "2024-02-27",
+            "runtime": 167,
+            "vote_average": 8.153,
+            "vote_count": 5000,
+            "release_dates": {"results": [
+                {"iso_3166_1": "US", "release_dates": [{"release_date": "2024-03-01T00:00:00.000Z", "type": 3}]},
+                {"iso_3166_1": "FI", "release_dates": [
+                    {"release_date": "2024-05-15T00:00:00.000Z", "type": 4},
+                    {"release_date": "2024-02-28T00:00:00.000Z", "type": 3}
+                ]}
+            ]}
+        }"#;
+        let data = parse_movie(movie).unwrap();
+        assert_eq!(
+            generate_msg(&data),
+            "Dune: Part Two (2024), rating 8.2/10, 2h 47min, released in Finland on 2024-02-28"
+        );
+    }
+}
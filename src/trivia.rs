@@ -0,0 +1,434 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use core::time::Duration;
+use log::warn;
+use rand::prelude::*;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::leaderboard::{self, leaderboard_msg, record_points};
+use crate::ChatTarget;
+
+const DEFAULT_QUESTION_COUNT: usize = 10;
+const MAX_QUESTION_COUNT: usize = 50;
+const DEFAULT_TIME_LIMIT_SECS: u64 = 30;
+
+/// "kysymys*vastaus|toinen vastaus", the format of the question files too
+const DEFAULT_QUESTIONS: [&str; 24] = [
+    "Mikä on Suomen pääkaupunki?*Helsinki",
+    "Kuinka monta maakuntaa Suomessa on?*18|kahdeksantoista",
+    "Mikä on Suomen korkein tunturi?*Halti",
+    "Mikä on Suomen suurin järvi?*Saimaa",
+    "Minä vuonna Suomi itsenäistyi?*1917",
+    "Kuka sävelsi Finlandian?*Jean Sibelius|Sibelius",
+    "Kuka kirjoitti Seitsemän veljestä?*Aleksis Kivi|Kivi",
+    "Mikä on Suomen kansalliseläin?*karhu|ruskeakarhu",
+    "Mikä on Suomen kansalliskukka?*kielo",
+    "Mikä on Suomen kansallislintu?*laulujoutsen|joutsen",
+    "Missä kaupungissa Muumimaailma sijaitsee?*Naantali",
+    "Mikä on Kalevalan päivä?*28. helmikuuta|28.2.",
+    "Kuka oli Suomen ensimmäinen presidentti?*K. J. Ståhlberg|Ståhlberg",
+    "Mikä alkuaine on kemialliselta merkiltään Fe?*rauta",
+    "Montako senttiä on metrissä?*100|sata",
+    "Mikä on aurinkokunnan suurin planeetta?*Jupiter",
+    "Mikä planeetta tunnetaan punaisena planeettana?*Mars",
+    "Mikä on maailman pisin joki?*Niili",
+    "Kuka maalasi Mona Lisan?*Leonardo da Vinci|da Vinci|Leonardo",
+    "Mikä on veden kemiallinen kaava?*H2O",
+    "Montako pelaajaa jääkiekkojoukkueella on kentällä maalivahti mukaan lukien?*6|kuusi",
+    "Minä vuonna Suomi voitti ensimmäisen jääkiekon maailmanmestaruutensa?*1995",
+    "Mikä on Ahvenanmaan pääkaupunki?*Maarianhamina|Mariehamn",
+    "Mikä on Suomen pisin joki?*Kemijoki",
+];
+
+pub const GAME: leaderboard::Game = leaderboard::Game {
+    id: "trivia",
+    name: "Trivia",
+};
+
+#[derive(Clone, Debug, PartialEq)]
+struct Question {
+    question: String,
+    answers: Vec<String>,
+}
+
+struct Game {
+    /// Distinguishes games, so a timeout from a stopped game is ignored
+    id: u64,
+    questions: Vec<Question>,
+    current: usize,
+    scores: HashMap<String, i64>,
+    time_limit: Duration,
+}
+
+lazy_static! {
+    static ref GAMES: Mutex<HashMap<(String, String), Game>> = Mutex::new(HashMap::new());
+}
+
+fn parse_question(line: &str) -> Option<Question> {
+    let (question, answers) = line.trim().split_once('*')?;
+    let answers: Vec<String> = answers
+        .split('|')
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty())
+        .collect();
+
+    if question.trim().is_empty() || answers.is_empty() {
+        return None;
+    }
+
+    Some(Question {
+        question: question.trim().to_owned(),
+        answers,
+    })
+}
+
+/// Questions from the files in `trivia: files` in config.yml, or the bundled ones
+fn questions_from_config(config: &Yaml) -> Vec<Question> {
+    let mut questions = Vec::new();
+
+    for file in config["trivia"]["files"]
+        .as_vec()
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.as_str())
+    {
+        match std::fs::read_to_string(file) {
+            Ok(contents) => questions.extend(contents.lines().filter_map(parse_question)),
+            Err(e) => warn!("Could not read trivia questions from {}: {}", file, e),
+        }
+    }
+
+    if questions.is_empty() {
+        DEFAULT_QUESTIONS
+            .iter()
+            .filter_map(|q| parse_question(q))
+            .collect()
+    } else {
+        questions
+    }
+}
+
+/// Lowercase words without punctuation, "K. J. Ståhlberg!" -> "k j ståhlberg"
+fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Allows one typo per five letters, numbers have to be exact
+fn is_correct(question: &Question, guess: &str) -> bool {
+    let guess = normalize(guess);
+
+    question.answers.iter().any(|answer| {
+        let answer = normalize(answer);
+        if answer.is_empty() {
+            return false;
+        }
+        if answer.chars().all(|c| c.is_numeric()) {
+            return guess == answer;
+        }
+        edit_distance(&guess, &answer) <= answer.chars().count() / 5
+    })
+}
+
+fn question_msg(game: &Game) -> String {
+    format!(
+        "Kysymys {}/{}: {}",
+        game.current + 1,
+        game.questions.len(),
+        game.questions[game.current].question
+    )
+}
+
+fn scores_msg(scores: &HashMap<String, i64>) -> String {
+    let mut scores: Vec<(&String, &i64)> = scores.iter().collect();
+    scores.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    if scores.is_empty() {
+        return "Trivia päättyi, kukaan ei saanut pisteitä.".to_owned();
+    }
+
+    let list: Vec<String> = scores
+        .iter()
+        .map(|(nick, points)| format!("{} {}", nick, points))
+        .collect();
+    format!("Trivia päättyi! Pisteet: {}", list.join(", "))
+}
+
+/// Moves to the next question, returning the message to send and whether
+/// a question was asked. The game is removed when it ends.
+fn advance(games: &mut HashMap<(String, String), Game>, key: &(String, String)) -> (String, bool) {
+    let game = match games.get_mut(key) {
+        Some(g) => g,
+        None => {
+            return (String::new(), false);
+        }
+    };
+
+    game.current += 1;
+    if game.current < game.questions.len() {
+        (question_msg(game), true)
+    } else {
+        let scores = scores_msg(&game.scores);
+        games.remove(key);
+        (scores, false)
+    }
+}
+
+fn game_key(source: &ChatTarget) -> (String, String) {
+    (source.network.to_owned(), source.channel.to_lowercase())
+}
+
+async fn send(sender: &mpsc::Sender<BotAction>, source: &ChatTarget, msg: String) {
+    let action = BotAction {
+        target: ChatTarget {
+            network: source.network.to_owned(),
+            channel: source.channel.to_owned(),
+        },
+        action_type: ActionType::Message(msg),
+    };
+
+    sender.send(action).await.unwrap();
+}
+
+/// Reveals the answer if question `index` of game `id` is still unanswered
+/// after the time limit, and asks the next one
+fn schedule_timeout(sender: mpsc::Sender<BotAction>, source: ChatTarget, id: u64, index: usize) {
+    tokio::spawn(async move {
+        let time_limit = match GAMES.lock().unwrap().get(&game_key(&source)) {
+            Some(g) => g.time_limit,
+            None => {
+                return;
+            }
+        };
+        sleep(time_limit).await;
+
+        let (answer, next) = {
+            let mut games = GAMES.lock().unwrap();
+            let key = game_key(&source);
+            match games.get(&key) {
+                Some(g) if g.id == id && g.current == index => {
+                    let answer = g.questions[index].answers[0].to_owned();
+                    (answer, advance(&mut games, &key))
+                }
+                _ => {
+                    return;
+                }
+            }
+        };
+
+        send(
+            &sender,
+            &source,
+            format!("Aika loppui! Oikea vastaus: {}", answer),
+        )
+        .await;
+        let (msg, asked) = next;
+        send(&sender, &source, msg).await;
+        if asked {
+            schedule_timeout(sender, source, id, index + 1);
+        }
+    });
+}
+
+/// Called for every message on a channel; checks answers to the current question
+pub async fn handle_trivia_answer(
+    sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    nick: &str,
+    msg: &str,
+) {
+    let result = {
+        let mut games = GAMES.lock().unwrap();
+        let key = game_key(&source);
+        let game = match games.get_mut(&key) {
+            Some(g) => g,
+            None => {
+                return;
+            }
+        };
+
+        let question = &game.questions[game.current];
+        if !is_correct(question, msg) {
+            return;
+        }
+
+        let answer = question.answers[0].to_owned();
+        let points = game.scores.entry(nick.to_owned()).or_insert(0);
+        *points += 1;
+        let correct = format!("Oikein, {}! Vastaus: {} ({} p)", nick, answer, points);
+        let (id, index) = (game.id, game.current);
+
+        (correct, advance(&mut games, &key), id, index)
+    };
+    let (correct, (next, asked), id, index) = result;
+
+    record_points(&GAME, &source, nick, 1).await;
+
+    send(&sender, &source, correct).await;
+    send(&sender, &source, next.to_owned()).await;
+    if asked {
+        schedule_timeout(sender, source, id, index + 1);
+    }
+}
+
+pub async fn command_trivia(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    params: &str,
+    config: Arc<Yaml>,
+) {
+    let mut words = params.split_whitespace();
+    let key = game_key(&source);
+
+    let msg = match (words.next(), words.next()) {
+        (Some("start"), count) => {
+            let count = match count.map(|c| c.parse::<usize>()) {
+                Some(Ok(c)) if c > 0 => c.min(MAX_QUESTION_COUNT),
+                Some(_) => {
+                    send(&bot_sender, &source, "Usage: .trivia start [n]".to_owned()).await;
+                    return;
+                }
+                None => DEFAULT_QUESTION_COUNT,
+            };
+            let time_limit = config["trivia"]["time_limit"]
+                .as_i64()
+                .filter(|t| *t > 0)
+                .map(|t| t as u64)
+                .unwrap_or(DEFAULT_TIME_LIMIT_SECS);
+
+            let started = {
+                let mut games = GAMES.lock().unwrap();
+                if let Entry::Vacant(entry) = games.entry(key) {
+                    let mut rng = thread_rng();
+                    let mut questions = questions_from_config(&config);
+                    questions.shuffle(&mut rng);
+                    questions.truncate(count);
+
+                    let game = Game {
+                        id: rng.gen(),
+                        questions,
+                        current: 0,
+                        scores: HashMap::new(),
+                        time_limit: Duration::from_secs(time_limit),
+                    };
+                    let msg = question_msg(&game);
+                    let id = game.id;
+                    entry.insert(game);
+                    Some((msg, id))
+                } else {
+                    None
+                }
+            };
+
+            match started {
+                Some((msg, id)) => {
+                    send(&bot_sender, &source, msg).await;
+                    schedule_timeout(bot_sender, source, id, 0);
+                    return;
+                }
+                None => "Trivia on jo käynnissä".to_owned(),
+            }
+        }
+        (Some("stop"), None) => match GAMES.lock().unwrap().remove(&key) {
+            Some(game) => scores_msg(&game.scores),
+            None => "Triviaa ei ole käynnissä".to_owned(),
+        },
+        (Some("top"), None) => leaderboard_msg(&GAME, &source).await,
+        _ => "Usage: .trivia start [n] | .trivia stop | .trivia top".to_owned(),
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_answers() {
+        let question = parse_question("Kuka sävelsi Finlandian? * Jean Sibelius|Sibelius").unwrap();
+        assert_eq!(question.answers, vec!["Jean Sibelius", "Sibelius"]);
+        assert!(parse_question("Ei vastausta*").is_none());
+        assert_eq!(
+            DEFAULT_QUESTIONS
+                .iter()
+                .filter_map(|q| parse_question(q))
+                .count(),
+            24
+        );
+
+        assert!(is_correct(&question, "jean sibelius!"));
+        assert!(is_correct(&question, "Sibelus"));
+        assert!(!is_correct(&question, "Sbls"));
+        assert!(!is_correct(&question, "Mozart"));
+
+        let year = parse_question("Minä vuonna Suomi itsenäistyi?*1917").unwrap();
+        assert!(is_correct(&year, " 1917 "));
+        assert!(!is_correct(&year, "1918"));
+    }
+
+    #[test]
+    fn game_flow() {
+        let mut games = HashMap::new();
+        let key = ("testnet".to_owned(), "#trivia".to_owned());
+        let mut scores = HashMap::new();
+        scores.insert("nick".to_owned(), 1);
+        scores.insert("toinen".to_owned(), 2);
+        games.insert(
+            key.clone(),
+            Game {
+                id: 1,
+                questions: vec![
+                    parse_question("Yksi?*1").unwrap(),
+                    parse_question("Kaksi?*2").unwrap(),
+                ],
+                current: 0,
+                scores,
+                time_limit: Duration::from_secs(1),
+            },
+        );
+
+        assert_eq!(question_msg(&games[&key]), "Kysymys 1/2: Yksi?");
+        assert_eq!(
+            advance(&mut games, &key),
+            ("Kysymys 2/2: Kaksi?".to_owned(), true)
+        );
+        assert_eq!(
+            advance(&mut games, &key),
+            (
+                "Trivia päättyi! Pisteet: toinen 2, nick 1".to_owned(),
+                false
+            )
+        );
+        assert!(games.is_empty());
+    }
+}
@@ -2,49 +2,171 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use log::warn;
+use log::{error, warn};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use ts3_query::*;
 use yaml_rust::yaml::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::IrcChannel;
+use crate::ChatTarget;
 
-fn get_clients(
-    host: &str,
+/// How nicknames are shown, from `teamspeak3: nick_length/initials` in config.yml
+#[derive(Debug, PartialEq)]
+struct NickFormat {
+    max_len: Option<usize>,
+    initials: bool,
+}
+
+impl NickFormat {
+    fn from_config(config: &Yaml) -> NickFormat {
+        NickFormat {
+            max_len: config["teamspeak3"]["nick_length"]
+                .as_i64()
+                .filter(|l| *l > 0)
+                .map(|l| l as usize),
+            initials: config["teamspeak3"]["initials"].as_bool().unwrap_or(false),
+        }
+    }
+
+    fn apply(&self, nick: &str) -> String {
+        let nick = if self.initials {
+            nick.split(|c: char| c.is_whitespace() || c == '_' || c == '-' || c == '.')
+                .filter_map(|part| part.chars().next())
+                .collect()
+        } else {
+            nick.to_owned()
+        };
+
+        match self.max_len {
+            Some(len) => nick.chars().take(len).collect(),
+            None => nick,
+        }
+    }
+}
+
+/// A TS3 server from config.yml, either the `teamspeak3` section itself or
+/// one of the named entries under `teamspeak3: servers:`
+#[derive(Clone, Debug, PartialEq)]
+struct ServerConfig {
+    name: String,
+    host: String,
     port: u16,
-    username: &str,
-    password: &str,
-) -> Result<Vec<String>, Ts3Error> {
-    let mut client = QueryClient::new(format!("{}:{}", host, port))?;
-    client.login(username, password)?;
-    client.select_server_by_id(1)?;
-
-    let clients_full = client.online_clients()?;
-    let real_clients: Vec<String> = clients_full
-        .iter()
-        .filter_map(|c| {
-            if c.client_type == 0 {
-                let chars = c.client_nickname.chars();
-                let mut out = String::new();
-                let max_len = 2;
-                for c in chars {
-                    out.push(c);
-                    if out.len() >= max_len {
-                        break;
-                    }
-                }
-                Some(out)
-            } else {
-                None
-            }
+    username: String,
+    password: String,
+    server_id: ServerId,
+}
+
+impl ServerConfig {
+    fn from_yaml(yaml: &Yaml, default_name: &str) -> Option<ServerConfig> {
+        Some(ServerConfig {
+            name: yaml["name"].as_str().unwrap_or(default_name).to_owned(),
+            host: yaml["host"].as_str()?.to_owned(),
+            port: yaml["port"].as_i64().unwrap_or(10011) as u16,
+            username: yaml["serverquery_login"].as_str()?.to_owned(),
+            password: yaml["serverquery_password"].as_str()?.to_owned(),
+            server_id: yaml["server_id"].as_i64().unwrap_or(1) as ServerId,
         })
+    }
+}
+
+fn servers_from_config(config: &Yaml) -> Vec<ServerConfig> {
+    match config["teamspeak3"]["servers"].as_vec() {
+        Some(servers) => servers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| ServerConfig::from_yaml(s, &(i + 1).to_string()))
+            .collect(),
+        None => ServerConfig::from_yaml(&config["teamspeak3"], "ts")
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// The server named by the first word of `params`, or the first server,
+/// and the rest of the parameters
+fn select_server<'a>(
+    servers: &'a [ServerConfig],
+    params: &'a str,
+) -> (Option<&'a ServerConfig>, &'a str) {
+    let (first, rest) = params.split_once(' ').unwrap_or((params, ""));
+
+    match servers.iter().find(|s| s.name.eq_ignore_ascii_case(first)) {
+        Some(s) => (Some(s), rest.trim()),
+        None => (servers.first(), params),
+    }
+}
+
+/// Online users grouped by channel, in the server's channel order
+struct Ts3Channel {
+    name: String,
+    nicks: Vec<String>,
+}
+
+/// Online users by channel. The channel list is only fetched `with_channels`,
+/// otherwise all users are in one unnamed group.
+fn get_clients(server: &ServerConfig, with_channels: bool) -> Result<Vec<Ts3Channel>, Ts3Error> {
+    let mut client = QueryClient::new(format!("{}:{}", server.host, server.port))?;
+    client.login(&server.username, &server.password)?;
+    client.select_server_by_id(server.server_id)?;
+
+    let channels: Option<Vec<(ChannelId, String)>> = if with_channels {
+        Some(
+            client
+                .channels()?
+                .into_iter()
+                .map(|c| (c.cid, c.channel_name))
+                .collect(),
+        )
+    } else {
+        None
+    };
+    let real_clients: Vec<(ChannelId, String)> = client
+        .online_clients()?
+        .into_iter()
+        .filter(|c| c.client_type == 0)
+        .map(|c| (c.cid, c.client_nickname))
         .collect();
 
     client.logout()?;
 
-    Ok(real_clients)
+    Ok(match channels {
+        Some(channels) => group_by_channel(&channels, &real_clients),
+        None => vec![Ts3Channel {
+            name: String::new(),
+            nicks: real_clients.into_iter().map(|(_, nick)| nick).collect(),
+        }],
+    })
+}
+
+fn group_by_channel(
+    channels: &[(ChannelId, String)],
+    clients: &[(ChannelId, String)],
+) -> Vec<Ts3Channel> {
+    channels
+        .iter()
+        .map(|(cid, name)| Ts3Channel {
+            name: name.to_owned(),
+            nicks: clients
+                .iter()
+                .filter(|(c, _)| c == cid)
+                .map(|(_, nick)| nick.to_owned())
+                .collect(),
+        })
+        .filter(|c| !c.nicks.is_empty())
+        .collect()
+}
+
+fn generate_channels_msg(channels: &[Ts3Channel]) -> String {
+    if channels.is_empty() {
+        return "TS:ssä ei ole ketään".to_owned();
+    }
+
+    channels
+        .iter()
+        .map(|c| format!("{}: {}", c.name, c.nicks.join(", ")))
+        .collect::<Vec<String>>()
+        .join(" | ")
 }
 
 fn generate_msg(nicks: Vec<String>) -> String {
@@ -57,29 +179,39 @@ fn generate_msg(nicks: Vec<String>) -> String {
 
 pub async fn command_ts(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
+    params: &str,
     config: Arc<Yaml>,
 ) {
-    let get_conf = || -> Option<(String, u16, String, String)> {
-        let host = config["teamspeak3"]["host"].as_str()?.to_owned();
-        let port = config["teamspeak3"]["port"].as_i64().unwrap_or(10011) as u16;
-        let username = config["teamspeak3"]["serverquery_login"]
-            .as_str()?
-            .to_owned();
-        let password = config["teamspeak3"]["serverquery_password"]
-            .as_str()?
-            .to_owned();
-
-        Some((host, port, username, password))
-    };
+    let servers = servers_from_config(&config);
+    let (server, params) = select_server(&servers, params);
 
-    let msg = if let Some((host, port, username, password)) = get_conf() {
-        match get_clients(&host, port, &username, &password) {
-            Ok(v) => generate_msg(v),
-            Err(e) => {
+    let msg = if let Some(server) = server {
+        let format = NickFormat::from_config(&config);
+        // ts3_query is synchronous, so keep the query off the async workers
+        let server = server.clone();
+        let with_channels = params == "channels";
+        let clients =
+            tokio::task::spawn_blocking(move || get_clients(&server, with_channels)).await;
+        match clients {
+            Ok(Ok(mut channels)) => {
+                for c in channels.iter_mut() {
+                    c.nicks = c.nicks.iter().map(|n| format.apply(n)).collect();
+                }
+                if with_channels {
+                    generate_channels_msg(&channels)
+                } else {
+                    generate_msg(channels.into_iter().flat_map(|c| c.nicks).collect())
+                }
+            }
+            Ok(Err(e)) => {
                 warn!("Error when fetching teamspeak clients: {:?}", e);
                 "Error when fetching teamspeak clients".to_owned()
             }
+            Err(e) => {
+                error!("Teamspeak query task failed: {}", e);
+                "Error when fetching teamspeak clients".to_owned()
+            }
         }
     } else {
         warn!("Unable to get teamspeak3 configuration from config file");
@@ -93,3 +225,82 @@ pub async fn command_ts(
 
     bot_sender.send(action).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn nick_format() {
+        let config = YamlLoader::load_from_str("foo: bar").unwrap();
+        let full = NickFormat::from_config(&config[0]);
+        assert_eq!(full.apply("Äijä Ölli"), "Äijä Ölli");
+
+        let config = YamlLoader::load_from_str("teamspeak3:\n  nick_length: 2").unwrap();
+        assert_eq!(NickFormat::from_config(&config[0]).apply("Äijä"), "Äi");
+
+        let config = YamlLoader::load_from_str("teamspeak3:\n  initials: true").unwrap();
+        assert_eq!(
+            NickFormat::from_config(&config[0]).apply("Matti Meikäläinen"),
+            "MM"
+        );
+        assert_eq!(NickFormat::from_config(&config[0]).apply("öljy_mies"), "öm");
+    }
+
+    #[test]
+    fn server_config() {
+        let config = YamlLoader::load_from_str(
+            "teamspeak3:\n  host: 'ts.example.com'\n  serverquery_login: 'name'\n  serverquery_password: 'pw'",
+        )
+        .unwrap();
+        let servers = servers_from_config(&config[0]);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].port, 10011);
+        assert_eq!(servers[0].server_id, 1);
+
+        let config = YamlLoader::load_from_str(
+            "teamspeak3:
+  servers:
+    - name: 'kaverit'
+      host: 'ts.example.com'
+      serverquery_login: 'name'
+      serverquery_password: 'pw'
+    - name: 'työ'
+      host: 'ts.example.com'
+      port: 10022
+      serverquery_login: 'name'
+      serverquery_password: 'pw'
+      server_id: 3",
+        )
+        .unwrap();
+        let servers = servers_from_config(&config[0]);
+        assert_eq!(servers.len(), 2);
+
+        let (server, rest) = select_server(&servers, "työ channels");
+        assert_eq!(server.unwrap().server_id, 3);
+        assert_eq!(rest, "channels");
+
+        let (server, rest) = select_server(&servers, "channels");
+        assert_eq!(server.unwrap().name, "kaverit");
+        assert_eq!(rest, "channels");
+    }
+
+    #[test]
+    fn channel_tree() {
+        let channels = vec![
+            (1, "Lobby".to_owned()),
+            (2, "AFK".to_owned()),
+            (3, "Pelit".to_owned()),
+        ];
+        let clients = vec![
+            (3, "C".to_owned()),
+            (1, "A".to_owned()),
+            (1, "B".to_owned()),
+        ];
+
+        let grouped = group_by_channel(&channels, &clients);
+        assert_eq!(generate_channels_msg(&grouped), "Lobby: A, B | Pelit: C");
+        assert_eq!(generate_channels_msg(&[]), "TS:ssä ei ole ketään");
+    }
+}
@@ -0,0 +1,75 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use irc::client::prelude::Prefix;
+use tokio::sync::mpsc;
+
+use crate::blitzortung::geocode;
+use crate::botaction::{ActionType, BotAction};
+use crate::weather_db::get_location;
+use crate::ChatTarget;
+
+const RADAR_PAGE: &str = "https://www.ilmatieteenlaitos.fi/sade-ja-pilvialueet";
+const RADAR_WMS: &str = "https://openwms.fmi.fi/geoserver/Radar/wms";
+const RADAR_LAYER: &str = "Radar:suomi_rr_eureffin";
+
+// Roughly 170 km across at Finnish latitudes
+const HALF_HEIGHT_DEG: f64 = 0.75;
+const HALF_WIDTH_DEG: f64 = 1.5;
+
+/// A rendered precipitation radar image from FMI's open WMS, centered on the coordinates
+fn radar_image_url(lat: f64, lon: f64) -> String {
+    // WMS 1.3.0 uses latitude first for EPSG:4326
+    format!(
+        "{}?service=WMS&version=1.3.0&request=GetMap&layers={}&styles=&crs=EPSG:4326&bbox={:.3},{:.3},{:.3},{:.3}&width=600&height=600&format=image/png",
+        RADAR_WMS,
+        RADAR_LAYER,
+        lat - HALF_HEIGHT_DEG,
+        lon - HALF_WIDTH_DEG,
+        lat + HALF_HEIGHT_DEG,
+        lon + HALF_WIDTH_DEG
+    )
+}
+
+pub async fn command_tutka(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+) {
+    let location = match params {
+        "" => get_location(&prefix, &source).await,
+        _ => params.to_owned(),
+    };
+
+    let msg = match geocode(&location).await {
+        Ok((lat, lon)) => format!(
+            "Sadetutka ({}): {} | {}",
+            location,
+            radar_image_url(lat, lon),
+            RADAR_PAGE
+        ),
+        Err(_) => format!("Sadetutka: {}", RADAR_PAGE),
+    };
+
+    let action = BotAction {
+        target: source,
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tutka_url() {
+        assert_eq!(
+            radar_image_url(61.4978, 23.761),
+            "https://openwms.fmi.fi/geoserver/Radar/wms?service=WMS&version=1.3.0&request=GetMap&layers=Radar:suomi_rr_eureffin&styles=&crs=EPSG:4326&bbox=60.748,22.261,62.248,25.261&width=600&height=600&format=image/png"
+        );
+    }
+}
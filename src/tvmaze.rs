@@ -3,12 +3,31 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use chrono::prelude::*;
+use chrono_tz::Tz;
+use core::time::Duration;
+use irc::client::prelude::Prefix;
 use log::{debug, error, warn};
+use rusqlite::{named_params, Connection};
 use tokio::sync::mpsc;
+use tokio::time::sleep;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::db;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::timezone::get_timezone;
+use crate::ChatTarget;
+
+// Schedules rarely change, and a single .ep can take three requests
+const CACHE_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Show queries are normalized so "The  Office" and "the office" share a cache entry
+fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
 
 #[derive(Debug)]
 enum ShowStatus {
@@ -20,6 +39,7 @@ enum ShowStatus {
 
 #[derive(Debug)]
 struct EpData {
+    id: Option<i64>,
     name: Option<String>,
     airdate: Option<DateTime<FixedOffset>>,
     season: Option<i64>,
@@ -30,6 +50,8 @@ struct EpData {
 struct ShowData {
     showname: String,
     status: Option<ShowStatus>,
+    network: Option<String>,
+    premiered: Option<NaiveDate>,
     previousep: Option<EpData>,
     nextep: Option<EpData>,
 }
@@ -37,20 +59,53 @@ struct ShowData {
 async fn get_json(showname: &str) -> reqwest::Result<String> {
     let baseurl = "https://api.tvmaze.com/singlesearch/shows";
 
-    let json = HTTP_CLIENT
-        .get(baseurl)
-        .query(&[("q", showname), ("embed", "episodes")])
-        .send()
-        .await?
-        .text()
-        .await?;
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("q", normalize_query(showname).as_str()),
+        ("embed", "episodes"),
+    ]);
+    http_client::get_cached(request, CACHE_TTL).await
+}
+
+/// Split "imdb:tt0903747" or "tvdb:81189" into the TVmaze lookup parameter and id
+fn parse_lookup(params: &str) -> Option<(&'static str, &str)> {
+    let (site, id) = params.split_once(':')?;
+    let id = id.trim();
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        return None;
+    }
+
+    match site.to_lowercase().as_str() {
+        "imdb" => Some(("imdb", id)),
+        "tvdb" | "thetvdb" => Some(("thetvdb", id)),
+        _ => None,
+    }
+}
+
+async fn get_json_by_lookup(site: &str, id: &str) -> reqwest::Result<String> {
+    let baseurl = "https://api.tvmaze.com/lookup/shows";
+
+    // The lookup redirects to the show, which does not carry the episode list
+    let request = HTTP_CLIENT.get(baseurl).query(&[(site, id)]);
+    let show = http_client::get_cached(request, CACHE_TTL).await?;
 
-    Ok(json)
+    let show_id = serde_json::from_str::<serde_json::Value>(&show)
+        .ok()
+        .and_then(|j| j["id"].as_i64());
+
+    match show_id {
+        Some(show_id) => {
+            get_url(&format!(
+                "https://api.tvmaze.com/shows/{}?embed=episodes",
+                show_id
+            ))
+            .await
+        }
+        None => Ok("null".to_owned()),
+    }
 }
 
 async fn get_url(url: &str) -> reqwest::Result<String> {
-    let j = HTTP_CLIENT.get(url).send().await?.text().await?;
-    Ok(j)
+    http_client::get_cached(HTTP_CLIENT.get(url), CACHE_TTL).await
 }
 
 async fn get_ep_info(url: &str) -> Result<EpData, String> {
@@ -62,65 +117,44 @@ async fn get_ep_info(url: &str) -> Result<EpData, String> {
             }
         };
 
-        let airdate = if let Some(airstamp) = nextj["airstamp"].as_str() {
-            if let Ok(dt) = DateTime::parse_from_rfc3339(airstamp) {
-                Some(dt)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-
-        let name = nextj["name"].as_str().map(|n| n.to_owned());
-
-        let season = nextj["season"].as_i64();
-        let number = nextj["number"].as_i64();
-
-        return Ok(EpData {
-            name,
-            airdate,
-            season,
-            number,
-        });
+        return Ok(parse_episode(&nextj));
     }
 
     Err("Error parsing JSON".to_owned())
 }
 
-fn last_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
-    if let Some(eps) = json["_embedded"]["episodes"].as_array() {
-        if let Some(ep) = eps.last() {
-            let airdate = if let Some(airstamp) = ep["airstamp"].as_str() {
-                if let Ok(dt) = DateTime::parse_from_rfc3339(airstamp) {
-                    Some(dt)
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
-
-            let name = ep["name"].as_str().map(|n| n.to_owned());
-
-            let season = ep["season"].as_i64();
-            let number = ep["number"].as_i64();
-
-            return Some(EpData {
-                name,
-                airdate,
-                season,
-                number,
-            });
+#[allow(clippy::manual_ok_err)]
+fn parse_episode(ep: &serde_json::Value) -> EpData {
+    let airdate = if let Some(airstamp) = ep["airstamp"].as_str() {
+        if let Ok(dt) = DateTime::parse_from_rfc3339(airstamp) {
+            Some(dt)
+        } else {
+            None
         }
+    } else {
+        None
+    };
+
+    EpData {
+        id: ep["id"].as_i64(),
+        name: ep["name"].as_str().map(|n| n.to_owned()),
+        airdate,
+        season: ep["season"].as_i64(),
+        number: ep["number"].as_i64(),
     }
+}
 
-    None
+fn last_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
+    json["_embedded"]["episodes"]
+        .as_array()?
+        .last()
+        .map(parse_episode)
 }
 
 fn next_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
     let now: DateTime<Utc> = Utc::now();
 
+    let mut id = None;
     let mut airdate = None;
     let mut name = None;
     let mut season = None;
@@ -131,6 +165,7 @@ fn next_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
             if let Some(airstamp) = ep["airstamp"].as_str() {
                 if let Ok(dt) = DateTime::parse_from_rfc3339(airstamp) {
                     if dt > now {
+                        id = ep["id"].as_i64();
                         airdate = Some(dt);
                         if let Some(n) = ep["name"].as_str() {
                             name = Some(n.to_owned());
@@ -145,6 +180,7 @@ fn next_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
 
         if name.is_some() && airdate.is_some() && season.is_some() && number.is_some() {
             return Some(EpData {
+                id,
                 name,
                 airdate,
                 season,
@@ -156,6 +192,21 @@ fn next_ep_from_eplist(json: &serde_json::Value) -> Option<EpData> {
     None
 }
 
+/// Name of the TV network or, for streaming shows, the web channel
+fn parse_network(json: &serde_json::Value) -> Option<String> {
+    json["network"]["name"]
+        .as_str()
+        .or_else(|| json["webChannel"]["name"].as_str())
+        .map(|n| n.to_owned())
+}
+
+fn parse_premiered(json: &serde_json::Value) -> Option<NaiveDate> {
+    json["premiered"]
+        .as_str()
+        .and_then(|p| NaiveDate::parse_from_str(p, "%Y-%m-%d").ok())
+}
+
+#[allow(clippy::collapsible_match)]
 async fn parse_json(json_text: &str) -> Result<ShowData, String> {
     let mut showname = String::new();
     let mut status = None;
@@ -233,14 +284,60 @@ async fn parse_json(json_text: &str) -> Result<ShowData, String> {
     Ok(ShowData {
         showname,
         status,
+        network: parse_network(&json),
+        premiered: parse_premiered(&json),
         nextep,
         previousep,
     })
 }
 
-fn generate_msg(data: ShowData) -> String {
+/// Air time in the user's timezone, or in the bot's local time if none is set
+fn to_timezone(dt: DateTime<FixedOffset>, tz: Option<Tz>) -> DateTime<FixedOffset> {
+    match tz {
+        Some(tz) => {
+            let t = dt.with_timezone(&tz);
+            t.with_timezone(&t.offset().fix())
+        }
+        None => {
+            let t = dt.with_timezone(&Local);
+            t.with_timezone(&t.offset().fix())
+        }
+    }
+}
+
+/// Countdown to an episode, in hours when it airs within a day
+fn time_until_next_ep(dt: DateTime<FixedOffset>, now: DateTime<Utc>) -> String {
+    let until = dt.signed_duration_since(now);
+    if until > chrono::Duration::zero() && until < chrono::Duration::hours(24) {
+        return match until.num_hours() {
+            0 => format!(", in {} minutes", until.num_minutes().max(1)),
+            1 => ", in 1 hour".to_string(),
+            h => format!(", in {} hours", h),
+        };
+    }
+
+    let today = now.with_timezone(dt.offset()).date_naive();
+    let dur = dt.date_naive().signed_duration_since(today);
+    let days = dur.num_days();
+    match days {
+        0 => ", today".to_string(),
+        1 => ", tomorrow".to_string(),
+        2.. => format!(", {} days from now", days),
+        _ => {
+            error!("Time until episode airdate was negative");
+            "".to_string()
+        }
+    }
+}
+
+#[allow(clippy::unnecessary_unwrap)]
+fn generate_msg(mut data: ShowData, tz: Option<Tz>) -> String {
+    for ep in data.nextep.iter_mut().chain(data.previousep.iter_mut()) {
+        ep.airdate = ep.airdate.map(|dt| to_timezone(dt, tz));
+    }
+
     fn time_from_last_ep(dt: DateTime<FixedOffset>) -> String {
-        let today = Local::now().date_naive();
+        let today = Utc::now().with_timezone(dt.offset()).date_naive();
         let dur = dt.date_naive().signed_duration_since(today);
         let days = -dur.num_days();
         match days {
@@ -255,27 +352,12 @@ fn generate_msg(data: ShowData) -> String {
             }
         }
     }
-    fn time_until_next_ep(dt: DateTime<FixedOffset>) -> String {
-        let today = Local::now().date_naive();
-        let dur = dt.date_naive().signed_duration_since(today);
-        let days = dur.num_days();
-        match days {
-            0 => ", today".to_string(),
-            1 => ", tomorrow".to_string(),
-            2.. => format!(", {} days from now", days),
-            _ => {
-                error!("Time until episode airdate was negative");
-                "".to_string()
-            }
-        }
-    }
-
     fn next_ep_msg(data: &ShowData) -> String {
         let msg;
         if let Some(nextep) = &data.nextep {
             if let Some(date) = nextep.airdate {
                 let datefmt = format!("{}-{:02}-{:02}", date.year(), date.month(), date.day());
-                let from_now = time_until_next_ep(date);
+                let from_now = time_until_next_ep(date, Utc::now());
 
                 if nextep.season.is_some() && nextep.number.is_some() && nextep.name.is_some() {
                     msg = format!(
@@ -337,6 +419,28 @@ fn generate_msg(data: ShowData) -> String {
         msg
     }
 
+    let network = match &data.network {
+        Some(n) => format!(" ({})", n),
+        None => "".to_owned(),
+    };
+
+    // Shows that have not premiered yet often have no episodes listed
+    let today = Local::now().date_naive();
+    if let (None, Some(date)) = (&data.nextep, data.premiered.filter(|p| *p > today)) {
+        let days = date.signed_duration_since(today).num_days();
+        let from_now = match days {
+            1 => ", tomorrow".to_string(),
+            _ => format!(", {} days from now", days),
+        };
+        return format!(
+            "{} will premiere on {}{}{}",
+            data.showname,
+            date.format("%Y-%m-%d"),
+            from_now,
+            network
+        );
+    }
+
     let msg;
 
     match data.status {
@@ -371,7 +475,7 @@ fn generate_msg(data: ShowData) -> String {
             if let Some(nextep) = data.nextep {
                 if let Some(date) = nextep.airdate {
                     let datefmt = format!("{}-{:02}-{:02}", date.year(), date.month(), date.day());
-                    let from_now = time_until_next_ep(date);
+                    let from_now = time_until_next_ep(date, Utc::now());
                     msg = format!("{} will premiere on {}{}", data.showname, datefmt, from_now);
                 } else {
                     msg = format!("{} is in development", data.showname);
@@ -384,21 +488,378 @@ fn generate_msg(data: ShowData) -> String {
             msg = next_ep_msg(&data);
         }
         None => {
-            msg = "Unknown status".to_owned();
+            return "Unknown status".to_owned();
         }
     }
 
-    msg
+    msg + &network
 }
 
-pub async fn command_ep(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel, params: &str) {
-    let msg = if let Ok(json) = get_json(params).await {
-        match parse_json(&json).await {
-            Ok(data) => generate_msg(data),
-            Err(e) => e,
+/// Most episodes listed by `.ep <show> next N`
+const MAX_UPCOMING: usize = 5;
+
+/// Split "show next 3" into the show and the number of episodes to list
+fn parse_next_count(params: &str) -> (&str, Option<usize>) {
+    if let Some((rest, n)) = params.trim_end().rsplit_once(' ') {
+        if let Some(show) = rest.strip_suffix(" next") {
+            if let Ok(n) = n.parse::<usize>() {
+                return (show.trim(), Some(n.clamp(1, MAX_UPCOMING)));
+            }
+        }
+    }
+
+    (params, None)
+}
+
+fn upcoming_from_eplist(json: &serde_json::Value, now: DateTime<Utc>, count: usize) -> Vec<EpData> {
+    json["_embedded"]["episodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(parse_episode)
+        .filter(|ep| ep.airdate.map(|a| a > now).unwrap_or(false))
+        .take(count)
+        .collect()
+}
+
+fn upcoming_msg(json_text: &str, count: usize, tz: Option<Tz>) -> String {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            return "Error parsing JSON".to_owned();
+        }
+    };
+
+    let showname = match json["name"].as_str() {
+        Some(n) => n,
+        None => {
+            return "Show not found".to_owned();
+        }
+    };
+
+    let eps: Vec<String> = upcoming_from_eplist(&json, Utc::now(), count)
+        .iter()
+        .filter_map(|ep| {
+            let date = to_timezone(ep.airdate?, tz).format("%Y-%m-%d");
+            Some(match (ep.season, ep.number, &ep.name) {
+                (Some(season), Some(number), Some(name)) => {
+                    format!("{}x{} '{}' {}", season, number, name, date)
+                }
+                _ => date.to_string(),
+            })
+        })
+        .collect();
+
+    if eps.is_empty() {
+        format!("No upcoming episodes of {} found", showname)
+    } else {
+        format!("Upcoming episodes of {}: {}", showname, eps.join(", "))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Follow {
+    id: i64,
+    target: ChatTarget,
+    show_id: i64,
+    show_name: String,
+    last_episode: Option<i64>,
+}
+
+pub const DB: db::Database = db::Database {
+    name: "tvmaze.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS follows (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            show_id INTEGER NOT NULL,
+            show_name TEXT NOT NULL,
+            last_episode INTEGER,
+            UNIQUE(network, channel, show_id)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+fn add_follow(
+    conn: &Connection,
+    source: &ChatTarget,
+    show_id: i64,
+    show_name: &str,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO follows (network, channel, show_id, show_name)
+        VALUES (:network, :channel, :show_id, :show_name)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":show_id": show_id,
+            ":show_name": show_name,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Returns whether the channel was following the show
+fn remove_follow(
+    conn: &Connection,
+    source: &ChatTarget,
+    show_name: &str,
+) -> rusqlite::Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM follows WHERE network = :network AND channel = :channel
+        AND lower(show_name) = lower(:show_name)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel,
+            ":show_name": show_name,
+        },
+    )?;
+
+    Ok(removed > 0)
+}
+
+fn get_follows(conn: &Connection) -> rusqlite::Result<Vec<Follow>> {
+    let mut statement = conn.prepare(
+        "SELECT id, network, channel, show_id, show_name, last_episode FROM follows ORDER BY id",
+    )?;
+    let mut rows = statement.query([])?;
+
+    let mut follows = Vec::new();
+    while let Some(row) = rows.next()? {
+        follows.push(Follow {
+            id: row.get(0)?,
+            target: ChatTarget {
+                network: row.get(1)?,
+                channel: row.get(2)?,
+            },
+            show_id: row.get(3)?,
+            show_name: row.get(4)?,
+            last_episode: row.get(5)?,
+        });
+    }
+
+    Ok(follows)
+}
+
+fn set_last_episode(conn: &Connection, follow_id: i64, episode_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE follows SET last_episode = :episode WHERE id = :id",
+        named_params! {
+            ":episode": episode_id,
+            ":id": follow_id,
+        },
+    )?;
+
+    Ok(())
+}
+
+async fn follow_msg(source: &ChatTarget, show: &str) -> String {
+    let json = match parse_lookup(show) {
+        Some((site, id)) => get_json_by_lookup(site, id).await,
+        None => get_json(show).await,
+    };
+    let json: serde_json::Value = match json.map(|j| serde_json::from_str(&j)) {
+        Ok(Ok(j)) => j,
+        _ => {
+            return "TVmaze API error".to_owned();
         }
+    };
+
+    let (show_id, show_name) = match (json["id"].as_i64(), json["name"].as_str()) {
+        (Some(i), Some(n)) => (i, n),
+        _ => {
+            return "Show not found".to_owned();
+        }
+    };
+
+    let (target, name) = (source.clone(), show_name.to_owned());
+    match db::call(&DB, move |c| add_follow(c, &target, show_id, &name)).await {
+        Ok(()) => format!("Announcing new episodes of {} on this channel", show_name),
+        Err(_) => "Database error".to_owned(),
+    }
+}
+
+async fn unfollow_msg(source: &ChatTarget, show: &str) -> String {
+    let (target, name) = (source.clone(), show.to_owned());
+    match db::call(&DB, move |c| remove_follow(c, &target, &name)).await {
+        Ok(true) => format!("No longer announcing {}", show),
+        Ok(false) => format!("{} is not followed on this channel", show),
+        Err(_) => "Database error".to_owned(),
+    }
+}
+
+async fn following_msg(source: &ChatTarget) -> String {
+    let follows = match db::call(&DB, get_follows).await {
+        Ok(f) => f,
+        Err(_) => {
+            return "Database error".to_owned();
+        }
+    };
+
+    let shows: Vec<&str> = follows
+        .iter()
+        .filter(|f| f.target == *source)
+        .map(|f| f.show_name.as_str())
+        .collect();
+
+    if shows.is_empty() {
+        "No shows followed on this channel".to_owned()
     } else {
-        "TVmaze API error".to_owned()
+        format!("Following: {}", shows.join(", "))
+    }
+}
+
+/// Episodes in a TVmaze episode list that air on `date` in local time
+fn episodes_airing_on(episodes: &serde_json::Value, date: NaiveDate) -> Vec<EpData> {
+    episodes
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(parse_episode)
+        .filter(|ep| {
+            ep.airdate
+                .map(|a| a.with_timezone(&Local).date_naive() == date)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn new_episode_msg(show_name: &str, ep: &EpData) -> String {
+    match (ep.season, ep.number, &ep.name) {
+        (Some(season), Some(number), Some(name)) => format!(
+            "New episode of {} ({}x{:02} '{}') airs today",
+            show_name, season, number, name
+        ),
+        _ => format!("New episode of {} airs today", show_name),
+    }
+}
+
+async fn announce_episodes(sender: &mpsc::Sender<BotAction>) {
+    let follows = match db::call(&DB, get_follows).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("Error reading followed shows: {}", e);
+            return;
+        }
+    };
+
+    let mut show_ids: Vec<i64> = follows.iter().map(|f| f.show_id).collect();
+    show_ids.sort_unstable();
+    show_ids.dedup();
+
+    let today = Local::now().date_naive();
+    let mut announced = Vec::new();
+
+    for show_id in show_ids {
+        let url = format!("https://api.tvmaze.com/shows/{}/episodes", show_id);
+        let episodes = match get_url(&url)
+            .await
+            .ok()
+            .and_then(|j| serde_json::from_str::<serde_json::Value>(&j).ok())
+        {
+            Some(e) => e,
+            None => {
+                warn!("Error fetching episodes of show {}", show_id);
+                continue;
+            }
+        };
+
+        for ep in episodes_airing_on(&episodes, today) {
+            let episode_id = match ep.id {
+                Some(i) => i,
+                None => continue,
+            };
+            for f in follows
+                .iter()
+                .filter(|f| f.show_id == show_id && f.last_episode < Some(episode_id))
+            {
+                let action = BotAction {
+                    target: ChatTarget {
+                        network: f.target.network.to_owned(),
+                        channel: f.target.channel.to_owned(),
+                    },
+                    action_type: ActionType::Message(new_episode_msg(&f.show_name, &ep)),
+                };
+                sender.send(action).await.unwrap();
+                announced.push((f.id, episode_id));
+            }
+        }
+    }
+
+    if announced.is_empty() {
+        return;
+    }
+
+    let result = db::call(&DB, move |conn| {
+        announced
+            .iter()
+            .try_for_each(|(follow_id, episode_id)| set_last_episode(conn, *follow_id, *episode_id))
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Error updating followed shows: {}", e);
+    }
+}
+
+pub async fn tvmaze_manager(sender: mpsc::Sender<BotAction>) {
+    let update_interval = Duration::from_secs(60 * 60);
+
+    loop {
+        announce_episodes(&sender).await;
+        debug!("Checked followed shows");
+        sleep(update_interval).await;
+    }
+}
+
+pub async fn command_ep(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    prefix: Option<Prefix>,
+    params: &str,
+    admin: bool,
+) {
+    let subcommand_msg = match params.split_once(' ').unwrap_or((params, "")) {
+        ("follow", "") => Some(following_msg(&source).await),
+        ("follow", _) | ("unfollow", _) if !admin => {
+            Some("Only admins can change the followed shows".to_owned())
+        }
+        ("follow", show) => Some(follow_msg(&source, show.trim()).await),
+        ("unfollow", show) if !show.is_empty() => Some(unfollow_msg(&source, show.trim()).await),
+        _ => None,
+    };
+    if let Some(msg) = subcommand_msg {
+        let action = BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        };
+        bot_sender.send(action).await.unwrap();
+        return;
+    }
+
+    let (show, next_count) = parse_next_count(params);
+
+    let json = match parse_lookup(show) {
+        Some((site, id)) => get_json_by_lookup(site, id).await,
+        None => get_json(show).await,
+    };
+
+    let tz = get_timezone(&prefix, &source.network).await;
+    let msg = match (json, next_count) {
+        (Ok(json), Some(count)) => upcoming_msg(&json, count, tz),
+        (Ok(json), None) => match parse_json(&json).await {
+            Ok(data) => generate_msg(data, tz),
+            Err(e) => e,
+        },
+        (Err(_), _) => "TVmaze API error".to_owned(),
     };
 
     let action = BotAction {
@@ -410,15 +871,147 @@ pub async fn command_ep(bot_sender: mpsc::Sender<BotAction>, source: IrcChannel,
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
     use regex::Regex;
 
+    fn open_db(testing: bool) -> rusqlite::Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn lookup_params() {
+        assert_eq!(parse_lookup("imdb:tt0903747"), Some(("imdb", "tt0903747")));
+        assert_eq!(parse_lookup("TVDB: 81189"), Some(("thetvdb", "81189")));
+        assert_eq!(parse_lookup("Star Trek: Picard"), None);
+        assert_eq!(parse_lookup("imdb:"), None);
+    }
+
+    #[test]
+    fn query_normalization() {
+        assert_eq!(normalize_query("  The   Office "), "the office");
+    }
+
+    #[test]
+    fn follows() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+
+        add_follow(&conn, &channel, 169, "Breaking Bad").unwrap();
+        add_follow(&conn, &channel, 169, "Breaking Bad").unwrap();
+        let follows = get_follows(&conn).unwrap();
+        assert_eq!(follows.len(), 1);
+        assert_eq!(follows[0].last_episode, None);
+
+        set_last_episode(&conn, follows[0].id, 12345).unwrap();
+        assert_eq!(get_follows(&conn).unwrap()[0].last_episode, Some(12345));
+
+        assert!(remove_follow(&conn, &channel, "breaking bad").unwrap());
+        assert!(!remove_follow(&conn, &channel, "breaking bad").unwrap());
+    }
+
+    #[test]
+    fn episodes_today() {
+        let episodes: serde_json::Value = serde_json::from_str(
+            r#"[
+            {"id":1,"name":"Pilot","season":1,"number":1,"airstamp":"2023-05-01T12:00:00+00:00"},
+            {"id":2,"name":"Second","season":1,"number":2,"airstamp":"2023-05-08T12:00:00+00:00"}
+        ]"#,
+        )
+        .unwrap();
+
+        let date = Utc
+            .with_ymd_and_hms(2023, 5, 8, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Local)
+            .date_naive();
+        let eps = episodes_airing_on(&episodes, date);
+        assert_eq!(eps.len(), 1);
+        assert_eq!(
+            new_episode_msg("Show", &eps[0]),
+            "New episode of Show (1x02 'Second') airs today"
+        );
+    }
+
+    #[test]
+    fn network_and_premiere() {
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"name":"Show","premiered":"2099-01-15","network":null,"webChannel":{"name":"Netflix"}}"#,
+        )
+        .unwrap();
+        assert_eq!(parse_network(&json).as_deref(), Some("Netflix"));
+
+        let data = ShowData {
+            showname: "Show".to_owned(),
+            status: Some(ShowStatus::InDevelopment),
+            network: parse_network(&json),
+            premiered: parse_premiered(&json),
+            previousep: None,
+            nextep: None,
+        };
+        let msg = generate_msg(data, None);
+        assert!(msg.starts_with("Show will premiere on 2099-01-15, "));
+        assert!(msg.ends_with(" days from now (Netflix)"));
+    }
+
+    #[test]
+    fn countdown() {
+        let now = Utc.with_ymd_and_hms(2023, 5, 8, 12, 0, 0).unwrap();
+        let airs = |h, m| {
+            to_timezone(
+                (now + chrono::Duration::hours(h) + chrono::Duration::minutes(m)).into(),
+                Some(chrono_tz::America::New_York),
+            )
+        };
+
+        assert_eq!(time_until_next_ep(airs(5, 10), now), ", in 5 hours");
+        assert_eq!(time_until_next_ep(airs(1, 0), now), ", in 1 hour");
+        assert_eq!(time_until_next_ep(airs(0, 20), now), ", in 20 minutes");
+        // 2023-05-10 08:00 in New York
+        assert_eq!(time_until_next_ep(airs(44, 0), now), ", 2 days from now");
+    }
+
+    #[test]
+    fn next_episodes() {
+        assert_eq!(
+            parse_next_count("the office next 3"),
+            ("the office", Some(3))
+        );
+        assert_eq!(
+            parse_next_count("the office next 30"),
+            ("the office", Some(5))
+        );
+        assert_eq!(
+            parse_next_count("the next generation"),
+            ("the next generation", None)
+        );
+
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{"name":"Show","_embedded":{"episodes":[
+            {"id":1,"name":"Pilot","season":1,"number":1,"airstamp":"2023-05-01T12:00:00+00:00"},
+            {"id":2,"name":"Second","season":1,"number":2,"airstamp":"2023-05-08T12:00:00+00:00"},
+            {"id":3,"name":"Third","season":1,"number":3,"airstamp":"2023-05-15T12:00:00+00:00"},
+            {"id":4,"name":"Fourth","season":1,"number":4,"airstamp":"2023-05-22T12:00:00+00:00"}
+        ]}}"#,
+        )
+        .unwrap();
+
+        let now = Utc.with_ymd_and_hms(2023, 5, 5, 0, 0, 0).unwrap();
+        let eps = upcoming_from_eplist(&json, now, 2);
+        assert_eq!(eps.len(), 2);
+        assert_eq!(eps[0].id, Some(2));
+        assert_eq!(eps[1].id, Some(3));
+    }
+
     #[tokio::test]
     async fn ended_series() {
         let json = get_json(&"Star Trek The Next Generation").await.unwrap();
         let data = parse_json(&json).await.unwrap();
-        let msg = generate_msg(data);
+        let msg = generate_msg(data, None);
 
         let re_episode_found = Regex::new(r"Last episode of Star Trek: The Next Generation 7x26 'All Good Things... \(2\)' aired on 1994-05-23, .* years ago").unwrap();
         assert!(re_episode_found.is_match(&msg));
@@ -428,13 +1021,13 @@ mod tests {
     async fn running_series() {
         let json = get_json(&"The Simpsons").await.unwrap();
         let data = parse_json(&json).await.unwrap();
-        let msg = generate_msg(data);
+        let msg = generate_msg(data, None);
 
         let re_episode_found = Regex::new(r"Next episode of The Simpsons .*airs on.*").unwrap();
 
         assert!(
             re_episode_found.is_match(&msg)
-                || msg == "No airdate found for next episode of The Simpsons"
+                || msg == "No airdate found for next episode of The Simpsons (FOX)"
         );
     }
 }
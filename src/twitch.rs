@@ -0,0 +1,457 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use chrono::prelude::*;
+use core::time::Duration;
+use log::{error, info};
+use rusqlite::{named_params, Connection, Result};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::db;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+const TOKEN_URL: &str = "https://id.twitch.tv/oauth2/token";
+const STREAMS_URL: &str = "https://api.twitch.tv/helix/streams";
+// Helix takes at most this many user_login parameters per request
+const MAX_LOGINS: usize = 100;
+// Tokens are renewed an hour early, or a tenth of their lifetime for short ones
+const REFRESH_MARGIN_SECS: i64 = 3600;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Stream {
+    id: String,
+    login: String,
+    name: String,
+    title: String,
+    game: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Follow {
+    target: ChatTarget,
+    login: String,
+    /// The stream that was last announced, None when offline
+    live_id: Option<String>,
+}
+
+lazy_static! {
+    static ref ACCESS_TOKEN: Mutex<Option<(String, DateTime<Utc>)>> = Mutex::new(None);
+}
+
+pub const DB: db::Database = db::Database {
+    name: "twitch.db",
+    migrations: &[create_tables],
+};
+
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS streamers (
+            network TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            login TEXT NOT NULL,
+            live_id TEXT,
+            PRIMARY KEY (network, channel, login)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// Returns whether the streamer was not followed already
+fn follow(conn: &Connection, source: &ChatTarget, login: &str) -> Result<bool> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO streamers (network, channel, login)
+        VALUES (:network, :channel, :login)",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+            ":login": login.to_lowercase(),
+        },
+    )?;
+
+    Ok(added > 0)
+}
+
+fn unfollow(conn: &Connection, source: &ChatTarget, login: &str) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM streamers WHERE network = :network AND channel = :channel AND login = :login",
+        named_params! {
+            ":network": source.network,
+            ":channel": source.channel.to_lowercase(),
+            ":login": login.to_lowercase(),
+        },
+    )?;
+
+    Ok(removed > 0)
+}
+
+fn followed(conn: &Connection, source: &ChatTarget) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT login FROM streamers WHERE network = :network AND channel = :channel
+        ORDER BY login",
+    )?;
+    let mut rows = statement.query(named_params! {
+        ":network": source.network,
+        ":channel": source.channel.to_lowercase(),
+    })?;
+
+    let mut logins = Vec::new();
+    while let Some(row) = rows.next()? {
+        logins.push(row.get(0)?);
+    }
+
+    Ok(logins)
+}
+
+fn all_follows(conn: &Connection) -> Result<Vec<Follow>> {
+    let mut statement = conn.prepare("SELECT network, channel, login, live_id FROM streamers")?;
+    let mut rows = statement.query([])?;
+
+    let mut follows = Vec::new();
+    while let Some(row) = rows.next()? {
+        follows.push(Follow {
+            target: ChatTarget {
+                network: row.get(0)?,
+                channel: row.get(1)?,
+            },
+            login: row.get(2)?,
+            live_id: row.get(3)?,
+        });
+    }
+
+    Ok(follows)
+}
+
+fn set_live(conn: &Connection, follow: &Follow, live_id: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE streamers SET live_id = :live_id
+        WHERE network = :network AND channel = :channel AND login = :login",
+        named_params! {
+            ":network": follow.target.network,
+            ":channel": follow.target.channel,
+            ":login": follow.login,
+            ":live_id": live_id,
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Announcements for streams that went live since the last check. The stored
+/// stream ids are updated so a stream is announced only once per channel.
+fn update_live(conn: &Connection, streams: &[Stream]) -> Result<Vec<(ChatTarget, String)>> {
+    let mut announcements = Vec::new();
+
+    for f in all_follows(conn)? {
+        let stream = streams.iter().find(|s| s.login == f.login);
+        match (stream, &f.live_id) {
+            (Some(s), Some(id)) if *id == s.id => (),
+            (Some(s), _) => {
+                set_live(conn, &f, Some(&s.id))?;
+                announcements.push((f.target, live_msg(s)));
+            }
+            (None, Some(_)) => set_live(conn, &f, None)?,
+            (None, None) => (),
+        }
+    }
+
+    Ok(announcements)
+}
+
+fn live_msg(stream: &Stream) -> String {
+    let mut msg = format!("{} went live: {}", stream.name, stream.title);
+    if !stream.game.is_empty() {
+        msg.push_str(&format!(" ({})", stream.game));
+    }
+
+    format!("{} https://twitch.tv/{}", msg, stream.login)
+}
+
+fn live_list_msg(streams: &[Stream]) -> String {
+    if streams.is_empty() {
+        return "Nobody is live".to_owned();
+    }
+
+    let list: Vec<String> = streams
+        .iter()
+        .map(|s| match s.game.as_str() {
+            "" => format!("{}: {}", s.name, s.title),
+            game => format!("{}: {} ({})", s.name, s.title, game),
+        })
+        .collect();
+    format!("Live: {}", list.join(" | "))
+}
+
+fn parse_streams(json_text: &str) -> Result<Vec<Stream>, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(json_text).map_err(|_| "Error parsing JSON".to_owned())?;
+    let text = |v: &serde_json::Value| v.as_str().unwrap_or_default().to_owned();
+
+    let streams = json["data"]
+        .as_array()
+        .ok_or_else(|| "Error parsing JSON".to_owned())?
+        .iter()
+        .filter(|s| s["type"] == "live")
+        .map(|s| Stream {
+            id: text(&s["id"]),
+            login: text(&s["user_login"]).to_lowercase(),
+            name: text(&s["user_name"]),
+            title: text(&s["title"]),
+            game: text(&s["game_name"]),
+        })
+        .collect();
+
+    Ok(streams)
+}
+
+/// How long a token given for `expires_in` seconds is used
+fn token_lifetime(expires_in: i64) -> chrono::Duration {
+    let margin = REFRESH_MARGIN_SECS.min(expires_in / 10);
+    chrono::Duration::seconds(expires_in - margin)
+}
+
+/// App access token for the client in `twitch: client_id/client_secret`
+async fn access_token(client_id: &str, client_secret: &str) -> Result<String, String> {
+    let now = Utc::now();
+    if let Some((token, expires)) = ACCESS_TOKEN.lock().unwrap().as_ref() {
+        if now < *expires {
+            return Ok(token.to_owned());
+        }
+    }
+
+    let request = HTTP_CLIENT.post(TOKEN_URL).query(&[
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+        ("grant_type", "client_credentials"),
+    ]);
+    let text = http_client::send(request)
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?
+        .text()
+        .await
+        .map_err(|e| e.to_string())?;
+    let json: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    let token = json["access_token"]
+        .as_str()
+        .ok_or("No access token")?
+        .to_owned();
+    let expires = now + token_lifetime(json["expires_in"].as_i64().unwrap_or(0));
+    *ACCESS_TOKEN.lock().unwrap() = Some((token.to_owned(), expires));
+
+    Ok(token)
+}
+
+/// Which of `logins` are streaming right now
+async fn live_streams(config: &Yaml, logins: &[String]) -> Result<Vec<Stream>, String> {
+    let (client_id, client_secret) = match (
+        config["twitch"]["client_id"].as_str(),
+        config["twitch"]["client_secret"].as_str(),
+    ) {
+        (Some(id), Some(secret)) => (id, secret),
+        _ => {
+            return Err("Twitch client is not configured".to_owned());
+        }
+    };
+    let token = access_token(client_id, client_secret).await?;
+
+    let mut streams = Vec::new();
+    for chunk in logins.chunks(MAX_LOGINS) {
+        let query: Vec<(&str, &str)> = chunk.iter().map(|l| ("user_login", l.as_str())).collect();
+        let request = HTTP_CLIENT
+            .get(STREAMS_URL)
+            .header("Client-Id", client_id)
+            .bearer_auth(&token)
+            .query(&query);
+        let text = http_client::send(request)
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())?;
+        streams.append(&mut parse_streams(&text)?);
+    }
+
+    Ok(streams)
+}
+
+/// Announces followed streamers going live every `twitch: interval` seconds
+pub async fn twitch_manager(sender: mpsc::Sender<BotAction>, config: Arc<Yaml>) {
+    let update_interval = Duration::from_secs(
+        config["twitch"]["interval"]
+            .as_i64()
+            .filter(|i| *i > 0)
+            .unwrap_or(120) as u64,
+    );
+
+    if config["twitch"]["client_id"].is_badvalue() {
+        info!("No Twitch client configured");
+        return;
+    }
+
+    loop {
+        let logins = match db::call(&DB, all_follows).await {
+            Ok(follows) => {
+                let mut logins: Vec<String> = follows.into_iter().map(|f| f.login).collect();
+                logins.sort_unstable();
+                logins.dedup();
+                logins
+            }
+            Err(e) => {
+                error!("Error reading Twitch follows: {}", e);
+                Vec::new()
+            }
+        };
+
+        if !logins.is_empty() {
+            match live_streams(&config, &logins).await {
+                Ok(streams) => match db::call(&DB, move |c| update_live(c, &streams)).await {
+                    Ok(announcements) => {
+                        for (target, msg) in announcements {
+                            let action = BotAction {
+                                target,
+                                action_type: ActionType::Message(msg),
+                            };
+                            sender.send(action).await.unwrap();
+                        }
+                    }
+                    Err(e) => error!("Error updating Twitch streams: {}", e),
+                },
+                Err(e) => error!("Error getting Twitch streams: {}", e),
+            }
+        }
+
+        sleep(update_interval).await;
+    }
+}
+
+async fn send(bot_sender: &mpsc::Sender<BotAction>, source: &ChatTarget, msg: String) {
+    let action = BotAction {
+        target: ChatTarget {
+            network: source.network.to_owned(),
+            channel: source.channel.to_owned(),
+        },
+        action_type: ActionType::Message(msg),
+    };
+
+    bot_sender.send(action).await.unwrap();
+}
+
+pub async fn command_twitch(bot_sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let mut words = params.split_whitespace();
+    let target = source.clone();
+
+    let msg = match (words.next(), words.next()) {
+        (Some("add"), Some(login)) => {
+            let streamer = login.to_owned();
+            match db::call(&DB, move |c| follow(c, &target, &streamer)).await {
+                Ok(true) => format!("Following {}", login),
+                Ok(false) => format!("Already following {}", login),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        (Some("remove"), Some(login)) => {
+            let streamer = login.to_owned();
+            match db::call(&DB, move |c| unfollow(c, &target, &streamer)).await {
+                Ok(true) => format!("Unfollowed {}", login),
+                Ok(false) => format!("Not following {}", login),
+                Err(_) => "Database error".to_owned(),
+            }
+        }
+        (Some("list"), None) => match db::call(&DB, move |c| followed(c, &target)).await {
+            Ok(logins) if logins.is_empty() => "No followed streamers".to_owned(),
+            Ok(logins) => format!("Following: {}", logins.join(", ")),
+            Err(_) => "Database error".to_owned(),
+        },
+        _ => "Usage: .twitch add <streamer> | remove <streamer> | list".to_owned(),
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+pub async fn command_live(
+    bot_sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    config: Arc<Yaml>,
+) {
+    let target = source.clone();
+    let msg = match db::call(&DB, move |c| followed(c, &target)).await {
+        Ok(logins) if logins.is_empty() => "No followed streamers".to_owned(),
+        Ok(logins) => match live_streams(&config, &logins).await {
+            Ok(streams) => live_list_msg(&streams),
+            Err(e) => {
+                error!("Error getting Twitch streams: {}", e);
+                "Twitch API error".to_owned()
+            }
+        },
+        Err(_) => "Database error".to_owned(),
+    };
+
+    send(&bot_sender, &source, msg).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
+    #[test]
+    fn go_live() {
+        let conn = open_db(true).unwrap();
+        let channel = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#Testing".to_owned(),
+        };
+        let other = ChatTarget {
+            network: "testnet".to_owned(),
+            channel: "#other".to_owned(),
+        };
+
+        assert!(follow(&conn, &channel, "Streamer").unwrap());
+        assert!(!follow(&conn, &channel, "streamer").unwrap());
+        assert!(follow(&conn, &channel, "toinen").unwrap());
+        assert!(follow(&conn, &other, "streamer").unwrap());
+        assert!(unfollow(&conn, &channel, "toinen").unwrap());
+        assert!(!unfollow(&conn, &channel, "toinen").unwrap());
+        assert_eq!(followed(&conn, &channel).unwrap(), vec!["streamer"]);
+
+        let json = r#"{"data": [{"id": "4242", "user_id": "1", "user_login": "streamer",
+            "user_name": "Streamer", "game_id": "2", "game_name": "Tetris", "type": "live",
+            "title": "Speedrun", "viewer_count": 10, "started_at": "2024-01-01T12:00:00Z"}],
+            "pagination": {}}"#;
+        let streams = parse_streams(json).unwrap();
+        assert_eq!(live_list_msg(&streams), "Live: Streamer: Speedrun (Tetris)");
+
+        let announcements = update_live(&conn, &streams).unwrap();
+        assert_eq!(announcements.len(), 2);
+        assert_eq!(
+            announcements[0].1,
+            "Streamer went live: Speedrun (Tetris) https://twitch.tv/streamer"
+        );
+        assert!(update_live(&conn, &streams).unwrap().is_empty());
+
+        // Offline, then live again
+        assert!(update_live(&conn, &[]).unwrap().is_empty());
+        assert_eq!(update_live(&conn, &streams).unwrap().len(), 2);
+        assert_eq!(live_list_msg(&[]), "Nobody is live");
+    }
+
+    #[test]
+    fn token_refresh() {
+        assert_eq!(token_lifetime(5_000_000).num_seconds(), 5_000_000 - 3600);
+        assert_eq!(token_lifetime(1800).num_seconds(), 1620);
+        assert_eq!(token_lifetime(0).num_seconds(), 0);
+    }
+}
@@ -4,36 +4,141 @@
 
 use log::debug;
 use regex::Regex;
-use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::Url;
 use select::document::Document;
 use select::predicate::Name;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::lookup_host;
 use tokio::sync::mpsc;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::http_client;
+use crate::links::{latest_link, record_link};
+use crate::ChatTarget;
+
+const MAX_REDIRECTS: usize = 10;
+// The same link is often pasted on several channels or repeated in replies
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
 
 lazy_static! {
     static ref RE_URL: Regex = Regex::new(r"(https?://[^ ]+)").unwrap();
+    // Host names are resolved by PublicResolver when connecting, also in
+    // redirects. Addresses in URLs skip the resolver, so redirects to ones
+    // that are not public are not followed.
+    static ref TITLE_CLIENT: reqwest::Client = http_client::client_builder()
+        .dns_resolver(Arc::new(PublicResolver {
+            proxy_host: http_client::proxy_host(),
+        }))
+        .redirect(Policy::custom(|attempt| {
+            if !is_public_url(attempt.url()) {
+                attempt.stop()
+            } else if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        }))
+        .build()
+        .unwrap();
 }
 
-async fn title_from_url(url: &str) -> Option<String> {
-    debug!("Trying to get title for url {}", url);
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            let this_network = a == 0;
+            let shared = a == 100 && b & 0xc0 == 64;
+            let protocol_assignments = a == 192 && b == 0 && c == 0;
+            let benchmarking = a == 198 && b & 0xfe == 18;
+            let reserved = a >= 240;
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || this_network
+                || shared
+                || protocol_assignments
+                || benchmarking
+                || reserved)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // ::ffff:a.b.c.d, the deprecated ::a.b.c.d and NAT64 64:ff9b::a.b.c.d
+            // reach IPv4 addresses
+            let embeds_v4 = segments[..5] == [0; 5] && (segments[5] == 0xffff || segments[5] == 0)
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+            if embeds_v4 && !ip.is_loopback() && !ip.is_unspecified() {
+                let [.., hi, lo] = segments;
+                let v4 = Ipv4Addr::from(((hi as u32) << 16) | lo as u32);
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let unique_local = segments[0] & 0xfe00 == 0xfc00;
+            let link_local = segments[0] & 0xffc0 == 0xfe80;
+            let documentation = segments[0] == 0x2001 && segments[1] == 0xdb8;
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || unique_local
+                || link_local
+                || documentation)
+        }
+    }
+}
 
-    lazy_static! {
-        static ref RE_WIKIPEDIA_URL: Regex =
-            Regex::new(r"https?://(?P<lang>..)\.wikipedia.org/wiki/(?P<title>[^/]+)").unwrap();
+/// Only http(s) URLs are fetched, and addresses in them must be public. Host
+/// names are checked by PublicResolver, so the bot cannot be used to look
+/// into the network it runs in.
+fn is_public_url(url: &Url) -> bool {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return false;
     }
 
-    if RE_WIKIPEDIA_URL.is_match(url) {
-        let caps = RE_WIKIPEDIA_URL.captures(url)?;
-        let title = caps.name("title")?.as_str();
-        let lang = caps.name("lang")?.as_str();
-        debug!("Looks like a Wikipedia URL");
-        return parse_wikipedia(lang, title).await;
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        Some(url::Host::Domain(_)) => true,
+        None => false,
     }
+}
 
-    let resp = match HTTP_CLIENT.get(url).send().await {
+/// Resolves host names for TITLE_CLIENT, leaving out addresses that are not
+/// public. The connection goes to the addresses checked here, so a name can't
+/// resolve to a public address for a check and a private one for the request.
+/// Through a proxy the proxy resolves the names, and only the proxy's own
+/// name is resolved here.
+struct PublicResolver {
+    proxy_host: Option<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        let is_proxy = self.proxy_host.as_deref() == Some(host.as_str());
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|a| is_proxy || is_public_ip(a.ip()))
+                .collect();
+            if addresses.is_empty() {
+                debug!("{} has no public addresses", host);
+                return Err(format!("{} has no public addresses", host).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The page at `url` if it is HTML and not too large to look for a title in,
+/// and its headers if it was fetched successfully
+async fn get_html(url: &str) -> Option<(String, Option<HeaderMap>)> {
+    let resp = match TITLE_CLIENT.get(url).send().await {
         Ok(r) => r,
         Err(e) => {
             debug!("Could not get url {}: {}", url, e);
@@ -71,12 +176,41 @@ async fn title_from_url(url: &str) -> Option<String> {
         }
     }
 
-    let body = match resp.text().await {
-        Ok(b) => b,
-        Err(_) => {
+    // Titles of error pages are shown but not kept
+    let headers = Some(headers.clone()).filter(|_| resp.status().is_success());
+    let body = resp.text().await.ok()?;
+
+    Some((body, headers))
+}
+
+async fn title_from_url(url: &str) -> Option<String> {
+    debug!("Trying to get title for url {}", url);
+
+    lazy_static! {
+        static ref RE_WIKIPEDIA_URL: Regex =
+            Regex::new(r"https?://(?P<lang>..)\.wikipedia.org/wiki/(?P<title>[^/]+)").unwrap();
+    }
+
+    if RE_WIKIPEDIA_URL.is_match(url) {
+        let caps = RE_WIKIPEDIA_URL.captures(url)?;
+        let title = caps.name("title")?.as_str();
+        let lang = caps.name("lang")?.as_str();
+        debug!("Looks like a Wikipedia URL");
+        return parse_wikipedia(lang, title).await;
+    }
+
+    match Url::parse(url) {
+        Ok(u) if is_public_url(&u) => {}
+        _ => {
+            debug!("Not a public URL: {}", url);
             return None;
         }
-    };
+    }
+
+    if let Some(title) = http_client::cached_value("title", url) {
+        return Some(title);
+    }
+    let (body, headers) = get_html(url).await?;
 
     let document = Document::from(body.as_str());
     let mut found_title = None;
@@ -106,14 +240,30 @@ async fn title_from_url(url: &str) -> Option<String> {
             title = title.replace('\t', " ");
             let trimmed = title.trim();
 
-            Some(format!("Title: {}", trimmed))
+            let title = format!("Title: {}", trimmed);
+            if let Some(headers) = headers {
+                http_client::cache_value("title", url, &headers, &title, CACHE_TTL);
+            }
+            Some(title)
         }
         None => None,
     }
 }
 
-async fn send_title(sender: mpsc::Sender<BotAction>, target: IrcChannel, url: &str) {
-    if let Some(t) = title_from_url(url).await {
+async fn send_title(
+    sender: mpsc::Sender<BotAction>,
+    target: ChatTarget,
+    nick: Option<String>,
+    url: &str,
+) {
+    let title = title_from_url(url).await;
+
+    if let Some(nick) = nick {
+        let plain_title = title.as_deref().and_then(|t| t.strip_prefix("Title: "));
+        record_link(&target, &nick, url, plain_title).await;
+    }
+
+    if let Some(t) = title {
         sender
             .send(BotAction {
                 target,
@@ -124,24 +274,58 @@ async fn send_title(sender: mpsc::Sender<BotAction>, target: IrcChannel, url: &s
     }
 }
 
-pub async fn handle_url_titles(sender: mpsc::Sender<BotAction>, source: IrcChannel, msg: &str) {
+pub async fn handle_url_titles(
+    sender: mpsc::Sender<BotAction>,
+    source: ChatTarget,
+    nick: Option<String>,
+    msg: &str,
+) {
     for mat in RE_URL.find_iter(msg) {
         let url = mat.as_str().to_string();
         debug!("URL DETECTED: {}", url);
 
         let s = sender.clone();
-        let src = IrcChannel {
+        let src = ChatTarget {
             network: source.network.to_owned(),
             channel: source.channel.to_owned(),
         };
+        let nick = nick.clone();
         tokio::spawn(async move {
-            send_title(s, src, &url).await;
+            send_title(s, src, nick, &url).await;
         });
     }
 }
 
+/// `.title <url>` fetches the title on request, without a URL the title of
+/// the latest link posted on the channel
+pub async fn command_title(sender: mpsc::Sender<BotAction>, source: ChatTarget, params: &str) {
+    let url = match RE_URL.find(params) {
+        Some(m) => Some(m.as_str().to_owned()),
+        None if params.trim().is_empty() => latest_link(&source).await,
+        None => None,
+    };
+
+    let msg = match url {
+        Some(url) => match title_from_url(&url).await {
+            Some(title) => title,
+            None => format!("No title found for {}", url),
+        },
+        None => "Usage: .title [url]".to_owned(),
+    };
+
+    sender
+        .send(BotAction {
+            target: source,
+            action_type: ActionType::Message(msg),
+        })
+        .await
+        .unwrap();
+}
+
 async fn parse_wikipedia(lang: &str, title: &str) -> Option<String> {
-    if let Ok(summary) = crate::wikipedia::get_summary(lang, title).await {
+    if let Ok(summary) =
+        crate::wikipedia::get_summary(lang, title, crate::wikipedia::DEFAULT_SENTENCES).await
+    {
         Some(format!("Title: {}", summary))
     } else {
         None
@@ -152,6 +336,48 @@ async fn parse_wikipedia(lang: &str, title: &str) -> Option<String> {
 mod tests {
     use super::*;
 
+    #[tokio::test]
+    async fn private_addresses() {
+        let public_url = |u: &str| is_public_url(&Url::parse(u).unwrap());
+        assert!(!public_url("http://127.0.0.1/"));
+        assert!(!public_url("http://[::1]:8080/"));
+        assert!(!public_url("http://192.168.1.1/admin"));
+        assert!(!public_url("ftp://8.8.8.8/"));
+        assert!(public_url("http://8.8.8.8/"));
+
+        let resolver = PublicResolver { proxy_host: None };
+        let localhost = "localhost".parse().unwrap();
+        assert!(resolver.resolve(localhost).await.is_err());
+        let proxy = PublicResolver {
+            proxy_host: Some("localhost".to_owned()),
+        };
+        assert!(proxy.resolve("localhost".parse().unwrap()).await.is_ok());
+        let refused = TITLE_CLIENT.get("http://localhost:9/").send().await;
+        assert!(format!("{:?}", refused.unwrap_err()).contains("no public addresses"));
+
+        assert!(!is_public_ip("10.1.2.3".parse().unwrap()));
+        assert!(!is_public_ip("169.254.169.254".parse().unwrap()));
+        assert!(!is_public_ip("100.64.0.1".parse().unwrap()));
+        assert!(!is_public_ip("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_public_ip("fd00::1".parse().unwrap()));
+        assert!(is_public_ip("2001:4860:4860::8888".parse().unwrap()));
+
+        for ip in [
+            "0.1.2.3",
+            "224.0.0.1",
+            "198.19.0.1",
+            "192.0.0.8",
+            "240.0.0.1",
+            "64:ff9b::7f00:1",
+            "::10.0.0.1",
+            "ff02::1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public_ip("64:ff9b::808:808".parse().unwrap()));
+        assert!(is_public_ip("198.20.0.1".parse().unwrap()));
+    }
+
     #[tokio::test]
     async fn urltitle_yle() {
         let url = "https://yle.fi/uutiset/3-11499937";
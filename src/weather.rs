@@ -0,0 +1,158 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use irc::client::prelude::Prefix;
+use std::future::Future;
+use yaml_rust::yaml::Yaml;
+
+use crate::fmi::{self, Language};
+use crate::openweathermap;
+use crate::weather_db::get_units;
+use crate::ChatTarget;
+
+/// Below this the humidex is not worth showing
+pub const HUMIDEX_MIN_TEMPERATURE: f64 = 20.0;
+
+#[derive(Debug, PartialEq)]
+pub enum WeatherError {
+    /// The provider has no data for the place, so another one may be tried
+    NotFound(String),
+    Failed(String),
+}
+
+impl WeatherError {
+    pub fn message(self) -> String {
+        match self {
+            WeatherError::NotFound(m) | WeatherError::Failed(m) => m,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Provider {
+    Fmi,
+    OpenWeatherMap,
+}
+
+/// Providers in the order they are tried
+const PROVIDERS: [Provider; 2] = [Provider::Fmi, Provider::OpenWeatherMap];
+
+impl Provider {
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Fmi => "FMI",
+            Provider::OpenWeatherMap => "OpenWeatherMap",
+        }
+    }
+
+    async fn current_weather(
+        self,
+        location: &str,
+        lang: Language,
+        prefix: &Option<Prefix>,
+        source: &ChatTarget,
+        config: &Yaml,
+    ) -> Result<String, WeatherError> {
+        match self {
+            Provider::Fmi => fmi::current_weather(location, lang).await,
+            Provider::OpenWeatherMap => {
+                let units = get_units(prefix, source).await;
+                openweathermap::current_weather(location, config, units).await
+            }
+        }
+    }
+}
+
+/// Humidex from temperature (°C) and relative humidity (%)
+pub fn humidex(temperature: f64, humidity: f64) -> f64 {
+    // https://en.wikipedia.org/wiki/Humidex
+    let vapour_pressure =
+        6.112 * 10f64.powf(7.5 * temperature / (237.7 + temperature)) * humidity / 100.0;
+
+    temperature + 5.0 / 9.0 * (vapour_pressure - 10.0)
+}
+
+/// Humidex for display, or None when it is not warm enough to matter
+pub fn summer_humidex(temperature: f64, humidity: f64) -> Option<f64> {
+    if temperature > HUMIDEX_MIN_TEMPERATURE {
+        Some(humidex(temperature, humidity))
+    } else {
+        None
+    }
+}
+
+fn label(msg: &str, provider: Provider) -> String {
+    format!("{} [{}]", msg, provider.name())
+}
+
+/// The weather from the first provider that knows the place. A failing
+/// provider is reported right away, and when none of them knows the place the
+/// error of the last one tried is shown.
+pub async fn first_weather<P, F, Fut>(providers: &[P], mut fetch: F) -> Result<(P, String), String>
+where
+    P: Copy,
+    F: FnMut(P) -> Fut,
+    Fut: Future<Output = Result<String, WeatherError>>,
+{
+    let mut last_error = String::new();
+
+    for &provider in providers {
+        match fetch(provider).await {
+            Ok(msg) => {
+                return Ok((provider, msg));
+            }
+            Err(WeatherError::NotFound(e)) => {
+                last_error = e;
+            }
+            Err(WeatherError::Failed(e)) => {
+                return Err(e);
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Current weather from the first provider that knows the place
+pub async fn current_weather(
+    location: &str,
+    lang: Language,
+    prefix: &Option<Prefix>,
+    source: &ChatTarget,
+    config: &Yaml,
+) -> String {
+    let weather = first_weather(&PROVIDERS, |p| {
+        p.current_weather(location, lang, prefix, source, config)
+    })
+    .await;
+
+    match weather {
+        Ok((provider, msg)) => label(&msg, provider),
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weather_label() {
+        assert_eq!(
+            label("Helsinki: lämpötila: 1.0°C", Provider::Fmi),
+            "Helsinki: lämpötila: 1.0°C [FMI]"
+        );
+        assert_eq!(
+            WeatherError::NotFound("Tietoja ei löytynyt".to_owned()).message(),
+            "Tietoja ei löytynyt"
+        );
+    }
+
+    #[test]
+    fn weather_humidex() {
+        assert!((humidex(30.0, 70.0) - 40.9).abs() < 0.1);
+        assert!((humidex(25.0, 50.0) - 28.2).abs() < 0.1);
+        assert_eq!(summer_humidex(15.0, 90.0), None);
+    }
+}
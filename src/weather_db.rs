@@ -3,42 +3,128 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::botaction::{ActionType, BotAction};
-use crate::IrcChannel;
+use crate::db;
+use crate::ChatTarget;
 use irc::client::prelude::Prefix;
 use rusqlite::{named_params, Connection, Result};
 use tokio::sync::mpsc;
 
 const DEFAULT_LOCATION: &str = "Helsinki";
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    fn from_str(s: &str) -> Option<Units> {
+        match s {
+            "metric" => Some(Units::Metric),
+            "imperial" => Some(Units::Imperial),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Units::Metric => "metric",
+            Units::Imperial => "imperial",
+        }
+    }
+}
+
 pub async fn command_weatherset(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     prefix: Option<Prefix>,
-    location: &str,
+    params: &str,
+    is_admin: bool,
 ) {
     if let Some(Prefix::Nickname(nick, _, _)) = prefix {
-        if let Ok(c) = open_db(false) {
-            let message = match set_location(&c, &nick, &source.network, location) {
-                Ok(()) => "Weather location set".to_owned(),
-                Err(_) => "Database error".to_owned(),
+        let params = params.to_owned();
+        let channel = source.clone();
+        let message = db::call(&DB, move |c| {
+            let source = channel;
+            let params = params.as_str();
+            let message = if let Some(u) = params.strip_prefix("channel units ") {
+                match Units::from_str(u.trim()) {
+                    Some(_) if !is_admin => "Only admins can set channel defaults".to_owned(),
+                    Some(units) => match set_units(c, &source.channel, &source.network, units) {
+                        Ok(()) => format!(
+                            "Default units for {} set to {}",
+                            source.channel,
+                            units.as_str()
+                        ),
+                        Err(_) => "Database error".to_owned(),
+                    },
+                    None => "Units must be metric or imperial".to_owned(),
+                }
+            } else if let Some(u) = params.strip_prefix("units ") {
+                match Units::from_str(u.trim()) {
+                    Some(units) => match set_units(c, &nick, &source.network, units) {
+                        Ok(()) => format!("Units set to {}", units.as_str()),
+                        Err(_) => "Database error".to_owned(),
+                    },
+                    None => "Units must be metric or imperial".to_owned(),
+                }
+            } else if let Some(l) = params.strip_prefix("channel ") {
+                if is_admin {
+                    // Channel defaults live in the same table, keyed by channel name
+                    match set_location(c, &source.channel, &source.network, l.trim()) {
+                        Ok(()) => format!(
+                            "Default weather location for {} set to {}",
+                            source.channel,
+                            l.trim()
+                        ),
+                        Err(_) => "Database error".to_owned(),
+                    }
+                } else {
+                    "Only admins can set channel defaults".to_owned()
+                }
+            } else if params.is_empty() {
+                match get_stored_location(c, &nick, &source.network) {
+                    Ok(Some(l)) => format!("Your weather location is {}", l),
+                    Ok(None) => format!(
+                        "No weather location set, using the default {}",
+                        find_location(c, None, &source)
+                            .unwrap_or_else(|_| DEFAULT_LOCATION.to_owned())
+                    ),
+                    Err(_) => "Database error".to_owned(),
+                }
+            } else if params == "delete" {
+                match delete_location(c, &nick, &source.network) {
+                    Ok(true) => "Weather location deleted".to_owned(),
+                    Ok(false) => "No weather location to delete".to_owned(),
+                    Err(_) => "Database error".to_owned(),
+                }
+            } else {
+                match set_location(c, &nick, &source.network, params) {
+                    Ok(()) => format!("Weather location set to {}", params),
+                    Err(_) => "Database error".to_owned(),
+                }
             };
 
-            let a = BotAction {
-                target: source,
-                action_type: ActionType::Message(message),
-            };
+            Ok(message)
+        })
+        .await
+        .unwrap_or_else(|_| "Database error".to_owned());
 
-            bot_sender.send(a).await.unwrap();
-        }
+        let a = BotAction {
+            target: source,
+            action_type: ActionType::Message(message),
+        };
+
+        bot_sender.send(a).await.unwrap();
     }
 }
 
-pub fn open_db(testing: bool) -> Result<Connection> {
-    let conn = match testing {
-        true => rusqlite::Connection::open(":memory:")?,
-        false => rusqlite::Connection::open("db/weather_locations.db")?,
-    };
+pub const DB: db::Database = db::Database {
+    name: "weather_locations.db",
+    migrations: &[create_tables],
+};
 
+fn create_tables(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS locations (
             id INTEGER PRIMARY KEY,
@@ -50,7 +136,19 @@ pub fn open_db(testing: bool) -> Result<Connection> {
         [],
     )?;
 
-    Ok(conn)
+    // name is either a nick or a channel, for per-channel defaults
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS units (
+            id INTEGER PRIMARY KEY,
+            network TEXT NOT NULL,
+            name TEXT NOT NULL,
+            units TEXT NOT NULL,
+            UNIQUE(network, name) ON CONFLICT REPLACE
+        )",
+        [],
+    )?;
+
+    Ok(())
 }
 
 fn get_stored_location(conn: &Connection, nick: &str, network: &str) -> Result<Option<String>> {
@@ -70,22 +168,28 @@ fn get_stored_location(conn: &Connection, nick: &str, network: &str) -> Result<O
     Ok(location)
 }
 
-pub fn get_location(prefix: &Option<Prefix>, network: &str) -> String {
-    let mut stored_location = None;
-    if let Some(Prefix::Nickname(nick, _, _)) = prefix {
-        if let Ok(c) = open_db(false) {
-            if let Ok(Some(l)) = get_stored_location(&c, nick, network) {
-                stored_location = Some(l);
-            }
+/// The user's own location, or the channel's default, or the global default
+fn find_location(conn: &Connection, nick: Option<&str>, source: &ChatTarget) -> Result<String> {
+    if let Some(nick) = nick {
+        if let Some(l) = get_stored_location(conn, nick, &source.network)? {
+            return Ok(l);
         }
     }
 
-    let location = match &stored_location {
-        Some(s) => s,
-        None => DEFAULT_LOCATION,
+    Ok(get_stored_location(conn, &source.channel, &source.network)?
+        .unwrap_or_else(|| DEFAULT_LOCATION.to_owned()))
+}
+
+pub async fn get_location(prefix: &Option<Prefix>, source: &ChatTarget) -> String {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => Some(nick.to_owned()),
+        _ => None,
     };
+    let source = source.clone();
 
-    location.to_owned()
+    db::call(&DB, move |c| find_location(c, nick.as_deref(), &source))
+        .await
+        .unwrap_or_else(|_| DEFAULT_LOCATION.to_owned())
 }
 
 pub fn set_location(conn: &Connection, nick: &str, network: &str, location: &str) -> Result<()> {
@@ -101,10 +205,73 @@ pub fn set_location(conn: &Connection, nick: &str, network: &str, location: &str
     Ok(())
 }
 
+/// Returns whether there was a location to delete
+pub fn delete_location(conn: &Connection, nick: &str, network: &str) -> Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM locations WHERE nick = :nick AND network = :network",
+        named_params! {":nick": nick, ":network": network},
+    )?;
+
+    Ok(deleted > 0)
+}
+
+pub fn set_units(conn: &Connection, name: &str, network: &str, units: Units) -> Result<()> {
+    let mut statement =
+        conn.prepare("INSERT INTO units (network, name, units) VALUES (:network, :name, :units)")?;
+    statement.execute(named_params! {
+        ":network": network,
+        ":name": name,
+        ":units": units.as_str(),
+    })?;
+
+    Ok(())
+}
+
+fn get_stored_units(conn: &Connection, name: &str, network: &str) -> Result<Option<Units>> {
+    let mut statement =
+        conn.prepare("SELECT units FROM units WHERE name = :name AND network = :network")?;
+    let mut rows = statement.query(named_params! {":name": name, ":network": network})?;
+
+    if let Some(row) = rows.next()? {
+        let units: String = row.get(0)?;
+        return Ok(Units::from_str(&units));
+    }
+
+    Ok(None)
+}
+
+fn find_units(conn: &Connection, nick: Option<&str>, source: &ChatTarget) -> Result<Units> {
+    if let Some(nick) = nick {
+        if let Some(u) = get_stored_units(conn, nick, &source.network)? {
+            return Ok(u);
+        }
+    }
+
+    Ok(get_stored_units(conn, &source.channel, &source.network)?.unwrap_or(Units::Metric))
+}
+
+/// The user's unit preference, falling back to the channel default and then metric
+pub async fn get_units(prefix: &Option<Prefix>, source: &ChatTarget) -> Units {
+    let nick = match prefix {
+        Some(Prefix::Nickname(nick, _, _)) => Some(nick.to_owned()),
+        _ => None,
+    };
+    let source = source.clone();
+
+    db::call(&DB, move |c| find_units(c, nick.as_deref(), &source))
+        .await
+        .unwrap_or(Units::Metric)
+}
+
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
+    fn open_db(testing: bool) -> Result<Connection> {
+        db::open(&DB, testing)
+    }
+
     #[test]
     fn weatherdb_setget() {
         let conn = open_db(true).unwrap();
@@ -132,5 +299,67 @@ mod tests {
 
         let diff_network = get_stored_location(&conn, &nick, &network2);
         assert_eq!(diff_network, Ok(None));
+
+        assert_eq!(delete_location(&conn, nick, network2), Ok(false));
+        assert_eq!(delete_location(&conn, nick, network), Ok(true));
+        assert_eq!(get_stored_location(&conn, nick, network), Ok(None));
+    }
+
+    #[test]
+    fn weatherdb_units() {
+        let conn = open_db(true).unwrap();
+        let source = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+
+        assert_eq!(
+            find_units(&conn, Some("testnick"), &source),
+            Ok(Units::Metric)
+        );
+
+        set_units(&conn, "#testing", "testnetwork", Units::Imperial).unwrap();
+        assert_eq!(
+            find_units(&conn, Some("testnick"), &source),
+            Ok(Units::Imperial)
+        );
+        assert_eq!(find_units(&conn, None, &source), Ok(Units::Imperial));
+
+        set_units(&conn, "testnick", "testnetwork", Units::Metric).unwrap();
+        assert_eq!(
+            find_units(&conn, Some("testnick"), &source),
+            Ok(Units::Metric)
+        );
+        assert_eq!(
+            find_units(&conn, Some("othernick"), &source),
+            Ok(Units::Imperial)
+        );
+    }
+
+    #[test]
+    fn weatherdb_channel_default() {
+        let conn = open_db(true).unwrap();
+        let source = ChatTarget {
+            network: "testnetwork".to_owned(),
+            channel: "#testing".to_owned(),
+        };
+
+        assert_eq!(
+            find_location(&conn, Some("testnick"), &source),
+            Ok(DEFAULT_LOCATION.to_owned())
+        );
+
+        set_location(&conn, "#testing", "testnetwork", "oulu").unwrap();
+        assert_eq!(
+            find_location(&conn, Some("testnick"), &source),
+            Ok("oulu".to_owned())
+        );
+
+        set_location(&conn, "testnick", "testnetwork", "tampere").unwrap();
+        assert_eq!(
+            find_location(&conn, Some("testnick"), &source),
+            Ok("tampere".to_owned())
+        );
+        assert_eq!(find_location(&conn, None, &source), Ok("oulu".to_owned()));
     }
 }
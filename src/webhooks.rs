@@ -0,0 +1,596 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Incoming webhooks at /webhook/<name>, relayed to the channel of the route
+//! in `webhooks:` in config.yml. Each route has its own token, given as a
+//! bearer token, `?token=`, GitLab's X-Gitlab-Token or as the secret of
+//! GitHub's X-Hub-Signature-256.
+
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request, Response, StatusCode};
+use log::{info, warn};
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use yaml_rust::Yaml;
+
+use crate::botaction::{ActionType, BotAction};
+use crate::http_server::read_body;
+use crate::ChatTarget;
+
+// Long pushes and alert groups are cut so a channel isn't flooded
+const MAX_LINES: usize = 4;
+// Larger requests are refused without reading them
+const MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// {"text": "..."} or {"message": "..."}
+    Generic,
+    GitHub,
+    GitLab,
+    Grafana,
+}
+
+struct Route {
+    name: String,
+    token: String,
+    target: ChatTarget,
+    format: Format,
+}
+
+fn routes(config: &Yaml) -> Vec<Route> {
+    config["webhooks"]
+        .as_vec()
+        .map(|routes| {
+            routes
+                .iter()
+                .filter_map(|r| {
+                    let format = match r["format"].as_str().unwrap_or("generic") {
+                        "generic" => Format::Generic,
+                        "github" => Format::GitHub,
+                        "gitlab" => Format::GitLab,
+                        "grafana" => Format::Grafana,
+                        f => {
+                            warn!("Unknown webhook format {}", f);
+                            return None;
+                        }
+                    };
+                    Some(Route {
+                        name: r["name"].as_str()?.to_owned(),
+                        token: r["token"].as_str().filter(|t| !t.is_empty())?.to_owned(),
+                        target: ChatTarget {
+                            network: r["network"].as_str()?.to_owned(),
+                            channel: r["channel"].as_str()?.to_owned(),
+                        },
+                        format,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn header<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok())
+}
+
+/// Whether the request carries the route's token as a bearer token,
+/// X-Gitlab-Token or `?token=`, which can be checked before reading the body
+fn token_authorized(req: &Request<Body>, token: &str) -> bool {
+    let query_token = req.uri().query().and_then(|q| {
+        url::form_urlencoded::parse(q.as_bytes())
+            .find(|(k, _)| k == "token")
+            .map(|(_, v)| v.into_owned())
+    });
+    let plain_tokens = [
+        header(req, "authorization").and_then(|a| a.strip_prefix("Bearer ")),
+        header(req, "x-gitlab-token"),
+        query_token.as_deref(),
+    ];
+    plain_tokens
+        .iter()
+        .flatten()
+        .any(|t| constant_time_eq(t.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Whether the body is signed with the route's token in X-Hub-Signature-256
+fn signature_valid(req: &Request<Body>, body: &[u8], token: &str) -> bool {
+    let signature = match header(req, "x-hub-signature-256").and_then(|s| s.strip_prefix("sha256="))
+    {
+        Some(s) => s,
+        None => {
+            return false;
+        }
+    };
+    let signature: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2)?, 16).ok())
+        .collect();
+    let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+    mac.update(body);
+
+    signature.is_some_and(|s| mac.verify_slice(&s).is_ok())
+}
+
+fn text<'a>(json: &'a serde_json::Value, pointer: &str) -> &'a str {
+    json.pointer(pointer)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+}
+
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or_default()
+}
+
+fn github_messages(event: &str, json: &serde_json::Value) -> Vec<String> {
+    let repo = text(json, "/repository/full_name");
+    let sender = text(json, "/sender/login");
+    let action = text(json, "/action");
+
+    match event {
+        "ping" => vec![format!("[{}] Webhook added: {}", repo, text(json, "/zen"))],
+        "push" => {
+            let commits = json["commits"].as_array().cloned().unwrap_or_default();
+            if commits.is_empty() {
+                return Vec::new();
+            }
+            let branch = text(json, "/ref").trim_start_matches("refs/heads/");
+            let mut messages = vec![format!(
+                "[{}] {} pushed {} commit{} to {}: {}",
+                repo,
+                text(json, "/pusher/name"),
+                commits.len(),
+                if commits.len() == 1 { "" } else { "s" },
+                branch,
+                text(json, "/compare")
+            )];
+            messages.extend(commits.iter().map(|c| {
+                format!(
+                    "{} {}: {}",
+                    text(c, "/id").get(..7).unwrap_or_default(),
+                    text(c, "/author/name"),
+                    first_line(text(c, "/message"))
+                )
+            }));
+            messages
+        }
+        "pull_request" if ["opened", "closed", "reopened"].contains(&action) => {
+            let action = if action == "closed" && json["pull_request"]["merged"] == true {
+                "merged"
+            } else {
+                action
+            };
+            vec![format!(
+                "[{}] {} {} pull request #{}: {} {}",
+                repo,
+                sender,
+                action,
+                json["pull_request"]["number"],
+                text(json, "/pull_request/title"),
+                text(json, "/pull_request/html_url")
+            )]
+        }
+        "issues" if ["opened", "closed", "reopened"].contains(&action) => vec![format!(
+            "[{}] {} {} issue #{}: {} {}",
+            repo,
+            sender,
+            action,
+            json["issue"]["number"],
+            text(json, "/issue/title"),
+            text(json, "/issue/html_url")
+        )],
+        "release" if action == "published" => vec![format!(
+            "[{}] {} released {}: {}",
+            repo,
+            sender,
+            text(json, "/release/tag_name"),
+            text(json, "/release/html_url")
+        )],
+        "workflow_run" if action == "completed" => vec![format!(
+            "[{}] {} on {}: {} {}",
+            repo,
+            text(json, "/workflow_run/name"),
+            text(json, "/workflow_run/head_branch"),
+            text(json, "/workflow_run/conclusion"),
+            text(json, "/workflow_run/html_url")
+        )],
+        _ => Vec::new(),
+    }
+}
+
+fn gitlab_messages(json: &serde_json::Value) -> Vec<String> {
+    let project = text(json, "/project/path_with_namespace");
+    let user = match text(json, "/user/username") {
+        "" => text(json, "/user_username"),
+        u => u,
+    };
+
+    match text(json, "/object_kind") {
+        "push" => {
+            let commits = json["commits"].as_array().cloned().unwrap_or_default();
+            if commits.is_empty() {
+                return Vec::new();
+            }
+            let branch = text(json, "/ref").trim_start_matches("refs/heads/");
+            let count = json["total_commits_count"]
+                .as_u64()
+                .unwrap_or(commits.len() as u64);
+            let mut messages = vec![format!(
+                "[{}] {} pushed {} commit{} to {}",
+                project,
+                user,
+                count,
+                if count == 1 { "" } else { "s" },
+                branch
+            )];
+            messages.extend(commits.iter().map(|c| {
+                format!(
+                    "{} {}: {}",
+                    text(c, "/id").get(..8).unwrap_or_default(),
+                    text(c, "/author/name"),
+                    first_line(text(c, "/message"))
+                )
+            }));
+            messages
+        }
+        "merge_request" => {
+            let action = text(json, "/object_attributes/action");
+            let action = match action {
+                "open" => "opened",
+                "close" => "closed",
+                "reopen" => "reopened",
+                "merge" => "merged",
+                _ => {
+                    return Vec::new();
+                }
+            };
+            vec![format!(
+                "[{}] {} {} merge request !{}: {} {}",
+                project,
+                user,
+                action,
+                json["object_attributes"]["iid"],
+                text(json, "/object_attributes/title"),
+                text(json, "/object_attributes/url")
+            )]
+        }
+        "issue" => {
+            let action = match text(json, "/object_attributes/action") {
+                "open" => "opened",
+                "close" => "closed",
+                "reopen" => "reopened",
+                _ => {
+                    return Vec::new();
+                }
+            };
+            vec![format!(
+                "[{}] {} {} issue #{}: {} {}",
+                project,
+                user,
+                action,
+                json["object_attributes"]["iid"],
+                text(json, "/object_attributes/title"),
+                text(json, "/object_attributes/url")
+            )]
+        }
+        "pipeline" => {
+            let status = text(json, "/object_attributes/status");
+            // Only finished pipelines, not every step in between
+            if !["success", "failed", "canceled"].contains(&status) {
+                return Vec::new();
+            }
+            vec![format!(
+                "[{}] Pipeline #{} on {}: {}",
+                project,
+                json["object_attributes"]["id"],
+                text(json, "/object_attributes/ref"),
+                status
+            )]
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn grafana_messages(json: &serde_json::Value) -> Vec<String> {
+    let mut messages = vec![format!(
+        "[grafana] {}",
+        match text(json, "/title") {
+            "" => text(json, "/status").to_uppercase(),
+            t => t.to_owned(),
+        }
+    )];
+
+    if let Some(alerts) = json["alerts"].as_array() {
+        messages.extend(alerts.iter().map(|a| {
+            let summary = match text(a, "/annotations/summary") {
+                "" => text(a, "/annotations/description"),
+                s => s,
+            };
+            format!(
+                "{} {}: {}",
+                text(a, "/status"),
+                text(a, "/labels/alertname"),
+                first_line(summary)
+            )
+            .trim_end_matches([':', ' '])
+            .to_owned()
+        }));
+    }
+
+    messages
+}
+
+/// Lines to send for a payload, `event` is the X-GitHub-Event header
+fn messages(format: Format, event: Option<&str>, json: &serde_json::Value) -> Vec<String> {
+    let mut messages = match format {
+        Format::Generic => {
+            let text = match text(json, "/text") {
+                "" => text(json, "/message"),
+                t => t,
+            };
+            text.lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(str::to_owned)
+                .collect()
+        }
+        Format::GitHub => github_messages(event.unwrap_or_default(), json),
+        Format::GitLab => gitlab_messages(json),
+        Format::Grafana => grafana_messages(json),
+    };
+
+    if messages.len() > MAX_LINES {
+        let more = messages.len() - (MAX_LINES - 1);
+        messages.truncate(MAX_LINES - 1);
+        messages.push(format!("…and {} more", more));
+    }
+
+    messages
+}
+
+fn response(status: StatusCode, body: &str) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body.to_owned()));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Handles a POST to /webhook/<name>
+pub async fn handle(
+    req: Request<Body>,
+    name: &str,
+    sender: mpsc::Sender<BotAction>,
+    config: &Yaml,
+) -> Response<Body> {
+    let route = match routes(config).into_iter().find(|r| r.name == name) {
+        Some(r) => r,
+        None => {
+            return response(StatusCode::NOT_FOUND, "");
+        }
+    };
+
+    // Only signed requests are read before they are known to be authorized
+    let has_token = token_authorized(&req, &route.token);
+    if !has_token && !req.headers().contains_key("x-hub-signature-256") {
+        warn!("Webhook {}: invalid token", route.name);
+        return response(StatusCode::UNAUTHORIZED, "Invalid token");
+    }
+
+    let (req, body) = match read_body(req, MAX_BODY).await {
+        Ok(r) => r,
+        Err(status) => {
+            return response(status, "");
+        }
+    };
+
+    if !has_token && !signature_valid(&req, &body, &route.token) {
+        warn!("Webhook {}: invalid signature", route.name);
+        return response(StatusCode::UNAUTHORIZED, "Invalid token");
+    }
+
+    let json: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(j) => j,
+        Err(_) => {
+            return response(StatusCode::BAD_REQUEST, "Invalid JSON");
+        }
+    };
+
+    let lines = messages(route.format, header(&req, "x-github-event"), &json);
+    info!("Webhook {}: relaying {} lines", route.name, lines.len());
+    for line in lines {
+        let _ = sender
+            .send(BotAction {
+                target: route.target.clone(),
+                action_type: ActionType::Message(line),
+            })
+            .await;
+    }
+
+    response(StatusCode::NO_CONTENT, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tokens() {
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "webhooks:\n  - name: ci\n    token: s3cret\n    network: IRCnet\n    channel: '#t-botti'\n    format: github\n  - name: broken\n    network: IRCnet\n    channel: '#t-botti'",
+        )
+        .unwrap();
+        let routes = routes(&config[0]);
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].format, Format::GitHub);
+
+        let request = |name: &str, value: &str| {
+            Request::builder()
+                .uri("/webhook/ci")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = br#"{"zen":"Keep it logically awesome."}"#;
+        assert!(token_authorized(
+            &request("authorization", "Bearer s3cret"),
+            "s3cret"
+        ));
+        assert!(token_authorized(
+            &request("x-gitlab-token", "s3cret"),
+            "s3cret"
+        ));
+        assert!(!token_authorized(
+            &request("x-gitlab-token", "s3cre"),
+            "s3cret"
+        ));
+
+        let query = Request::builder()
+            .uri("/webhook/ci?token=s3cret")
+            .body(Body::empty())
+            .unwrap();
+        assert!(token_authorized(&query, "s3cret"));
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(body);
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let signed = format!("sha256={}", signature);
+        assert!(signature_valid(
+            &request("x-hub-signature-256", &signed),
+            body,
+            "s3cret"
+        ));
+        assert!(!signature_valid(
+            &request("x-hub-signature-256", &signed),
+            b"{}",
+            "s3cret"
+        ));
+        assert!(!token_authorized(
+            &request("x-hub-signature-256", &signed),
+            "s3cret"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unauthorized_bodies_are_not_read() {
+        let config = yaml_rust::YamlLoader::load_from_str(
+            "webhooks:\n  - name: ci\n    token: s3cret\n    network: IRCnet\n    channel: '#t-botti'",
+        )
+        .unwrap();
+        let (tx, mut rx) = mpsc::channel(10);
+        let huge = (MAX_BODY + 1).to_string();
+        let request = |auth: Option<&str>, length: &str, body: &'static str| {
+            let mut builder = Request::builder()
+                .method("POST")
+                .uri("/webhook/ci")
+                .header("content-length", length);
+            if let Some(a) = auth {
+                builder = builder.header("authorization", a);
+            }
+            builder.body(Body::from(body)).unwrap()
+        };
+
+        let resp = handle(request(None, &huge, ""), "ci", tx.clone(), &config[0]).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = handle(
+            request(Some("Bearer s3cret"), &huge, ""),
+            "ci",
+            tx.clone(),
+            &config[0],
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(rx.try_recv().is_err());
+
+        let body = r#"{"text": "deployed"}"#;
+        let resp = handle(
+            request(Some("Bearer s3cret"), &body.len().to_string(), body),
+            "ci",
+            tx,
+            &config[0],
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            rx.recv().await.unwrap().action_type,
+            ActionType::Message("deployed".to_owned())
+        );
+    }
+
+    #[test]
+    fn payloads() {
+        assert_eq!(
+            messages(
+                Format::Generic,
+                None,
+                &json!({"text": "Backup done\n\nAll good"})
+            ),
+            vec!["Backup done", "All good"]
+        );
+
+        let push = json!({
+            "ref": "refs/heads/master",
+            "compare": "https://github.com/gestra/t-botti/compare/1234567...89abcde",
+            "repository": {"full_name": "gestra/t-botti"},
+            "pusher": {"name": "gestra"},
+            "sender": {"login": "gestra"},
+            "commits": [
+                {"id": "89abcdef0123", "message": "Add webhooks\n\nDetails", "author": {"name": "Tero"}},
+            ]
+        });
+        assert_eq!(
+            messages(Format::GitHub, Some("push"), &push),
+            vec![
+                "[gestra/t-botti] gestra pushed 1 commit to master: https://github.com/gestra/t-botti/compare/1234567...89abcde",
+                "89abcde Tero: Add webhooks"
+            ]
+        );
+
+        let merged = json!({
+            "action": "closed",
+            "repository": {"full_name": "gestra/t-botti"},
+            "sender": {"login": "gestra"},
+            "pull_request": {"number": 42, "title": "Webhooks", "merged": true, "html_url": "https://github.com/gestra/t-botti/pull/42"}
+        });
+        assert_eq!(
+            messages(Format::GitHub, Some("pull_request"), &merged),
+            vec!["[gestra/t-botti] gestra merged pull request #42: Webhooks https://github.com/gestra/t-botti/pull/42"]
+        );
+        assert!(messages(Format::GitHub, Some("star"), &merged).is_empty());
+
+        let pipeline = json!({
+            "object_kind": "pipeline",
+            "project": {"path_with_namespace": "gestra/t-botti"},
+            "user": {"username": "gestra"},
+            "object_attributes": {"id": 31, "ref": "master", "status": "failed"}
+        });
+        assert_eq!(
+            messages(Format::GitLab, None, &pipeline),
+            vec!["[gestra/t-botti] Pipeline #31 on master: failed"]
+        );
+
+        let alerts: Vec<serde_json::Value> = (0..6)
+            .map(|i| {
+                json!({"status": "firing", "labels": {"alertname": format!("Disk{}", i)}, "annotations": {"summary": "Disk almost full"}})
+            })
+            .collect();
+        let grafana = json!({"status": "firing", "title": "[FIRING:6] Disks", "alerts": alerts});
+        assert_eq!(
+            messages(Format::Grafana, None, &grafana),
+            vec![
+                "[grafana] [FIRING:6] Disks",
+                "firing Disk0: Disk almost full",
+                "firing Disk1: Disk almost full",
+                "…and 4 more"
+            ]
+        );
+    }
+}
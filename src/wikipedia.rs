@@ -3,29 +3,31 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use log::error;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use yaml_rust::Yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
+
+/// Summary length in sentences when neither config.yml nor -l sets one
+pub const DEFAULT_SENTENCES: u32 = 3;
+// The TextExtracts API returns at most ten sentences
+const MAX_SENTENCES: u32 = 10;
 
 async fn get_json(title: &str, lang: &str) -> reqwest::Result<String> {
     let baseurl = format!("https://{}.wikipedia.org/w/api.php", lang);
 
-    let json = HTTP_CLIENT
-        .get(baseurl)
-        .query(&[
-            ("action", "query"),
-            ("list", "search"),
-            ("srlimit", "1"),
-            ("srsearch", title),
-            ("srinfo", "suggestion"),
-            ("format", "json"),
-        ])
-        .send()
-        .await?
-        .text()
-        .await?;
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("action", "query"),
+        ("list", "search"),
+        ("srlimit", "1"),
+        ("srsearch", title),
+        ("srinfo", "suggestion"),
+        ("format", "json"),
+    ]);
+    let json = http_client::send(request).await?.text().await?;
 
     Ok(json)
 }
@@ -52,42 +54,42 @@ fn get_page_title_from_json(json_text: &str) -> Result<String, String> {
     }
 }
 
-async fn get_summary_json(title: &str, lang: &str) -> reqwest::Result<String> {
+async fn get_summary_json(title: &str, lang: &str, sentences: u32) -> reqwest::Result<String> {
     let baseurl = format!("https://{}.wikipedia.org/w/api.php", lang);
-    let json = HTTP_CLIENT
-        .get(baseurl)
-        .query(&[
-            ("action", "query"),
-            ("prop", "extracts"),
-            ("exsentences", "3"),
-            ("exlimit", "1"),
-            ("titles", title),
-            ("explaintext", "1"),
-            ("formatversion", "2"),
-            ("format", "json"),
-        ])
-        .send()
-        .await?
-        .text()
-        .await?;
+    let request = HTTP_CLIENT.get(baseurl).query(&[
+        ("action", "query"),
+        ("prop", "extracts|info"),
+        ("inprop", "url"),
+        ("exsentences", &sentences.to_string()),
+        ("exlimit", "1"),
+        ("titles", title),
+        ("explaintext", "1"),
+        ("formatversion", "2"),
+        ("format", "json"),
+    ]);
+    let json = http_client::send(request).await?.text().await?;
 
     Ok(json)
 }
 
-pub async fn get_summary(lang: &str, title: &str) -> Result<String, String> {
-    if let Ok(json_text) = get_summary_json(title, lang).await {
-        let json: serde_json::Value = match serde_json::from_str(&json_text) {
-            Ok(j) => j,
-            Err(_) => {
-                error!("Error parsing summary JSON");
-                return Err("Error parsing JSON".to_owned());
-            }
-        };
-
-        if let Some(e) = json["query"]["pages"][0]["extract"].as_str() {
-            let summary = e.replace('\n', " / ");
-            return Ok(summary);
+/// The summary and the canonical article URL
+fn parse_summary(json_text: &str) -> Result<(String, Option<String>), String> {
+    let json: serde_json::Value = match serde_json::from_str(json_text) {
+        Ok(j) => j,
+        Err(_) => {
+            error!("Error parsing summary JSON");
+            return Err("Error parsing JSON".to_owned());
         }
+    };
+
+    let page = &json["query"]["pages"][0];
+    if let Some(e) = page["extract"].as_str() {
+        let summary = e.replace('\n', " / ");
+        let url = page["canonicalurl"]
+            .as_str()
+            .or_else(|| page["fullurl"].as_str())
+            .map(|u| u.to_owned());
+        return Ok((summary, url));
     }
 
     error!("Error parsing summary JSON");
@@ -95,17 +97,60 @@ pub async fn get_summary(lang: &str, title: &str) -> Result<String, String> {
     Err("Error parsing summary JSON".to_owned())
 }
 
+async fn get_summary_and_url(
+    lang: &str,
+    title: &str,
+    sentences: u32,
+) -> Result<(String, Option<String>), String> {
+    match get_summary_json(title, lang, sentences).await {
+        Ok(json_text) => parse_summary(&json_text),
+        Err(_) => Err("Error fetching summary".to_owned()),
+    }
+}
+
+pub async fn get_summary(lang: &str, title: &str, sentences: u32) -> Result<String, String> {
+    get_summary_and_url(lang, title, sentences)
+        .await
+        .map(|(summary, _)| summary)
+}
+
+/// Sentence count from a leading "-l N" and the rest of the query
+fn parse_length(params: &str, default: u32) -> (u32, &str) {
+    if let Some(rest) = params.strip_prefix("-l ") {
+        if let Some((n, query)) = rest.trim_start().split_once(' ') {
+            if let Ok(n) = n.parse::<u32>() {
+                return (n.clamp(1, MAX_SENTENCES), query.trim());
+            }
+        }
+    }
+
+    (default, params)
+}
+
+fn sentences_from_config(config: &Yaml) -> u32 {
+    config["wikipedia"]["sentences"]
+        .as_i64()
+        .map(|s| (s.max(1) as u32).min(MAX_SENTENCES))
+        .unwrap_or(DEFAULT_SENTENCES)
+}
+
 async fn wikipedia_summary(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
-    title: &str,
+    source: ChatTarget,
+    params: &str,
     lang: &str,
+    config: Arc<Yaml>,
 ) {
+    let (sentences, title) = parse_length(params, sentences_from_config(&config));
+
     let msg;
     if let Ok(json) = get_json(title, lang).await {
         if let Ok(article_title) = get_page_title_from_json(&json) {
-            if let Ok(summary) = get_summary(lang, &article_title).await {
-                msg = summary;
+            if let Ok((summary, url)) = get_summary_and_url(lang, &article_title, sentences).await {
+                msg = match url {
+                    Some(url) => format!("{} | {}", summary, url),
+                    None => summary,
+                };
             } else {
                 msg = "API error".to_owned();
             }
@@ -126,27 +171,52 @@ async fn wikipedia_summary(
 
 pub async fn command_wikipedia(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
+    config: Arc<Yaml>,
 ) {
-    wikipedia_summary(bot_sender, source, params, "en").await;
+    wikipedia_summary(bot_sender, source, params, "en", config).await;
 }
 
 pub async fn command_wikipediafi(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
+    config: Arc<Yaml>,
 ) {
-    wikipedia_summary(bot_sender, source, params, "fi").await;
+    wikipedia_summary(bot_sender, source, params, "fi", config).await;
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn summary_length_and_url() {
+        assert_eq!(parse_length("-l 1 Taiko drum", 3), (1, "Taiko drum"));
+        assert_eq!(parse_length("-l 50 Taiko", 3), (10, "Taiko"));
+        assert_eq!(parse_length("Taiko", 3), (3, "Taiko"));
+        assert_eq!(parse_length("-l Taiko", 3), (3, "-l Taiko"));
+
+        let json = r#"{"batchcomplete":true,"query":{"pages":[{"pageid":1,"ns":0,"title":"Taiko",
+            "extract":"Taiko are drums.\nThey are Japanese.",
+            "fullurl":"https://en.wikipedia.org/wiki/Taiko",
+            "canonicalurl":"https://en.wikipedia.org/wiki/Taiko"}]}}"#;
+        assert_eq!(
+            parse_summary(json),
+            Ok((
+                "Taiko are drums. / They are Japanese.".to_owned(),
+                Some("https://en.wikipedia.org/wiki/Taiko".to_owned())
+            ))
+        );
+    }
+
     #[tokio::test]
     async fn en_wikipedia_title() {
-        let summary = get_summary(&"en", &"Taiko").await.unwrap();
+        let summary = get_summary(&"en", &"Taiko", DEFAULT_SENTENCES)
+            .await
+            .unwrap();
 
         assert!(summary.starts_with("Taiko (太鼓)"));
     }
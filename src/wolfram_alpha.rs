@@ -8,28 +8,53 @@ use tokio::sync::mpsc;
 use yaml_rust::yaml;
 
 use crate::botaction::{ActionType, BotAction};
-use crate::http_client::HTTP_CLIENT;
-use crate::IrcChannel;
+use crate::http_client::{self, HTTP_CLIENT};
+use crate::ChatTarget;
 
 async fn get_xml(query: &str, appid: &str) -> reqwest::Result<String> {
     let apiurl = "http://api.wolframalpha.com/v2/query";
 
-    let xml = HTTP_CLIENT
+    let request = HTTP_CLIENT
         .get(apiurl)
-        .query(&[("appid", appid), ("input", query)])
-        .send()
-        .await?
-        .text()
-        .await?;
+        .query(&[("appid", appid), ("input", query)]);
+    let xml = http_client::send(request).await?.text().await?;
 
     Ok(xml)
 }
 
+async fn get_short_answer(query: &str, appid: &str) -> reqwest::Result<Option<String>> {
+    let apiurl = "http://api.wolframalpha.com/v1/result";
+
+    let request = HTTP_CLIENT
+        .get(apiurl)
+        .query(&[("appid", appid), ("i", query)]);
+    let response = http_client::send(request).await?;
+
+    // 501 means there is no short answer for the input
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+
+    let answer = response.text().await?;
+    Ok(Some(answer.trim().to_owned()).filter(|a| !a.is_empty()))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Pods {
+    interpretation: Option<String>,
+    answer: Option<String>,
+    didyoumean: Option<String>,
+}
+
 fn clean_plaintext(text: &str) -> String {
-    text.to_string().replace(" | ", ": ").replace('\n', " | ").trim().to_owned()
+    text.to_string()
+        .replace(" | ", ": ")
+        .replace('\n', " | ")
+        .trim()
+        .to_owned()
 }
 
-fn response_from_xml(xml: &str) -> Result<String, String> {
+fn parse_xml(xml: &str) -> Result<Pods, String> {
     let root = match xmltree::Element::parse(xml.as_bytes()) {
         Ok(r) => r,
         Err(_) => {
@@ -98,34 +123,98 @@ fn response_from_xml(xml: &str) -> Result<String, String> {
         }
     }
 
-    let msg = if interpretation.is_some() && answer.is_some() {
-        format!("{} = {}", interpretation.unwrap(), answer.unwrap())
-    } else if answer.is_some() {
-        answer.unwrap()
-    } else if didyoumean.is_some() {
-        format!("Did you mean: {}", didyoumean.unwrap())
-    } else {
-        "Sorry, couldn't understand the question".to_owned()
+    Ok(Pods {
+        interpretation,
+        answer,
+        didyoumean,
+    })
+}
+
+/// The answer from the full results pods, if there is one
+fn pods_msg(pods: &Pods) -> Option<String> {
+    match (&pods.interpretation, &pods.answer) {
+        (Some(i), Some(a)) => Some(format!("{} = {}", i, a)),
+        (None, Some(a)) => Some(a.to_owned()),
+        _ => None,
+    }
+}
+
+/// Answer from the full results API, falling back to the Short Answers API
+async fn answer(query: &str, appid: &str) -> (Option<String>, Option<String>) {
+    let pods = match get_xml(query, appid).await.map(|xml| parse_xml(&xml)) {
+        Ok(Ok(p)) => p,
+        _ => Pods::default(),
     };
 
-    Ok(msg)
+    if let Some(msg) = pods_msg(&pods) {
+        return (Some(msg), None);
+    }
+
+    match get_short_answer(query, appid).await {
+        Ok(Some(short)) => (Some(short), None),
+        _ => (None, pods.didyoumean),
+    }
+}
+
+pub async fn response(query: &str, appid: &str) -> String {
+    let didyoumean = match answer(query, appid).await {
+        (Some(msg), _) => {
+            return msg;
+        }
+        (None, Some(d)) => d,
+        (None, None) => {
+            return "Sorry, couldn't understand the question".to_owned();
+        }
+    };
+
+    // Ask again with Wolfram|Alpha's own rewrite of the query
+    match answer(&didyoumean, appid).await {
+        (Some(msg), _) => format!("(did you mean: {}) {}", didyoumean, msg),
+        _ => format!("Did you mean: {}", didyoumean),
+    }
 }
 
 pub async fn command_wa(
     bot_sender: mpsc::Sender<BotAction>,
-    source: IrcChannel,
+    source: ChatTarget,
     params: &str,
     config: Arc<yaml::Yaml>,
 ) {
     if let Some(apikey) = config["wolfram_alpha"]["apikey"].as_str() {
-        if let Ok(xml) = get_xml(params, apikey).await {
-            if let Ok(response) = response_from_xml(&xml) {
-                let action = BotAction {
-                    target: source,
-                    action_type: ActionType::Message(response),
-                };
-                bot_sender.send(action).await.unwrap();
-            }
-        }
+        let action = BotAction {
+            target: source,
+            action_type: ActionType::Message(response(params, apikey).await),
+        };
+        bot_sender.send(action).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pods() {
+        let xml = r#"<?xml version='1.0' encoding='UTF-8'?>
+<queryresult success='true' error='false' numpods='2'>
+ <pod title='Input' id='Input'>
+  <subpod title=''><plaintext>2 + 2</plaintext></subpod>
+ </pod>
+ <pod title='Result' id='Result' primary='true'>
+  <subpod title=''><plaintext>4</plaintext></subpod>
+ </pod>
+</queryresult>"#;
+        let pods = parse_xml(xml).unwrap();
+        assert_eq!(pods_msg(&pods), Some("2 + 2 = 4".to_owned()));
+
+        let xml = r#"<?xml version='1.0' encoding='UTF-8'?>
+<queryresult success='false' error='false' numpods='0'>
+ <didyoumeans count='1'>
+  <didyoumean score='0.5' level='medium'>population of finland</didyoumean>
+ </didyoumeans>
+</queryresult>"#;
+        let pods = parse_xml(xml).unwrap();
+        assert_eq!(pods_msg(&pods), None);
+        assert_eq!(pods.didyoumean.as_deref(), Some("population of finland"));
     }
 }
@@ -0,0 +1,60 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use tbotti::botaction::{ActionType, BotAction};
+use tbotti::config::Yaml;
+use tbotti::message_handler::handle_command;
+use tbotti::ChatTarget;
+
+fn channel() -> ChatTarget {
+    ChatTarget {
+        network: "testnet".to_owned(),
+        channel: "#testing".to_owned(),
+    }
+}
+
+/// The replies to `message` without a running bot
+async fn replies(message: &str) -> Vec<BotAction> {
+    let (bot_tx, mut bot_rx) = mpsc::channel(10);
+    let (timer_tx, _timer_rx) = mpsc::channel(10);
+    let (clientquery_tx, _clientquery_rx) = mpsc::channel(10);
+
+    handle_command(
+        bot_tx,
+        timer_tx,
+        clientquery_tx,
+        channel(),
+        message,
+        None,
+        Arc::new(Yaml::Null),
+    )
+    .await;
+
+    let mut actions = Vec::new();
+    while let Some(action) = bot_rx.recv().await {
+        actions.push(action);
+    }
+    actions
+}
+
+#[tokio::test]
+async fn commands() {
+    assert_eq!(
+        replies(".echo moi").await,
+        vec![BotAction {
+            target: channel(),
+            action_type: ActionType::Message("Echo: moi".to_owned()),
+        }]
+    );
+    assert_eq!(
+        replies(".CALC 2 * (3 + 4)").await,
+        vec![BotAction {
+            target: channel(),
+            action_type: ActionType::Message("2 * (3 + 4) = 14".to_owned()),
+        }]
+    );
+}